@@ -0,0 +1,116 @@
+//! Selection of the libproxyc build matching the architecture of the hooked
+//! program, a 64 bits library cannot be preloaded in a 32 bits process.
+use anyhow::{anyhow, bail, Result};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Paths of the libproxyc builds for other architectures, `{}` standing for
+/// the target triple, besides the proxyc directory next to the library of
+/// the host, where they are installed.
+#[cfg(debug_assertions)]
+const VARIANT_LIB_PATHS: [&str; 1] = ["./target/{}/debug/libproxyc.so"];
+#[cfg(not(debug_assertions))]
+const VARIANT_LIB_PATHS: [&str; 0] = [];
+
+/// Architectures libproxyc is built for: ELF class, ELF machine, target
+/// triple and short name.
+const ARCHS: [(u8, u16, &str, &str); 4] = [
+    (2, 62, "x86_64-unknown-linux-gnu", "x86_64"),
+    (2, 183, "aarch64-unknown-linux-gnu", "aarch64"),
+    (1, 3, "i686-unknown-linux-gnu", "i686"),
+    (1, 40, "armv7-unknown-linux-gnueabihf", "armhf"),
+];
+
+/// Class and machine of an ELF binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ElfArch {
+    class: u8,
+    machine: u16,
+}
+
+/// Reads the architecture of an ELF binary, None if it is not one.
+fn elf_arch(path: &Path) -> Option<ElfArch> {
+    let mut header = [0; 20];
+    File::open(path).ok()?.read_exact(&mut header).ok()?;
+    if header[..4] != *b"\x7fELF" {
+        return None;
+    }
+    let machine = match header[5] {
+        1 => u16::from_le_bytes([header[18], header[19]]),
+        2 => u16::from_be_bytes([header[18], header[19]]),
+        _ => return None,
+    };
+    Some(ElfArch {
+        class: header[4],
+        machine,
+    })
+}
+
+/// Finds the binary executed for `program`, searching the PATH like execvp
+/// and following the interpreter of scripts.
+fn resolve_program(program: &str) -> Option<PathBuf> {
+    let path = match program.contains('/') {
+        true => PathBuf::from(program),
+        false => std::env::var_os("PATH")
+            .iter()
+            .flat_map(std::env::split_paths)
+            .map(|dir| dir.join(program))
+            .find(|p| p.is_file())?,
+    };
+
+    let mut head = [0; 256];
+    let len = File::open(&path).ok()?.read(&mut head).ok()?;
+    match head[..len].strip_prefix(b"#!") {
+        Some(shebang) => {
+            let line = shebang.split(|&b| b == b'\n').next()?;
+            let interpreter = String::from_utf8_lossy(line);
+            interpreter.split_whitespace().next().map(PathBuf::from)
+        }
+        None => Some(path),
+    }
+}
+
+/// Returns the libproxyc to preload in `program`, `host_lib` unless the
+/// program was built for another architecture than proxyc.
+pub fn library_for(program: &str, host_lib: &str) -> Result<String> {
+    let arch = match resolve_program(program).and_then(|p| elf_arch(&p)) {
+        Some(a) => a,
+        // exec() reports missing programs
+        None => return Ok(host_lib.to_string()),
+    };
+    if elf_arch(Path::new("/proc/self/exe")) == Some(arch) {
+        return Ok(host_lib.to_string());
+    }
+
+    let (triple, name) = match ARCHS
+        .iter()
+        .find(|(class, machine, ..)| arch.class == *class && arch.machine == *machine)
+    {
+        Some((_, _, triple, name)) => (triple, name),
+        None => bail!(
+            "{} is built for an unsupported architecture (ELF machine {})",
+            program,
+            arch.machine
+        ),
+    };
+
+    let installed = Path::new(host_lib)
+        .parent()
+        .map(|dir| dir.join("proxyc").join(triple).join("libproxyc.so"));
+    VARIANT_LIB_PATHS
+        .iter()
+        .map(|p| PathBuf::from(p.replace("{}", triple)))
+        .chain(installed)
+        .find(|p| p.is_file())
+        .and_then(|p| std::fs::canonicalize(p).ok())
+        .map(|p| p.display().to_string())
+        .ok_or_else(|| {
+            anyhow!(
+                "{} is built for {}, but libproxyc is not built for {}",
+                program,
+                name,
+                triple
+            )
+        })
+}
@@ -0,0 +1,130 @@
+//! Summary of the audit files written by hooked programs.
+use anyhow::{Context, Result};
+use proxyc_common::AuditRecord;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+/// Totals of a group of connections.
+#[derive(Debug, Default)]
+struct Totals {
+    connections: u64,
+    failures: u64,
+    sent: u64,
+    received: u64,
+}
+
+impl Totals {
+    fn add(&mut self, r: &AuditRecord) {
+        self.connections += 1;
+        if r.error.is_some() {
+            self.failures += 1;
+        }
+        self.sent += r.bytes_sent;
+        self.received += r.bytes_received;
+    }
+
+    fn print(&self, name: &str) {
+        println!(
+            "  {}: {} connections, {} failed, {} sent, {} received",
+            name,
+            self.connections,
+            self.failures,
+            human_bytes(self.sent),
+            human_bytes(self.received)
+        );
+    }
+}
+
+pub fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let mut size = n as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn human_duration(ms: u64) -> String {
+    let secs = ms / 1000;
+    match secs {
+        0..=59 => format!("{}.{:03}s", secs, ms % 1000),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60),
+    }
+}
+
+/// Groups the records by key, the largest groups first.
+fn group<'a>(
+    records: &'a [AuditRecord],
+    key: impl Fn(&'a AuditRecord) -> String,
+) -> Vec<(String, Totals)> {
+    let mut groups: HashMap<String, Totals> = HashMap::new();
+    for r in records {
+        groups.entry(key(r)).or_default().add(r);
+    }
+    let mut groups: Vec<_> = groups.into_iter().collect();
+    groups.sort_by(|a, b| {
+        b.1.connections
+            .cmp(&a.1.connections)
+            .then_with(|| a.0.cmp(&b.0))
+    });
+    groups
+}
+
+/// Prints a summary of an audit file.
+pub fn report(path: &Path) -> Result<()> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+    let mut records = vec![];
+    for (i, line) in content.lines().enumerate().filter(|(_, l)| !l.is_empty()) {
+        records.push(
+            serde_json::from_str::<AuditRecord>(line)
+                .with_context(|| format!("invalid record at line {}", i + 1))?,
+        );
+    }
+
+    let mut total = Totals::default();
+    records.iter().for_each(|r| total.add(r));
+    let pids: BTreeSet<_> = records.iter().map(|r| r.pid).collect();
+    let span = match (
+        records.iter().map(|r| r.start).min(),
+        records.iter().map(|r| r.end).max(),
+    ) {
+        (Some(start), Some(end)) => end.saturating_sub(start),
+        _ => 0,
+    };
+
+    println!(
+        "{} connections from {} processes over {}, {} failed, {} sent, {} received",
+        total.connections,
+        pids.len(),
+        human_duration(span),
+        total.failures,
+        human_bytes(total.sent),
+        human_bytes(total.received)
+    );
+
+    println!("destinations:");
+    for (name, t) in group(&records, |r| r.target.clone()) {
+        t.print(&name);
+    }
+
+    println!("chains:");
+    for (name, t) in group(&records, |r| r.chain.join(" -> ")) {
+        t.print(&name);
+    }
+
+    let failed: Vec<_> = records.into_iter().filter(|r| r.error.is_some()).collect();
+    if !failed.is_empty() {
+        println!("errors:");
+        for (name, t) in group(&failed, |r| r.error.clone().unwrap_or_default()) {
+            println!("  {}: {}", name, t.connections);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,320 @@
+//! Concurrent benchmarking of a proxy pool.
+use anyhow::{anyhow, bail, Context, Result};
+use proxyc_common::{Auth, AuthMethod, ProxyConf, ProxyType};
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Outcome of the benchmark of one proxy.
+#[derive(Debug)]
+pub struct Report {
+    pub proxy: ProxyConf,
+    /// Time to connect to the proxy and complete the handshake.
+    pub latency: Option<Duration>,
+    /// Address the target saw the request coming from.
+    pub exit_ip: Option<IpAddr>,
+    pub error: Option<String>,
+}
+
+/// Target echoing the address of its client, queried through each proxy.
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+    /// Address of the target, for the protocols unable to send hostnames.
+    ip: Option<IpAddr>,
+}
+
+impl Target {
+    /// Parses `host[:port][/path]`, the port defaulting to 80.
+    pub fn parse(s: &str) -> Result<Self> {
+        let (hostport, path) = match s.find('/') {
+            Some(i) => (&s[..i], s[i..].to_string()),
+            None => (s, "/".to_string()),
+        };
+        let (host, port) = match hostport.rsplit_once(':') {
+            Some((h, p)) => (
+                h,
+                p.parse()
+                    .with_context(|| format!("invalid target port {:?}", p))?,
+            ),
+            None => (hostport, 80),
+        };
+        if host.is_empty() || host.len() > 255 {
+            bail!("invalid target host {:?}", host);
+        }
+
+        let ip = (host, port)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut a| a.find(SocketAddr::is_ipv4))
+            .map(|a| a.ip());
+        Ok(Target {
+            host: host.to_string(),
+            port,
+            path,
+            ip,
+        })
+    }
+}
+
+/// Benchmarks every proxy with `jobs` concurrent workers, `auth` being used
+/// with the proxies without credentials. Returns the reports sorted by
+/// latency, the dead proxies last.
+pub fn run(
+    proxies: &[ProxyConf],
+    auth: Option<&Auth>,
+    target: &Target,
+    jobs: usize,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+) -> Vec<Report> {
+    let total = proxies.len();
+    let next = AtomicUsize::new(0);
+    let reports = Mutex::new(Vec::with_capacity(total));
+
+    std::thread::scope(|s| {
+        for _ in 0..jobs.clamp(1, total.max(1)) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= total {
+                    break;
+                }
+                let report = bench(
+                    proxies[i].clone(),
+                    auth,
+                    target,
+                    connect_timeout,
+                    read_timeout,
+                );
+                reports.lock().unwrap().push(report);
+            });
+        }
+    });
+
+    let mut reports = reports.into_inner().unwrap();
+    reports.sort_by_key(|r| (r.latency.is_none(), r.latency));
+    reports
+}
+
+fn bench(
+    proxy: ProxyConf,
+    auth: Option<&Auth>,
+    target: &Target,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+) -> Report {
+    let start = Instant::now();
+    // each proxy is benchmarked as a first hop, named ones resolve locally
+    let res = proxy_addr(&proxy)
+        .and_then(|addr| Ok(TcpStream::connect_timeout(&addr, connect_timeout)?))
+        .and_then(|mut sock| {
+            sock.set_read_timeout(Some(read_timeout))?;
+            sock.set_write_timeout(Some(read_timeout))?;
+            handshake(&mut sock, &proxy, proxy.auth.as_ref().or(auth), target)?;
+            let latency = start.elapsed();
+            let exit_ip = match proxy.proto {
+                // a raw proxy leads to an unknown service
+                ProxyType::Raw => None,
+                _ => Some(query_exit_ip(&mut sock, target)?),
+            };
+            Ok((latency, exit_ip))
+        });
+
+    match res {
+        Ok((latency, exit_ip)) => Report {
+            proxy,
+            latency: Some(latency),
+            exit_ip,
+            error: None,
+        },
+        Err(e) => Report {
+            proxy,
+            latency: None,
+            exit_ip: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+/// Returns the address of a proxy, resolving its hostname if it has one.
+pub fn proxy_addr(proxy: &ProxyConf) -> Result<SocketAddr> {
+    match &proxy.hostname {
+        Some(hostname) => (hostname.as_str(), proxy.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("cannot resolve {}", hostname)),
+        None => Ok(SocketAddr::new(proxy.ip, proxy.port)),
+    }
+}
+
+/// Asks the proxy to connect to the target, checking each reply is well
+/// formed.
+fn handshake(
+    sock: &mut TcpStream,
+    proxy: &ProxyConf,
+    auth: Option<&Auth>,
+    target: &Target,
+) -> Result<()> {
+    match proxy.proto {
+        ProxyType::Raw => Ok(()),
+        ProxyType::Http => {
+            write!(
+                sock,
+                "CONNECT {}:{} HTTP/1.0\r\nHost: {}:{}\r\n\r\n",
+                target.host, target.port, target.host, target.port
+            )?;
+            let head = read_head(sock)?;
+            match head.split_whitespace().nth(1) {
+                Some(code) if code.starts_with('2') => Ok(()),
+                Some(code) => bail!("http proxy replied {}", code),
+                None => bail!("invalid http reply"),
+            }
+        }
+        ProxyType::Socks4 => {
+            let ip = match target.ip {
+                Some(IpAddr::V4(ip)) => ip,
+                _ => bail!("socks4 requires an ipv4 target"),
+            };
+            let mut packet = vec![4, 1];
+            packet.extend_from_slice(&target.port.to_be_bytes());
+            packet.extend_from_slice(&ip.octets());
+            packet.push(0);
+            sock.write_all(&packet)?;
+
+            let mut reply = [0; 8];
+            sock.read_exact(&mut reply)?;
+            match reply {
+                [0, 90, ..] => Ok(()),
+                [0, code, ..] => bail!("socks4 request rejected ({})", code),
+                _ => bail!("invalid socks4 reply"),
+            }
+        }
+        ProxyType::Socks5 => {
+            let methods: Vec<u8> = match (proxy.auth_methods.is_empty(), auth) {
+                (false, _) => proxy.auth_methods.iter().map(|m| auth_id(*m)).collect(),
+                (true, Some(Auth::UserPassword(..))) => vec![2],
+                (true, None) => vec![0],
+            };
+            sock.write_all(&[5, methods.len() as u8])?;
+            sock.write_all(&methods)?;
+            let mut reply = [0; 2];
+            sock.read_exact(&mut reply)?;
+            let method = match reply {
+                [5, 0xff] => bail!("no acceptable socks5 auth method"),
+                [5, m] if methods.contains(&m) => m,
+                [5, m] => bail!("unoffered socks5 auth method {:#x} selected", m),
+                _ => bail!("invalid socks5 greeting reply"),
+            };
+
+            match (method, auth) {
+                (0, _) => (),
+                (_, Some(Auth::UserPassword(user, pass))) => {
+                    let mut packet = vec![1, user.len() as u8];
+                    packet.extend_from_slice(user.as_bytes());
+                    packet.push(pass.len() as u8);
+                    packet.extend_from_slice(pass.as_bytes());
+                    sock.write_all(&packet)?;
+                    sock.read_exact(&mut reply)?;
+                    if reply[1] != 0 {
+                        bail!("socks5 authentication failed");
+                    }
+                }
+                (_, None) => bail!("no credentials for the selected socks5 auth method"),
+            }
+
+            let mut packet = vec![5, 1, 0, 3, target.host.len() as u8];
+            packet.extend_from_slice(target.host.as_bytes());
+            packet.extend_from_slice(&target.port.to_be_bytes());
+            sock.write_all(&packet)?;
+
+            let mut reply = [0; 4];
+            sock.read_exact(&mut reply)?;
+            if reply[0] != 5 {
+                bail!("invalid socks5 reply");
+            }
+            if reply[1] != 0 {
+                bail!("socks5 request failed ({})", reply[1]);
+            }
+            let len = match reply[3] {
+                1 => 4,
+                4 => 16,
+                3 => {
+                    let mut len = [0; 1];
+                    sock.read_exact(&mut len)?;
+                    len[0] as usize
+                }
+                _ => bail!("invalid socks5 reply address type"),
+            };
+            let mut bound = vec![0; len + 2];
+            sock.read_exact(&mut bound)?;
+            Ok(())
+        }
+    }
+}
+
+/// Returns the socks5 identifier of an authentication method.
+fn auth_id(method: AuthMethod) -> u8 {
+    match method {
+        AuthMethod::None => 0,
+        AuthMethod::UserPass => 2,
+    }
+}
+
+/// Reads an http header block.
+fn read_head(sock: &mut TcpStream) -> Result<String> {
+    let mut head = vec![];
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= 8192 {
+            bail!("http header too long");
+        }
+        sock.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Requests the target, which answers with the address of its client.
+fn query_exit_ip(sock: &mut TcpStream, target: &Target) -> Result<IpAddr> {
+    write!(
+        sock,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: proxyc\r\n\r\n",
+        target.path, target.host
+    )?;
+    let head = read_head(sock)?;
+    if head.split_whitespace().nth(1) != Some("200") {
+        bail!("unexpected reply from the target");
+    }
+
+    let mut body = String::new();
+    sock.take(256).read_to_string(&mut body)?;
+    body.trim()
+        .parse()
+        .map_err(|_| anyhow!("the target did not answer with an address"))
+}
+
+/// Writes the live proxies, as a toml configuration when the file name ends
+/// with `.toml`, one per line otherwise.
+pub fn write_list(path: &Path, reports: &[Report]) -> Result<()> {
+    let live = reports
+        .iter()
+        .filter(|r| r.error.is_none())
+        .map(|r| r.proxy.to_string());
+
+    let content = match path.extension().is_some_and(|e| e == "toml") {
+        true => {
+            let mut s = String::from("proxy = [\n");
+            for p in live {
+                s.push_str(&format!("\t{:?},\n", p));
+            }
+            s.push_str("]\n");
+            s
+        }
+        false => live.map(|p| p + "\n").collect(),
+    };
+    std::fs::write(path, content).with_context(|| format!("failed to write {:?}", path))
+}
@@ -0,0 +1,102 @@
+//! Commands running containers hooked by the library.
+use crate::{pooled_json, sh_quote, write_pool, EnvChanges, MAX_CONFIG_ENV};
+use anyhow::{bail, Result};
+use proxyc_common::ProxycConfig;
+use std::str::FromStr;
+
+/// Path of the library in containers.
+const LIBRARY: &str = "/usr/lib/proxyc/libproxyc.so";
+/// Path of the proxy pool in containers, when the proxies are too many to
+/// be inlined.
+const POOL: &str = "/usr/lib/proxyc/pool.json";
+
+#[derive(Debug)]
+pub enum Engine {
+    Docker,
+    Podman,
+}
+
+impl FromStr for Engine {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "docker" => Engine::Docker,
+            "podman" => Engine::Podman,
+            _ => bail!(
+                "unknown container engine {:?}, expected docker or podman",
+                s
+            ),
+        })
+    }
+}
+
+impl Engine {
+    fn program(&self) -> &'static str {
+        match self {
+            Engine::Docker => "docker",
+            Engine::Podman => "podman",
+        }
+    }
+}
+
+/// Returns the run command of `engine` hooking the container of `args` with
+/// `config`. The library is mounted read-only along with the proxy pool, and
+/// the network of the host shared when the chain starts on loopback.
+pub fn command(
+    engine: &Engine,
+    lib_path: &str,
+    config: &ProxycConfig,
+    changes: &EnvChanges,
+    args: &[String],
+) -> Result<Vec<String>> {
+    let mut command = vec![engine.program().to_string(), "run".to_string()];
+    let volume = |host: &str, guest: &str| ["-v".to_string(), format!("{}:{}:ro", host, guest)];
+    command.extend(volume(lib_path, LIBRARY));
+
+    let json = config.to_json()?;
+    let config_env = if json.len() <= MAX_CONFIG_ENV {
+        json
+    } else {
+        command.extend(volume(&write_pool(config)?.to_string_lossy(), POOL));
+        pooled_json(config, POOL)?
+    };
+
+    // the container can only reach the proxies on the loopback of the host
+    // through its network
+    if config.proxies.iter().any(|p| p.ip.is_loopback()) {
+        command.extend(["--network".to_string(), "host".to_string()]);
+    }
+
+    // containers start from a clean environment, there is nothing to unset
+    for (k, v) in &changes.set {
+        command.extend(["-e".to_string(), format!("{}={}", k, v)]);
+    }
+    command.extend([
+        "-e".to_string(),
+        format!("LD_PRELOAD={}", LIBRARY),
+        "-e".to_string(),
+        format!("PROXYC_CONFIG={}", config_env),
+    ]);
+    command.extend(args.iter().cloned());
+    Ok(command)
+}
+
+/// Returns `command` as a shell command line, quoting the arguments which
+/// need it.
+pub fn quote(command: &[String]) -> String {
+    command
+        .iter()
+        .map(|a| {
+            let plain = !a.is_empty()
+                && a.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c));
+            if plain {
+                a.clone()
+            } else {
+                sh_quote(a)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,635 @@
+//! Redirection of the connections of a cgroup by eBPF programs.
+//!
+//! A cgroup/connect4 program rewrites the TCP connections of the programs of
+//! a cgroup to a relay of this process, which runs hooked, recording their
+//! destination by socket cookie in a map. The relay finds the cookie of each
+//! client with sock_diag, and chains the connection to its destination.
+//! Static binaries are covered as well, without ptrace. A cgroup/connect6
+//! program redirects the connections of IPv6 sockets to IPv4-mapped
+//! addresses likewise, and refuses the other IPv6 ones, rather than leak.
+use crate::{run, serve};
+use anyhow::{anyhow, bail, Context, Result};
+use nix::libc::{self, c_long};
+use nix::sys::socket::{socket, AddressFamily, SockFlag, SockProtocol, SockType};
+use nix::unistd::{close, Uid};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+
+const BPF_MAP_CREATE: c_long = 0;
+const BPF_MAP_LOOKUP_ELEM: c_long = 1;
+const BPF_MAP_DELETE_ELEM: c_long = 3;
+const BPF_PROG_LOAD: c_long = 5;
+const BPF_PROG_ATTACH: c_long = 8;
+
+const BPF_MAP_TYPE_LRU_HASH: u32 = 9;
+const BPF_PROG_TYPE_CGROUP_SOCK_ADDR: u32 = 18;
+const BPF_CGROUP_INET4_CONNECT: u32 = 10;
+const BPF_CGROUP_INET6_CONNECT: u32 = 11;
+
+const BPF_FUNC_MAP_UPDATE_ELEM: i32 = 2;
+const BPF_FUNC_GET_SOCKET_COOKIE: i32 = 46;
+
+/// Offsets of the fields of struct bpf_sock_addr.
+const CTX_USER_IP4: i16 = 4;
+const CTX_USER_IP6: i16 = 8;
+const CTX_USER_PORT: i16 = 24;
+const CTX_TYPE: i16 = 32;
+
+/// Destinations recorded at once, the oldest being evicted first.
+const MAX_DESTINATIONS: u32 = 65536;
+
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const NLMSG_ERROR: u16 = 2;
+
+/// Instruction of the eBPF virtual machine.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Insn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    Insn {
+        code,
+        regs: src << 4 | dst,
+        off,
+        imm,
+    }
+}
+
+/// dst = src
+fn mov(dst: u8, src: u8) -> Insn {
+    insn(0xbf, dst, src, 0, 0)
+}
+
+/// dst = imm
+fn mov_imm(dst: u8, imm: i32) -> Insn {
+    insn(0xb7, dst, 0, 0, imm)
+}
+
+/// dst += imm
+fn add_imm(dst: u8, imm: i32) -> Insn {
+    insn(0x07, dst, 0, 0, imm)
+}
+
+/// dst &= imm
+fn and_imm(dst: u8, imm: i32) -> Insn {
+    insn(0x57, dst, 0, 0, imm)
+}
+
+/// dst = *(u32 *)(src + off)
+fn load32(dst: u8, src: u8, off: i16) -> Insn {
+    insn(0x61, dst, src, off, 0)
+}
+
+/// *(u32 *)(dst + off) = src
+fn store32(dst: u8, off: i16, src: u8) -> Insn {
+    insn(0x63, dst, src, off, 0)
+}
+
+/// *(u64 *)(dst + off) = src
+fn store64(dst: u8, off: i16, src: u8) -> Insn {
+    insn(0x7b, dst, src, off, 0)
+}
+
+/// Skips `off` instructions if dst == imm.
+fn jeq_imm(dst: u8, imm: i32, off: i16) -> Insn {
+    insn(0x15, dst, 0, off, imm)
+}
+
+/// Skips `off` instructions if the low half of dst == imm, which is not
+/// sign-extended.
+fn jeq32_imm(dst: u8, imm: i32, off: i16) -> Insn {
+    insn(0x16, dst, 0, off, imm)
+}
+
+/// Skips `off` instructions if dst != imm.
+fn jne_imm(dst: u8, imm: i32, off: i16) -> Insn {
+    insn(0x55, dst, 0, off, imm)
+}
+
+fn call(helper: i32) -> Insn {
+    insn(0x85, 0, 0, 0, helper)
+}
+
+fn exit() -> Insn {
+    insn(0x95, 0, 0, 0, 0)
+}
+
+/// dst = the map `fd`, over two instructions.
+fn load_map(dst: u8, fd: RawFd) -> [Insn; 2] {
+    [insn(0x18, dst, 1, 0, fd), insn(0, 0, 0, 0, 0)]
+}
+
+/// Returns `bytes`, in network order, as loaded by the programs.
+fn ne(bytes: [u8; 4]) -> i32 {
+    i32::from_ne_bytes(bytes)
+}
+
+/// Returns the instructions recording the destination of the connection
+/// of the context in r6, whose IPv4 address is at `ip`, to `map` before
+/// rewriting it to `relay`, then allowing it.
+fn redirect(map: RawFd, relay: SocketAddrV4, ip: i16) -> Vec<Insn> {
+    let mut prog = vec![
+        mov(1, 6),
+        call(BPF_FUNC_GET_SOCKET_COOKIE),
+        // key: the cookie, value: the address and port
+        store64(10, -8, 0),
+        load32(2, 6, ip),
+        store32(10, -16, 2),
+        load32(2, 6, CTX_USER_PORT),
+        store32(10, -12, 2),
+    ];
+    prog.extend(load_map(1, map));
+    prog.extend([
+        mov(2, 10),
+        add_imm(2, -8),
+        mov(3, 10),
+        add_imm(3, -16),
+        mov_imm(4, 0),
+        call(BPF_FUNC_MAP_UPDATE_ELEM),
+        mov_imm(2, ne(relay.ip().octets())),
+        store32(6, ip, 2),
+        mov_imm(2, relay.port().to_be() as i32),
+        store32(6, CTX_USER_PORT, 2),
+        // allowed
+        mov_imm(0, 1),
+        exit(),
+    ]);
+    prog
+}
+
+/// Returns the program redirecting the TCP connections to `relay`,
+/// loopback ones excepted.
+fn connect4(map: RawFd, relay: SocketAddrV4) -> Vec<Insn> {
+    let mut prog = vec![
+        mov(6, 1),
+        load32(2, 6, CTX_TYPE),
+        jne_imm(2, libc::SOCK_STREAM, 22),
+        load32(2, 6, CTX_USER_IP4),
+        and_imm(2, ne([0xff, 0, 0, 0])),
+        jeq_imm(2, ne([127, 0, 0, 0]), 19),
+    ];
+    prog.extend(redirect(map, relay, CTX_USER_IP4));
+    prog
+}
+
+/// Returns the program redirecting the TCP connections to IPv4-mapped
+/// addresses to `relay`, and refusing the other ones over IPv6, loopback
+/// ones excepted.
+fn connect6(map: RawFd, relay: SocketAddrV4) -> Vec<Insn> {
+    let mut prog = vec![
+        mov(6, 1),
+        load32(2, 6, CTX_TYPE),
+        jne_imm(2, libc::SOCK_STREAM, 9),
+        load32(2, 6, CTX_USER_IP6),
+        jne_imm(2, 0, 9),
+        load32(2, 6, CTX_USER_IP6 + 4),
+        jne_imm(2, 0, 7),
+        load32(2, 6, CTX_USER_IP6 + 8),
+        jeq32_imm(2, ne([0, 0, 0xff, 0xff]), 7),
+        jne_imm(2, 0, 4),
+        load32(2, 6, CTX_USER_IP6 + 12),
+        jne_imm(2, ne([0, 0, 0, 1]), 2),
+        mov_imm(0, 1),
+        exit(),
+        mov_imm(0, 0),
+        exit(),
+        // ::ffff:a.b.c.d, rewritten to ::ffff:relay
+        load32(2, 6, CTX_USER_IP6 + 12),
+        and_imm(2, ne([0xff, 0, 0, 0])),
+        jeq_imm(2, ne([127, 0, 0, 0]), 19),
+    ];
+    prog.extend(redirect(map, relay, CTX_USER_IP6 + 12));
+    prog
+}
+
+/// Calls bpf() with the attributes `attr`.
+fn bpf<T>(cmd: c_long, attr: &T) -> io::Result<RawFd> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *const T,
+            std::mem::size_of::<T>(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as RawFd)
+}
+
+#[repr(C)]
+struct MapCreate {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+}
+
+#[repr(C)]
+struct MapElem {
+    map_fd: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct ProgLoad {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+struct ProgAttach {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+}
+
+/// Loads `prog` to be attached as `attach_type`, the log of the verifier
+/// being returned on failure.
+fn load(prog: &[Insn], attach_type: u32) -> Result<RawFd> {
+    let license = CString::new("GPL").unwrap();
+    let mut log = vec![0u8; 64 * 1024];
+    let mut name = [0; 16];
+    name[..6].copy_from_slice(b"proxyc");
+    let attr = ProgLoad {
+        prog_type: BPF_PROG_TYPE_CGROUP_SOCK_ADDR,
+        insn_cnt: prog.len() as u32,
+        insns: prog.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 1,
+        log_size: log.len() as u32,
+        log_buf: log.as_mut_ptr() as u64,
+        kern_version: 0,
+        prog_flags: 0,
+        prog_name: name,
+        prog_ifindex: 0,
+        expected_attach_type: attach_type,
+    };
+    bpf(BPF_PROG_LOAD, &attr).map_err(|e| {
+        let end = log.iter().position(|b| *b == 0).unwrap_or(log.len());
+        anyhow!("{}: {}", e, String::from_utf8_lossy(&log[..end]).trim_end())
+    })
+}
+
+/// Returns the cookie of the socket connected from `client` to `relay`, as
+/// given by sock_diag.
+fn cookie(client: SocketAddrV4, relay: SocketAddrV4) -> io::Result<u64> {
+    let sock = socket(
+        AddressFamily::Netlink,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::NetlinkSockDiag,
+    )?;
+    // nlmsghdr, then inet_diag_req_v2 looking up the socket by its ends
+    let mut req = [0u8; 72];
+    req[..4].copy_from_slice(&72u32.to_ne_bytes());
+    req[4..6].copy_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+    req[6..8].copy_from_slice(&(libc::NLM_F_REQUEST as u16).to_ne_bytes());
+    req[16] = libc::AF_INET as u8;
+    req[17] = libc::IPPROTO_TCP as u8;
+    req[20..24].copy_from_slice(&u32::MAX.to_ne_bytes());
+    req[24..26].copy_from_slice(&client.port().to_be_bytes());
+    req[26..28].copy_from_slice(&relay.port().to_be_bytes());
+    req[28..32].copy_from_slice(&client.ip().octets());
+    req[44..48].copy_from_slice(&relay.ip().octets());
+    // any cookie
+    req[64..72].copy_from_slice(&u64::MAX.to_ne_bytes());
+
+    let mut reply = [0u8; 1024];
+    let res = (|| {
+        if unsafe { libc::send(sock, req.as_ptr() as *const _, req.len(), 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let n = unsafe { libc::recv(sock, reply.as_mut_ptr() as *mut _, reply.len(), 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n < 68 {
+            return Err(io::Error::from_raw_os_error(libc::EPROTO));
+        }
+        if u16::from_ne_bytes([reply[4], reply[5]]) == NLMSG_ERROR {
+            let errno = i32::from_ne_bytes(reply[16..20].try_into().unwrap());
+            return Err(io::Error::from_raw_os_error(-errno));
+        }
+        // idiag_cookie of inet_diag_msg, low half first
+        let low = u32::from_ne_bytes(reply[60..64].try_into().unwrap());
+        let high = u32::from_ne_bytes(reply[64..68].try_into().unwrap());
+        Ok((high as u64) << 32 | low as u64)
+    })();
+    let _ = close(sock);
+    res
+}
+
+/// Returns the destination recorded in `map` for `client`, forgetting it.
+fn original_dst(map: RawFd, client: &TcpStream) -> io::Result<SocketAddr> {
+    let (peer, local) = match (client.peer_addr()?, client.local_addr()?) {
+        (SocketAddr::V4(peer), SocketAddr::V4(local)) => (peer, local),
+        _ => return Err(io::Error::from_raw_os_error(libc::EAFNOSUPPORT)),
+    };
+    let key = cookie(peer, local)?;
+    let mut value = [0u32; 2];
+    let elem = MapElem {
+        map_fd: map as u32,
+        key: &key as *const u64 as u64,
+        value: value.as_mut_ptr() as u64,
+        flags: 0,
+    };
+    bpf(BPF_MAP_LOOKUP_ELEM, &elem)?;
+    let _ = bpf(BPF_MAP_DELETE_ELEM, &elem);
+    Ok(SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::from(value[0].to_ne_bytes())),
+        u16::from_be(value[1] as u16),
+    ))
+}
+
+/// Returns the mount point of the cgroup2 hierarchy.
+fn cgroup2() -> Result<PathBuf> {
+    let mounts = std::fs::read_to_string("/proc/self/mounts")?;
+    mounts
+        .lines()
+        .map(|l| l.split(' ').collect::<Vec<_>>())
+        .find(|f| f.len() > 2 && f[2] == "cgroup2")
+        .map(|f| PathBuf::from(f[1]))
+        .ok_or_else(|| anyhow!("the ebpf subcommand requires a cgroup2 hierarchy"))
+}
+
+/// Cgroup of the program, removed when dropped along with its programs.
+struct Cgroup {
+    path: PathBuf,
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // fails while daemons of the program are still running
+        let _ = std::fs::remove_dir(&self.path);
+    }
+}
+
+/// Runs `args` in a new cgroup whose TCP connections are chained by this
+/// process. Returns its exit code.
+pub fn run(args: &[String]) -> Result<i32> {
+    if !Uid::effective().is_root() {
+        bail!("the ebpf subcommand requires root");
+    }
+
+    let listener =
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).context("failed to bind the relay")?;
+    let relay = match listener.local_addr()? {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+
+    let map = bpf(
+        BPF_MAP_CREATE,
+        &MapCreate {
+            map_type: BPF_MAP_TYPE_LRU_HASH,
+            key_size: 8,
+            value_size: 8,
+            max_entries: MAX_DESTINATIONS,
+        },
+    )
+    .context("failed to create the map of destinations")?;
+    let prog4 = load(&connect4(map, relay), BPF_CGROUP_INET4_CONNECT)
+        .context("failed to load the connect4 program")?;
+    let prog6 = load(&connect6(map, relay), BPF_CGROUP_INET6_CONNECT)
+        .context("failed to load the connect6 program")?;
+
+    let cgroup = Cgroup {
+        path: cgroup2()?.join(format!("proxyc-{}", std::process::id())),
+    };
+    std::fs::create_dir(&cgroup.path)
+        .with_context(|| format!("failed to create cgroup {:?}", cgroup.path))?;
+    let dir = File::open(&cgroup.path)?;
+    for (prog, attach_type) in [
+        (prog4, BPF_CGROUP_INET4_CONNECT),
+        (prog6, BPF_CGROUP_INET6_CONNECT),
+    ] {
+        let attr = ProgAttach {
+            target_fd: dir.as_raw_fd() as u32,
+            attach_bpf_fd: prog as u32,
+            attach_type,
+            attach_flags: 0,
+        };
+        bpf(BPF_PROG_ATTACH, &attr).context("failed to attach the programs to the cgroup")?;
+    }
+
+    thread::spawn(move || serve::redirected(listener, move |c| original_dst(map, c)));
+
+    let procs = cgroup.path.join("cgroup.procs");
+    let mut program = Command::new(&args[0]);
+    program
+        .args(&args[1..])
+        .env_remove("LD_PRELOAD")
+        .env_remove("PROXYC_CONFIG");
+    unsafe {
+        // joins the cgroup, its children following
+        program.pre_exec(move || File::create(&procs)?.write_all(b"0"));
+    }
+    let child = program
+        .spawn()
+        .with_context(|| format!("failed to execute {:?}", args[0]))?;
+    let code = run::wait(child)?;
+    drop(cgroup);
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    const MAP: RawFd = 42;
+    const COOKIE: u64 = 0x1234;
+    /// Addresses of the context and of the top of the stack, as held in the
+    /// registers.
+    const CTX: u64 = 1 << 32;
+    const STACK: u64 = 2 << 32;
+
+    /// Outcome of a run: the verdict, the context and the map updates.
+    struct Run {
+        allowed: bool,
+        ctx: [u8; 40],
+        updates: Vec<(u64, [u8; 8])>,
+    }
+
+    /// Runs the subset of eBPF the programs use with the context `ctx`.
+    fn run(prog: &[Insn], mut ctx: [u8; 40]) -> Run {
+        let mut stack = [0u8; 16];
+        let mut regs = [0u64; 11];
+        regs[1] = CTX;
+        regs[10] = STACK;
+        let mut updates = vec![];
+        let mut pc = 0;
+        loop {
+            let i = prog[pc];
+            let (dst, src) = ((i.regs & 0xf) as usize, (i.regs >> 4) as usize);
+            let imm = i.imm as i64 as u64;
+            let mem = |ctx: &mut [u8; 40], stack: &mut [u8; 16], addr: u64| -> *mut u8 {
+                match addr {
+                    a if (STACK - 16..STACK).contains(&a) => {
+                        stack[(a - (STACK - 16)) as usize..].as_mut_ptr()
+                    }
+                    a => ctx[(a - CTX) as usize..].as_mut_ptr(),
+                }
+            };
+            let addr = |base: u64| base.wrapping_add(i.off as i64 as u64);
+            pc += 1;
+            match i.code {
+                0xbf => regs[dst] = regs[src],
+                0xb7 => regs[dst] = imm,
+                0x07 => regs[dst] = regs[dst].wrapping_add(imm),
+                0x57 => regs[dst] &= imm,
+                0x61 => {
+                    let p = mem(&mut ctx, &mut stack, addr(regs[src]));
+                    regs[dst] = unsafe { (p as *const u32).read_unaligned() } as u64;
+                }
+                0x63 => {
+                    let p = mem(&mut ctx, &mut stack, addr(regs[dst]));
+                    unsafe { (p as *mut u32).write_unaligned(regs[src] as u32) };
+                }
+                0x7b => {
+                    let p = mem(&mut ctx, &mut stack, addr(regs[dst]));
+                    unsafe { (p as *mut u64).write_unaligned(regs[src]) };
+                }
+                0x15 if regs[dst] == imm => pc += i.off as usize,
+                0x16 if regs[dst] as u32 == i.imm as u32 => pc += i.off as usize,
+                0x55 if regs[dst] != imm => pc += i.off as usize,
+                0x15 | 0x16 | 0x55 => (),
+                0x85 if i.imm == BPF_FUNC_GET_SOCKET_COOKIE => {
+                    assert_eq!(regs[1], CTX);
+                    regs[0] = COOKIE;
+                }
+                0x85 if i.imm == BPF_FUNC_MAP_UPDATE_ELEM => {
+                    assert_eq!(regs[1], MAP as u64);
+                    let key = mem(&mut ctx, &mut stack, regs[2]);
+                    let value = mem(&mut ctx, &mut stack, regs[3]);
+                    let key = unsafe { (key as *const u64).read_unaligned() };
+                    let value = unsafe { (value as *const [u8; 8]).read_unaligned() };
+                    updates.push((key, value));
+                    regs[0] = 0;
+                }
+                0x18 => {
+                    regs[dst] = i.imm as u32 as u64;
+                    pc += 1;
+                }
+                0x95 => {
+                    return Run {
+                        allowed: regs[0] == 1,
+                        ctx,
+                        updates,
+                    }
+                }
+                code => panic!("unexpected instruction {:#x}", code),
+            }
+        }
+    }
+
+    fn relay() -> SocketAddrV4 {
+        "127.0.0.1:4000".parse().unwrap()
+    }
+
+    /// Returns a context of a connection of type `ty` to `dest`, whose port
+    /// is 443.
+    fn context(ty: libc::c_int, dest: IpAddr) -> [u8; 40] {
+        let mut ctx = [0; 40];
+        match dest {
+            IpAddr::V4(ip) => ctx[4..8].copy_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => ctx[8..24].copy_from_slice(&ip.octets()),
+        }
+        ctx[24..26].copy_from_slice(&443u16.to_be_bytes());
+        ctx[32..36].copy_from_slice(&ty.to_ne_bytes());
+        ctx
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn connect4_redirects_tcp_connections() {
+        let ctx = context(libc::SOCK_STREAM, ip("93.184.216.34"));
+        let run = run(&connect4(MAP, relay()), ctx);
+        assert!(run.allowed);
+        assert_eq!(run.ctx[4..8], [127, 0, 0, 1]);
+        assert_eq!(run.ctx[24..26], 4000u16.to_be_bytes());
+        let mut value = [0; 8];
+        value[..4].copy_from_slice(&[93, 184, 216, 34]);
+        value[4..6].copy_from_slice(&443u16.to_be_bytes());
+        assert_eq!(run.updates, [(COOKIE, value)]);
+    }
+
+    #[test]
+    fn connect4_allows_loopback_and_other_types() {
+        for ctx in [
+            context(libc::SOCK_STREAM, ip("127.0.0.53")),
+            context(libc::SOCK_DGRAM, ip("93.184.216.34")),
+        ] {
+            let run = run(&connect4(MAP, relay()), ctx);
+            assert!(run.allowed);
+            assert_eq!(run.ctx, ctx);
+            assert!(run.updates.is_empty());
+        }
+    }
+
+    #[test]
+    fn connect6_redirects_mapped_addresses() {
+        let ctx = context(libc::SOCK_STREAM, ip("::ffff:93.184.216.34"));
+        let run = run(&connect6(MAP, relay()), ctx);
+        assert!(run.allowed);
+        let dest = Ipv6Addr::from(<[u8; 16]>::try_from(&run.ctx[8..24]).unwrap());
+        assert_eq!(dest, ip("::ffff:127.0.0.1"));
+        assert_eq!(run.ctx[24..26], 4000u16.to_be_bytes());
+        assert_eq!(run.updates.len(), 1);
+        assert_eq!(run.updates[0].1[..4], [93, 184, 216, 34]);
+    }
+
+    #[test]
+    fn connect6_allows_loopback_and_other_types() {
+        for ctx in [
+            context(libc::SOCK_STREAM, ip("::1")),
+            context(libc::SOCK_STREAM, ip("::ffff:127.0.0.1")),
+            context(libc::SOCK_DGRAM, ip("2001:db8::1")),
+        ] {
+            let run = run(&connect6(MAP, relay()), ctx);
+            assert!(run.allowed);
+            assert_eq!(run.ctx, ctx);
+            assert!(run.updates.is_empty());
+        }
+    }
+
+    #[test]
+    fn connect6_refuses_other_addresses() {
+        for dest in ["2001:db8::1", "::2", "::", "::ffff:0:0:1", "0:0:1:ffff::1"] {
+            let ctx = context(libc::SOCK_STREAM, ip(dest));
+            let run = run(&connect6(MAP, relay()), ctx);
+            assert!(!run.allowed, "{}", dest);
+            assert_eq!(run.ctx, ctx);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Exit codes of the failures of the CLI, and their reports on stderr, as
+//! text or as JSON for the scripts running proxyc.
+//!
+//! Failures are tagged with their kind as the context of the error, the
+//! codes following sysexits.h, and the shells for missing programs.
+use anyhow::{bail, Result};
+use serde_json::json;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// Exit code of the failures of no particular kind.
+const GENERIC: i32 = 1;
+
+/// Kind of failure with its own exit code.
+#[derive(Debug, Clone, Copy)]
+pub enum Failure {
+    /// Invalid configuration file or arguments.
+    Config,
+    /// libproxyc missing, or not built for the program architecture.
+    Library,
+    /// Program to hook not found.
+    ProgramNotFound,
+    /// No proxy of the chain alive with validate_on_start.
+    ChainValidation,
+}
+
+impl Failure {
+    fn code(self) -> i32 {
+        match self {
+            Failure::Config => 78,           // EX_CONFIG
+            Failure::Library => 72,          // EX_OSFILE
+            Failure::ProgramNotFound => 127, // as the shells
+            Failure::ChainValidation => 69,  // EX_UNAVAILABLE
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Failure::Config => "config",
+            Failure::Library => "library",
+            Failure::ProgramNotFound => "program_not_found",
+            Failure::ChainValidation => "chain_validation",
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} error", self.name())
+    }
+}
+
+/// Returns the error of a failed exec() or spawn() of a program, `context`
+/// explaining it, tagged ProgramNotFound when the program is missing.
+pub fn launch_error(e: io::Error, context: impl fmt::Display) -> anyhow::Error {
+    let not_found = e.kind() == io::ErrorKind::NotFound;
+    let e = anyhow::Error::new(e).context(context.to_string());
+    match not_found {
+        true => e.context(Failure::ProgramNotFound),
+        false => e,
+    }
+}
+
+/// Format of the error reports.
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "text" => ErrorFormat::Text,
+            "json" => ErrorFormat::Json,
+            _ => bail!("invalid format: {}", s),
+        })
+    }
+}
+
+/// Reports `e` on stderr in `format`, returning the exit code of its kind.
+pub fn report(e: &anyhow::Error, format: ErrorFormat) -> i32 {
+    let failure = e.downcast_ref::<Failure>().copied();
+    // the kind is given by the code, not repeated in the message
+    let tag = failure.map(|f| f.to_string());
+    let causes: Vec<String> = e
+        .chain()
+        .map(ToString::to_string)
+        .filter(|c| Some(c) != tag.as_ref())
+        .collect();
+    let code = failure.map_or(GENERIC, Failure::code);
+
+    match format {
+        ErrorFormat::Text => eprintln!("proxyc: {}", causes.join(": ")),
+        ErrorFormat::Json => eprintln!(
+            "{}",
+            json!({
+                "error": failure.map_or("error", Failure::name),
+                "code": code,
+                "message": causes.join(": "),
+                "causes": causes,
+            })
+        ),
+    }
+    code
+}
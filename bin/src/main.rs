@@ -1,23 +1,290 @@
 use anyhow::{anyhow, bail, Context, Result};
+use cidr::Ipv4Cidr;
 use log::LevelFilter;
-use proxyc_common::{ChainType, ProxyConf, ProxycConfig};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use nix::unistd::{getgid, getgroups, getuid};
+use proxyc_common::{
+    AdaptiveTimeout, ChainType, DefaultAuth, IgnoreSubnet, Keepalive, ProxyConf, ProxyDnsMode,
+    ProxyType, ProxycConfig, RandomScope, UnsupportedFamily,
+};
 use std::env;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::BufWriter;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
 use std::process::Command;
+use std::str::FromStr;
+use std::time::Duration;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
+mod arch;
+mod audit;
+mod bench;
+mod container;
+mod ebpf;
+mod exit;
+mod netns;
+mod probe;
+mod rules;
+mod run;
+mod serve;
+mod upstream;
+mod wrap;
+
+use exit::{ErrorFormat, Failure};
+use run::RestartPolicy;
+use upstream::Upstreams;
+
+#[derive(StructOpt, Debug)]
+enum ProxycCmd {
+    /// Print the environment variables hooking a program with the current
+    /// configuration, for manual sourcing
+    Env {
+        /// Output format: sh, systemd or docker (env-file)
+        #[structopt(long, default_value = "sh")]
+        format: EnvFormat,
+    },
+
+    /// Run and supervise the hooked program, restarting it according to the
+    /// restart policy
+    #[structopt(setting = AppSettings::TrailingVarArg)]
+    Run {
+        /// Restart policy: no, on-failure[:max-restarts] or
+        /// always[:max-restarts]
+        #[structopt(long, default_value = "no")]
+        restart: RestartPolicy,
+
+        /// Program and args to hook
+        #[structopt(required = true)]
+        args: Vec<String>,
+    },
+
+    /// Test every proxy concurrently for liveness, latency and exit address,
+    /// and write the live ones sorted by latency
+    Bench {
+        /// File receiving the live proxies, as a toml configuration if its
+        /// name ends with .toml, one per line otherwise
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        /// Number of proxies tested at once
+        #[structopt(short, long, default_value = "64")]
+        jobs: usize,
+
+        /// HTTP service answering with the address of its client, queried
+        /// through each proxy, in the form host[:port][/path]
+        #[structopt(long, default_value = "api.ipify.org")]
+        target: String,
+    },
+
+    /// Run the hooked program, recording every proxied connection to an
+    /// audit file
+    #[structopt(setting = AppSettings::TrailingVarArg)]
+    Record {
+        /// Audit file, truncated first
+        #[structopt(short, long, parse(from_os_str))]
+        output: PathBuf,
+
+        /// Program and args to hook
+        #[structopt(required = true)]
+        args: Vec<String>,
+    },
+
+    /// Summarize an audit file written by the record subcommand
+    Report {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+
+    /// Chain the connections of other programs, handed over by those
+    /// accepting them or made through socks5 or HTTP CONNECT listeners
+    Serve {
+        /// Unix seqpacket socket receiving the connected sockets, each passed
+        /// with SCM_RIGHTS in a message giving its destination as host:port
+        #[structopt(long, parse(from_os_str))]
+        takeover: Option<PathBuf>,
+
+        /// Address of the socks5 listener, requiring the clients to
+        /// authenticate as one of the serve_user entries if any
+        #[structopt(long)]
+        socks: Option<SocketAddr>,
+
+        /// Address of the HTTP CONNECT listener, requiring the clients to
+        /// authenticate as one of the serve_user entries if any
+        #[structopt(long)]
+        http: Option<SocketAddr>,
+    },
+
+    /// Forward local ports to fixed destinations through the chain, like
+    /// ssh -L, for the programs which cannot be hooked but can connect to
+    /// localhost
+    Forward {
+        /// Forwarded port, as [bind_address:]port:host:hostport. The bind
+        /// address defaults to 127.0.0.1
+        #[structopt(short = "L", long = "local", required = true, number_of_values = 1)]
+        forwards: Vec<serve::Forward>,
+    },
+
+    /// Forward ports the last proxy listens on with socks5 BIND back to
+    /// local services, like ssh -R, for the tools expecting callbacks. Each
+    /// listener accepts a single connection before being replaced
+    Reverse {
+        /// Local service, as host:hostport, reached directly
+        #[structopt(short = "R", long = "remote", required = true, number_of_values = 1)]
+        reverses: Vec<serve::Reverse>,
+    },
+
+    /// Print the docker or podman command running a container hooked with
+    /// the current configuration, or run it
+    #[structopt(
+        setting = AppSettings::TrailingVarArg,
+        setting = AppSettings::AllowLeadingHyphen
+    )]
+    Containerize {
+        /// Container engine: docker or podman
+        #[structopt(long, default_value = "docker")]
+        engine: container::Engine,
+
+        /// Run the container rather than printing the command
+        #[structopt(long)]
+        apply: bool,
+
+        /// Run options, image and args of the container, the command
+        /// printing the options preceding them otherwise
+        args: Vec<String>,
+    },
+
+    /// As root, run the program in a network namespace whose only egress is
+    /// the chain, leaving no leak even to raw sockets
+    #[structopt(setting = AppSettings::TrailingVarArg)]
+    Netns {
+        /// User the program runs as, the one who ran sudo by default
+        #[structopt(short, long)]
+        user: Option<String>,
+        /// Program and args to run
+        #[structopt(required = true)]
+        args: Vec<String>,
+    },
+
+    /// Run the program, as root, in a cgroup whose TCP connections are
+    /// redirected to the chain by eBPF programs, static binaries included
+    #[structopt(setting = AppSettings::TrailingVarArg)]
+    Ebpf {
+        /// Program and args to run
+        #[structopt(required = true)]
+        args: Vec<String>,
+    },
+
+    /// Configuration file tools
+    Config(ConfigCmd),
+
+    /// Routing rule tools
+    Rules(RulesCmd),
+
+    /// Write a shell script running a program hooked with the options given
+    /// before wrap, to drop into PATH in place of the program
+    Wrap {
+        /// Program to wrap, looked up in PATH
+        program: String,
+
+        /// Path of the script, the program name in the current directory by
+        /// default
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        /// Overwrite the script if it exists
+        #[structopt(long)]
+        force: bool,
+    },
+
+    /// Program and args to hook, use "--" before programs sharing the name of
+    /// a subcommand
+    #[structopt(external_subcommand)]
+    Exec(Vec<String>),
+}
+
+#[derive(StructOpt, Debug)]
+enum ConfigCmd {
+    /// Print the JSON Schema of configuration files, to validate them in
+    /// editors and pipelines
+    Schema,
+}
+
+#[derive(StructOpt, Debug)]
+enum RulesCmd {
+    /// Print which ignored subnet or rule a destination matches, why the
+    /// previous ones do not, and the resulting route
+    Explain {
+        /// Destination, as ip:port
+        target: SocketAddr,
+
+        /// Name of the program making the connection, for the rules
+        /// restricted to some programs
+        #[structopt(long)]
+        process: Option<String>,
+    },
+}
+
+#[derive(Debug)]
+enum EnvFormat {
+    Sh,
+    Systemd,
+    Docker,
+}
+
+impl FromStr for EnvFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "sh" => EnvFormat::Sh,
+            "systemd" => EnvFormat::Systemd,
+            "docker" => EnvFormat::Docker,
+            _ => bail!("invalid format: {}", s),
+        })
+    }
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(
     name = "proxyc",
     about = "proxy chaining tool",
-    setting = AppSettings::TrailingVarArg
+    setting = AppSettings::AllowExternalSubcommands
 )]
 struct ProxycOpt {
-    /// Proxy config list
+    /// Proxy config list, either URLs or ip:port when --proxy-type is set.
+    /// Address and port ranges are accepted (10.0.0.1-10.0.0.20:1080-1090)
     #[structopt(short, long, require_delimiter = true)]
-    proxy: Vec<ProxyConf>,
+    proxy: Vec<String>,
+
+    /// File listing one proxy per line, either URLs or ip:port when
+    /// --proxy-type is set
+    #[structopt(long, parse(from_os_str))]
+    proxy_file: Option<PathBuf>,
+
+    /// Type of the proxies given as ip:port (raw, http, socks4, socks5)
+    #[structopt(long)]
+    proxy_type: Option<ProxyType>,
+
+    /// Probe the proxies given as ip:port for their protocol (socks5, socks4
+    /// then http), those answering none getting --proxy-type if set and
+    /// being skipped otherwise
+    #[structopt(long)]
+    probe_type: bool,
+
+    /// Check the proxies before launching the program, removing the dead
+    /// ones
+    #[structopt(long)]
+    validate_on_start: bool,
+
+    /// Seconds between the health checks of the proxies defining one
+    #[structopt(long)]
+    health_interval: Option<usize>,
 
     /// Log level
     #[structopt(rename_all = "lowercase", short, long)]
@@ -27,14 +294,46 @@ struct ProxycOpt {
     #[structopt(short, long)]
     quiet: bool,
 
+    /// Format of the errors of proxyc on stderr: text or json, a single
+    /// line with the kind, exit code and message of the error
+    #[structopt(long, default_value = "text")]
+    error_format: ErrorFormat,
+
     /// Chain type
     #[structopt(short, long)]
     chain: Option<ChainType>,
 
+    /// Chains tried in turn until one connects, names of the configuration
+    /// chains, default for the proxies and direct (vpn,default,direct)
+    #[structopt(long, require_delimiter = true)]
+    chain_order: Vec<String>,
+
+    /// Minimum number of live hops a dynamic chain must keep
+    #[structopt(long)]
+    min_chain_len: Option<usize>,
+
+    /// Number of proxies a random chain is made of
+    #[structopt(long)]
+    chain_len: Option<usize>,
+
+    /// Whether random chains are drawn for every connection or once per
+    /// process (connection, process)
+    #[structopt(long)]
+    random_scope: Option<RandomScope>,
+
+    /// Seed of the random chains, for reproducible runs
+    #[structopt(long)]
+    random_seed: Option<u64>,
+
     /// Custom path to config file
     #[structopt(short, long, parse(from_os_str))]
     file_config: Option<PathBuf>,
 
+    /// Configuration file loaded after the others, its proxies and rules
+    /// being appended to theirs
+    #[structopt(long, number_of_values = 1, parse(from_os_str))]
+    append_config: Vec<PathBuf>,
+
     /// Read timeout
     #[structopt(long = "tr")]
     tcp_read_timeout: Option<usize>,
@@ -43,11 +342,351 @@ struct ProxycOpt {
     #[structopt(long = "tc")]
     tcp_connect_timeout: Option<usize>,
 
-    /// Program and args to hook
-    args: Vec<String>,
+    /// Do not proxy connections to this subnet, in the form cidr[:port]
+    #[structopt(long, number_of_values = 1)]
+    ignore: Vec<IgnoreSubnet>,
+
+    /// Proxy DNS requests
+    #[structopt(long, overrides_with = "no-proxy-dns")]
+    proxy_dns: bool,
+
+    /// Do not proxy DNS requests
+    #[structopt(long, overrides_with = "proxy-dns")]
+    no_proxy_dns: bool,
+
+    /// Relay UDP datagrams through the socks5 UDP ASSOCIATE of the proxy,
+    /// requires a single socks5 proxy
+    #[structopt(long)]
+    proxy_udp: bool,
+
+    /// Make getsockname() report the address bound by the proxy on relayed
+    /// sockets
+    #[structopt(long)]
+    spoof_sockname: bool,
+
+    /// Forward plain HTTP requests to port 80 in absolute-URI form when the
+    /// last proxy is an HTTP proxy, instead of tunneling them
+    #[structopt(long)]
+    http_absolute_uri: bool,
+
+    /// Send the requests of a chain of socks5 proxies without
+    /// authentication at once, saving a round trip per proxy
+    #[structopt(long)]
+    socks5_pipelining: bool,
+
+    /// Perform the handshakes of non-blocking sockets on this many
+    /// background threads, connect() returning at once
+    #[structopt(long)]
+    handshake_threads: Option<usize>,
+
+    /// Most proxied connections open at once per hooked process
+    #[structopt(long)]
+    max_connections: Option<usize>,
+
+    /// Most proxied connections open at once to the same destination per
+    /// hooked process
+    #[structopt(long)]
+    max_connections_per_destination: Option<usize>,
+
+    /// Milliseconds connect() waits for a connection under the quotas
+    /// before failing with EAGAIN
+    #[structopt(long)]
+    quota_wait: Option<usize>,
+
+    /// Reuse the routing decision of a destination for this many
+    /// milliseconds
+    #[structopt(long)]
+    route_cache_ttl: Option<usize>,
+
+    /// Try first the chain which reached a destination, and the address its
+    /// first proxy was reached at, for this many milliseconds
+    #[structopt(long)]
+    chain_reuse_window: Option<usize>,
+
+    /// Send TCP keep-alives on proxied connections, with the default
+    /// settings unless configured
+    #[structopt(long)]
+    keepalive: bool,
+
+    /// Mark the packets of the connections to the first proxy with this
+    /// DSCP class (0-63), for local QoS policies
+    #[structopt(long)]
+    dscp: Option<u8>,
+
+    /// Scale the read timeouts of the handshakes from the time each hop
+    /// took so far, with the default settings unless configured
+    #[structopt(long)]
+    adaptive_timeout: bool,
+
+    /// Delay the handshake of every hop by this many milliseconds, for
+    /// testing
+    #[structopt(long)]
+    chaos_delay: Option<usize>,
+
+    /// Fail the handshake of every hop with this probability, from 0 to 1,
+    /// for testing
+    #[structopt(long)]
+    chaos_failure_rate: Option<f64>,
+
+    /// Shut down the proxied connections exchanging nothing for this many
+    /// seconds
+    #[structopt(long)]
+    idle_timeout: Option<usize>,
+
+    /// How proxied DNS requests are answered: fake (internal addresses) or
+    /// tor (RESOLVE extension of the last proxy)
+    #[structopt(long)]
+    proxy_dns_mode: Option<ProxyDnsMode>,
+
+    /// Resolve locally the hostnames known to the local sources of
+    /// nsswitch.conf (files, myhostname) rather than through the proxies
+    #[structopt(long)]
+    nsswitch_sources: bool,
+
+    /// Answer the queries of the resolver of the C library (res_query) with
+    /// a DNS responder in the hooked processes
+    #[structopt(long)]
+    dns_server: bool,
+
+    /// What connect() does with address families other than IPv4 and IPv6,
+    /// such as Unix sockets: direct or deny
+    #[structopt(long)]
+    unsupported_family: Option<UnsupportedFamily>,
+
+    /// Connect directly to loopback addresses and Unix sockets, before the
+    /// rules and policies are considered
+    #[structopt(long)]
+    protect_loopback: bool,
+
+    /// Only hook the processes of this user, by uid
+    #[structopt(long, number_of_values = 1)]
+    hooked_uid: Vec<u32>,
+
+    /// Only hook the processes of the members of this group, by gid
+    #[structopt(long, number_of_values = 1)]
+    hooked_gid: Vec<u32>,
+
+    /// Subnet from which internal addresses are assigned to resolved hosts,
+    /// must be a /8 (e.g. 224.0.0.0/8)
+    #[structopt(long, parse(try_from_str = parse_dns_cidr))]
+    dns_cidr: Option<Ipv4Cidr>,
+
+    /// Most hostnames holding an internal address at once, the least
+    /// recently used ones being reclaimed past it
+    #[structopt(long)]
+    dns_table_size: Option<usize>,
+
+    /// Log which rule and chain each connection would use, but always
+    /// connect directly
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Connect directly when the chain fails, trading anonymity for
+    /// availability
+    #[structopt(long)]
+    fallback_direct: bool,
+
+    /// Log the state of the hooked processes when they receive SIGUSR1
+    #[structopt(long)]
+    state_dump: bool,
+
+    /// End the chain at the last proxy exiting in this country (ISO 3166
+    /// code, e.g. de), set with ?country=de in the proxy URLs
+    #[structopt(long)]
+    exit_country: Option<String>,
+
+    /// Set an environment variable in the hooked program, in the form
+    /// KEY=VALUE
+    #[structopt(long = "env", number_of_values = 1, parse(try_from_str = parse_env_var))]
+    env_vars: Vec<(String, String)>,
+
+    /// Remove an environment variable from the hooked program
+    #[structopt(long, number_of_values = 1)]
+    unset: Vec<String>,
+
+    /// Also export HTTP_PROXY, HTTPS_PROXY and ALL_PROXY pointing at the
+    /// socks5 listener of `proxyc serve --socks` at this loopback address,
+    /// the connections to it not being chained again
+    #[structopt(long, parse(try_from_str = parse_proxy_env))]
+    proxy_env: Option<SocketAddr>,
+
+    #[structopt(subcommand)]
+    cmd: Option<ProxycCmd>,
+}
+
+/// Only /8 subnets can be used to assign internal addresses.
+fn parse_dns_cidr(s: &str) -> Result<Ipv4Cidr> {
+    let cidr = Ipv4Cidr::from_str(s).with_context(|| format!("invalid cidr {:?}", s))?;
+    if cidr.network_length() != 8 {
+        bail!("only /8 subnets are supported, got {}", cidr);
+    }
+    Ok(cidr)
+}
+
+/// The exported variables carry no credentials, they can only point at a
+/// listener of proxyc on the host.
+fn parse_proxy_env(s: &str) -> Result<SocketAddr> {
+    let addr = SocketAddr::from_str(s).with_context(|| format!("invalid address {:?}", s))?;
+    if !addr.ip().is_loopback() {
+        bail!(
+            "{} is not the loopback address of a proxyc serve listener",
+            addr
+        );
+    }
+    Ok(addr)
+}
+
+fn parse_env_var(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.is_empty() => Ok((k.to_string(), v.to_string())),
+        _ => bail!("expected KEY=VALUE, got {:?}", s),
+    }
+}
+
+/// Variables read by applications that honor an egress proxy by themselves.
+const PROXY_ENV_VARS: [&str; 6] = [
+    "http_proxy",
+    "HTTP_PROXY",
+    "https_proxy",
+    "HTTPS_PROXY",
+    "all_proxy",
+    "ALL_PROXY",
+];
+
+/// Set by proxyc when executing itself under the library to run a server or
+/// a relay, telling it apart from the commands run by a shell which sourced
+/// the env subcommand.
+const SELF_HOOKED_ENV: &str = "PROXYC_SELF_HOOKED";
+
+/// Environment changes applied to the hooked program on top of the hooking
+/// variables.
+#[derive(Clone)]
+struct EnvChanges {
+    set: Vec<(String, String)>,
+    unset: Vec<String>,
+}
+
+impl EnvChanges {
+    fn new(opts: &ProxycOpt) -> Self {
+        let mut set = vec![];
+        if let Some(addr) = &opts.proxy_env {
+            // hostnames are resolved by the chain as well
+            let proxy = format!("socks5h://{}", addr);
+            set.extend(
+                PROXY_ENV_VARS
+                    .iter()
+                    .map(|k| (k.to_string(), proxy.clone())),
+            );
+        }
+        set.extend(opts.env_vars.iter().cloned());
+
+        EnvChanges {
+            set,
+            unset: opts.unset.clone(),
+        }
+    }
+
+    /// Returns the changes marking proxyc executing itself hooked.
+    fn self_hooked(&self) -> Self {
+        let mut changes = self.clone();
+        changes
+            .set
+            .push((SELF_HOOKED_ENV.to_string(), "1".to_string()));
+        changes
+    }
 }
 
-const CONFIG_FILE_PATHS: [&str; 3] = ["./proxyc.toml", "~/proxyc.toml", "/etc/proxyc/proxyc.toml"];
+/// Parses the proxies given on the command line or in a proxy file, probing
+/// the protocol of those given as ip:port with --probe-type.
+fn parse_proxies(opts: &ProxycOpt, config: &ProxycConfig) -> Result<Vec<ProxyConf>> {
+    let mut lines = opts.proxy.clone();
+
+    if let Some(path) = &opts.proxy_file {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read proxy file {:?}", path))?;
+        lines.extend(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(String::from),
+        );
+    }
+
+    let mut proxies = vec![];
+    let mut unlabeled = vec![];
+    for l in &lines {
+        let probed = opts.probe_type && !l.contains("://");
+        // the type of probed proxies is a placeholder until labeled
+        let proto = match probed {
+            true => Some(ProxyType::Raw),
+            false => opts.proxy_type,
+        };
+        let parsed =
+            ProxyConf::parse_range(l, proto).with_context(|| format!("invalid proxy {:?}", l))?;
+        unlabeled.extend(std::iter::repeat_n(probed, parsed.len()));
+        proxies.extend(parsed);
+    }
+    if !unlabeled.contains(&true) {
+        return Ok(proxies);
+    }
+
+    let probed: Vec<_> = proxies
+        .iter()
+        .zip(&unlabeled)
+        .filter(|(_, probed)| **probed)
+        .map(|(p, _)| p.clone())
+        .collect();
+    let mut labels = probe::label(
+        &probed,
+        Duration::from_millis(config.tcp_connect_timeout as u64),
+        Duration::from_millis(config.tcp_read_timeout as u64),
+    )
+    .into_iter();
+    let mut labeled = vec![];
+    for (proxy, probed) in proxies.into_iter().zip(unlabeled) {
+        if !probed {
+            labeled.push(proxy);
+            continue;
+        }
+        match labels.next().flatten().or(opts.proxy_type) {
+            Some(proto) => labeled.push(ProxyConf { proto, ..proxy }),
+            None => eprintln!(
+                "proxyc: warning: {} answers no proxy protocol, skipped",
+                proxy.host_port()
+            ),
+        }
+    }
+    Ok(labeled)
+}
+
+const SYSTEM_CONFIG_PATH: &str = "/etc/proxyc/proxyc.toml";
+const LOCAL_CONFIG_PATH: &str = "./proxyc.toml";
+
+/// Lists the existing configuration files, from the most generic to the most
+/// specific: system, user and local.
+fn config_layers() -> Vec<PathBuf> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let xdg_config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| home.as_ref().map(|h| h.join(".config")));
+
+    let mut paths = vec![PathBuf::from(SYSTEM_CONFIG_PATH)];
+    if let Some(h) = home {
+        paths.push(h.join("proxyc.toml"));
+    }
+    if let Some(x) = xdg_config {
+        paths.push(x.join("proxyc").join("config.toml"));
+    }
+    paths.push(PathBuf::from(LOCAL_CONFIG_PATH));
+
+    paths
+        .into_iter()
+        .filter(|x| std::fs::metadata(x).is_ok())
+        .filter_map(|x| std::fs::canonicalize(x).ok())
+        .collect()
+}
 
 // search the debug libproxyc.so in the current directory if proxyc is compiled
 // in debug profile.
@@ -57,97 +696,744 @@ const SHARED_LIB_PATHS: [&str; 2] = ["./target/debug/libproxyc.so", "/usr/lib/li
 #[cfg(not(debug_assertions))]
 const SHARED_LIB_PATHS: [&str; 1] = ["/usr/lib/libproxyc.so"];
 
-fn main() -> Result<()> {
-    let opts = ProxycOpt::from_args();
+/// Returns the canonical path of libproxyc.so.
+fn find_library() -> Result<String> {
+    // Termux installs libraries under its own prefix
+    let termux_lib = env::var("PREFIX")
+        .ok()
+        .filter(|_| cfg!(target_os = "android"))
+        .map(|p| format!("{}/lib/libproxyc.so", p));
 
-    let program = opts.args.get(0);
-    let args = opts.args.iter().skip(1);
-
-    // find libproxyc.so
-    let lib_path = SHARED_LIB_PATHS
+    Ok(SHARED_LIB_PATHS
         .iter()
+        .map(|x| x.to_string())
+        .chain(termux_lib)
         .find(|x| std::fs::metadata(x).is_ok())
         .map(|x| std::fs::canonicalize(x).ok())
         .and_then(|x| x)
         .ok_or_else(|| anyhow!("libproxyc.so not found"))?
         .display()
-        .to_string();
-
-    // no files provided, try to find one
-    let config_path = match opts.file_config {
-        Some(p) => Some(p),
-        None => CONFIG_FILE_PATHS
-            .iter()
-            .find(|x| std::fs::metadata(x).is_ok())
-            .map(|x| std::fs::canonicalize(x).ok())
-            .and_then(|x| x),
+        .to_string())
+}
+
+/// Builds the effective configuration from the configuration files and the
+/// command line arguments.
+fn build_config(opts: &ProxycOpt) -> Result<ProxycConfig> {
+    // an explicit configuration file is used on its own, otherwise every
+    // layer found is merged.
+    let config_paths = match &opts.file_config {
+        Some(p) => vec![p.clone()],
+        None => config_layers(),
     };
 
-    // parse the config before passing it down the shared library through the
-    // environment
-    let config = {
-        let mut config = {
-            if let Some(p) = &config_path {
-                ProxycConfig::new(p)
-                    .with_context(|| format!("Invalid configuration file: {:?}", config_path))?
-            } else {
-                ProxycConfig::default()
-            }
-        };
-        // providing proxies in CLI parameters overwrites the proxies defined
-        // in the configuration file, if any.
-        if !opts.proxy.is_empty() {
-            config.proxies = opts.proxy;
+    let (config, warnings) =
+        ProxycConfig::from_layers_with_warnings(&config_paths, &opts.append_config)
+            .context("Invalid configuration")?;
+    if !opts.quiet {
+        for w in warnings {
+            eprintln!("proxyc: warning: {}", w);
         }
+    }
 
-        if opts.quiet {
-            config.log_level = LevelFilter::Off;
-        } else if let Some(level) = opts.log_level {
-            config.log_level = level;
-        }
+    // providing proxies in CLI parameters overwrites the proxies defined
+    // in the configuration file, if any. Without any of them, the egress
+    // proxy of the environment is used.
+    let mut proxies = parse_proxies(opts, &config)?;
+    if proxies.is_empty()
+        && config.proxies.is_empty()
+        && config.upstreams.is_empty()
+        && config.transport.is_none()
+    {
+        proxies.extend(ProxyConf::from_proxy_env()?);
+    }
+    if proxies.is_empty() {
+        proxies = config.proxies.clone();
+    }
+
+    // managed upstreams are the first hops of the chain, or the bridge of the
+    // transport, whose client is inserted in front of it once started.
+    let bridge = config.transport.as_ref().map(|t| ProxyConf {
+        proto: t.proto,
+        ip: t.bridge.ip,
+        port: t.bridge.port,
+        auth: None,
+        alt_ips: vec![],
+        hostname: None,
+        country: None,
+        auth_methods: vec![],
+        check: None,
+    });
+    let proxies = config
+        .upstreams
+        .iter()
+        .map(|u| u.proxy.clone())
+        .chain(bridge)
+        .chain(proxies)
+        .collect();
+    let config_keepalive = config.keepalive;
+    let config_adaptive_timeout = config.adaptive_timeout;
+    let config_chaos = config.chaos;
+    let mut builder = config.into_builder().proxies(proxies);
+
+    if opts.validate_on_start {
+        builder = builder.validate_on_start(true);
+    }
+
+    if let Some(seconds) = opts.health_interval {
+        builder = builder.health_interval(seconds);
+    }
+
+    if opts.quiet {
+        builder = builder.log_level(LevelFilter::Off);
+    } else if let Some(level) = opts.log_level {
+        builder = builder.log_level(level);
+    }
+
+    if let Some(chain) = &opts.chain {
+        builder = builder.chain(*chain);
+    }
+
+    if !opts.chain_order.is_empty() {
+        builder = builder.chain_order(opts.chain_order.clone());
+    }
+
+    if let Some(len) = opts.min_chain_len {
+        builder = builder.min_chain_len(len);
+    }
+
+    if let Some(len) = opts.chain_len {
+        builder = builder.chain_len(len);
+    }
+
+    if let Some(scope) = opts.random_scope {
+        builder = builder.random_scope(scope);
+    }
 
-        if let Some(chain) = opts.chain {
-            config.chain_type = chain;
+    if let Some(seed) = opts.random_seed {
+        builder = builder.random_seed(seed);
+    }
+
+    if let Some(tcp_connect_timeout) = opts.tcp_connect_timeout {
+        builder = builder.tcp_connect_timeout(tcp_connect_timeout);
+    }
+
+    if let Some(tcp_read_timeout) = opts.tcp_read_timeout {
+        builder = builder.tcp_read_timeout(tcp_read_timeout);
+    }
+
+    // ignored subnets given in CLI parameters are added to the ones
+    // defined in the configuration file.
+    for subnet in &opts.ignore {
+        builder = builder.ignore_subnet(subnet.clone());
+    }
+
+    if opts.proxy_dns {
+        builder = builder.proxy_dns(true);
+    } else if opts.no_proxy_dns {
+        builder = builder.proxy_dns(false);
+    }
+
+    if opts.proxy_udp {
+        builder = builder.proxy_udp(true);
+    }
+
+    if opts.spoof_sockname {
+        builder = builder.spoof_sockname(true);
+    }
+
+    if opts.http_absolute_uri {
+        builder = builder.http_absolute_uri(true);
+    }
+
+    if opts.socks5_pipelining {
+        builder = builder.socks5_pipelining(true);
+    }
+
+    if let Some(threads) = opts.handshake_threads {
+        builder = builder.handshake_threads(threads);
+    }
+    if let Some(dscp) = opts.dscp {
+        builder = builder.dscp(dscp);
+    }
+    if let Some(max) = opts.max_connections {
+        builder = builder.max_connections(max);
+    }
+    if let Some(max) = opts.max_connections_per_destination {
+        builder = builder.max_connections_per_destination(max);
+    }
+    if let Some(wait) = opts.quota_wait {
+        builder = builder.quota_wait(wait);
+    }
+
+    if let Some(ttl) = opts.route_cache_ttl {
+        builder = builder.route_cache_ttl(ttl);
+    }
+
+    if let Some(window) = opts.chain_reuse_window {
+        builder = builder.chain_reuse_window(window);
+    }
+
+    if let Some(idle_timeout) = opts.idle_timeout {
+        builder = builder.idle_timeout(idle_timeout);
+    }
+
+    if opts.keepalive && config_keepalive.is_none() {
+        builder = builder.keepalive(Keepalive::default());
+    }
+
+    if opts.adaptive_timeout && config_adaptive_timeout.is_none() {
+        builder = builder.adaptive_timeout(AdaptiveTimeout::default());
+    }
+
+    if opts.chaos_delay.is_some() || opts.chaos_failure_rate.is_some() {
+        let mut chaos = config_chaos.unwrap_or_default();
+        if let Some(delay) = opts.chaos_delay {
+            chaos.delay = delay;
+        }
+        if let Some(rate) = opts.chaos_failure_rate {
+            chaos.failure_rate = rate;
         }
+        builder = builder.chaos(chaos);
+    }
+
+    if let Some(mode) = opts.proxy_dns_mode {
+        builder = builder.proxy_dns_mode(mode);
+    }
+
+    if opts.nsswitch_sources {
+        builder = builder.nsswitch_sources(true);
+    }
 
-        if let Some(tcp_connect_timeout) = opts.tcp_connect_timeout {
-            config.tcp_connect_timeout = tcp_connect_timeout;
+    if opts.dns_server {
+        builder = builder.dns_server(true);
+    }
+
+    if let Some(policy) = opts.unsupported_family {
+        builder = builder.unsupported_family(policy);
+    }
+    if opts.protect_loopback {
+        builder = builder.protect_loopback(true);
+    }
+
+    for uid in &opts.hooked_uid {
+        builder = builder.hooked_uid(*uid);
+    }
+    for gid in &opts.hooked_gid {
+        builder = builder.hooked_gid(*gid);
+    }
+
+    if let Some(dns_cidr) = opts.dns_cidr {
+        builder = builder.dns_cidr(dns_cidr);
+    }
+
+    if let Some(size) = opts.dns_table_size {
+        builder = builder.dns_table_size(size);
+    }
+
+    if opts.dry_run {
+        builder = builder.dry_run(true);
+    }
+
+    if opts.fallback_direct {
+        builder = builder.fallback_direct(true);
+    }
+
+    if opts.state_dump {
+        builder = builder.state_dump(true);
+    }
+
+    if let Some(country) = &opts.exit_country {
+        builder = builder.exit_country(country.clone());
+    }
+
+    // connections of applications honoring the proxy variables are made to
+    // the exported proxy, they must not be chained a second time.
+    if let Some(SocketAddr::V4(addr)) = &opts.proxy_env {
+        builder = builder.ignore_subnet(IgnoreSubnet {
+            cidr: Ipv4Cidr::new_host(*addr.ip()),
+            port: Some(addr.port()),
+        });
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Quotes a value for a POSIX shell.
+fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Prints the variables required to hook a program, in a format that can be
+/// sourced by a shell, used in a systemd unit or given as a docker env-file.
+fn print_env(lib_path: &str, config_env: &str, changes: &EnvChanges, format: &EnvFormat) {
+    match format {
+        EnvFormat::Sh => {
+            for k in &changes.unset {
+                println!("unset {}", k);
+            }
+            for (k, v) in &changes.set {
+                println!("export {}={}", k, sh_quote(v));
+            }
+            // keep the libraries already preloaded by the sourcing shell
+            println!(
+                "export LD_PRELOAD=\"${{LD_PRELOAD:+$LD_PRELOAD:}}\"{}",
+                sh_quote(lib_path)
+            );
+            println!("export PROXYC_CONFIG={}", sh_quote(config_env));
         }
+        EnvFormat::Systemd => {
+            let quote = |s: &str| {
+                format!(
+                    "\"{}\"",
+                    s.replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('%', "%%")
+                )
+            };
+            println!("[Service]");
+            for k in &changes.unset {
+                println!("UnsetEnvironment={}", k);
+            }
+            for (k, v) in &changes.set {
+                println!("Environment={}", quote(&format!("{}={}", k, v)));
+            }
+            println!("Environment={}", quote(&format!("LD_PRELOAD={}", lib_path)));
+            println!(
+                "Environment={}",
+                quote(&format!("PROXYC_CONFIG={}", config_env))
+            );
+        }
+        EnvFormat::Docker => {
+            // containers start from a clean environment, there is nothing to
+            // unset.
+            for (k, v) in &changes.set {
+                println!("{}={}", k, v);
+            }
+            println!("LD_PRELOAD={}", lib_path);
+            println!("PROXYC_CONFIG={}", config_env);
+        }
+    }
+}
 
-        if let Some(tcp_read_timeout) = opts.tcp_read_timeout {
-            config.tcp_read_timeout = tcp_read_timeout;
+/// Parses the command line arguments.
+///
+/// clap does not let an external subcommand follow "--", which is needed to
+/// hook programs sharing the name of a subcommand (e.g. `proxyc -- env`).
+fn parse_args() -> ProxycOpt {
+    let args: Vec<String> = env::args().collect();
+
+    if let Some(i) = args.iter().position(|a| a == "--") {
+        let mut opts = ProxycOpt::from_iter(&args[..i]);
+        if opts.cmd.is_none() && i + 1 < args.len() {
+            opts.cmd = Some(ProxycCmd::Exec(args[i + 1..].to_vec()));
+            return opts;
         }
+    }
 
-        config
-    };
+    ProxycOpt::from_iter(&args)
+}
 
-    // check if there are any proxies defined
-    if config.proxies.is_empty() {
-        bail!("at least one proxy is required, use --proxy or define the list of proxies in the configuration file.");
+/// Size above which the proxies are moved out of PROXYC_CONFIG, exec()
+/// refusing environment strings over 128 KiB.
+const MAX_CONFIG_ENV: usize = 64 * 1024;
+
+/// Returns the configuration as passed in PROXYC_CONFIG, along with the file
+/// holding its proxies when they are too many to be inlined. The file is an
+/// anonymous memory file inherited by the hooked program, kept open as long
+/// as it is returned.
+fn config_env(config: &ProxycConfig) -> Result<(String, Option<File>)> {
+    let json = config.to_json()?;
+    if json.len() <= MAX_CONFIG_ENV {
+        return Ok((json, None));
     }
 
-    // pass config in env variable
-    let config_env = config.to_json()?;
+    let name = CString::new("proxyc-pool")?;
+    let fd = memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC)
+        .context("failed to create the proxy pool")?;
+    let mut pool = unsafe { File::from_raw_fd(fd) };
+    serde_json::to_writer(BufWriter::new(&mut pool), &config.proxies)?;
+    let path = format!("/proc/self/fd/{}", fd);
+    Ok((pooled_json(config, &path)?, Some(pool)))
+}
+
+/// Writes the proxies to a file for the env subcommand, whose output outlives
+/// it, and returns the configuration referencing it.
+fn config_env_file(config: &ProxycConfig) -> Result<String> {
+    let json = config.to_json()?;
+    if json.len() <= MAX_CONFIG_ENV {
+        return Ok(json);
+    }
+
+    let path = write_pool(config)?;
+    pooled_json(config, &path.to_string_lossy())
+}
+
+/// Writes the proxies to a temporary file outliving proxyc, and returns its
+/// path.
+fn write_pool(config: &ProxycConfig) -> Result<PathBuf> {
+    let path = env::temp_dir().join(format!("proxyc-pool-{}.json", std::process::id()));
+    let pool =
+        File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+    serde_json::to_writer(BufWriter::new(pool), &config.proxies)?;
+    eprintln!(
+        "proxyc: {} proxies written to {}",
+        config.proxies.len(),
+        path.display()
+    );
+    Ok(path)
+}
+
+/// Returns the configuration with its proxies replaced by the pool at `path`.
+fn pooled_json(config: &ProxycConfig, path: &str) -> Result<String> {
+    let mut value = serde_json::to_value(config)?;
+    let table = value
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("configuration is not an object"))?;
+    table.insert("proxy".into(), serde_json::Value::Array(vec![]));
+    table.insert("proxy_pool".into(), path.into());
+    Ok(value.to_string())
+}
 
+/// Builds the command running `args` hooked by libproxyc.
+fn hook_command(
+    args: &[String],
+    lib_path: &str,
+    config: &ProxycConfig,
+    changes: &EnvChanges,
+) -> Result<Command> {
+    let lib_path = arch::library_for(&args[0], lib_path).context(Failure::Library)?;
     // do not overwrite LD_PRELOAD variable if it is already set
     let ld_preload = match env::var("LD_PRELOAD") {
         Ok(val) => format!("{}:{}", val, lib_path),
         Err(_e) => lib_path,
     };
 
-    match program {
-        Some(x) => {
-            Command::new(&x)
-                .args(args)
-                .env("LD_PRELOAD", ld_preload)
-                .env("PROXYC_CONFIG", config_env)
-                .exec();
+    // pass config in env variable
+    let (config_env, pool) = config_env(config)?;
+    let mut command = Command::new(&args[0]);
+    if let Some(pool) = pool {
+        // the pool is inherited across exec() only, and lives as long as the
+        // command
+        unsafe {
+            command.pre_exec(move || {
+                fcntl(pool.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::empty()))?;
+                Ok(())
+            });
+        }
+    }
+    for k in &changes.unset {
+        command.env_remove(k);
+    }
+    command
+        .args(&args[1..])
+        .envs(changes.set.iter().map(|(k, v)| (k, v)))
+        .env("LD_PRELOAD", ld_preload)
+        .env("PROXYC_CONFIG", config_env);
+    Ok(command)
+}
+
+/// Removes the dead proxies of the pool with validate_on_start, printing a
+/// summary. The proxies of upstreams and transports, not started yet, are
+/// kept.
+fn validate_pool(config: ProxycConfig, quiet: bool) -> Result<ProxycConfig> {
+    if !config.validate_on_start {
+        return Ok(config);
+    }
+
+    let managed = config.managed_hops();
+    let pool = &config.proxies[managed..];
+    let errors = probe::validate(
+        pool,
+        Duration::from_millis(config.tcp_connect_timeout as u64),
+        Duration::from_millis(config.tcp_read_timeout as u64),
+    );
+    let mut proxies = config.proxies[..managed].to_vec();
+    for (proxy, error) in pool.iter().zip(errors) {
+        match error {
+            None => proxies.push(proxy.clone()),
+            Some(e) if !quiet => eprintln!("proxyc:   {}: dead, {}", proxy, e),
+            Some(_) => {}
+        }
+    }
+
+    let alive = proxies.len() - managed;
+    if !quiet {
+        eprintln!("proxyc: {}/{} proxies alive", alive, pool.len());
+    }
+    if alive == 0 && !pool.is_empty() {
+        return Err(anyhow!("no proxy of the pool is alive").context(Failure::ChainValidation));
+    }
+    Ok(config.into_builder().proxies(proxies).build()?)
+}
+
+/// Replaces this process with the hooked program, or runs it as a child when
+/// upstreams have to be torn down once it exits.
+fn exec_hooked(
+    args: &[String],
+    lib_path: &str,
+    mut config: ProxycConfig,
+    changes: &EnvChanges,
+) -> Result<()> {
+    let mut gids = getgroups().unwrap_or_default();
+    gids.push(getgid());
+    let gids: Vec<u32> = gids.into_iter().map(|g| g.as_raw()).collect();
+    if !config.hooks(getuid().as_raw(), &gids) {
+        eprintln!(
+            "proxyc: warning: hooked_uids and hooked_gids leave {} unhooked",
+            args[0]
+        );
+    }
+
+    if Upstreams::needed(&config) {
+        let upstreams = Upstreams::start(&mut config)?;
+        let code = run::run_once(hook_command(args, lib_path, &config, changes)?)?;
+        // exit() does not run destructors
+        drop(upstreams);
+        std::process::exit(code);
+    }
+
+    let err = hook_command(args, lib_path, &config, changes)?.exec();
+    Err(exit::launch_error(
+        err,
+        format!("failed to execute {:?}", args[0]),
+    ))
+}
+
+fn main() {
+    let opts = parse_args();
+    let format = opts.error_format;
+    if let Err(e) = try_main(opts) {
+        std::process::exit(exit::report(&e, format));
+    }
+}
+
+fn try_main(opts: ProxycOpt) -> Result<()> {
+    // reports and the schema neither hook nor need a configuration
+    if let Some(ProxycCmd::Report { file }) = &opts.cmd {
+        return audit::report(file);
+    }
+
+    if let Some(ProxycCmd::Config(ConfigCmd::Schema)) = &opts.cmd {
+        println!("{}", ProxycConfig::json_schema()?);
+        return Ok(());
+    }
+
+    // explanations only need the configuration
+    if let Some(ProxycCmd::Rules(RulesCmd::Explain { target, process })) = &opts.cmd {
+        let process: Vec<String> = process.iter().cloned().collect();
+        let config = build_config(&opts).context(Failure::Config)?;
+        return rules::explain(&config, *target, &process);
+    }
+
+    // wrappers run proxyc again, checking the options once now
+    if let Some(ProxycCmd::Wrap {
+        program,
+        output,
+        force,
+    }) = &opts.cmd
+    {
+        build_config(&opts).context(Failure::Config)?;
+        let path = wrap::write(program, output.as_deref(), *force, &wrap::options()?)?;
+        eprintln!("proxyc: {} runs {} hooked", path.display(), program);
+        return Ok(());
+    }
+
+    // the server and the relays of namespaces and cgroups run hooked, proxyc
+    // executing itself under the library. The marker is not passed down to
+    // the programs they run.
+    if env::var_os(SELF_HOOKED_ENV).is_some() {
+        env::remove_var(SELF_HOOKED_ENV);
+        match &opts.cmd {
+            Some(ProxycCmd::Serve {
+                takeover,
+                socks,
+                http,
+            }) => return serve::serve(takeover.as_deref(), *socks, *http),
+            Some(ProxycCmd::Forward { forwards }) => return serve::forward(forwards),
+            Some(ProxycCmd::Reverse { reverses }) => return serve::reverse(reverses),
+            Some(ProxycCmd::Netns { user, args }) => {
+                std::process::exit(netns::run(user.as_deref(), args)?)
+            }
+            Some(ProxycCmd::Ebpf { args }) => std::process::exit(ebpf::run(args)?),
+            _ => {}
+        }
+    }
+
+    let lib_path = find_library().context(Failure::Library)?;
+
+    // parse the config before passing it down the shared library through the
+    // environment
+    let config = build_config(&opts).context(Failure::Config)?;
+    let changes = EnvChanges::new(&opts);
+
+    // the pool is checked by the commands launching a hooked program
+    let config = match &opts.cmd {
+        None | Some(ProxycCmd::Env { .. } | ProxycCmd::Bench { .. }) => config,
+        Some(ProxycCmd::Containerize { apply: false, .. }) => config,
+        _ => validate_pool(config, opts.quiet)?,
+    };
+
+    match &opts.cmd {
+        Some(ProxycCmd::Env { format }) => {
+            if !config.upstreams.is_empty() || config.transport.is_some() {
+                eprintln!("proxyc: upstreams are not started by the env subcommand");
+            }
+            print_env(&lib_path, &config_env_file(&config)?, &changes, format);
+            Ok(())
         }
+        Some(ProxycCmd::Bench {
+            output,
+            jobs,
+            target,
+        }) => {
+            let mut proxies = parse_proxies(&opts, &config)?;
+            if proxies.is_empty() {
+                proxies = config.proxies.clone();
+            }
+            let target = bench::Target::parse(target)?;
+            let auth = config.auth.as_ref().map(DefaultAuth::auth);
+            let reports = bench::run(
+                &proxies,
+                auth.as_ref(),
+                &target,
+                *jobs,
+                Duration::from_millis(config.tcp_connect_timeout as u64),
+                Duration::from_millis(config.tcp_read_timeout as u64),
+            );
+
+            for r in &reports {
+                match (&r.latency, &r.error) {
+                    (Some(latency), _) => println!(
+                        "{}\t{}ms\t{}",
+                        r.proxy,
+                        latency.as_millis(),
+                        r.exit_ip.map_or("-".to_string(), |ip| ip.to_string())
+                    ),
+                    (None, Some(e)) => println!("{}\tdead\t{}", r.proxy, e),
+                    (None, None) => unreachable!(),
+                }
+            }
+            let live = reports.iter().filter(|r| r.error.is_none()).count();
+            eprintln!("proxyc: {}/{} proxies alive", live, reports.len());
+
+            if let Some(path) = output {
+                bench::write_list(path, &reports)?;
+            }
+            Ok(())
+        }
+        Some(ProxycCmd::Run { restart, args }) => {
+            let mut config = config;
+            let upstreams = Upstreams::start(&mut config)?;
+            let code = run::supervise(restart, config, |c| {
+                hook_command(args, &lib_path, c, &changes)
+            })?;
+            // exit() does not run destructors
+            drop(upstreams);
+            std::process::exit(code);
+        }
+        Some(ProxycCmd::Record { output, args }) => {
+            // hooked programs may change their working directory
+            let output = env::current_dir()?.join(output);
+            std::fs::File::create(&output)
+                .with_context(|| format!("failed to create {:?}", output))?;
+            let config = config.into_builder().audit_file(output).build()?;
+            exec_hooked(args, &lib_path, config, &changes)
+        }
+        Some(ProxycCmd::Serve {
+            takeover,
+            socks,
+            http,
+        }) => {
+            let mut args = vec![
+                env::current_exe()?.to_string_lossy().into_owned(),
+                "serve".to_string(),
+            ];
+            if let Some(takeover) = takeover {
+                let takeover = takeover
+                    .to_str()
+                    .ok_or_else(|| anyhow!("takeover path {:?} is not UTF-8", takeover))?;
+                args.extend(["--takeover".to_string(), takeover.to_string()]);
+            }
+            if let Some(socks) = socks {
+                args.extend(["--socks".to_string(), socks.to_string()]);
+            }
+            if let Some(http) = http {
+                args.extend(["--http".to_string(), http.to_string()]);
+            }
+            exec_hooked(&args, &lib_path, config, &changes.self_hooked())
+        }
+        Some(ProxycCmd::Forward { forwards }) => {
+            let mut args = vec![
+                env::current_exe()?.to_string_lossy().into_owned(),
+                "forward".to_string(),
+            ];
+            for f in forwards {
+                args.extend(["-L".to_string(), f.to_string()]);
+            }
+            exec_hooked(&args, &lib_path, config, &changes.self_hooked())
+        }
+        Some(ProxycCmd::Reverse { reverses }) => {
+            let mut args = vec![
+                env::current_exe()?.to_string_lossy().into_owned(),
+                "reverse".to_string(),
+            ];
+            for r in reverses {
+                args.extend(["-R".to_string(), r.to_string()]);
+            }
+            exec_hooked(&args, &lib_path, config, &changes.self_hooked())
+        }
+        Some(ProxycCmd::Containerize {
+            engine,
+            apply,
+            args,
+        }) => {
+            if !*apply {
+                if Upstreams::needed(&config) {
+                    eprintln!("proxyc: upstreams are only started with --apply");
+                }
+                let command = container::command(engine, &lib_path, &config, &changes, args)?;
+                println!("{}", container::quote(&command));
+                return Ok(());
+            }
+            if args.is_empty() {
+                bail!("--apply requires the image to run");
+            }
+            let mut config = config;
+            let upstreams = Upstreams::start(&mut config)?;
+            let command = container::command(engine, &lib_path, &config, &changes, args)?;
+            let mut run = Command::new(&command[0]);
+            run.args(&command[1..]);
+            let code = run::run_once(run)?;
+            drop(upstreams);
+            std::process::exit(code);
+        }
+        Some(ProxycCmd::Netns { args, .. } | ProxycCmd::Ebpf { args }) => {
+            let mut relay = vec![env::current_exe()?.to_string_lossy().into_owned()];
+            match &opts.cmd {
+                Some(ProxycCmd::Netns { user, .. }) => {
+                    relay.push("netns".to_string());
+                    if let Some(user) = user {
+                        relay.extend(["--user".to_string(), user.clone()]);
+                    }
+                }
+                _ => relay.push("ebpf".to_string()),
+            }
+            relay.extend(args.iter().cloned());
+            exec_hooked(&relay, &lib_path, config, &changes.self_hooked())
+        }
+        Some(
+            ProxycCmd::Report { .. }
+            | ProxycCmd::Config(_)
+            | ProxycCmd::Rules(_)
+            | ProxycCmd::Wrap { .. },
+        ) => {
+            unreachable!()
+        }
+        Some(ProxycCmd::Exec(args)) => exec_hooked(args, &lib_path, config, &changes),
         None => {
             ProxycOpt::clap().print_help().unwrap();
             println!();
             std::process::exit(1);
         }
-    };
-    Ok(())
+    }
 }
@@ -0,0 +1,284 @@
+//! Full tunnel running a program in its own network namespace.
+//!
+//! The namespace is only linked to the host by a veth pair, whose host end
+//! redirects TCP to a relay of this process, which runs hooked: the
+//! connections are chained by the library, without the program being hooked
+//! itself. Other packets are neither redirected nor forwarded, so that raw
+//! sockets and UDP have no egress at all.
+use crate::{run, serve};
+use anyhow::{anyhow, bail, Context, Result};
+use nix::libc::{self, sockaddr_in, socklen_t};
+use nix::sched::{setns, unshare, CloneFlags};
+use nix::unistd::{getgrouplist, setgid, setgroups, setuid, Uid, User};
+use std::env;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// Block of the /30 subnet holding 169.254.169.254, skipped for cloud
+/// metadata services to stay reachable from the host.
+const METADATA_BLOCK: u32 = (169 * 256 + 252) / 4;
+
+/// Number of /30 subnets in 169.254.0.0/16.
+const BLOCKS: u32 = 1 << 14;
+
+/// Prefix of the comment tagging the redirections with the pid of the
+/// process which added them.
+const TAG: &str = "proxyc-netns-";
+
+/// Veth pair and redirection of its traffic, removed when dropped.
+struct Tunnel {
+    host_if: String,
+    port: Option<u16>,
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        if let Some(port) = self.port {
+            let _ = command(&iptables(&self.host_if, port, "-D"));
+        }
+        // the namespace end goes with it
+        let _ = command(&format!("ip link del {}", self.host_if));
+    }
+}
+
+/// Runs the command `line`, whose arguments are separated by spaces,
+/// quietly.
+fn command(line: &str) -> Result<()> {
+    output(line).map(|_| ())
+}
+
+/// Runs the command `line`, whose arguments are separated by spaces, and
+/// returns its output.
+fn output(line: &str) -> Result<String> {
+    let args: Vec<&str> = line.split(' ').collect();
+    let output = Command::new(args[0])
+        .args(&args[1..])
+        .env_remove("LD_PRELOAD")
+        .env_remove("PROXYC_CONFIG")
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("failed to execute {}", args[0]))?;
+    if !output.status.success() {
+        bail!("{:?} failed: {}", line, output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Returns the iptables command applying `action` to the redirection of the
+/// TCP connections arriving on `host_if` to `port`, tagged with the pid of
+/// this process.
+fn iptables(host_if: &str, port: u16, action: &str) -> String {
+    format!(
+        "iptables -t nat {} PREROUTING -i {} -p tcp -m comment --comment {}{} -j REDIRECT --to-ports {}",
+        action,
+        host_if,
+        TAG,
+        std::process::id(),
+        port
+    )
+}
+
+/// Whether process `pid` is running.
+fn alive(pid: u32) -> bool {
+    Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+/// Removes the redirections and veth pairs left behind by the killed netns
+/// processes, found by the pid in their tag and name.
+fn remove_stale() -> Result<()> {
+    // lines as "-A PREROUTING -i pxc1234h ... --comment proxyc-netns-1234 ..."
+    let rules = output("iptables -t nat -S PREROUTING")?;
+    for rule in rules.lines() {
+        let pid = rule
+            .split(' ')
+            .find_map(|w| w.strip_prefix(TAG))
+            .and_then(|pid| pid.parse().ok());
+        if pid.is_some_and(|pid| !alive(pid)) {
+            let _ = command(&format!("iptables -t nat {}", rule.replacen("-A", "-D", 1)));
+        }
+    }
+
+    // lines as "5: pxc1234h@if4: <BROADCAST,...", the namespace end going
+    // with the host one
+    let links = output("ip -o link show")?;
+    for link in links.lines() {
+        let name = match link.split(": ").nth(1) {
+            Some(name) => name.split('@').next().unwrap_or(name),
+            None => continue,
+        };
+        let pid = name
+            .strip_prefix("pxc")
+            .and_then(|n| n.strip_suffix('h'))
+            .and_then(|pid| pid.parse().ok());
+        if pid.is_some_and(|pid| !alive(pid)) {
+            let _ = command(&format!("ip link del {}", name));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the first and last addresses of the IPv4 networks of the host
+/// interfaces.
+fn host_networks() -> Result<Vec<(u32, u32)>> {
+    // lines as "2: eth0    inet 10.0.0.2/24 brd 10.0.0.255 scope global eth0"
+    let addrs = output("ip -4 -o addr show")?;
+    let networks = addrs
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            words.find(|w| *w == "inet")?;
+            let (ip, len) = words.next()?.split_once('/')?;
+            let ip = u32::from(ip.parse::<Ipv4Addr>().ok()?);
+            let len: u32 = len.parse().ok().filter(|l| *l <= 32)?;
+            let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+            Some((ip & mask, ip | !mask))
+        })
+        .collect();
+    Ok(networks)
+}
+
+/// Returns the addresses of the host and namespace ends of the veth pair, in
+/// the first /30 subnet of 169.254.0.0/16 from the one of process `pid`
+/// overlapping no network of the host interfaces.
+fn subnet(pid: u32) -> Result<(Ipv4Addr, Ipv4Addr)> {
+    let networks = host_networks()?;
+    let start = u32::from(Ipv4Addr::new(169, 254, 0, 0));
+    (0..BLOCKS)
+        .map(|i| (pid % BLOCKS + i) % BLOCKS)
+        .filter(|block| *block != METADATA_BLOCK)
+        .map(|block| start + block * 4)
+        .find(|base| {
+            networks
+                .iter()
+                .all(|(first, last)| *last < *base || *first > base + 3)
+        })
+        .map(|base| (Ipv4Addr::from(base + 1), Ipv4Addr::from(base + 2)))
+        .ok_or_else(|| anyhow!("no free /30 subnet left in 169.254.0.0/16"))
+}
+
+/// Returns the user the program runs as: `name`, else the one who ran sudo,
+/// else none to stay root.
+fn program_user(name: Option<&str>) -> Result<Option<User>> {
+    if let Some(name) = name {
+        return User::from_name(name)?
+            .map(Some)
+            .ok_or_else(|| anyhow!("unknown user {}", name));
+    }
+    match env::var("SUDO_UID").ok().and_then(|uid| uid.parse().ok()) {
+        Some(uid) => Ok(User::from_uid(Uid::from_raw(uid))?),
+        None => Ok(None),
+    }
+}
+
+/// Returns the destination `client` connected to, before its redirection.
+fn original_dst(client: &TcpStream) -> io::Result<SocketAddr> {
+    let mut addr: sockaddr_in = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<sockaddr_in>() as socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            client.as_raw_fd(),
+            libc::SOL_IP,
+            libc::SO_ORIGINAL_DST,
+            &mut addr as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(SocketAddr::V4(SocketAddrV4::new(
+        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+        u16::from_be(addr.sin_port),
+    )))
+}
+
+/// Runs `args` in a new network namespace whose TCP connections are chained
+/// by this process, as `user`, the one who ran sudo by default. Returns its
+/// exit code.
+pub fn run(user: Option<&str>, args: &[String]) -> Result<i32> {
+    if !Uid::effective().is_root() {
+        bail!("the netns subcommand requires root");
+    }
+    command("iptables --version")
+        .context("iptables is required to redirect the connections of the namespace")?;
+    let user = program_user(user)?;
+    let groups = match &user {
+        Some(u) => getgrouplist(&CString::new(u.name.as_str())?, u.gid)?,
+        None => vec![],
+    };
+    remove_stale()?;
+
+    let pid = std::process::id();
+    // the namespace lives as long as its file, unsharing only affecting the
+    // thread
+    let ns = thread::spawn(|| -> Result<File> {
+        unshare(CloneFlags::CLONE_NEWNET)?;
+        Ok(File::open("/proc/thread-self/ns/net")?)
+    })
+    .join()
+    .map_err(|_| anyhow!("failed to create the namespace"))?
+    .context("failed to create the namespace")?;
+    let nsenter = format!("nsenter --net=/proc/{}/fd/{}", pid, ns.as_raw_fd());
+
+    let (host_ip, ns_ip) = subnet(pid)?;
+    let mut tunnel = Tunnel {
+        host_if: format!("pxc{}h", pid),
+        port: None,
+    };
+    let ns_if = format!("pxc{}n", pid);
+    let host_if = &tunnel.host_if;
+    for c in [
+        format!(
+            "{} ip link add {} type veth peer name {} netns {}",
+            nsenter, ns_if, host_if, pid
+        ),
+        format!("ip addr add {}/30 dev {}", host_ip, host_if),
+        format!("ip link set {} up", host_if),
+        format!("{} ip link set lo up", nsenter),
+        format!("{} ip addr add {}/30 dev {}", nsenter, ns_ip, ns_if),
+        format!("{} ip link set {} up", nsenter, ns_if),
+        format!("{} ip route add default via {}", nsenter, host_ip),
+    ] {
+        command(&c)?;
+    }
+    // hosts forwarding for containers must not route the namespace
+    let forwarding = format!("/proc/sys/net/ipv4/conf/{}/forwarding", host_if);
+    std::fs::write(&forwarding, "0").with_context(|| format!("failed to write {}", forwarding))?;
+
+    let listener = TcpListener::bind((host_ip, 0)).context("failed to bind the relay")?;
+    let port = listener.local_addr()?.port();
+    command(&iptables(host_if, port, "-A"))?;
+    tunnel.port = Some(port);
+    thread::spawn(move || serve::redirected(listener, original_dst));
+
+    let mut program = Command::new(&args[0]);
+    program
+        .args(&args[1..])
+        .env_remove("LD_PRELOAD")
+        .env_remove("PROXYC_CONFIG");
+    let ns_fd = ns.as_raw_fd();
+    let ids = user.map(|u| (u.uid, u.gid));
+    unsafe {
+        program.pre_exec(move || {
+            setns(ns_fd, CloneFlags::CLONE_NEWNET)?;
+            // root is only needed to set up the namespace
+            if let Some((uid, gid)) = ids {
+                setgroups(&groups)?;
+                setgid(gid)?;
+                setuid(uid)?;
+            }
+            Ok(())
+        });
+    }
+    let child = program
+        .spawn()
+        .with_context(|| format!("failed to execute {:?}", args[0]))?;
+    run::wait(child)
+}
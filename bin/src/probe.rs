@@ -0,0 +1,138 @@
+//! Probes of the proxies at startup: detection of the protocol of those given
+//! as bare ip:port, and validation of the pool.
+//!
+//! Each protocol is tried on a new connection, socks5 first then socks4 then
+//! http: a socks5 greeting gets no well formed reply from the others, and
+//! plain web servers answer anything with an HTTP status.
+use crate::bench::proxy_addr;
+use anyhow::{anyhow, bail, Result};
+use proxyc_common::{ProxyConf, ProxyType};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Proxies probed concurrently, raw lists counting thousands of them.
+const JOBS: usize = 64;
+
+/// Longest wait for a reply to a probe, the proxies of other protocols
+/// staying silent on the requests they do not understand.
+const MAX_REPLY_WAIT: Duration = Duration::from_secs(3);
+
+/// Protocols tried in turn.
+const ORDER: [ProxyType; 3] = [ProxyType::Socks5, ProxyType::Socks4, ProxyType::Http];
+
+/// Statuses plain web servers answer a CONNECT request with.
+const NOT_A_PROXY: [&str; 4] = ["400", "404", "405", "501"];
+
+/// Checks the proxy at `addr` answers `proto`, on a new connection.
+fn speaks(
+    addr: SocketAddr,
+    proto: ProxyType,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+) -> Result<()> {
+    let mut sock = TcpStream::connect_timeout(&addr, connect_timeout)?;
+    sock.set_read_timeout(Some(read_timeout))?;
+    sock.set_write_timeout(Some(read_timeout))?;
+    answers(&mut sock, proto).map_err(|e| {
+        match e.downcast_ref::<io::Error>().map(io::Error::kind) {
+            Some(io::ErrorKind::UnexpectedEof) => anyhow!("connection closed before the reply"),
+            _ => e,
+        }
+    })
+}
+
+/// Checks the peer of `sock` answers a request of `proto` as a proxy would.
+/// The requests lead to a port of the proxy host nothing listens on.
+fn answers(sock: &mut TcpStream, proto: ProxyType) -> Result<()> {
+    match proto {
+        ProxyType::Socks5 => {
+            sock.write_all(&[5, 1, 0])?;
+            let mut reply = [0; 2];
+            sock.read_exact(&mut reply)?;
+            match reply {
+                [5, 0 | 2 | 0xff] => Ok(()),
+                _ => bail!("not a socks5 proxy"),
+            }
+        }
+        ProxyType::Socks4 => {
+            sock.write_all(&[4, 1, 0, 1, 127, 0, 0, 1, 0])?;
+            let mut reply = [0; 8];
+            sock.read_exact(&mut reply)?;
+            match reply {
+                [0, 90..=93, ..] => Ok(()),
+                _ => bail!("not a socks4 proxy"),
+            }
+        }
+        ProxyType::Http => {
+            sock.write_all(b"CONNECT 127.0.0.1:1 HTTP/1.0\r\n\r\n")?;
+            let mut status = [0; 12];
+            sock.read_exact(&mut status)?;
+            let status = String::from_utf8_lossy(&status);
+            match status.strip_prefix("HTTP/1.").and_then(|s| s.get(2..5)) {
+                Some(code) if !NOT_A_PROXY.contains(&code) => Ok(()),
+                _ => bail!("not an http proxy"),
+            }
+        }
+        // raw proxies say nothing before the data
+        ProxyType::Raw => Ok(()),
+    }
+}
+
+/// Runs `probe` on each of `proxies` concurrently, returning the results in
+/// order.
+fn each<T: Clone + Default + Send>(
+    proxies: &[ProxyConf],
+    probe: impl Fn(&ProxyConf) -> T + Sync,
+) -> Vec<T> {
+    let total = proxies.len();
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![T::default(); total]);
+
+    std::thread::scope(|s| {
+        for _ in 0..JOBS.min(total) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= total {
+                    break;
+                }
+                let res = probe(&proxies[i]);
+                results.lock().unwrap()[i] = res;
+            });
+        }
+    });
+
+    results.into_inner().unwrap()
+}
+
+/// Returns the protocol each of `proxies` answers, if any.
+pub fn label(
+    proxies: &[ProxyConf],
+    connect_timeout: Duration,
+    read_timeout: Duration,
+) -> Vec<Option<ProxyType>> {
+    let read_timeout = read_timeout.min(MAX_REPLY_WAIT);
+    each(proxies, |proxy| {
+        let addr = proxy_addr(proxy).ok()?;
+        ORDER
+            .into_iter()
+            .find(|proto| speaks(addr, *proto, connect_timeout, read_timeout).is_ok())
+    })
+}
+
+/// Checks each of `proxies` answers its protocol, returning why the dead ones
+/// do not.
+pub fn validate(
+    proxies: &[ProxyConf],
+    connect_timeout: Duration,
+    read_timeout: Duration,
+) -> Vec<Option<String>> {
+    each(proxies, |proxy| {
+        proxy_addr(proxy)
+            .and_then(|addr| speaks(addr, proxy.proto, connect_timeout, read_timeout))
+            .err()
+            .map(|e| e.to_string())
+    })
+}
@@ -0,0 +1,90 @@
+//! Explanation of the routing of a destination by the rules.
+use anyhow::Result;
+use proxyc_common::{ProxyConf, ProxycConfig, Rule, DIRECT_CHAIN};
+use std::net::{IpAddr, SocketAddr};
+
+/// Returns why `rule` does not apply to `target` for the program `process`,
+/// None if it does.
+fn mismatch(rule: &Rule, target: SocketAddr, process: &[String]) -> Option<String> {
+    let in_cidr = match target.ip() {
+        IpAddr::V4(ip) => rule.cidr.contains(&ip),
+        IpAddr::V6(_) => false,
+    };
+    if !in_cidr {
+        return Some(format!("{} is not in {}", target.ip(), rule.cidr));
+    }
+    if let Some(port) = rule.port.filter(|p| *p != target.port()) {
+        return Some(format!("port {} is not {}", target.port(), port));
+    }
+    rule.when.as_ref().and_then(|w| w.unmet(process))
+}
+
+/// Prints the ignored subnets and rules considered for connections of the
+/// program `process` to `target`, in the order the hooked processes do, and
+/// the resulting route.
+pub fn explain(config: &ProxycConfig, target: SocketAddr, process: &[String]) -> Result<()> {
+    println!("{}", target);
+
+    let ip = match target.ip() {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    };
+    if config.protect_loopback && ip.is_loopback() {
+        println!("direct, by protect_loopback");
+        return Ok(());
+    }
+
+    // ignored subnets apply before the rules
+    for s in &config.ignore_subnets {
+        if s.matches(target.ip(), target.port()) {
+            println!("  ignore {}: matches", s);
+            println!("direct, by ignore {}", s);
+            return Ok(());
+        }
+        println!("  ignore {}: no match", s);
+    }
+
+    let mut matched = None;
+    for (i, rule) in config.rules.iter().enumerate() {
+        match mismatch(rule, target, process) {
+            Some(why) => println!("  rule #{} {}: {}", i + 1, rule, why),
+            None => {
+                println!("  rule #{} {}: matches", i + 1, rule);
+                matched = Some((i, rule));
+                break;
+            }
+        }
+    }
+
+    let by = match matched {
+        Some((i, _)) => format!("rule #{}", i + 1),
+        None => "no rule".to_string(),
+    };
+    let rule = matched.map(|(_, r)| r);
+    if rule.is_some_and(|r| r.direct) {
+        println!("direct, by {}", by);
+        return Ok(());
+    }
+
+    println!(
+        "proxied, by {} (connect {}ms, read {}ms)",
+        by,
+        rule.and_then(|r| r.tcp_connect_timeout)
+            .unwrap_or(config.tcp_connect_timeout),
+        rule.and_then(|r| r.tcp_read_timeout)
+            .unwrap_or(config.tcp_read_timeout),
+    );
+    for name in config.chain_names() {
+        let chain = match config.chain_named(name, rule) {
+            Some(proxies) => proxies
+                .iter()
+                .map(ProxyConf::endpoint)
+                .collect::<Vec<_>>()
+                .join(" -> "),
+            None if name == DIRECT_CHAIN => "direct".to_string(),
+            None => "no proxy exits in the requested country".to_string(),
+        };
+        println!("  chain {}: {}", name, chain);
+    }
+    Ok(())
+}
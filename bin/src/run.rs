@@ -0,0 +1,319 @@
+//! Supervised execution of a hooked program.
+use crate::audit::human_bytes;
+use crate::exit;
+use anyhow::{bail, Context, Result};
+use log::LevelFilter;
+use nix::libc::{self, c_int};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use proxyc_common::{ProcessStats, ProxyStats, ProxycConfig, RuleStats};
+use std::ffi::{CString, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::Duration;
+
+/// Delay between the end of a run and the next restart.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+const FORWARDED_SIGNALS: [Signal; 6] = [
+    Signal::SIGHUP,
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTERM,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+];
+
+static CHILD: AtomicI32 = AtomicI32::new(0);
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Directory of the temporary directory only the user can access, removed
+/// with its content once dropped.
+pub struct PrivateDir(PathBuf);
+
+impl PrivateDir {
+    /// Creates the directory with mkdtemp, other users can neither predict
+    /// its name nor create it first.
+    pub fn new(prefix: &str) -> Result<Self> {
+        let template = std::env::temp_dir().join(format!("{}-XXXXXX", prefix));
+        let mut path = CString::new(template.as_os_str().as_bytes())?.into_bytes_with_nul();
+        if unsafe { libc::mkdtemp(path.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("failed to create {:?}", template));
+        }
+        path.pop();
+        Ok(PrivateDir(OsString::from_vec(path).into()))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+#[derive(Debug)]
+pub enum RestartPolicy {
+    No,
+    /// Restart when the program fails, at most the given number of times.
+    OnFailure(Option<u32>),
+    /// Restart whatever the exit status, at most the given number of times.
+    Always(Option<u32>),
+}
+
+impl FromStr for RestartPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (policy, max) = match s.split_once(':') {
+            Some((policy, max)) => (
+                policy,
+                Some(
+                    max.parse()
+                        .with_context(|| format!("invalid restart count {:?}", max))?,
+                ),
+            ),
+            None => (s, None),
+        };
+
+        Ok(match (policy, max) {
+            ("no", None) => RestartPolicy::No,
+            ("on-failure", max) => RestartPolicy::OnFailure(max),
+            ("always", max) => RestartPolicy::Always(max),
+            _ => bail!("invalid restart policy: {}", s),
+        })
+    }
+}
+
+impl RestartPolicy {
+    fn should_restart(&self, failed: bool, restarts: u32) -> bool {
+        let (applies, max) = match self {
+            RestartPolicy::No => return false,
+            RestartPolicy::OnFailure(max) => (failed, max),
+            RestartPolicy::Always(max) => (true, max),
+        };
+        applies && max.is_none_or(|m| restarts < m)
+    }
+}
+
+extern "C" fn forward_signal(sig: c_int) {
+    if sig == libc::SIGINT || sig == libc::SIGTERM {
+        STOPPING.store(true, Ordering::SeqCst);
+    }
+    let pid = CHILD.load(Ordering::SeqCst);
+    if pid > 0 {
+        unsafe { libc::kill(pid, sig) };
+    }
+}
+
+/// Averages `a` and `b` of the processes of a run, weighted by the number of
+/// successful hops they were measured on.
+fn weighted(a: Option<f64>, a_hops: u64, b: Option<f64>, b_hops: u64) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) if a_hops + b_hops > 0 => {
+            Some((a * a_hops as f64 + b * b_hops as f64) / (a_hops + b_hops) as f64)
+        }
+        (a, b) => a.or(b),
+    }
+}
+
+/// Formats the measured RTT and throughput of a proxy, if any.
+fn proxy_averages(p: &ProxyStats) -> String {
+    let mut out = String::new();
+    if let Some(rtt) = p.rtt_ms {
+        out.push_str(&format!(", rtt {:.1}ms", rtt));
+    }
+    if let Some(throughput) = p.throughput {
+        out.push_str(&format!(", {}/s", human_bytes(throughput as u64)));
+    }
+    out
+}
+
+/// Aggregated statistics of one or several runs.
+#[derive(Debug, Default)]
+struct Summary {
+    connections: u64,
+    failures: u64,
+    sent: u64,
+    received: u64,
+    proxies: Vec<ProxyStats>,
+    rules: Vec<RuleStats>,
+    /// Most hostnames held by the table of a process.
+    dns_entries: u64,
+    dns_reclaimed: u64,
+}
+
+impl Summary {
+    fn add(&mut self, stats: &ProcessStats) {
+        self.connections += stats.connections;
+        self.failures += stats.failures;
+        self.sent += stats.bytes_sent;
+        self.received += stats.bytes_received;
+        self.dns_entries = self.dns_entries.max(stats.dns_entries);
+        self.dns_reclaimed += stats.dns_reclaimed;
+        for p in &stats.proxies {
+            match self.proxies.iter_mut().find(|x| x.proxy == p.proxy) {
+                Some(x) => {
+                    x.rtt_ms = weighted(x.rtt_ms, x.success, p.rtt_ms, p.success);
+                    x.throughput = weighted(x.throughput, x.success, p.throughput, p.success);
+                    x.success += p.success;
+                    x.failures += p.failures;
+                }
+                None => self.proxies.push(p.clone()),
+            }
+        }
+        for r in &stats.rules {
+            match self.rules.iter_mut().find(|x| x.rule == r.rule) {
+                Some(x) => x.hits += r.hits,
+                None => self.rules.push(r.clone()),
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &Summary) {
+        self.add(&ProcessStats {
+            pid: 0,
+            connections: other.connections,
+            failures: other.failures,
+            bytes_sent: other.sent,
+            bytes_received: other.received,
+            proxies: other.proxies.clone(),
+            rules: other.rules.clone(),
+            dns_entries: other.dns_entries,
+            dns_reclaimed: other.dns_reclaimed,
+        });
+    }
+
+    fn print(&self, title: &str) {
+        eprintln!(
+            "proxyc: {}: {} connections, {} failed, {} sent, {} received",
+            title,
+            self.connections,
+            self.failures,
+            human_bytes(self.sent),
+            human_bytes(self.received)
+        );
+        for p in &self.proxies {
+            eprintln!(
+                "proxyc:   {}: {} ok, {} failed{}",
+                p.proxy,
+                p.success,
+                p.failures,
+                proxy_averages(p)
+            );
+        }
+        for r in self.rules.iter().filter(|r| r.hits > 0) {
+            eprintln!("proxyc:   {}: {} connections", r.rule, r.hits);
+        }
+        if self.dns_reclaimed > 0 {
+            eprintln!(
+                "proxyc:   dns table: {} entries at most, {} reclaimed",
+                self.dns_entries, self.dns_reclaimed
+            );
+        }
+    }
+}
+
+/// Reads the statistics appended by every hooked process of a run.
+fn read_stats(path: &Path) -> Summary {
+    let mut summary = Summary::default();
+    if let Ok(content) = std::fs::read_to_string(path) {
+        for stats in content
+            .lines()
+            .filter_map(|l| serde_json::from_str::<ProcessStats>(l).ok())
+        {
+            summary.add(&stats);
+        }
+    }
+    summary
+}
+
+fn exit_code(status: &ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|s| 128 + s))
+        .unwrap_or(1)
+}
+
+fn forward_signals() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(forward_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for sig in FORWARDED_SIGNALS {
+        unsafe { sigaction(sig, &action) }.context("failed to install signal handler")?;
+    }
+    Ok(())
+}
+
+/// Runs the hooked program once, forwarding signals to it. Returns its exit
+/// code.
+pub fn run_once(mut command: Command) -> Result<i32> {
+    forward_signals()?;
+    let child = command
+        .spawn()
+        .map_err(|e| exit::launch_error(e, "failed to spawn program"))?;
+    wait(child)
+}
+
+/// Waits for the spawned `child`, forwarding signals to it. Returns its exit
+/// code.
+pub fn wait(mut child: Child) -> Result<i32> {
+    forward_signals()?;
+    CHILD.store(child.id() as i32, Ordering::SeqCst);
+    let status = child.wait().context("failed to wait for program")?;
+    CHILD.store(0, Ordering::SeqCst);
+    Ok(exit_code(&status))
+}
+
+/// Runs the hooked program until it exits and the restart policy does not
+/// allow it to be restarted. Returns the exit code of the last run.
+pub fn supervise(
+    policy: &RestartPolicy,
+    mut config: ProxycConfig,
+    mut command: impl FnMut(&ProxycConfig) -> Result<Command>,
+) -> Result<i32> {
+    forward_signals()?;
+
+    let verbose = config.log_level != LevelFilter::Off;
+    let mut total = Summary::default();
+    let mut restarts = 0;
+    let stats_dir = PrivateDir::new("proxyc-stats")?;
+
+    loop {
+        let stats_path = stats_dir.path().join(format!("{}.jsonl", restarts));
+        config.stats_file = Some(stats_path.clone());
+
+        let mut child = command(&config)?
+            .spawn()
+            .map_err(|e| exit::launch_error(e, "failed to spawn program"))?;
+        CHILD.store(child.id() as i32, Ordering::SeqCst);
+        let status = child.wait().context("failed to wait for program")?;
+        CHILD.store(0, Ordering::SeqCst);
+
+        let summary = read_stats(&stats_path);
+        std::fs::remove_file(&stats_path).ok();
+        if verbose {
+            summary.print(&format!("run {} exited with {}", restarts + 1, status));
+        }
+        total.merge(&summary);
+
+        if STOPPING.load(Ordering::SeqCst) || !policy.should_restart(!status.success(), restarts) {
+            if verbose && restarts > 0 {
+                total.print(&format!("total over {} runs", restarts + 1));
+            }
+            return Ok(exit_code(&status));
+        }
+
+        restarts += 1;
+        std::thread::sleep(RESTART_DELAY);
+    }
+}
@@ -0,0 +1,106 @@
+//! Local ports forwarded to fixed destinations through the chain, like
+//! `ssh -L`, for the programs which cannot be hooked but can connect to
+//! localhost.
+use super::relay;
+use anyhow::{anyhow, bail, Context, Result};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::thread;
+
+/// Forwarded port, as `[bind_address:]port:host:hostport`.
+#[derive(Debug, Clone)]
+pub struct Forward {
+    pub listen: SocketAddr,
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for Forward {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let err = || {
+            anyhow!(
+                "invalid forward {:?}, expected [bind_address:]port:host:hostport",
+                s
+            )
+        };
+        let (rest, port) = s.rsplit_once(':').ok_or_else(err)?;
+        let port = port.parse().map_err(|_| err())?;
+        // ipv6 addresses are bracketed
+        let (rest, host) = match rest.strip_suffix(']') {
+            Some(rest) => {
+                let (rest, host) = rest.rsplit_once('[').ok_or_else(err)?;
+                (rest.strip_suffix(':').ok_or_else(err)?, host)
+            }
+            None => rest.rsplit_once(':').ok_or_else(err)?,
+        };
+        if host.is_empty() {
+            return Err(err());
+        }
+
+        let listen = match rest.parse::<u16>() {
+            Ok(p) => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), p),
+            Err(_) => {
+                let (addr, p) = rest.rsplit_once(':').ok_or_else(err)?;
+                let addr = addr.trim_start_matches('[').trim_end_matches(']');
+                let ip = IpAddr::from_str(addr).map_err(|_| err())?;
+                SocketAddr::new(ip, p.parse().map_err(|_| err())?)
+            }
+        };
+        Ok(Forward {
+            listen,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "{}:[{}]:{}", self.listen, self.host, self.port),
+            false => write!(f, "{}:{}:{}", self.listen, self.host, self.port),
+        }
+    }
+}
+
+/// Forwards the connections to the ports of `forwards`, until killed.
+pub fn forward(forwards: &[Forward]) -> Result<()> {
+    if forwards.is_empty() {
+        bail!("nothing to forward, use -L");
+    }
+    let mut listeners = vec![];
+    for f in forwards {
+        let listener =
+            TcpListener::bind(f.listen).with_context(|| format!("failed to bind {}", f.listen))?;
+        eprintln!("proxyc: forwarding {} to {}:{}", f.listen, f.host, f.port);
+        let f = f.clone();
+        listeners.push(thread::spawn(move || clients(listener, f)));
+    }
+    for listener in listeners {
+        listener.join().map_err(|_| anyhow!("listener panicked"))?;
+    }
+    Ok(())
+}
+
+/// Chains each client of `listener` to the destination of `forward`.
+fn clients(listener: TcpListener, forward: Forward) {
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        let forward = forward.clone();
+        thread::spawn(move || {
+            let dest = (forward.host.as_str(), forward.port);
+            let res = TcpStream::connect(dest)
+                .with_context(|| format!("failed to reach {}:{}", forward.host, forward.port))
+                .and_then(|upstream| relay(client, upstream));
+            if let Err(e) = res {
+                eprintln!("proxyc: forward {}: {:#}", forward.listen, e);
+            }
+        });
+    }
+}
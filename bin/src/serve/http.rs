@@ -0,0 +1,191 @@
+//! HTTP CONNECT listener of the server, for the programs only speaking HTTP
+//! proxies.
+//!
+//! Once serve_user entries are configured, clients must authenticate with
+//! Basic credentials in Proxy-Authorization, the connections of each of them
+//! trying its own chains like those of the socks5 listener.
+use super::{relay, select_chains, HANDSHAKE_TIMEOUT};
+use anyhow::{anyhow, bail, Context, Result};
+use nix::libc;
+use proxyc_common::ServeUser;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+/// Longest request head, up to the empty line ending its headers.
+const MAX_HEAD_LEN: usize = 8192;
+
+/// Reads the head of the request of `client`, byte by byte so that the data
+/// sent after it is left to the relay.
+fn read_head(client: &mut TcpStream) -> Result<String> {
+    let mut head = vec![];
+    let mut b = [0];
+    while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+        if head.len() == MAX_HEAD_LEN {
+            bail!("request head longer than {} bytes", MAX_HEAD_LEN);
+        }
+        client.read_exact(&mut b)?;
+        head.push(b[0]);
+    }
+    String::from_utf8(head).map_err(|_| anyhow!("request head is not UTF-8"))
+}
+
+fn respond(client: &mut TcpStream, status: &str, headers: &str) -> io::Result<()> {
+    write!(client, "HTTP/1.1 {}\r\n{}\r\n", status, headers)
+}
+
+/// Returns the status of a connection failing with `e`.
+fn failure(e: &io::Error) -> &'static str {
+    match e.raw_os_error() {
+        Some(libc::ETIMEDOUT) => "504 Gateway Timeout",
+        _ => "502 Bad Gateway",
+    }
+}
+
+/// Decodes the standard base64 `s`, padding optional.
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = acc << 6 | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Returns the user of the Basic `credentials`, if they match one of
+/// `users`.
+fn find_user<'a>(users: &'a [ServeUser], credentials: &str) -> Option<&'a ServeUser> {
+    let (scheme, encoded) = credentials.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64_decode(encoded.trim())?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    users
+        .iter()
+        .find(|u| u.user == user && u.password == password)
+}
+
+/// Chains the connection requested by `client`, authenticated as one of
+/// `users` if any, through `chain` if selected.
+pub fn handle(mut client: TcpStream, users: &[ServeUser], chain: Option<&str>) -> Result<()> {
+    client.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let head = read_head(&mut client)?;
+    let mut lines = head.lines();
+    let request = lines.next().unwrap_or_default();
+    let mut parts = request.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+            (method, target)
+        }
+        _ => {
+            respond(&mut client, "400 Bad Request", "")?;
+            bail!("malformed request {:?}", request);
+        }
+    };
+    if method != "CONNECT" {
+        respond(&mut client, "405 Method Not Allowed", "Allow: CONNECT\r\n")?;
+        bail!("method {} not supported", method);
+    }
+
+    let user = if users.is_empty() {
+        None
+    } else {
+        let user = lines
+            .filter_map(|l| l.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("proxy-authorization"))
+            .and_then(|(_, credentials)| find_user(users, credentials));
+        if user.is_none() {
+            respond(
+                &mut client,
+                "407 Proxy Authentication Required",
+                "Proxy-Authenticate: Basic realm=\"proxyc\"\r\n",
+            )?;
+            bail!("authentication failed");
+        }
+        user
+    };
+
+    let (host, port) = match target
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+    {
+        Some((host, port)) => (host.trim_start_matches('[').trim_end_matches(']'), port),
+        None => {
+            respond(&mut client, "400 Bad Request", "")?;
+            bail!("target {:?} is not host:port", target);
+        }
+    };
+
+    if let Err(e) = select_chains(chain, user) {
+        respond(&mut client, "500 Internal Server Error", "")?;
+        return Err(e);
+    }
+    let upstream = match TcpStream::connect((host, port)) {
+        Ok(s) => s,
+        Err(e) => {
+            respond(&mut client, failure(&e), "")?;
+            return Err(e).with_context(|| format!("failed to reach {}", target));
+        }
+    };
+    respond(&mut client, "200 Connection established", "")?;
+    client.set_read_timeout(None)?;
+    relay(client, upstream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_with_and_without_padding() {
+        assert_eq!(base64_decode(""), Some(vec![]));
+        assert_eq!(base64_decode("Zg=="), Some(b"f".to_vec()));
+        assert_eq!(base64_decode("Zg"), Some(b"f".to_vec()));
+        assert_eq!(base64_decode("Zm8="), Some(b"fo".to_vec()));
+        assert_eq!(base64_decode("Zm9v"), Some(b"foo".to_vec()));
+        assert_eq!(base64_decode("Zm9vYmFy"), Some(b"foobar".to_vec()));
+    }
+
+    #[test]
+    fn base64_of_every_byte() {
+        assert_eq!(base64_decode("+/8A"), Some(vec![0xfb, 0xff, 0x00]));
+        assert_eq!(
+            base64_decode("dXNlcjpwQHNzOncwcmQ="),
+            Some(b"user:p@ss:w0rd".to_vec())
+        );
+    }
+
+    #[test]
+    fn base64_rejects_other_alphabets() {
+        assert_eq!(base64_decode("Zm9v YmFy"), None);
+        assert_eq!(base64_decode("-_8A"), None);
+        assert_eq!(base64_decode("Zg=x"), None);
+    }
+
+    #[test]
+    fn basic_credentials_find_their_user() {
+        let users = [ServeUser {
+            user: "user".into(),
+            password: "p@ss:w0rd".into(),
+            chains: vec![],
+        }];
+        let found = find_user(&users, " basic dXNlcjpwQHNzOncwcmQ= ");
+        assert_eq!(found.map(|u| u.user.as_str()), Some("user"));
+        // user:wrong
+        assert!(find_user(&users, "Basic dXNlcjp3cm9uZw==").is_none());
+        assert!(find_user(&users, "Bearer dXNlcjpwQHNzOncwcmQ=").is_none());
+    }
+}
@@ -0,0 +1,335 @@
+//! Standalone server chaining the connections of other programs: handed
+//! over on a Unix socket, made through its socks5 or HTTP CONNECT listeners,
+//! forwarded from local ports, or back from the ports of the last proxy.
+//!
+//! The server runs under the library like any hooked program: the
+//! connections it makes to the destinations are chained by the hooks.
+use anyhow::{anyhow, bail, Context, Result};
+use nix::cmsg_space;
+use nix::errno::Errno;
+use nix::libc::{self, c_char, c_int};
+use nix::sys::socket::{
+    accept4, bind, listen, recvmsg, send, socket, AddressFamily, ControlMessageOwned, MsgFlags,
+    SockAddr, SockFlag, SockType,
+};
+use nix::sys::uio::IoVec;
+use nix::unistd::close;
+use proxyc_common::{ProxycConfig, ServeUser};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+mod forward;
+mod http;
+mod reverse;
+mod socks;
+
+pub use forward::{forward, Forward};
+pub use reverse::{reverse, Reverse};
+
+/// Longest handover message, the destination of the socket.
+const MAX_MESSAGE_LEN: usize = 512;
+
+/// Pending handover connections.
+const BACKLOG: usize = 128;
+
+/// Delay for the clients of the listeners to complete their handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// First bytes of the loopback addresses selecting a chain by its serve_id,
+/// 83 being 'S'.
+const CHAIN_ADDR_PREFIX: [u8; 2] = [127, 83];
+
+/// proxyc_use_chains() of the library.
+type UseChains = unsafe extern "C" fn(*const c_char) -> c_int;
+
+/// Serves on the takeover socket at `takeover`, the socks5 listener at
+/// `socks` and the HTTP CONNECT listener at `http`, until killed.
+pub fn serve(
+    takeover: Option<&Path>,
+    socks: Option<SocketAddr>,
+    http: Option<SocketAddr>,
+) -> Result<()> {
+    let mut listeners = vec![];
+    if let Some(path) = takeover {
+        let sock = bind_takeover(path)?;
+        listeners.push(thread::spawn(move || takeovers(sock)));
+    }
+    let (users, chains) = match socks.or(http) {
+        Some(_) => {
+            let config = ProxycConfig::from_env()?;
+            let chains: ChainAddrs = config
+                .chains
+                .iter()
+                .filter_map(|(name, c)| Some((chain_addr(c.serve_id?), name.clone())))
+                .collect();
+            (Arc::new(config.serve_users), Arc::new(chains))
+        }
+        None => Default::default(),
+    };
+    for (addr, protocol, handle) in [
+        (socks, "socks5", socks::handle as Handler),
+        (http, "HTTP CONNECT", http::handle),
+    ] {
+        let addr = match addr {
+            Some(addr) => addr,
+            None => continue,
+        };
+        // listeners on loopback are joined by those of the chains, which the
+        // wildcard addresses cover already
+        let mut addrs = vec![(addr, None)];
+        if addr.ip().is_loopback() {
+            addrs.extend(
+                chains
+                    .iter()
+                    .map(|(ip, name)| (SocketAddr::new(*ip, addr.port()), Some(name))),
+            );
+        }
+        for (addr, chain) in addrs {
+            let listener =
+                TcpListener::bind(addr).with_context(|| format!("failed to bind {}", addr))?;
+            match chain {
+                Some(name) => eprintln!(
+                    "proxyc: {} listening on {} for chain {}",
+                    protocol, addr, name
+                ),
+                None => eprintln!("proxyc: {} listening on {}", protocol, addr),
+            }
+            let (users, chains) = (users.clone(), chains.clone());
+            listeners.push(thread::spawn(move || {
+                clients(listener, users, chains, protocol, handle)
+            }));
+        }
+    }
+    if listeners.is_empty() {
+        bail!("nothing to serve, use --takeover, --socks or --http");
+    }
+    for listener in listeners {
+        listener
+            .join()
+            .map_err(|_| anyhow!("listener panicked"))??;
+    }
+    Ok(())
+}
+
+fn bind_takeover(path: &Path) -> Result<RawFd> {
+    let sock = socket(
+        AddressFamily::Unix,
+        SockType::SeqPacket,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    bind(sock, &SockAddr::new_unix(path)?).with_context(|| format!("failed to bind {:?}", path))?;
+    listen(sock, BACKLOG)?;
+    eprintln!("proxyc: taking over connections on {:?}", path);
+    Ok(sock)
+}
+
+/// Accepts connections on the takeover socket `sock`, each handing over
+/// sockets to chain.
+fn takeovers(sock: RawFd) -> Result<()> {
+    loop {
+        let control = match accept4(sock, SockFlag::SOCK_CLOEXEC) {
+            Ok(fd) => fd,
+            Err(Errno::EINTR | Errno::ECONNABORTED) => continue,
+            Err(e) => return Err(e).context("failed to accept a handover connection"),
+        };
+        thread::spawn(move || handovers(control));
+    }
+}
+
+/// Chains the sockets handed over on `control`, one per message, replying
+/// to each message with "ok" or the error met.
+fn handovers(control: RawFd) {
+    let mut buf = [0; MAX_MESSAGE_LEN];
+    loop {
+        let mut cmsg = cmsg_space!([RawFd; 1]);
+        let msg = match recvmsg(
+            control,
+            &[IoVec::from_mut_slice(&mut buf)],
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        ) {
+            Ok(msg) => msg,
+            Err(Errno::EINTR) => continue,
+            Err(_) => break,
+        };
+        let fds: Vec<RawFd> = msg
+            .cmsgs()
+            .flat_map(|c| match c {
+                ControlMessageOwned::ScmRights(fds) => fds,
+                _ => vec![],
+            })
+            .collect();
+        let truncated = msg
+            .flags
+            .intersects(MsgFlags::MSG_TRUNC | MsgFlags::MSG_CTRUNC);
+        let len = msg.bytes;
+        if len == 0 && fds.is_empty() {
+            break;
+        }
+
+        let reply = match handover(&buf[..len], fds, truncated) {
+            Ok(()) => "ok\n".to_string(),
+            Err(e) => {
+                eprintln!("proxyc: handover failed: {:#}", e);
+                format!("error: {:#}\n", e)
+            }
+        };
+        if send(control, reply.as_bytes(), MsgFlags::empty()).is_err() {
+            break;
+        }
+    }
+    let _ = close(control);
+}
+
+/// Chains the socket of `fds` to the destination of `message`, and relays
+/// it in the background.
+fn handover(message: &[u8], fds: Vec<RawFd>, truncated: bool) -> Result<()> {
+    // owned first, for the sockets to be closed on errors
+    let mut sockets: Vec<TcpStream> = fds
+        .into_iter()
+        .map(|fd| unsafe { TcpStream::from_raw_fd(fd) })
+        .collect();
+    if truncated {
+        bail!(
+            "message truncated, one socket and {} bytes at most",
+            MAX_MESSAGE_LEN
+        );
+    }
+    if sockets.len() != 1 {
+        bail!("expected one socket, got {}", sockets.len());
+    }
+    let client = sockets.remove(0);
+
+    let dest = std::str::from_utf8(message)
+        .map_err(|_| anyhow!("destination is not UTF-8"))?
+        .trim_end();
+    let (host, port) = dest
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("destination {:?} is not host:port", dest))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port: u16 = port
+        .parse()
+        .map_err(|_| anyhow!("invalid port in destination {:?}", dest))?;
+
+    let upstream =
+        TcpStream::connect((host, port)).with_context(|| format!("failed to reach {}", dest))?;
+    relay(client, upstream)
+}
+
+/// Handles a client of a listener, authenticated as one of the users if
+/// any, with the chain selected by the address it reached if any.
+type Handler = fn(TcpStream, &[ServeUser], Option<&str>) -> Result<()>;
+
+/// Chains selected by the clients of the listeners reaching them at their
+/// address.
+type ChainAddrs = HashMap<IpAddr, String>;
+
+/// Returns the loopback address selecting the chain of serve_id `id`.
+fn chain_addr(id: u16) -> IpAddr {
+    let [x, y] = id.to_be_bytes();
+    let [a, b] = CHAIN_ADDR_PREFIX;
+    Ipv4Addr::new(a, b, x, y).into()
+}
+
+/// Hands the clients of `listener` to `handle`, each in its own thread.
+fn clients(
+    listener: TcpListener,
+    users: Arc<Vec<ServeUser>>,
+    chains: Arc<ChainAddrs>,
+    protocol: &'static str,
+    handle: Handler,
+) -> Result<()> {
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        let (users, chains) = (users.clone(), chains.clone());
+        thread::spawn(move || {
+            let peer = client
+                .peer_addr()
+                .map_or("?".to_string(), |a| a.to_string());
+            let chain = client.local_addr().ok().and_then(|a| chains.get(&a.ip()));
+            if let Err(e) = handle(client, &users, chain.map(String::as_str)) {
+                eprintln!("proxyc: {} client {}: {:#}", protocol, peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Selects `chains` for the connections of this thread.
+fn use_chains(chains: &[String]) -> Result<()> {
+    let symbol = CString::new("proxyc_use_chains").unwrap();
+    let f = unsafe { libc::dlsym(libc::RTLD_DEFAULT, symbol.as_ptr()) };
+    if f.is_null() {
+        bail!("the library does not select chains");
+    }
+    let f: UseChains = unsafe { std::mem::transmute(f) };
+    let names = CString::new(chains.join(","))?;
+    match unsafe { f(names.as_ptr()) } {
+        0 => Ok(()),
+        _ => bail!("chains {:?} not configured", chains),
+    }
+}
+
+/// Selects the chain `chain` reached by address for the connections of this
+/// thread, or else the chains of `user`.
+fn select_chains(chain: Option<&str>, user: Option<&ServeUser>) -> Result<()> {
+    match (chain, user) {
+        (Some(name), _) => use_chains(&[name.to_string()]),
+        (None, Some(u)) if !u.chains.is_empty() => use_chains(&u.chains),
+        _ => Ok(()),
+    }
+}
+
+/// Relays `client` and `upstream` in the background, until both ends are
+/// done.
+pub fn relay(client: TcpStream, upstream: TcpStream) -> Result<()> {
+    let (client_read, upstream_write) = (client.try_clone()?, upstream.try_clone()?);
+    thread::spawn(move || copy(client_read, upstream_write));
+    thread::spawn(move || copy(upstream, client));
+    Ok(())
+}
+
+/// Chains the connections redirected to `listener` to the destination
+/// `original_dst` returns for them.
+pub fn redirected<F>(listener: TcpListener, original_dst: F)
+where
+    F: Fn(&TcpStream) -> io::Result<SocketAddr> + Copy + Send + 'static,
+{
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        thread::spawn(move || {
+            let dest = match original_dst(&client) {
+                Ok(dest) => dest,
+                Err(e) => return eprintln!("proxyc: no original destination: {}", e),
+            };
+            match TcpStream::connect(dest) {
+                Ok(upstream) => {
+                    if let Err(e) = relay(client, upstream) {
+                        eprintln!("proxyc: failed to relay {}: {:#}", dest, e);
+                    }
+                }
+                Err(e) => eprintln!("proxyc: failed to reach {}: {}", dest, e),
+            }
+        });
+    }
+}
+
+/// Copies `from` to `to` until the end of `from`, which is then passed on.
+fn copy(mut from: TcpStream, mut to: TcpStream) {
+    let _ = io::copy(&mut from, &mut to);
+    let _ = to.shutdown(Shutdown::Write);
+}
@@ -0,0 +1,131 @@
+//! Ports of the last proxy forwarded back to local services with socks5
+//! BIND, like `ssh -R`, the counterpart of forward for the tools expecting
+//! callbacks.
+use super::{relay, use_chains};
+use anyhow::{anyhow, bail, Context, Result};
+use nix::libc::{self, c_char, c_int, size_t};
+use nix::unistd::close;
+use proxyc_common::DIRECT_CHAIN;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::net::TcpStream;
+use std::os::unix::io::FromRawFd;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+/// Delay before requesting a new listener after the proxy refused one.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Longest address written by the library, host:port.
+const MAX_ADDR_LEN: usize = 300;
+
+/// proxyc_bind() of the library.
+type Bind = unsafe extern "C" fn(*mut c_char, size_t) -> c_int;
+
+/// proxyc_bind_accept() of the library.
+type BindAccept = unsafe extern "C" fn(c_int, *mut c_char, size_t) -> c_int;
+
+/// Local service the connections to the proxy are forwarded to, as
+/// `host:hostport`.
+#[derive(Debug, Clone)]
+pub struct Reverse {
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for Reverse {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let err = || anyhow!("invalid reverse forward {:?}, expected host:hostport", s);
+        let (host, port) = s.rsplit_once(':').ok_or_else(err)?;
+        let port = port.parse().map_err(|_| err())?;
+        // ipv6 addresses are bracketed
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(err());
+        }
+        Ok(Reverse {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for Reverse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+/// Forwards the connections to the listeners the last proxy opens to the
+/// services of `reverses`, until killed.
+pub fn reverse(reverses: &[Reverse]) -> Result<()> {
+    if reverses.is_empty() {
+        bail!("nothing to forward, use -R");
+    }
+    let bind: Bind = unsafe { symbol("proxyc_bind")? };
+    let accept: BindAccept = unsafe { symbol("proxyc_bind_accept")? };
+
+    let mut listeners = vec![];
+    for r in reverses {
+        let r = r.clone();
+        listeners.push(thread::spawn(move || peers(bind, accept, r)));
+    }
+    for listener in listeners {
+        listener.join().map_err(|_| anyhow!("listener panicked"))?;
+    }
+    Ok(())
+}
+
+/// Looks up the function `name` of the library.
+unsafe fn symbol<T>(name: &str) -> Result<T> {
+    let name = CString::new(name).unwrap();
+    let f = libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr());
+    if f.is_null() {
+        bail!("the library does not support BIND");
+    }
+    Ok(std::mem::transmute_copy(&f))
+}
+
+/// Forwards the peers of one listener of the proxy after the other to
+/// `reverse`, each BIND accepting a single connection.
+fn peers(bind: Bind, accept: BindAccept, reverse: Reverse) {
+    let mut buf = [0 as c_char; MAX_ADDR_LEN];
+    loop {
+        let sock = unsafe { bind(buf.as_mut_ptr(), buf.len()) };
+        if sock < 0 {
+            eprintln!("proxyc: reverse {}: BIND refused, retrying", reverse);
+            thread::sleep(RETRY_DELAY);
+            continue;
+        }
+        let bound = unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy();
+        eprintln!("proxyc: forwarding {} to {}", bound, reverse);
+
+        if unsafe { accept(sock, buf.as_mut_ptr(), buf.len()) } < 0 {
+            close(sock).ok();
+            continue;
+        }
+        let peer = unsafe { CStr::from_ptr(buf.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        let client = unsafe { TcpStream::from_raw_fd(sock) };
+        let reverse = reverse.clone();
+        thread::spawn(move || {
+            // the local service is reached directly rather than chained
+            let res = use_chains(&[DIRECT_CHAIN.to_string()])
+                .and_then(|_| {
+                    TcpStream::connect((reverse.host.as_str(), reverse.port))
+                        .with_context(|| format!("failed to reach {}", reverse))
+                })
+                .and_then(|service| relay(client, service));
+            if let Err(e) = res {
+                eprintln!("proxyc: reverse {} peer {}: {:#}", reverse, peer, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,160 @@
+//! Socks5 listener of the server, CONNECT only.
+//!
+//! Once serve_user entries are configured, clients must authenticate with
+//! username and password, the connections of each of them trying its own
+//! chains, selected for the thread connecting through the library.
+use super::{relay, select_chains, HANDSHAKE_TIMEOUT};
+use anyhow::{anyhow, bail, Context, Result};
+use nix::libc;
+use proxyc_common::ServeUser;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+
+const METHOD_NONE: u8 = 0;
+const METHOD_USERPASS: u8 = 2;
+const METHOD_UNACCEPTABLE: u8 = 0xFF;
+
+const CMD_CONNECT: u8 = 1;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+const REP_SUCCEEDED: u8 = 0;
+const REP_FAILURE: u8 = 1;
+const REP_NETWORK_UNREACHABLE: u8 = 3;
+const REP_HOST_UNREACHABLE: u8 = 4;
+const REP_CONNECTION_REFUSED: u8 = 5;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+fn read_u8(client: &mut TcpStream) -> io::Result<u8> {
+    let mut b = [0];
+    client.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+/// Reads a string prefixed by its length.
+fn read_string(client: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let len = read_u8(client)? as usize;
+    let mut s = vec![0; len];
+    client.read_exact(&mut s)?;
+    Ok(s)
+}
+
+/// Returns the user authenticating on `client`, None if no users are
+/// configured.
+fn authenticate<'a>(
+    client: &mut TcpStream,
+    users: &'a [ServeUser],
+) -> Result<Option<&'a ServeUser>> {
+    if read_u8(client)? != VERSION {
+        bail!("not a socks5 client");
+    }
+    let methods = read_string(client)?;
+    let method = match users.is_empty() {
+        true => METHOD_NONE,
+        false => METHOD_USERPASS,
+    };
+    if !methods.contains(&method) {
+        client.write_all(&[VERSION, METHOD_UNACCEPTABLE])?;
+        bail!("no acceptable authentication method");
+    }
+    client.write_all(&[VERSION, method])?;
+    if users.is_empty() {
+        return Ok(None);
+    }
+
+    if read_u8(client)? != AUTH_VERSION {
+        bail!("unknown authentication version");
+    }
+    let user = read_string(client)?;
+    let password = read_string(client)?;
+    let found = users
+        .iter()
+        .find(|u| u.user.as_bytes() == user && u.password.as_bytes() == password);
+    match found {
+        Some(u) => {
+            client.write_all(&[AUTH_VERSION, 0])?;
+            Ok(Some(u))
+        }
+        None => {
+            client.write_all(&[AUTH_VERSION, 1])?;
+            bail!(
+                "authentication failed for {:?}",
+                String::from_utf8_lossy(&user)
+            )
+        }
+    }
+}
+
+fn reply(client: &mut TcpStream, rep: u8) -> io::Result<()> {
+    // the bound address is not disclosed
+    client.write_all(&[VERSION, rep, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+}
+
+/// Returns the reply of a connection failing with `e`.
+fn failure(e: &io::Error) -> u8 {
+    match e.raw_os_error() {
+        Some(libc::ECONNREFUSED) => REP_CONNECTION_REFUSED,
+        Some(libc::ENETUNREACH) => REP_NETWORK_UNREACHABLE,
+        Some(libc::EHOSTUNREACH | libc::ETIMEDOUT) => REP_HOST_UNREACHABLE,
+        _ => REP_FAILURE,
+    }
+}
+
+/// Chains the connection requested by `client`, authenticated as one of
+/// `users` if any, through `chain` if selected.
+pub fn handle(mut client: TcpStream, users: &[ServeUser], chain: Option<&str>) -> Result<()> {
+    client.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let user = authenticate(&mut client, users)?;
+
+    let mut head = [0; 4];
+    client.read_exact(&mut head)?;
+    if head[0] != VERSION {
+        bail!("not a socks5 request");
+    }
+    let host = match head[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            client.read_exact(&mut ip)?;
+            IpAddr::V4(Ipv4Addr::from(ip)).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            client.read_exact(&mut ip)?;
+            IpAddr::V6(Ipv6Addr::from(ip)).to_string()
+        }
+        ATYP_DOMAIN => String::from_utf8(read_string(&mut client)?)
+            .map_err(|_| anyhow!("domain name is not UTF-8"))?,
+        _ => {
+            reply(&mut client, REP_ADDRESS_NOT_SUPPORTED)?;
+            bail!("unknown address type {}", head[3]);
+        }
+    };
+    let mut port = [0; 2];
+    client.read_exact(&mut port)?;
+    let port = u16::from_be_bytes(port);
+    if head[1] != CMD_CONNECT {
+        reply(&mut client, REP_COMMAND_NOT_SUPPORTED)?;
+        bail!("command {} not supported", head[1]);
+    }
+
+    if let Err(e) = select_chains(chain, user) {
+        reply(&mut client, REP_FAILURE)?;
+        return Err(e);
+    }
+    let upstream = match TcpStream::connect((host.as_str(), port)) {
+        Ok(s) => s,
+        Err(e) => {
+            reply(&mut client, failure(&e))?;
+            return Err(e).with_context(|| format!("failed to reach {}:{}", host, port));
+        }
+    };
+    reply(&mut client, REP_SUCCEEDED)?;
+    client.set_read_timeout(None)?;
+    relay(client, upstream)
+}
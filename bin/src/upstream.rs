@@ -0,0 +1,264 @@
+//! Helper processes providing the first hops of the chain.
+use crate::run::PrivateDir;
+use anyhow::{anyhow, bail, Context, Result};
+use nix::libc;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use proxyc_common::{Auth, ProxyConf, ProxyType, ProxycConfig, Transport, Upstream};
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// Delay between two attempts to connect to a starting helper.
+const READY_POLL: Duration = Duration::from_millis(100);
+/// Delay given to helpers to exit after SIGTERM, before being killed.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Running helpers, torn down when dropped.
+pub struct Upstreams {
+    children: Vec<Child>,
+    /// State directory of the transport client, removed once the children
+    /// are stopped.
+    state_dir: Option<PrivateDir>,
+}
+
+impl Upstreams {
+    /// Whether the configuration has helpers to manage.
+    pub fn needed(config: &ProxycConfig) -> bool {
+        !config.upstreams.is_empty() || config.transport.is_some()
+    }
+
+    /// Launches the helpers in order and waits for each of them to accept
+    /// connections on its proxy port. The proxy of the transport client is
+    /// inserted at the start of the chain once known.
+    pub fn start(config: &mut ProxycConfig) -> Result<Self> {
+        let mut started = Upstreams {
+            children: vec![],
+            state_dir: None,
+        };
+        for u in &config.upstreams {
+            let child = spawn(&u.command, Stdio::null(), Stdio::from(std::io::stderr()))
+                .with_context(|| format!("failed to launch upstream {:?}", u.command[0]))?;
+            started.children.push(child);
+            let child = started.children.last_mut().unwrap();
+            wait_ready(child, u)?;
+        }
+
+        if let Some(t) = config.transport.take() {
+            let proxy = started.start_transport(&t)?;
+            config.proxies.insert(0, proxy);
+        }
+        Ok(started)
+    }
+
+    /// Launches a pluggable transport client, following the managed proxy
+    /// protocol of the pluggable transport specification. Returns the proxy it
+    /// exposes, carrying the bridge arguments.
+    fn start_transport(&mut self, transport: &Transport) -> Result<ProxyConf> {
+        let state_dir = self
+            .state_dir
+            .insert(PrivateDir::new("proxyc-pt")?)
+            .path()
+            .to_owned();
+
+        let name = &transport.bridge.transport;
+        let mut command = Command::new(&transport.command[0]);
+        command
+            .env("TOR_PT_MANAGED_TRANSPORT_VER", "1")
+            .env("TOR_PT_STATE_LOCATION", &state_dir)
+            .env("TOR_PT_CLIENT_TRANSPORTS", name)
+            // the client exits when proxyc does
+            .env("TOR_PT_EXIT_ON_STDIN_CLOSE", "1");
+        let mut child = spawn_command(
+            command.args(&transport.command[1..]),
+            Stdio::piped(),
+            Stdio::piped(),
+        )
+        .with_context(|| format!("failed to launch transport {:?}", transport.command[0]))?;
+
+        // the client keeps writing log lines, its output is drained until it
+        // exits.
+        let stdout = child.stdout.take().unwrap();
+        self.children.push(child);
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                match line {
+                    Ok(l) => tx.send(l).ok(),
+                    Err(_) => break,
+                };
+            }
+        });
+
+        let timeout = Duration::from_millis(transport.ready_timeout as u64);
+        let deadline = Instant::now() + timeout;
+        let mut proxy = None;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let line = rx.recv_timeout(remaining).map_err(|_| {
+                anyhow!(
+                    "transport {:?} did not start within {}ms",
+                    transport.command[0],
+                    transport.ready_timeout
+                )
+            })?;
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("CMETHOD") if words.next() == Some(name) => {
+                    proxy = Some(cmethod_proxy(transport, words.next(), words.next())?);
+                }
+                Some("CMETHODS") => break,
+                Some("VERSION-ERROR" | "ENV-ERROR" | "CMETHOD-ERROR" | "PROXY-ERROR") => {
+                    bail!("transport {:?} failed: {}", transport.command[0], line)
+                }
+                _ => (),
+            }
+        }
+
+        proxy.ok_or_else(|| {
+            anyhow!(
+                "transport {:?} does not provide {}",
+                transport.command[0],
+                name
+            )
+        })
+    }
+}
+
+impl Drop for Upstreams {
+    fn drop(&mut self) {
+        for child in self.children.iter_mut().rev() {
+            stop(child);
+        }
+    }
+}
+
+fn spawn(args: &[String], stdin: Stdio, stdout: Stdio) -> std::io::Result<Child> {
+    spawn_command(Command::new(&args[0]).args(&args[1..]), stdin, stdout)
+}
+
+fn spawn_command(command: &mut Command, stdin: Stdio, stdout: Stdio) -> std::io::Result<Child> {
+    // the output of the hooked program is kept clean
+    command.stdin(stdin).stdout(stdout);
+    // helpers must not outlive proxyc if it gets killed
+    unsafe {
+        command.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.spawn()
+}
+
+/// Builds the proxy announced by a `CMETHOD name protocol address` line. The
+/// bridge arguments are passed as socks5 credentials, split over the username
+/// and the password.
+fn cmethod_proxy(
+    transport: &Transport,
+    proto: Option<&str>,
+    addr: Option<&str>,
+) -> Result<ProxyConf> {
+    let addr = addr
+        .and_then(|a| SocketAddr::from_str(a).ok())
+        .ok_or_else(|| anyhow!("invalid CMETHOD address"))?;
+    let proto = proto
+        .and_then(|p| ProxyType::from_str(p).ok())
+        .ok_or_else(|| anyhow!("invalid CMETHOD protocol"))?;
+
+    let args = transport
+        .bridge
+        .args
+        .iter()
+        .map(|(k, v)| format!("{}={}", pt_escape(k), pt_escape(v)))
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let auth = match (proto, args.len()) {
+        (_, 0) => None,
+        (ProxyType::Socks5, _) if args.is_ascii() && args.len() <= 255 * 2 => {
+            let (user, pass) = args.split_at(args.len().min(255));
+            // an empty password is not allowed, a single NUL is sent instead
+            let pass = match pass.is_empty() {
+                true => "\0".to_string(),
+                false => pass.to_string(),
+            };
+            Some(Auth::UserPassword(user.to_string(), pass))
+        }
+        _ => bail!(
+            "the bridge arguments cannot be passed through {} {}",
+            proto,
+            addr
+        ),
+    };
+
+    Ok(ProxyConf {
+        proto,
+        ip: addr.ip(),
+        port: addr.port(),
+        auth,
+        alt_ips: vec![],
+        hostname: None,
+        country: None,
+        auth_methods: vec![],
+        check: None,
+    })
+}
+
+/// Escapes the separators of pluggable transport arguments.
+fn pt_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace('=', "\\=")
+}
+
+fn wait_ready(child: &mut Child, upstream: &Upstream) -> Result<()> {
+    let addr = SocketAddr::new(upstream.proxy.ip, upstream.proxy.port);
+    let deadline = Instant::now() + Duration::from_millis(upstream.ready_timeout as u64);
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            bail!(
+                "upstream {:?} exited with {} before {} was ready",
+                upstream.command[0],
+                status,
+                upstream.proxy
+            );
+        }
+        if TcpStream::connect_timeout(&addr, READY_POLL).is_ok() {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            bail!(
+                "upstream {:?} did not open {} within {}ms",
+                upstream.command[0],
+                upstream.proxy,
+                upstream.ready_timeout
+            );
+        }
+        std::thread::sleep(READY_POLL);
+    }
+}
+
+/// Asks a helper to exit, killing it if it does not in time.
+fn stop(child: &mut Child) {
+    if let Ok(Some(_)) = child.try_wait() {
+        return;
+    }
+
+    kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).ok();
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while Instant::now() < deadline {
+        if let Ok(Some(_)) = child.try_wait() {
+            return;
+        }
+        std::thread::sleep(READY_POLL);
+    }
+    child.kill().ok();
+    child.wait().ok();
+}
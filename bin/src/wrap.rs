@@ -0,0 +1,113 @@
+//! Shell scripts launching a program hooked with the options proxyc was given,
+//! to be dropped into PATH in place of the program.
+//!
+//! The scripts run proxyc again rather than the program under a frozen
+//! environment: the configuration files are read each time, and upstreams
+//! and validate_on_start keep working.
+use crate::sh_quote;
+use anyhow::{anyhow, bail, Context, Result};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Options taking a path, made absolute for the scripts to run anywhere.
+const PATH_OPTIONS: [&str; 4] = ["-f", "--file-config", "--append-config", "--proxy-file"];
+
+/// Returns the options given to proxyc before the wrap subcommand.
+pub fn options() -> Result<Vec<String>> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let end = args.iter().position(|a| a == "wrap").unwrap_or(args.len());
+
+    let mut options = vec![];
+    let mut path_next = false;
+    for arg in &args[..end] {
+        let option = match arg.split_once('=') {
+            _ if path_next => absolute(arg)?,
+            Some((flag, path)) if PATH_OPTIONS.contains(&flag) => {
+                format!("{}={}", flag, absolute(path)?)
+            }
+            _ => arg.clone(),
+        };
+        path_next = PATH_OPTIONS.contains(&arg.as_str());
+        options.push(option);
+    }
+    Ok(options)
+}
+
+fn absolute(path: &str) -> Result<String> {
+    Ok(env::current_dir()?
+        .join(path)
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// Returns the absolute path of `program`, looked up in PATH unless it holds
+/// a slash. `script` is skipped, not to wrap itself.
+fn resolve(program: &str, script: &Path) -> Result<PathBuf> {
+    let script = fs::canonicalize(script).ok();
+    let found = match program.contains('/') {
+        true => Some(PathBuf::from(program)),
+        false => env::var_os("PATH")
+            .iter()
+            .flat_map(env::split_paths)
+            .map(|dir| dir.join(program))
+            .filter(|p| p.is_file())
+            .find(|p| script.is_none() || fs::canonicalize(p).ok() != script),
+    };
+    let path = found
+        .and_then(|p| fs::canonicalize(p).ok())
+        .ok_or_else(|| anyhow!("{}: program not found", program))?;
+    if Some(&path) == script.as_ref() {
+        bail!("{} would wrap itself", path.display());
+    }
+    Ok(path)
+}
+
+/// Writes to `output`, or to the program name in the current directory, a
+/// script running `program` hooked with `options`. Returns the path of the
+/// script.
+pub fn write(
+    program: &str,
+    output: Option<&Path>,
+    force: bool,
+    options: &[String],
+) -> Result<PathBuf> {
+    let output = match output {
+        Some(o) => o.to_path_buf(),
+        None => PathBuf::from(
+            Path::new(program)
+                .file_name()
+                .ok_or_else(|| anyhow!("{:?} names no program", program))?,
+        ),
+    };
+    let program = resolve(program, &output)?;
+    let proxyc = env::current_exe()?;
+
+    let mut command = vec![sh_quote(&proxyc.to_string_lossy())];
+    command.extend(options.iter().map(|o| sh_quote(o)));
+    command.push("--".to_string());
+    command.push(sh_quote(&program.to_string_lossy()));
+    let script = format!(
+        "#!/bin/sh\n# {} hooked by proxyc, written by proxyc wrap\nexec {} \"$@\"\n",
+        program.display(),
+        command.join(" ")
+    );
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .create_new(!force)
+        .mode(0o755)
+        .open(&output);
+    let mut file = match file {
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            bail!("{} already exists, use --force", output.display())
+        }
+        file => file.with_context(|| format!("failed to write {}", output.display()))?,
+    };
+    file.write_all(script.as_bytes())?;
+    Ok(output)
+}
@@ -0,0 +1,112 @@
+//! Versioning of the hooks, so that programs looking up a symbol at the
+//! version of the glibc they were linked against, with dlvsym() or through
+//! libpthread, find libproxyc's definition as well.
+//!
+//! Each hook `foo` has a forwarder `proxyc_v_foo`, exported as
+//! `foo@<version>` with `PROXYC_SYMVER_foo` holding the version.
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Hooked symbols.
+const SYMBOLS: [&str; 26] = [
+    "accept",
+    "accept4",
+    "close",
+    "connect",
+    "dup2",
+    "dup3",
+    "freeaddrinfo",
+    "gai_strerror",
+    "getaddrinfo",
+    "gethostbyaddr",
+    "gethostbyaddr_r",
+    "gethostbyname",
+    "getnameinfo",
+    "getsockname",
+    "herror",
+    "read",
+    "recv",
+    "recvfrom",
+    "recvmsg",
+    "send",
+    "sendfile",
+    "sendfile64",
+    "sendto",
+    "socket",
+    "splice",
+    "write",
+];
+
+/// Version of the glibc symbols on an architecture, and the symbols added
+/// or changed by a later version.
+fn versions(arch: &str) -> Option<(&'static str, &'static [(&'static str, &'static str)])> {
+    match arch {
+        "x86_64" => Some((
+            "GLIBC_2.2.5",
+            &[
+                ("accept4", "GLIBC_2.10"),
+                ("dup3", "GLIBC_2.9"),
+                ("sendfile64", "GLIBC_2.3"),
+                ("splice", "GLIBC_2.5"),
+            ],
+        )),
+        "aarch64" => Some(("GLIBC_2.17", &[])),
+        "x86" => Some((
+            "GLIBC_2.0",
+            &[
+                ("accept4", "GLIBC_2.10"),
+                ("dup3", "GLIBC_2.9"),
+                ("gai_strerror", "GLIBC_2.1"),
+                ("gethostbyaddr_r", "GLIBC_2.1.2"),
+                ("sendfile", "GLIBC_2.1"),
+                ("sendfile64", "GLIBC_2.3"),
+                ("splice", "GLIBC_2.5"),
+            ],
+        )),
+        "arm" => Some((
+            "GLIBC_2.4",
+            &[
+                ("accept4", "GLIBC_2.10"),
+                ("dup3", "GLIBC_2.9"),
+                ("splice", "GLIBC_2.5"),
+            ],
+        )),
+        _ => None,
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-check-cfg=cfg(proxyc_symver)");
+
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let target_env = env::var("CARGO_CFG_TARGET_ENV").unwrap_or_default();
+    let (base, later) = match versions(&arch) {
+        Some(v) if target_env == "gnu" => v,
+        // bionic and musl do not version their symbols
+        _ => return,
+    };
+
+    let mut nodes = vec![base];
+    for symbol in SYMBOLS {
+        let version = later
+            .iter()
+            .find(|(s, _)| *s == symbol)
+            .map_or(base, |(_, v)| v);
+        if !nodes.contains(&version) {
+            nodes.push(version);
+        }
+        println!("cargo:rustc-env=PROXYC_SYMVER_{}={}", symbol, version);
+    }
+
+    // the linker only exports the versions defined by a script
+    let script = Path::new(&env::var("OUT_DIR").unwrap()).join("symver.map");
+    let nodes: String = nodes.iter().map(|n| format!("{} {{ }};\n", n)).collect();
+    fs::write(&script, nodes).unwrap();
+    println!(
+        "cargo:rustc-cdylib-link-arg=-Wl,--version-script={}",
+        script.display()
+    );
+    println!("cargo:rustc-cfg=proxyc_symver");
+}
@@ -0,0 +1,162 @@
+/// Forwarding of plain HTTP in absolute-URI form
+///
+/// HTTP proxies commonly refuse to CONNECT to port 80. Connections to port 80
+/// then stop at the last proxy, and the request line the program sends first
+/// is rewritten from origin form (`GET /path HTTP/1.1`) to the absolute form
+/// the proxy forwards (`GET http://host/path HTTP/1.1`). Only the first
+/// request of a connection is rewritten.
+use crate::core::{self, CONFIG};
+use crate::util::poll_retry;
+use nix::errno::Errno;
+use nix::libc::ssize_t;
+use nix::poll::{PollFd, PollFlags};
+use once_cell::sync::Lazy;
+use proxyc_common::{ProxyConf, ProxyType};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Longest request line rewritten.
+const MAX_LINE_LEN: usize = 8192;
+
+/// Sockets waiting for their first request, with the authority of their
+/// destination used when the request has no Host header.
+static PENDING: Lazy<Mutex<HashMap<RawFd, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Number of pending sockets, lets write() skip the lock in the common case.
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether connections to `port` whose last hop is `last` are forwarded in
+/// absolute-URI form rather than tunneled.
+pub fn applies(last: Option<&ProxyConf>, port: u16) -> bool {
+    CONFIG.http_absolute_uri && port == 80 && last.is_some_and(|p| p.proto == ProxyType::Http)
+}
+
+/// Rewrites the first request sent on `sock`, connected to the last proxy,
+/// for the destination `ip`, `hostname` if it was resolved by proxyc.
+pub fn register(sock: RawFd, ip: IpAddr, hostname: Option<String>) {
+    let authority = match (hostname, ip) {
+        (Some(h), _) => h,
+        (None, IpAddr::V6(ip)) => format!("[{}]", ip),
+        (None, IpAddr::V4(ip)) => ip.to_string(),
+    };
+    debug!("socket {} forwards http for {}", sock, authority);
+
+    let previous = PENDING
+        .lock()
+        .expect("mutex poisoned")
+        .insert(sock, authority);
+    if previous.is_none() {
+        COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Takes the authority of `sock` if it waits for its first request.
+///
+/// Logging goes through write(), the lock must not be held while logging.
+fn take(sock: RawFd) -> Option<String> {
+    if COUNT.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    let removed = PENDING.lock().expect("mutex poisoned").remove(&sock);
+    if removed.is_some() {
+        COUNT.fetch_sub(1, Ordering::Relaxed);
+    }
+    removed
+}
+
+/// Whether `sock` waits for its first request.
+pub fn is_pending(sock: RawFd) -> bool {
+    COUNT.load(Ordering::Relaxed) > 0 && PENDING.lock().expect("mutex poisoned").contains_key(&sock)
+}
+
+/// Returns the value of the Host header of a request head, if complete.
+fn host_header(head: &[u8]) -> Option<&str> {
+    head.split(|&b| b == b'\n')
+        .skip(1)
+        .take_while(|l| !l.is_empty() && *l != b"\r")
+        .filter_map(|l| std::str::from_utf8(l).ok())
+        .find_map(|l| {
+            let (name, value) = l.split_once(':')?;
+            name.eq_ignore_ascii_case("host").then(|| value.trim())
+        })
+}
+
+/// Rewrites an origin-form request line, None if `line` is not one.
+fn rewrite_line(line: &[u8], host: &str) -> Option<Vec<u8>> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut parts = line.splitn(3, ' ');
+    let (method, target, version) = (parts.next()?, parts.next()?, parts.next()?);
+    if !target.starts_with('/') || !version.starts_with("HTTP/") {
+        return None;
+    }
+    Some(format!("{} http://{}{} {}", method, host, target, version).into_bytes())
+}
+
+/// Sends `buf` on `sock` with `send`, rewriting its request line if it is
+/// the first request of a socket forwarded in absolute-URI form. Returns
+/// None if the data is sent unchanged, the result of the send otherwise,
+/// counted in bytes of `buf`.
+pub fn send(sock: RawFd, buf: &[u8], send: impl Fn(&[u8]) -> ssize_t) -> Option<ssize_t> {
+    let authority = take(sock)?;
+
+    let line_len = match buf.iter().take(MAX_LINE_LEN).position(|&b| b == b'\n') {
+        Some(i) => i + 1,
+        None => {
+            warn!("socket {}: no request line to rewrite", sock);
+            return None;
+        }
+    };
+    let (line, rest) = buf.split_at(line_len);
+    let eol = match line.ends_with(b"\r\n") {
+        true => 2,
+        false => 1,
+    };
+    let host = host_header(buf).unwrap_or(&authority);
+    let rewritten = match rewrite_line(&line[..line_len - eol], host) {
+        Some(mut l) => {
+            l.extend_from_slice(&line[line_len - eol..]);
+            l
+        }
+        None => {
+            warn!("socket {}: not an origin-form http request", sock);
+            return None;
+        }
+    };
+    trace!("socket {} request line rewritten", sock);
+
+    // the rewritten line is sent whole, the program then sees its own line
+    // as consumed
+    let mut sent = 0;
+    while sent < rewritten.len() {
+        let ret = send(&rewritten[sent..]);
+        match Errno::result(ret) {
+            Ok(n) => sent += n as usize,
+            Err(Errno::EINTR) => (),
+            Err(Errno::EAGAIN) => {
+                let mut fds = [PollFd::new(sock, PollFlags::POLLOUT)];
+                if poll_retry(&mut fds, CONFIG.tcp_read_timeout).is_err() {
+                    core::set_errno(Errno::ETIMEDOUT);
+                    return Some(-1);
+                }
+            }
+            Err(_) => return Some(ret),
+        }
+    }
+    if rest.is_empty() {
+        return Some(line_len as ssize_t);
+    }
+
+    match send(rest) {
+        n if n >= 0 => Some(line_len as ssize_t + n),
+        // the error is reported by the next send
+        _ => Some(line_len as ssize_t),
+    }
+}
+
+/// Forgets a closed socket.
+pub fn close(sock: RawFd) {
+    take(sock);
+}
@@ -0,0 +1,108 @@
+/// Functions exported to the programs aware of the library
+///
+/// Programs looking them up with dlsym() can steer their own connections,
+/// such as proxyc serve selecting the chains of each of its clients.
+use crate::core::{self, CONFIG};
+use crate::proxy::{Bound, Socks5};
+use nix::libc::{c_char, c_int, size_t};
+use nix::sys::socket::{getpeername, SockAddr};
+use proxyc_common::{ProxyType, DEFAULT_CHAIN, DIRECT_CHAIN};
+use std::cell::RefCell;
+use std::ffi::CStr;
+
+thread_local! {
+    /// Chains tried by the connections of the thread rather than those of
+    /// chain_order.
+    static CHAINS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Returns the chains selected for the connections of the calling thread.
+pub fn chains() -> Option<Vec<String>> {
+    CHAINS.with(|c| c.borrow().clone())
+}
+
+/// Makes the connections of the calling thread try the chains `names`,
+/// comma separated, in turn rather than those of chain_order, until called
+/// with NULL. Returns -1, leaving the selection unchanged, if one of them is
+/// not configured.
+#[no_mangle]
+pub extern "C" fn proxyc_use_chains(names: *const c_char) -> c_int {
+    if names.is_null() {
+        CHAINS.with(|c| c.replace(None));
+        return 0;
+    }
+    let names: Vec<String> = unsafe { CStr::from_ptr(names) }
+        .to_string_lossy()
+        .split(',')
+        .map(str::to_string)
+        .collect();
+    if let Some(name) = names.iter().find(|n| {
+        !matches!(n.as_str(), DEFAULT_CHAIN | DIRECT_CHAIN) && !CONFIG.chains.contains_key(*n)
+    }) {
+        error!("no chain named {:?}", name);
+        return -1;
+    }
+    CHAINS.with(|c| c.replace(Some(names)));
+    0
+}
+
+/// Copies `s` to the buffer `buf` of `len` bytes as a C string, truncated to
+/// fit.
+fn write_str(buf: *mut c_char, len: size_t, s: &str) {
+    if buf.is_null() || len == 0 {
+        return;
+    }
+    let n = s.len().min(len - 1);
+    unsafe {
+        std::ptr::copy_nonoverlapping(s.as_ptr() as *const c_char, buf, n);
+        *buf.add(n) = 0;
+    }
+}
+
+/// Asks the last proxy of the chain to listen for a connection with socks5
+/// BIND, writing the address it listens at to `bound` as host:port. Returns
+/// the socket announcing the connection to proxyc_bind_accept(), or -1.
+#[no_mangle]
+pub extern "C" fn proxyc_bind(bound: *mut c_char, len: size_t) -> c_int {
+    match CONFIG.chain_for(None).and_then(|p| p.last()) {
+        Some(last) if last.proto == ProxyType::Socks5 => {}
+        _ => {
+            error!("bind: the last proxy of the chain is not a socks5 proxy");
+            return -1;
+        }
+    }
+    let (sock, mut addr) = match core::last_hop_request(Socks5::bind) {
+        Ok(res) => res,
+        Err(e) => {
+            error!("bind: {}", e);
+            return -1;
+        }
+    };
+    // proxies listening on every interface are reachable at their address
+    if let Bound::Addr(a) = &mut addr {
+        if a.ip().is_unspecified() {
+            if let Ok(SockAddr::Inet(peer)) = getpeername(sock) {
+                a.set_ip(peer.to_std().ip());
+            }
+        }
+    }
+    write_str(bound, len, &addr.to_string());
+    sock
+}
+
+/// Waits for the connection to the address of proxyc_bind(), writing the
+/// address of its peer to `peer`. `sock` then carries the connection.
+/// Returns -1 on failure, `sock` being left to close.
+#[no_mangle]
+pub extern "C" fn proxyc_bind_accept(sock: c_int, peer: *mut c_char, len: size_t) -> c_int {
+    match Socks5::bind_accepted(sock) {
+        Ok(addr) => {
+            write_str(peer, len, &addr.to_string());
+            0
+        }
+        Err(e) => {
+            error!("bind: {}", e);
+            -1
+        }
+    }
+}
@@ -0,0 +1,222 @@
+/// Audit file, one record per proxied connection, and the events of the
+/// connections posted to the webhook
+use crate::conn::{self, Connection, Direction};
+use crate::core::{self, CONFIG};
+use crate::error::Error;
+use crate::util;
+use crate::webhook;
+use proxyc_common::{AuditRecord, ProxyConf, Rule};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::RawFd;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn unix_ms(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Names the destination by its hostname when it was resolved by the
+/// proxies.
+fn target_name(ip: IpAddr, port: u16) -> String {
+    match core::find_ip_hostname(ip) {
+        Some(hn) => format!("{}:{}", hn, port),
+        None => SocketAddr::new(ip, port).to_string(),
+    }
+}
+
+/// Names the proxies of the chain used with `rule`.
+fn chain(rule: Option<&Rule>) -> Vec<String> {
+    CONFIG
+        .chain_for(rule)
+        .unwrap_or_default()
+        .iter()
+        .map(ProxyConf::endpoint)
+        .collect()
+}
+
+/// Returns the bytes sent and received on a TCP socket, as counted by the
+/// kernel.
+fn tcp_bytes(fd: RawFd) -> Option<(u64, u64)> {
+    util::tcp_info(fd).map(|info| (info.tcpi_bytes_acked, info.tcpi_bytes_received))
+}
+
+/// Event of a connection, as posted to the webhook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Event {
+    Established,
+    Failed,
+    Closed,
+}
+
+impl Event {
+    fn name(self) -> &'static str {
+        match self {
+            Event::Established => "established",
+            Event::Failed => "failed",
+            Event::Closed => "closed",
+        }
+    }
+}
+
+/// Whether the connections are recorded, to the audit file or the webhook.
+fn enabled() -> bool {
+    CONFIG.audit_file.is_some() || webhook::enabled()
+}
+
+/// Posts `record` to the webhook as `event`, and appends it to the audit file
+/// unless the connection was only established.
+fn write(event: Event, record: &AuditRecord) {
+    if webhook::enabled() {
+        match serde_json::to_value(record) {
+            Ok(mut value) => {
+                value["event"] = event.name().into();
+                webhook::push(value);
+            }
+            Err(e) => error!("failed to serialize webhook event: {}", e),
+        }
+    }
+    if event == Event::Established {
+        return;
+    }
+
+    let path = match &CONFIG.audit_file {
+        Some(p) => p,
+        None => return,
+    };
+
+    let line = match serde_json::to_string(record) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("failed to serialize audit record: {}", e);
+            return;
+        }
+    };
+
+    // records are small enough for appends of concurrent processes not to
+    // interleave.
+    let res = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut f| f.write_all(format!("{}\n", line).as_bytes()));
+    if let Err(e) = res {
+        error!("failed to write audit record to {:?}: {}", path, e);
+    }
+}
+
+/// Records a connection established through the chain to `ip` on `port`.
+pub fn established(ip: IpAddr, port: u16) {
+    if !webhook::enabled() {
+        return;
+    }
+
+    let now = unix_ms(SystemTime::now());
+    write(
+        Event::Established,
+        &AuditRecord {
+            pid: std::process::id(),
+            start: now,
+            end: now,
+            target: target_name(ip, port),
+            chain: chain(CONFIG.rule_for(ip, port)),
+            error: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+        },
+    );
+}
+
+/// Records a connection that could not be established.
+pub fn failed(ip: IpAddr, port: u16, start: SystemTime, error: &Error) {
+    if !enabled() {
+        return;
+    }
+
+    write(
+        Event::Failed,
+        &AuditRecord {
+            pid: std::process::id(),
+            start: unix_ms(start),
+            end: unix_ms(SystemTime::now()),
+            target: target_name(ip, port),
+            chain: chain(CONFIG.rule_for(ip, port)),
+            error: Some(error.to_string()),
+            bytes_sent: 0,
+            bytes_received: 0,
+        },
+    );
+}
+
+/// Records a connection refused before reaching the proxies.
+pub fn denied(target: String, error: &Error) {
+    if !enabled() {
+        return;
+    }
+
+    let now = unix_ms(SystemTime::now());
+    write(
+        Event::Failed,
+        &AuditRecord {
+            pid: std::process::id(),
+            start: now,
+            end: now,
+            target,
+            chain: vec![],
+            error: Some(error.to_string()),
+            bytes_sent: 0,
+            bytes_received: 0,
+        },
+    );
+}
+
+/// Records a proxied connection about to be closed.
+pub fn closed(fd: RawFd, c: &Connection) {
+    if !enabled()
+        || c.direction != Direction::Outbound
+        || !c.proxied
+        || c.owner != std::process::id()
+    {
+        return;
+    }
+
+    let (target, rule) = match c.target.parse::<SocketAddr>() {
+        Ok(addr) => (
+            target_name(addr.ip(), addr.port()),
+            CONFIG.rule_for(addr.ip(), addr.port()),
+        ),
+        Err(_) => (c.target.clone(), None),
+    };
+    // the kernel also counts the data moved by the calls libproxyc does not
+    // hook, the counters of the hooks only serve the local socket pairs of
+    // the tls relays
+    let (bytes_sent, bytes_received) = tcp_bytes(fd).unwrap_or((c.sent, c.received));
+    let now = SystemTime::now();
+    let start = now.checked_sub(c.since.elapsed()).unwrap_or(now);
+
+    write(
+        Event::Closed,
+        &AuditRecord {
+            pid: std::process::id(),
+            start: unix_ms(start),
+            end: unix_ms(now),
+            target,
+            chain: chain(rule),
+            error: None,
+            bytes_sent,
+            bytes_received,
+        },
+    );
+}
+
+/// Records the connections still open when the process exits.
+pub fn dump_open() {
+    if !enabled() {
+        return;
+    }
+
+    for (fd, c) in conn::snapshot() {
+        closed(fd, &c);
+    }
+}
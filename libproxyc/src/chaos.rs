@@ -0,0 +1,37 @@
+/// Delays and failures injected in the handshakes of the hops
+///
+/// Each hop of a chain waits for the configured delay, and a random part up
+/// to the jitter, before its handshake, which then fails with the configured
+/// probability, as with a proxy slowing down or dying. A delay reaching the
+/// read timeout of the hop times out, like a proxy too slow would.
+use crate::core::CONFIG;
+use crate::error::Error;
+use crate::util;
+use std::thread;
+use std::time::Duration;
+
+/// Degrades the handshake of a hop given `timeout` milliseconds to read
+/// each reply, if configured.
+pub fn hop(timeout: usize) -> Result<(), Error> {
+    let chaos = match &CONFIG.chaos {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+    let jitter = match chaos.jitter {
+        0 => 0,
+        j => (util::random_u64() % (j as u64 + 1)) as usize,
+    };
+    let delay = chaos.delay + jitter;
+    if delay >= timeout {
+        thread::sleep(Duration::from_millis(timeout as u64));
+        return Err(Error::Timeout);
+    }
+    thread::sleep(Duration::from_millis(delay as u64));
+
+    if chaos.failure_rate > 0.0
+        && (util::random_u64() as f64 / u64::MAX as f64) < chaos.failure_rate
+    {
+        return Err(Error::Generic("failure injected by chaos".into()));
+    }
+    Ok(())
+}
@@ -0,0 +1,295 @@
+/// Table of the sockets known to libproxyc, inbound and outbound
+use crate::proxy::Bound;
+use crate::stats::STATS;
+use nix::sys::socket::{getpeername, shutdown, Shutdown, SockAddr};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Connected by the program, through the proxies or not.
+    Outbound,
+    /// Accepted by the program, never proxied.
+    Inbound,
+}
+
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub direction: Direction,
+    pub proxied: bool,
+    /// Destination as requested by the program, or the remote end of an
+    /// inbound connection.
+    pub target: String,
+    /// Peer of the socket, the first proxy of the chain for proxied
+    /// connections.
+    pub peer: SockAddr,
+    /// Address bound by the last proxy for the connection, as seen by the
+    /// target.
+    pub bound: Option<Bound>,
+    /// Indexes in the statistics of the proxies of the chain.
+    pub hops: Vec<usize>,
+    pub since: Instant,
+    /// Process that opened the connection, forked children inherit it.
+    pub owner: u32,
+    /// Bytes the program sent and received through the data-path hooks.
+    pub sent: u64,
+    pub received: u64,
+}
+
+static CONNECTIONS: Lazy<Mutex<HashMap<RawFd, Connection>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Number of tracked sockets, lets close() skip the lock in the common case.
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Highest file descriptor whose bytes are counted.
+const MAX_COUNTED_FD: usize = 16383;
+
+/// Byte counters of a tracked socket.
+#[derive(Default)]
+struct Counters {
+    tracked: AtomicBool,
+    /// Whether the bytes feed the statistics of the proxied connections.
+    proxied: AtomicBool,
+    sent: AtomicU64,
+    received: AtomicU64,
+    /// Time of the last traffic, in milliseconds since EPOCH.
+    last_active: AtomicU64,
+}
+
+/// Origin of the activity times.
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+fn now_ms() -> u64 {
+    EPOCH.elapsed().as_millis() as u64
+}
+
+/// Counters indexed by file descriptor. Every read and write of the program
+/// updates them, from any thread and in forked children whatever the state
+/// of the other threads: they are atomics rather than part of the table.
+static COUNTERS: Lazy<Vec<Counters>> =
+    Lazy::new(|| (0..=MAX_COUNTED_FD).map(|_| Counters::default()).collect());
+
+fn counters(fd: RawFd) -> Option<&'static Counters> {
+    COUNTERS.get(usize::try_from(fd).ok()?)
+}
+
+/// Stops counting the bytes of `fd`, returning the bytes sent and received.
+fn untrack(fd: RawFd) -> (u64, u64) {
+    match counters(fd) {
+        Some(c) => {
+            c.tracked.store(false, Ordering::Release);
+            (
+                c.sent.load(Ordering::Relaxed),
+                c.received.load(Ordering::Relaxed),
+            )
+        }
+        None => (0, 0),
+    }
+}
+
+/// Records a connected socket.
+pub fn register(
+    fd: RawFd,
+    direction: Direction,
+    proxied: bool,
+    target: String,
+    bound: Option<Bound>,
+    hops: Vec<usize>,
+) {
+    let peer = match getpeername(fd) {
+        Ok(p) => p,
+        Err(_) => return,
+    };
+
+    let conn = Connection {
+        direction,
+        proxied,
+        target,
+        peer,
+        bound,
+        hops,
+        since: Instant::now(),
+        owner: std::process::id(),
+        sent: 0,
+        received: 0,
+    };
+    if let Some(c) = counters(fd) {
+        c.sent.store(0, Ordering::Relaxed);
+        c.received.store(0, Ordering::Relaxed);
+        c.last_active.store(now_ms(), Ordering::Relaxed);
+        c.proxied.store(
+            proxied && direction == Direction::Outbound,
+            Ordering::Relaxed,
+        );
+        c.tracked.store(true, Ordering::Release);
+    }
+    if CONNECTIONS
+        .lock()
+        .expect("mutex poisoned")
+        .insert(fd, conn)
+        .is_none()
+    {
+        COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Forgets a closed socket, returning its entry. The throughput of a proxied
+/// connection is recorded.
+pub fn forget(fd: RawFd) -> Option<Connection> {
+    if COUNT.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+
+    let mut removed = CONNECTIONS.lock().expect("mutex poisoned").remove(&fd);
+    if let Some(c) = &mut removed {
+        COUNT.fetch_sub(1, Ordering::Relaxed);
+        let last_active = counters(fd).map(|c| c.last_active.load(Ordering::Relaxed));
+        (c.sent, c.received) = untrack(fd);
+        if c.proxied && c.direction == Direction::Outbound && c.owner == std::process::id() {
+            let since = c.since.saturating_duration_since(*EPOCH).as_millis() as u64;
+            let active = last_active.map_or(0, |l| l.saturating_sub(since));
+            STATS.throughput(&c.hops, c.sent + c.received, Duration::from_millis(active));
+        }
+    }
+    removed
+}
+
+/// Accounts the bytes sent and received on `fd` by the program, if tracked.
+pub fn count(fd: RawFd, sent: usize, received: usize) {
+    if COUNT.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+
+    let c = match counters(fd) {
+        Some(c) if c.tracked.load(Ordering::Acquire) => c,
+        _ => return,
+    };
+    c.sent.fetch_add(sent as u64, Ordering::Relaxed);
+    c.received.fetch_add(received as u64, Ordering::Relaxed);
+    c.last_active.store(now_ms(), Ordering::Relaxed);
+    if c.proxied.load(Ordering::Relaxed) {
+        STATS.bytes(sent as u64, received as u64);
+    }
+}
+
+/// Whether `fd` is a connection of the program through the proxies.
+pub fn is_proxied(fd: RawFd) -> bool {
+    COUNT.load(Ordering::Relaxed) > 0
+        && counters(fd)
+            .is_some_and(|c| c.tracked.load(Ordering::Acquire) && c.proxied.load(Ordering::Relaxed))
+}
+
+/// Returns the address bound by the last proxy for `fd`, if known and not
+/// reported by hostname.
+pub fn bound(fd: RawFd) -> Option<SocketAddr> {
+    if COUNT.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    CONNECTIONS
+        .lock()
+        .expect("mutex poisoned")
+        .get(&fd)
+        .and_then(|c| c.bound.as_ref()?.addr())
+}
+
+/// Returns the addresses the outbound connections of this process go to.
+pub fn destinations() -> HashSet<IpAddr> {
+    if COUNT.load(Ordering::Relaxed) == 0 {
+        return HashSet::new();
+    }
+    CONNECTIONS
+        .lock()
+        .expect("mutex poisoned")
+        .values()
+        .filter(|c| c.direction == Direction::Outbound)
+        .filter_map(|c| c.target.parse::<SocketAddr>().ok())
+        .map(|t| t.ip())
+        .collect()
+}
+
+/// Returns the number of proxied connections of this process, and of those
+/// going to `ip`.
+pub fn proxied_to(ip: IpAddr) -> (usize, usize) {
+    if COUNT.load(Ordering::Relaxed) == 0 {
+        return (0, 0);
+    }
+    let connections = CONNECTIONS.lock().expect("mutex poisoned");
+    // the sockets relayed by the handshake threads, connected to a local
+    // socket pair, count by the slot of their relay
+    let proxied = connections.values().filter(|c| {
+        c.direction == Direction::Outbound && c.proxied && !matches!(c.peer, SockAddr::Unix(_))
+    });
+    proxied.fold((0, 0), |(all, to), c| {
+        let to_ip = c.target.parse::<SocketAddr>().is_ok_and(|t| t.ip() == ip);
+        (all + 1, to + usize::from(to_ip))
+    })
+}
+
+/// Returns the tracked sockets, sorted by file descriptor.
+///
+/// Sockets may have been closed without going through close() (dup2,
+/// close_range), only the ones still connected to the same peer are kept.
+pub fn snapshot() -> Vec<(RawFd, Connection)> {
+    let mut connections = CONNECTIONS.lock().expect("mutex poisoned");
+    connections.retain(|fd, c| {
+        let connected = getpeername(*fd).is_ok_and(|p| p == c.peer);
+        if !connected {
+            untrack(*fd);
+        }
+        connected
+    });
+    COUNT.store(connections.len(), Ordering::Relaxed);
+
+    let mut res: Vec<_> = connections
+        .iter()
+        .map(|(fd, c)| {
+            let mut c = c.clone();
+            if let Some(counters) = counters(*fd) {
+                c.sent = counters.sent.load(Ordering::Relaxed);
+                c.received = counters.received.load(Ordering::Relaxed);
+            }
+            (*fd, c)
+        })
+        .collect();
+    res.sort_by_key(|(fd, _)| *fd);
+    res
+}
+
+/// Shuts down the proxied connections of this process exchanging nothing for
+/// `timeout`, which `kernel_idle` may know to be active otherwise. Returns
+/// the sockets shut down and their target.
+pub fn shut_idle(
+    timeout: Duration,
+    kernel_idle: impl Fn(RawFd) -> Option<Duration>,
+) -> Vec<(RawFd, String)> {
+    let now = now_ms();
+    let pid = std::process::id();
+    let mut shut = vec![];
+
+    let connections = CONNECTIONS.lock().expect("mutex poisoned");
+    for (fd, c) in connections.iter() {
+        if c.owner != pid || !c.proxied || c.direction != Direction::Outbound {
+            continue;
+        }
+        let counters = match counters(*fd) {
+            Some(counters) if counters.tracked.load(Ordering::Acquire) => counters,
+            _ => continue,
+        };
+        let idle = now.saturating_sub(counters.last_active.load(Ordering::Relaxed));
+        if Duration::from_millis(idle) < timeout || kernel_idle(*fd).is_some_and(|k| k < timeout) {
+            continue;
+        }
+        // the descriptor may have been reused without going through close()
+        if getpeername(*fd).is_ok_and(|p| p == c.peer) && shutdown(*fd, Shutdown::Both).is_ok() {
+            counters.tracked.store(false, Ordering::Release);
+            shut.push((*fd, c.target.clone()));
+        }
+    }
+    shut
+}
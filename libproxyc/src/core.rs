@@ -1,26 +1,54 @@
-use crate::error::Error;
-use crate::proxy::{self, Proxy};
+use crate::absolute_uri;
+use crate::api;
+use crate::audit;
+use crate::chaos;
+use crate::conn::{self, Direction};
+use crate::dns_server;
+use crate::error::{Error, Stage};
+use crate::filter;
+use crate::health;
+use crate::idle;
+use crate::netdb;
+use crate::nss;
+use crate::proxy::{self, Bound, Proxy};
+#[cfg(feature = "quic")]
+use crate::quic;
+use crate::random;
+use crate::reuse;
+use crate::rotation;
+use crate::route::{self, Route};
+use crate::stats::STATS;
+use crate::tls;
+use crate::transcript;
+use crate::username::Vars;
 use crate::util::poll_retry;
 use cstr::cstr;
 use nix::errno::Errno;
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::libc::{
-    self, addrinfo, c_char, c_int, c_void, hostent, servent, size_t, sockaddr, sockaddr_in,
-    sockaddr_in6, sockaddr_storage, socklen_t,
+    self, addrinfo, c_char, c_int, c_uint, c_void, hostent, loff_t, msghdr, off64_t, off_t,
+    servent, size_t, sockaddr, sockaddr_in, sockaddr_in6, sockaddr_storage, socklen_t, ssize_t,
 };
-use nix::poll::{PollFd, PollFlags};
-use nix::sys::socket::sockopt::SocketError;
-use nix::sys::socket::{getsockopt, AddressFamily, InetAddr, IpAddr, SockAddr};
-use nix::unistd::{close, dup2};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::sockopt::{self, SocketError};
+use nix::sys::socket::{
+    getpeername, getsockopt, setsockopt, socket, AddressFamily, InetAddr, IpAddr, SockAddr,
+    SockFlag, SockType,
+};
+use nix::unistd::{close, dup2, write};
 use once_cell::sync::Lazy;
-use proxyc_common::{ChainType, ProxyConf, ProxyType, ProxycConfig};
-use std::collections::HashMap;
+use proxyc_common::{
+    AdaptiveTimeout, Auth, AuthMethod, ChainType, Keepalive, ProxyConf, ProxyDnsMode, ProxyType,
+    ProxycConfig, QuicProxy, Rule, DEFAULT_CHAIN, DIRECT_CHAIN,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::CStr;
 use std::mem;
 use std::mem::MaybeUninit;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::os::unix::io::RawFd;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
 type ConnectFn =
     unsafe extern "C" fn(socket: RawFd, address: *const sockaddr, len: socklen_t) -> c_int;
@@ -36,24 +64,192 @@ type FreeAddrInfoFn = unsafe extern "C" fn(res: *mut addrinfo) -> c_void;
 
 type GetHostByNameFn = unsafe extern "C" fn(name: *const c_char) -> *mut hostent;
 
-pub static CONNECT: Lazy<Option<ConnectFn>> = Lazy::new(|| unsafe {
-    std::mem::transmute(libc::dlsym(libc::RTLD_NEXT, cstr!("connect").as_ptr()))
-});
+type GetHostByAddrFn =
+    unsafe extern "C" fn(addr: *const c_void, len: socklen_t, type_: c_int) -> *mut hostent;
+
+type GetHostByAddrRFn = unsafe extern "C" fn(
+    addr: *const c_void,
+    len: socklen_t,
+    type_: c_int,
+    ret: *mut hostent,
+    buf: *mut c_char,
+    buflen: size_t,
+    result: *mut *mut hostent,
+    h_errnop: *mut c_int,
+) -> c_int;
+
+type SendToFn = unsafe extern "C" fn(
+    socket: RawFd,
+    buf: *const c_void,
+    len: size_t,
+    flags: c_int,
+    addr: *const sockaddr,
+    addrlen: socklen_t,
+) -> ssize_t;
+
+type SendfileFn =
+    unsafe extern "C" fn(out_fd: RawFd, in_fd: RawFd, offset: *mut off_t, count: size_t) -> ssize_t;
+
+type Sendfile64Fn = unsafe extern "C" fn(
+    out_fd: RawFd,
+    in_fd: RawFd,
+    offset: *mut off64_t,
+    count: size_t,
+) -> ssize_t;
+
+type SpliceFn = unsafe extern "C" fn(
+    fd_in: RawFd,
+    off_in: *mut loff_t,
+    fd_out: RawFd,
+    off_out: *mut loff_t,
+    len: size_t,
+    flags: c_uint,
+) -> ssize_t;
+
+type RecvFromFn = unsafe extern "C" fn(
+    socket: RawFd,
+    buf: *mut c_void,
+    len: size_t,
+    flags: c_int,
+    addr: *mut sockaddr,
+    addrlen: *mut socklen_t,
+) -> ssize_t;
+
+type ResInitFn = unsafe extern "C" fn() -> c_int;
+
+type ResNinitFn = unsafe extern "C" fn(state: *mut dns_server::ResState) -> c_int;
+
+type RecvMsgFn = unsafe extern "C" fn(socket: RawFd, msg: *mut msghdr, flags: c_int) -> ssize_t;
+
+type Accept4Fn = unsafe extern "C" fn(
+    socket: RawFd,
+    addr: *mut sockaddr,
+    len: *mut socklen_t,
+    flags: c_int,
+) -> c_int;
+
+type GetsocknameFn =
+    unsafe extern "C" fn(socket: RawFd, addr: *mut sockaddr, len: *mut socklen_t) -> c_int;
+
+type CloseFn = unsafe extern "C" fn(fd: RawFd) -> c_int;
+
+type WriteFn = unsafe extern "C" fn(fd: RawFd, buf: *const c_void, count: size_t) -> ssize_t;
+
+type ReadFn = unsafe extern "C" fn(fd: RawFd, buf: *mut c_void, count: size_t) -> ssize_t;
+
+type SocketFn = unsafe extern "C" fn(domain: c_int, ty: c_int, protocol: c_int) -> c_int;
+
+type Dup2Fn = unsafe extern "C" fn(oldfd: RawFd, newfd: RawFd) -> c_int;
+
+type Dup3Fn = unsafe extern "C" fn(oldfd: RawFd, newfd: RawFd, flags: c_int) -> c_int;
+
+type GaiStrerrorFn = unsafe extern "C" fn(errcode: c_int) -> *const c_char;
+
+type HerrorFn = unsafe extern "C" fn(s: *const c_char);
+
+type GetNameInfoFn = unsafe extern "C" fn(
+    sa: *const sockaddr,
+    salen: socklen_t,
+    host: *mut c_char,
+    hostlen: socklen_t,
+    serv: *mut c_char,
+    servlen: socklen_t,
+    flags: c_int,
+) -> c_int;
+
+/// Looks up the libc implementation of a hooked function.
+///
+/// Android linker namespaces may hide libc from RTLD_NEXT, it is then
+/// looked up in libc itself.
+unsafe fn real_symbol(name: &CStr) -> *mut c_void {
+    let sym = libc::dlsym(libc::RTLD_NEXT, name.as_ptr());
+    #[cfg(target_os = "android")]
+    if sym.is_null() {
+        let handle = libc::dlopen(
+            cstr!("libc.so").as_ptr(),
+            libc::RTLD_NOW | libc::RTLD_NOLOAD,
+        );
+        if !handle.is_null() {
+            return libc::dlsym(handle, name.as_ptr());
+        }
+    }
+    sym
+}
+
+pub static CONNECT: Lazy<Option<ConnectFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("connect"))) });
+
+pub static GETADDRINFO: Lazy<Option<GetAddrInfoFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("getaddrinfo"))) });
+
+pub static GETHOSTBYNAME: Lazy<Option<GetHostByNameFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("gethostbyname"))) });
+
+pub static GETHOSTBYADDR: Lazy<Option<GetHostByAddrFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("gethostbyaddr"))) });
+
+pub static GETHOSTBYADDR_R: Lazy<Option<GetHostByAddrRFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("gethostbyaddr_r"))) });
+
+pub static GETNAMEINFO: Lazy<Option<GetNameInfoFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("getnameinfo"))) });
 
-pub static GETADDRINFO: Lazy<Option<GetAddrInfoFn>> = Lazy::new(|| unsafe {
-    std::mem::transmute(libc::dlsym(libc::RTLD_NEXT, cstr!("getaddrinfo").as_ptr()))
-});
+pub static SENDTO: Lazy<Option<SendToFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("sendto"))) });
 
-pub static GETHOSTBYNAME: Lazy<Option<GetHostByNameFn>> = Lazy::new(|| unsafe {
-    std::mem::transmute(libc::dlsym(
-        libc::RTLD_NEXT,
-        cstr!("gethostbyname").as_ptr(),
-    ))
-});
+pub static SENDFILE: Lazy<Option<SendfileFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("sendfile"))) });
 
-pub static FREEADDRINFO: Lazy<Option<FreeAddrInfoFn>> = Lazy::new(|| unsafe {
-    std::mem::transmute(libc::dlsym(libc::RTLD_NEXT, cstr!("freeaddrinfo").as_ptr()))
-});
+pub static SENDFILE64: Lazy<Option<Sendfile64Fn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("sendfile64"))) });
+
+pub static SPLICE: Lazy<Option<SpliceFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("splice"))) });
+
+pub static RECVFROM: Lazy<Option<RecvFromFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("recvfrom"))) });
+
+pub static RES_INIT: Lazy<Option<ResInitFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("__res_init"))) });
+
+pub static RES_NINIT: Lazy<Option<ResNinitFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("__res_ninit"))) });
+
+pub static RECVMSG: Lazy<Option<RecvMsgFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("recvmsg"))) });
+
+pub static ACCEPT4: Lazy<Option<Accept4Fn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("accept4"))) });
+
+pub static GETSOCKNAME: Lazy<Option<GetsocknameFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("getsockname"))) });
+
+pub static CLOSE: Lazy<Option<CloseFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("close"))) });
+
+pub static WRITE: Lazy<Option<WriteFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("write"))) });
+
+pub static READ: Lazy<Option<ReadFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("read"))) });
+
+pub static SOCKET: Lazy<Option<SocketFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("socket"))) });
+
+pub static DUP2: Lazy<Option<Dup2Fn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("dup2"))) });
+
+pub static DUP3: Lazy<Option<Dup3Fn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("dup3"))) });
+
+pub static GAI_STRERROR: Lazy<Option<GaiStrerrorFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("gai_strerror"))) });
+
+pub static HERROR: Lazy<Option<HerrorFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("herror"))) });
+
+pub static FREEADDRINFO: Lazy<Option<FreeAddrInfoFn>> =
+    Lazy::new(|| unsafe { std::mem::transmute(real_symbol(cstr!("freeaddrinfo"))) });
 
 pub static CONFIG: Lazy<ProxycConfig> =
     Lazy::new(|| ProxycConfig::from_env().expect("failed to parse config"));
@@ -61,10 +257,31 @@ pub static CONFIG: Lazy<ProxycConfig> =
 pub static INTERNALADDR: Lazy<Mutex<InternalIpAddr>> =
     Lazy::new(|| Mutex::new(InternalIpAddr::new()));
 
-/// Initiate a connection on a socket
-///
-/// We can't use nix::sys::socket::connect since it would call our hooked
-/// connect function and recurse infinitely.
+/// Returns the hostname an internal address was assigned to, if any. IPv6
+/// callers get internal addresses mapped.
+pub fn find_ip_hostname(ip: std::net::IpAddr) -> Option<String> {
+    let config = &*CONFIG;
+
+    // without proxy_dns, onion names still get internal addresses
+    let ip = match ip {
+        std::net::IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, Into::into),
+        std::net::IpAddr::V4(_) => ip,
+    };
+    if let std::net::IpAddr::V4(addr) = ip {
+        let parts = addr.octets();
+        let idx: u32 = addr.into();
+        if parts[0] == config.dns_octet() {
+            let internal_addr = &mut *INTERNALADDR.lock().expect("mutex poisoned");
+            return internal_addr.get_hostname(idx).ok();
+        }
+    }
+    None
+}
+
+// Initiate a connection on a socket
+//
+// We can't use nix::sys::socket::connect since it would call our hooked
+// connect function and recurse infinitely.
 // pub fn connect(fd: RawFd, addr: &SockAddr) -> Result<(), Error> {
 //     let c_connect = CONNECT.expect("Cannot load symbol 'connect'");
 
@@ -127,6 +344,201 @@ pub fn timed_connect(fd: RawFd, addr: &SockAddr, timeout: usize) -> Result<(), E
     }
 }
 
+/// Starts a non-blocking connection to `addr` on a new socket.
+fn start_connect(addr: SocketAddr) -> Result<RawFd, Error> {
+    let c_connect = CONNECT.expect("Cannot load symbol 'connect'");
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let fd = socket(
+        family,
+        SockType::Stream,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    // marked from the SYN on
+    if let Some(dscp) = CONFIG.dscp {
+        if let Err(e) = set_dscp(fd, addr.is_ipv6(), dscp) {
+            close(fd).ok();
+            return Err(e);
+        }
+    }
+
+    let target = SockAddr::new_inet(InetAddr::from_std(&addr));
+    let res = unsafe {
+        let (ptr, len) = target.as_ffi_pair();
+        c_connect(fd, ptr, len)
+    };
+    match Errno::result(res) {
+        Ok(_) | Err(Errno::EINPROGRESS) => Ok(fd),
+        Err(e) => {
+            close(fd).ok();
+            Err(e.into())
+        }
+    }
+}
+
+/// Connects to the first reachable of `addrs`, RFC 8305 style. Attempts
+/// start in order, `delay` milliseconds apart or as soon as one fails, and
+/// run concurrently until one succeeds or `timeout` milliseconds elapse.
+/// Returns the winning socket in blocking mode, the others being closed.
+fn race_connect(addrs: &[SocketAddr], delay: usize, timeout: usize) -> Result<RawFd, Error> {
+    let start = Instant::now();
+    let deadline = start + Duration::from_millis(timeout as u64);
+    let delay = Duration::from_millis(delay as u64);
+
+    let mut queue = addrs.iter();
+    let mut next_attempt = start;
+    let mut pending: Vec<(RawFd, SocketAddr)> = vec![];
+    let mut last_error = Error::Timeout;
+
+    let res = loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break Err(Error::Timeout);
+        }
+
+        if now >= next_attempt || pending.is_empty() {
+            match queue.next() {
+                Some(addr) => {
+                    trace!("connection attempt to {}", addr);
+                    next_attempt = now + delay;
+                    match start_connect(*addr) {
+                        Ok(fd) => pending.push((fd, *addr)),
+                        Err(e) => {
+                            debug!("{} unreachable: {}", addr, e);
+                            last_error = e;
+                            next_attempt = now;
+                        }
+                    }
+                    continue;
+                }
+                None if pending.is_empty() => break Err(last_error),
+                None => (),
+            }
+        }
+
+        let wake = match queue.len() {
+            0 => deadline,
+            _ => next_attempt.min(deadline),
+        };
+        // round up, not to spin until the next attempt
+        let wait = (wake - now).as_micros().div_ceil(1000) as c_int;
+        let mut fds: Vec<_> = pending
+            .iter()
+            .map(|(fd, _)| PollFd::new(*fd, PollFlags::POLLOUT))
+            .collect();
+        match poll(&mut fds, wait) {
+            Ok(_) | Err(Errno::EINTR) => (),
+            Err(e) => break Err(e.into()),
+        }
+
+        let mut winner = None;
+        let mut still_pending = vec![];
+        for ((fd, addr), pfd) in pending.drain(..).zip(&fds) {
+            if winner.is_some() || pfd.revents().is_none_or(|r| r.is_empty()) {
+                still_pending.push((fd, addr));
+                continue;
+            }
+            match getsockopt(fd, SocketError) {
+                Ok(0) => winner = Some((fd, addr)),
+                res => {
+                    let e = match res {
+                        Ok(e) => Errno::from_i32(e),
+                        Err(e) => e,
+                    };
+                    debug!("{} unreachable: {}", addr, e);
+                    last_error = e.into();
+                    next_attempt = now;
+                    close(fd).ok();
+                }
+            }
+        }
+        pending = still_pending;
+
+        if let Some((fd, addr)) = winner {
+            debug!("connected to {} after {:?}", addr, start.elapsed());
+            break Ok(fd);
+        }
+    };
+
+    for (fd, _) in pending {
+        close(fd).ok();
+    }
+    let fd = res?;
+    if let Err(e) = fcntl(fd, FcntlArg::F_SETFL(OFlag::empty())) {
+        close(fd).ok();
+        return Err(e.into());
+    }
+    Ok(fd)
+}
+
+/// Orders the addresses of a proxy as RFC 8305 does, alternating between
+/// address families starting with the family of its main address `ip`.
+fn happy_eyeballs_order(
+    ip: std::net::IpAddr,
+    alt_ips: &[std::net::IpAddr],
+    port: u16,
+) -> Vec<SocketAddr> {
+    let (same, other): (Vec<_>, Vec<_>) = std::iter::once(ip)
+        .chain(alt_ips.iter().copied())
+        .partition(|i| i.is_ipv6() == ip.is_ipv6());
+    let (mut same, mut other) = (same.into_iter(), other.into_iter());
+
+    let mut addrs = vec![];
+    loop {
+        match (same.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+    addrs
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect()
+}
+
+/// Enables TCP keep-alives on a connection to a proxy.
+fn set_keepalive(sock: RawFd, keepalive: &Keepalive) -> Result<(), Error> {
+    setsockopt(sock, sockopt::KeepAlive, &true)?;
+    setsockopt(sock, sockopt::TcpKeepIdle, &keepalive_secs(keepalive.idle))?;
+    setsockopt(
+        sock,
+        sockopt::TcpKeepInterval,
+        &keepalive_secs(keepalive.interval),
+    )?;
+    setsockopt(sock, sockopt::TcpKeepCount, &keepalive.count)?;
+    Ok(())
+}
+
+/// Marks the packets of a connection to a proxy with the DSCP class `dscp`,
+/// in the traffic class of IPv6 sockets or the TOS of IPv4 ones.
+fn set_dscp(sock: RawFd, ipv6: bool, dscp: u8) -> Result<(), Error> {
+    // the two low bits are ECN's
+    let tos = c_int::from(dscp) << 2;
+    let (level, name) = match ipv6 {
+        true => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+        false => (libc::IPPROTO_IP, libc::IP_TOS),
+    };
+    let res = unsafe {
+        libc::setsockopt(
+            sock,
+            level,
+            name,
+            &tos as *const c_int as *const c_void,
+            mem::size_of::<c_int>() as socklen_t,
+        )
+    };
+    Errno::result(res)?;
+    Ok(())
+}
+
+/// Converts a keep-alive delay to the seconds taken by the kernel, rounding up.
+fn keepalive_secs(ms: usize) -> u32 {
+    ms.div_ceil(1000) as u32
+}
+
 /// Creates a `SockAddr` struct from libc's sockaddr.
 ///
 /// Supports only the following address families: Inet (v4 & v6)
@@ -158,9 +570,13 @@ pub fn set_errno(errno: Errno) {
 }
 
 extern "C" {
+    #[cfg_attr(target_os = "android", link_name = "__errno")]
     pub fn __errno_location() -> *mut i32;
-    fn inet_aton(cp: *const c_char, inp: *const libc::in_addr) -> c_int;
-    fn inet_pton(af: c_int, src: *const c_char, dst: *const c_void) -> c_int;
+    fn inet_aton(cp: *const c_char, inp: *mut libc::in_addr) -> c_int;
+    // bionic only has the non reentrant version, using thread local storage
+    #[cfg(target_os = "android")]
+    fn getservbyname(name: *const c_char, proto: *const c_char) -> *mut servent;
+    #[cfg(not(target_os = "android"))]
     fn getservbyname_r(
         name: *const c_char,
         proto: *const c_char,
@@ -171,48 +587,759 @@ extern "C" {
     ) -> c_int;
 }
 
-/// main logic
+// main logic
 
-fn chain_start(sock: RawFd, proxy: &ProxyConf) -> Result<(), Error> {
-    let config = &*CONFIG;
+/// Timeouts in milliseconds applying to a proxied connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: usize,
+    pub read: usize,
+}
 
+impl Timeouts {
+    /// Returns the timeouts of a destination, the first matching rule
+    /// overriding the global ones.
+    pub fn for_target(config: &ProxycConfig, ip: std::net::IpAddr, port: u16) -> Self {
+        let rule = config.rule_for(ip, port);
+        Timeouts {
+            connect: rule
+                .and_then(|r| r.tcp_connect_timeout)
+                .unwrap_or(config.tcp_connect_timeout),
+            read: rule
+                .and_then(|r| r.tcp_read_timeout)
+                .unwrap_or(config.tcp_read_timeout),
+        }
+    }
+
+    /// Returns the timeouts of a handshake, whose read timeout is scaled from
+    /// its average time `avg_ms` with `adaptive`.
+    fn adapted(&self, adaptive: Option<&AdaptiveTimeout>, avg_ms: Option<f64>) -> Self {
+        match (adaptive, avg_ms) {
+            (Some(a), Some(avg)) => Timeouts {
+                read: ((avg * a.factor as f64) as usize).clamp(a.min, a.max),
+                ..*self
+            },
+            _ => *self,
+        }
+    }
+
+    /// Returns the timeouts of the handshake reaching `proxy`.
+    fn for_hop(&self, proxy: &ProxyConf) -> Self {
+        self.adapted(CONFIG.adaptive_timeout.as_ref(), STATS.rtt_ms(proxy))
+    }
+
+    /// Returns the timeouts of the request of `proxy` reaching `target`.
+    fn for_exit(&self, proxy: &ProxyConf, target: &ProxyConf) -> Self {
+        self.adapted(
+            CONFIG.adaptive_timeout.as_ref(),
+            STATS.exit_ms(proxy, target),
+        )
+    }
+}
+
+/// Whether `sock` is an IPv6 socket.
+pub fn is_ipv6_socket(sock: RawFd) -> bool {
+    matches!(
+        nix::sys::socket::getsockname(sock),
+        Ok(SockAddr::Inet(InetAddr::V6(_)))
+    )
+}
+
+fn chain_start(sock: RawFd, proxy: &ProxyConf, timeouts: &Timeouts) -> Result<(), Error> {
     debug!("start chain {}", proxy);
-    let target = SockAddr::new_inet(InetAddr::new(IpAddr::from_std(&proxy.ip), proxy.port));
-    timed_connect(sock, &target, config.tcp_connect_timeout)?;
+    let reused = match proxy.hostname.is_some() || !proxy.alt_ips.is_empty() {
+        true => reuse::first_hop(proxy),
+        false => None,
+    };
+    let addrs = match &proxy.hostname {
+        _ if reused.is_some() => reused.into_iter().collect(),
+        Some(hostname) => {
+            let mut addrs = resolve_local(hostname, proxy.port, libc::AF_UNSPEC);
+            addrs.sort_by_key(|a| a.is_ipv6());
+            let ips: Vec<_> = addrs.iter().map(|a| a.ip()).collect();
+            match ips.split_first() {
+                Some((ip, alt_ips)) => happy_eyeballs_order(*ip, alt_ips, proxy.port),
+                None => {
+                    return Err(Error::Unresolved(hostname.clone()));
+                }
+            }
+        }
+        None if !proxy.alt_ips.is_empty() => {
+            happy_eyeballs_order(proxy.ip, &proxy.alt_ips, proxy.port)
+        }
+        // the socket has the family of the target, not of an IPv6 proxy
+        None if proxy.ip.is_ipv6() != is_ipv6_socket(sock) => {
+            vec![SocketAddr::new(proxy.ip, proxy.port)]
+        }
+        None => vec![],
+    };
+
+    if !addrs.is_empty() {
+        let winner = race_connect(&addrs, CONFIG.happy_eyeballs_delay, timeouts.connect)
+            .inspect_err(|_| reuse::forget_first_hop(proxy))?;
+        if reused.is_none() {
+            if let Ok(SockAddr::Inet(addr)) = getpeername(winner) {
+                reuse::set_first_hop(proxy, addr.to_std());
+            }
+        }
+        // the socket takes over the winning connection, whatever its family
+        let res = dup2(winner, sock);
+        close(winner).ok();
+        res?;
+    } else {
+        let target = SockAddr::new_inet(InetAddr::new(IpAddr::from_std(&proxy.ip), proxy.port));
+        if let Some(dscp) = CONFIG.dscp {
+            set_dscp(sock, proxy.ip.is_ipv6(), dscp)?;
+        }
+        timed_connect(sock, &target, timeouts.connect)?;
+    }
+
+    if let Some(keepalive) = &CONFIG.keepalive {
+        set_keepalive(sock, keepalive)?;
+    }
     Ok(())
 }
 
-fn chain_step(sock: RawFd, from: &ProxyConf, to: &ProxyConf) -> Result<(), Error> {
+/// Returns the credentials to authenticate to `proxy` with: those `rule` has
+/// for it, else the rotated ones, else its own. The rotated credentials are
+/// returned as well, to report their failure.
+fn credentials(
+    proxy: &ProxyConf,
+    rule: Option<&Rule>,
+    vars: &Vars,
+) -> (Option<Auth>, Option<rotation::Rotated>) {
+    let rule_auth = rule
+        .and_then(|r| r.credentials_for(proxy))
+        .map(|c| c.auth());
+    let rotated = match rule_auth {
+        Some(_) => None,
+        None => rotation::credentials(proxy),
+    };
+    let auth = rule_auth
+        .or_else(|| rotated.as_ref().map(|r| r.auth.clone()))
+        .or_else(|| CONFIG.auth_for(proxy))
+        .map(|a| vars.auth(a));
+    (auth, rotated)
+}
+
+/// Tunnels `sock` from one proxy, the `hop`th of the chain, to the next,
+/// returning the address bound by `from` when it reports one. The
+/// credentials `rule` has for `from` replace the rotated ones, which replace
+/// its own.
+fn chain_step(
+    sock: RawFd,
+    hop: usize,
+    from: &ProxyConf,
+    to: &ProxyConf,
+    rule: Option<&Rule>,
+    timeouts: &Timeouts,
+    vars: &Vars,
+) -> Result<Option<Bound>, Error> {
     debug!("chain {} <=> {}", from, to);
 
-    match from.proto {
-        ProxyType::Raw => Ok(()),
-        ProxyType::Http => Ok(proxy::Http::connect(sock, to, from.auth.as_ref())?),
-        ProxyType::Socks4 => Ok(proxy::Socks4::connect(sock, to, from.auth.as_ref())?),
-        ProxyType::Socks5 => Ok(proxy::Socks5::connect(sock, to, from.auth.as_ref())?),
+    let (auth, rotated) = credentials(from, rule, vars);
+    let auth = auth.as_ref();
+    let title = || format!("hop {} {} => {}", hop, from.endpoint(), to.endpoint());
+    transcript::record(title, || {
+        chaos::hop(timeouts.read)?;
+        match from.proto {
+            ProxyType::Raw => Ok(None),
+            ProxyType::Http => proxy::Http::connect(sock, from, to, auth, timeouts.read),
+            ProxyType::Socks4 => proxy::Socks4::connect(sock, from, to, auth, timeouts.read),
+            ProxyType::Socks5 => proxy::Socks5::connect(sock, from, to, auth, timeouts.read),
+        }
+    })
+    .map_err(|e| {
+        rotation::failed(from, rotated.as_ref(), &e);
+        e.at_hop(hop, from)
+    })
+}
+
+/// Runs the handshake `step` with `timeouts`, recording the time it took with
+/// `record`. A handshake timing out counts for its whole read timeout, the
+/// adapted timeouts growing back when a hop slows down.
+fn timed<T>(
+    timeouts: &Timeouts,
+    record: impl Fn(Duration),
+    step: impl FnOnce(&Timeouts) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let start = Instant::now();
+    let res = step(timeouts);
+    match &res {
+        Ok(_) => record(start.elapsed()),
+        Err(e) if matches!(e.root(), Error::Timeout) => {
+            record(Duration::from_millis(timeouts.read as u64))
+        }
+        Err(_) => (),
     }
+    res
 }
 
-// TODO handle ipv6
-pub fn connect_proxyc(sock: RawFd, ns: RawFd, target: &SockAddr) -> Result<(), Error> {
+/// Tunnels `sock` through every proxy in order, then to the target unless
+/// there is none. Returns the address bound by the last proxy to reach the
+/// target, if known.
+fn chain_strict(
+    sock: RawFd,
+    proxies: &[ProxyConf],
+    target: Option<&ProxyConf>,
+    rule: Option<&Rule>,
+    timeouts: &Timeouts,
+    vars: &Vars,
+) -> Result<Option<Bound>, Error> {
+    let first = proxies.first().expect("chain_strict: empty proxy list");
+    let start = Instant::now();
+
+    // start the chain by connecting to the first proxy, or through the quic
+    // proxy, hop 0, to the first proxy
+    match &CONFIG.quic {
+        Some(quic) => quic_start(sock, quic, first, rule, timeouts, vars)
+            .map_err(|e| e.at_hop(0, &quic.proxy)),
+        None => {
+            chain_start(sock, first, timeouts).map_err(|e| e.at(Stage::Connect).at_hop(1, first))
+        }
+    }
+    .inspect_err(|_| STATS.hop(first, false))?;
+    STATS.hop(first, true);
+    STATS.rtt(first, start.elapsed());
+
+    let bound = chain_hops(sock, proxies, target, rule, timeouts, vars)?;
+    STATS.chained(proxies);
+    Ok(bound)
+}
+
+/// Connects `sock` to `first` with a CONNECT request of the quic proxy.
+#[cfg(feature = "quic")]
+fn quic_start(
+    sock: RawFd,
+    quic: &QuicProxy,
+    first: &ProxyConf,
+    rule: Option<&Rule>,
+    timeouts: &Timeouts,
+    vars: &Vars,
+) -> Result<(), Error> {
+    let (auth, rotated) = credentials(&quic.proxy, rule, vars);
+    quic::connect(sock, quic, first, auth.as_ref(), timeouts)
+        .inspect_err(|e| rotation::failed(&quic.proxy, rotated.as_ref(), e))
+}
+
+#[cfg(not(feature = "quic"))]
+fn quic_start(
+    _sock: RawFd,
+    _quic: &QuicProxy,
+    _first: &ProxyConf,
+    _rule: Option<&Rule>,
+    _timeouts: &Timeouts,
+    _vars: &Vars,
+) -> Result<(), Error> {
+    Err(Error::Quic("libproxyc was built without the quic feature".into()).at(Stage::Quic))
+}
+
+/// Whether the requests to `proxies` can be sent at once: they are socks5
+/// proxies offering no authentication.
+fn pipelinable(proxies: &[ProxyConf], rule: Option<&Rule>) -> bool {
+    CONFIG.socks5_pipelining
+        && proxies.iter().all(|p| {
+            p.proto == ProxyType::Socks5
+                && p.auth_methods.iter().all(|m| *m == AuthMethod::None)
+                && rule.and_then(|r| r.credentials_for(p)).is_none()
+                && !rotation::applies(p)
+                && CONFIG.auth_for(p).is_none()
+        })
+}
+
+/// Tunnels `sock` like chain_hops(), sending every request at once and
+/// reading the replies as the tunnel extends.
+fn chain_pipelined(
+    sock: RawFd,
+    proxies: &[ProxyConf],
+    target: Option<&ProxyConf>,
+    timeouts: &Timeouts,
+) -> Result<Option<Bound>, Error> {
+    let hops: Vec<_> = proxies.iter().chain(target).collect();
+    let mut packet = vec![];
+    for w in hops.windows(2) {
+        packet.extend(proxy::Socks5::pipelined_request(w[1])?);
+    }
+    transcript::sent(&packet, &[]);
+    let written = write(sock, &packet)
+        .map_err(Error::from)
+        .and_then(|n| match n {
+            n if n == packet.len() => Ok(()),
+            _ => Err(Error::Generic(
+                "short write of the pipelined requests".into(),
+            )),
+        });
+    written.map_err(|e| e.at(Stage::Request).at_hop(1, hops[0]))?;
+
+    let mut bound = None;
+    for (i, w) in hops.windows(2).enumerate() {
+        debug!("chain {} <=> {} (pipelined)", w[0], w[1]);
+        // a reply follows the previous one by the time its proxy took
+        let reply = |t: &Timeouts| {
+            chaos::hop(t.read)
+                .and_then(|_| proxy::Socks5::pipelined_reply(sock, t.read))
+                .map_err(|e| e.at_hop(i + 1, w[0]))
+        };
+        bound = match i + 1 == proxies.len() {
+            true => timed(
+                &timeouts.for_exit(w[0], w[1]),
+                |d| STATS.exit(w[0], w[1], d),
+                reply,
+            ),
+            false => timed(&timeouts.for_hop(w[1]), |d| STATS.rtt(w[1], d), reply),
+        }
+        .inspect_err(|_| STATS.hop(w[1], false))?;
+        STATS.hop(w[1], true);
+    }
+    Ok(bound)
+}
+
+/// Tunnels `sock`, connected to the first proxy, through the others then to
+/// the target unless there is none.
+fn chain_hops(
+    sock: RawFd,
+    proxies: &[ProxyConf],
+    target: Option<&ProxyConf>,
+    rule: Option<&Rule>,
+    timeouts: &Timeouts,
+    vars: &Vars,
+) -> Result<Option<Bound>, Error> {
+    if pipelinable(proxies, rule) {
+        let title = || {
+            let hops: Vec<_> = proxies.iter().chain(target).map(|p| p.endpoint()).collect();
+            format!("pipelined {}", hops.join(" => "))
+        };
+        return transcript::record(title, || chain_pipelined(sock, proxies, target, timeouts));
+    }
+
+    // chain each proxy ends
+    for (i, w) in proxies.windows(2).enumerate() {
+        timed(
+            &timeouts.for_hop(&w[1]),
+            |d| STATS.rtt(&w[1], d),
+            |t| chain_step(sock, i + 1, &w[0], &w[1], rule, t, vars),
+        )
+        .inspect_err(|_| STATS.hop(&w[1], false))?;
+        STATS.hop(&w[1], true);
+    }
+    // chain the target
+    match target {
+        Some(target) => {
+            let last = &proxies[proxies.len() - 1];
+            timed(
+                &timeouts.for_exit(last, target),
+                |d| STATS.exit(last, target, d),
+                |t| chain_step(sock, proxies.len(), last, target, rule, t, vars),
+            )
+        }
+        None => Ok(None),
+    }
+}
+
+/// Tunnels `sock` through the proxies, then through the TLS terminator if one
+/// is configured. Returns the socket carrying the connection to the target,
+/// `sock` unless wrapped in TLS, and the address bound to reach the target.
+fn chain_connect(
+    sock: RawFd,
+    proxies: &[ProxyConf],
+    target: Option<&ProxyConf>,
+    rule: Option<&Rule>,
+    timeouts: &Timeouts,
+    vars: &Vars,
+) -> Result<(RawFd, Option<Bound>), Error> {
+    let terminator = match &CONFIG.tls_terminator {
+        Some(t) => t,
+        None => {
+            return chain_strict(sock, proxies, target, rule, timeouts, vars).map(|b| (sock, b))
+        }
+    };
+
+    chain_strict(sock, proxies, Some(&terminator.proxy), rule, timeouts, vars)?;
+    // the terminator follows the proxies
+    let hop = proxies.len() + 1;
+    let plain = tls::wrap(sock, terminator, timeouts)
+        .map_err(|e| e.at(Stage::Tls).at_hop(hop, &terminator.proxy))?;
+    let res = match target {
+        Some(target) => chain_step(plain, hop, &terminator.proxy, target, rule, timeouts, vars),
+        None => Ok(None),
+    };
+    match res {
+        Ok(bound) => Ok((plain, bound)),
+        Err(e) => {
+            close(plain).ok();
+            Err(e)
+        }
+    }
+}
+
+/// Hops of a chain which are never skipped nor drawn: the upstreams and
+/// transport managed by proxyc ahead of the default chain, and the last proxy
+/// when it was picked for the exit country.
+#[derive(Clone, Copy)]
+pub struct Pinned {
+    pub first: usize,
+    pub last: bool,
+}
+
+impl Pinned {
+    fn new(name: &str, rule: Option<&Rule>) -> Self {
+        Pinned {
+            first: match name {
+                DEFAULT_CHAIN => CONFIG.managed_hops(),
+                _ => 0,
+            },
+            last: CONFIG.exit_country_for(rule).is_some(),
+        }
+    }
+
+    /// Whether the hop `i` of a chain of `len` proxies is pinned.
+    fn contains(&self, i: usize, len: usize) -> bool {
+        i < self.first || (self.last && i + 1 == len)
+    }
+}
+
+/// Tunnels `sock` through the proxies in order, skipping the dead ones: those
+/// failing their health checks, and after a failure the proxy at fault, the
+/// chain starting over on a new socket, as long as min_chain_len proxies are
+/// left. The chain fails rather than skip a `pinned` hop.
+fn chain_dynamic(
+    sock: RawFd,
+    proxies: &[ProxyConf],
+    pinned: Pinned,
+    target: Option<&ProxyConf>,
+    rule: Option<&Rule>,
+    timeouts: &Timeouts,
+    vars: &Vars,
+) -> Result<(RawFd, Option<Bound>), Error> {
+    // the first proxy may replace the socket by one of its own family
+    let family = match is_ipv6_socket(sock) {
+        true => AddressFamily::Inet6,
+        false => AddressFamily::Inet,
+    };
+    let pinned = |i: usize| pinned.contains(i, proxies.len());
+    // indexes of the proxies kept, those failing their health checks being
+    // left out, unless too few would be left
+    let mut alive: Vec<usize> = (0..proxies.len())
+        .filter(|i| pinned(*i) || health::is_healthy(&proxies[*i]))
+        .collect();
+    if alive.len() < CONFIG.min_chain_len.min(proxies.len()) {
+        alive = (0..proxies.len()).collect();
+    }
+    loop {
+        let chain: Vec<ProxyConf> = alive.iter().map(|i| proxies[*i].clone()).collect();
+        let e = match chain_connect(sock, &chain, target, rule, timeouts, vars) {
+            Ok(res) => return Ok(res),
+            Err(e) => e,
+        };
+        let dead = match dead_proxy(&e, &chain) {
+            Some(i) if alive.len() > CONFIG.min_chain_len && !pinned(alive[i]) => i,
+            _ => return Err(e),
+        };
+        warn!("{}, skipping {}", e, chain[dead].endpoint());
+        alive.remove(dead);
+        renew_socket(sock, family)?;
+    }
+}
+
+/// Returns the index in `proxies` of the proxy a chain failed on: the one
+/// which could not be connected or failed its handshake, or the one the
+/// previous proxy could not reach. Failures to reach the target, or of the
+/// relays around the proxies, point at none.
+fn dead_proxy(e: &Error, proxies: &[ProxyConf]) -> Option<usize> {
+    let (hop, stage) = match e {
+        Error::Hop { hop, source, .. } => match &**source {
+            Error::Stage { stage, .. } => (*hop, Some(*stage)),
+            _ => (*hop, None),
+        },
+        _ => return None,
+    };
+    match stage {
+        Some(Stage::Request) => (hop < proxies.len()).then_some(hop),
+        Some(Stage::Tls | Stage::Quic) => None,
+        _ if hop == 0 || hop > proxies.len() => None,
+        _ => Some(hop - 1),
+    }
+}
+
+/// Tunnels a new socket up to the last proxy and hands it over to `request`,
+/// for requests other than CONNECT. The socket is left open on success.
+pub fn last_hop_request<T>(
+    request: impl FnOnce(RawFd, &ProxyConf, Option<&Auth>, usize) -> Result<T, Error>,
+) -> Result<(RawFd, T), Error> {
     let config = &*CONFIG;
+    let timeouts = Timeouts {
+        connect: config.tcp_connect_timeout,
+        read: config.tcp_read_timeout,
+    };
 
-    // Build a proxyconf from the target sockaddr
-    let (target_ip, target_port) = match target {
+    let proxies = config
+        .chain_for(None)
+        .expect("last_hop_request: empty proxy list");
+    let first = proxies.first().expect("last_hop_request: empty proxy list");
+    let last = proxies.last().expect("last_hop_request: empty proxy list");
+    let family = match first.ip {
+        std::net::IpAddr::V4(_) => AddressFamily::Inet,
+        std::net::IpAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let sock = socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)?;
+    let vars = Vars::new(None);
+
+    if let Err(e) = chain_strict(sock, proxies, None, None, &timeouts, &vars) {
+        close(sock).ok();
+        // failing to reach the last proxy says nothing of the request
+        return Err(Error::Connect(e.to_string()));
+    }
+    let rotated = rotation::credentials(last);
+    let auth = rotated
+        .as_ref()
+        .map(|r| r.auth.clone())
+        .or_else(|| config.auth_for(last))
+        .map(|a| vars.auth(a));
+    let res = request(sock, last, auth.as_ref(), timeouts.read).map_err(|e| {
+        rotation::failed(last, rotated.as_ref(), &e);
+        e.at_hop(proxies.len(), last)
+    });
+
+    match res {
+        Ok(v) => Ok((sock, v)),
+        Err(e) => {
+            close(sock).ok();
+            Err(e)
+        }
+    }
+}
+
+/// Tunnels a new socket through `proxies` to `target`, for the health checks
+/// of the last proxy. The statistics of the chains are left untouched.
+pub fn tunnel_check(proxies: &[ProxyConf], target: &ProxyConf) -> Result<RawFd, Error> {
+    let config = &*CONFIG;
+    let timeouts = Timeouts {
+        connect: config.tcp_connect_timeout,
+        read: config.tcp_read_timeout,
+    };
+
+    let first = proxies.first().expect("tunnel_check: empty proxy list");
+    let family = match first.ip {
+        std::net::IpAddr::V4(_) => AddressFamily::Inet,
+        std::net::IpAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let sock = socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)?;
+    let vars = Vars::new(Some(target));
+
+    let res = chain_start(sock, first, &timeouts)
+        .map_err(|e| e.at(Stage::Connect).at_hop(1, first))
+        .and_then(|_| {
+            for (i, w) in proxies.windows(2).enumerate() {
+                chain_step(sock, i + 1, &w[0], &w[1], None, &timeouts, &vars)?;
+            }
+            let last = &proxies[proxies.len() - 1];
+            chain_step(sock, proxies.len(), last, target, None, &timeouts, &vars)
+        });
+    match res {
+        Ok(_) => Ok(sock),
+        Err(e) => {
+            close(sock).ok();
+            Err(e)
+        }
+    }
+}
+
+/// Runs a one-shot request to the last proxy, used for the Tor extensions.
+fn tor_request<T>(
+    request: impl FnOnce(RawFd, &ProxyConf, Option<&Auth>, usize) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let (sock, res) = last_hop_request(request)?;
+    close(sock).ok();
+    Ok(res)
+}
+
+/// Whether connections to a destination bypass the proxies.
+pub fn is_ignored(ip: std::net::IpAddr, port: u16) -> bool {
+    filter::bypasses(ip, port)
+}
+
+/// Whether `name` is resolved by multicast DNS on the local network, which
+/// the proxies cannot reach.
+pub fn is_mdns(name: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    name.len() > 6 && name[name.len() - 6..].eq_ignore_ascii_case(".local")
+}
+
+/// Whether `name` is a Tor onion service, which only the last proxy can
+/// reach.
+pub fn is_onion(name: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    name.len() > 6 && name[name.len() - 6..].eq_ignore_ascii_case(".onion")
+}
+
+/// Whether the hostname `node` is resolved through the proxies. Onion names
+/// always are, .local names never.
+pub fn proxies_name(node: *const c_char) -> bool {
+    let config = &*CONFIG;
+    if config.dry_run || node.is_null() {
+        return config.proxy_dns && !config.dry_run;
+    }
+    let name = unsafe { CStr::from_ptr(node) }.to_string_lossy();
+    is_onion(&name) || (config.proxy_dns && !is_mdns(&name))
+}
+
+/// Resolves a hostname with the Tor RESOLVE extension of the last proxy.
+fn tor_resolve(hostname: &str) -> Result<std::net::IpAddr, Error> {
+    let res = tor_request(|sock, last, auth, timeout| {
+        proxy::Socks5::resolve(sock, last, hostname, auth, timeout)
+    });
+    debug!("tor resolved {} to {:?}", hostname, res);
+    res
+}
+
+/// Resolves an address to a hostname with the Tor RESOLVE_PTR extension of
+/// the last proxy.
+pub fn tor_resolve_ptr(ip: std::net::IpAddr) -> Result<String, Error> {
+    let res = tor_request(|sock, last, auth, timeout| {
+        proxy::Socks5::resolve_ptr(sock, last, ip, auth, timeout)
+    });
+    debug!("tor resolved {} to {:?}", ip, res);
+    res
+}
+
+/// Logs how a connection would be routed, used in dry-run mode.
+pub fn log_dry_run(ip: std::net::IpAddr, port: u16, ignored: bool) {
+    let config = &*CONFIG;
+
+    if ignored {
+        info!("dry-run: {}:{} is ignored, connecting directly", ip, port);
+        return;
+    }
+
+    let rule = match config.rules.iter().position(|r| r.matches(ip, port)) {
+        Some(i) => format!("rule #{} ({})", i + 1, config.rules[i]),
+        None => "no rule".to_string(),
+    };
+    let chain = config
+        .chain_for(config.rule_for(ip, port))
+        .unwrap_or_default()
+        .iter()
+        .map(ProxyConf::endpoint)
+        .collect::<Vec<_>>()
+        .join(" -> ");
+    let timeouts = Timeouts::for_target(config, ip, port);
+
+    info!(
+        "dry-run: {}:{} matches {}, {:?} chain {} (connect {}ms, read {}ms)",
+        ip, port, rule, config.chain_type, chain, timeouts.connect, timeouts.read
+    );
+}
+
+/// Returns the address and port of an inet target.
+fn inet_target(target: &SockAddr) -> Result<(std::net::IpAddr, u16), Error> {
+    match target {
         SockAddr::Inet(x) => {
             let tmp = x.to_std();
             Ok((tmp.ip(), tmp.port()))
         }
         _ => Err(Error::Generic("not an inet sockaddr".into())),
-    }?;
+    }
+}
+
+/// Whether the connection to `ip` on `port` is plain http forwarded in
+/// absolute-URI form, stopping at the last hop.
+pub fn forwards_http(ip: std::net::IpAddr, port: u16) -> bool {
+    let config = &*CONFIG;
+    let last_hop = match &config.tls_terminator {
+        Some(t) => Some(&t.proxy),
+        None => config
+            .chain_for(config.rule_for(ip, port))
+            .and_then(|p| p.last()),
+    };
+    absolute_uri::applies(last_hop, port)
+}
+
+/// Tunnels `ns` to `target` through the chain. Returns the socket carrying
+/// the tunnel, `ns` or one replacing it, and the address bound by the last
+/// proxy.
+// TODO handle ipv6
+pub fn chain_target(ns: RawFd, target: &SockAddr) -> Result<(RawFd, Option<Bound>), Error> {
+    let config = &*CONFIG;
+    let (target_ip, target_port) = inet_target(target)?;
 
+    // Build a proxyconf from the target sockaddr
     let target_conf = ProxyConf {
         proto: ProxyType::Raw,
         ip: target_ip,
         port: target_port,
         auth: None,
+        alt_ips: vec![],
+        hostname: None,
+        country: None,
+        auth_methods: vec![],
+        check: None,
     };
 
+    let route = route::lookup(target_ip, target_port);
+    if let Some(i) = route.rule_index() {
+        STATS.rule(i);
+    }
+    // the chain reaching the target is recorded by the last chain_strict()
+    STATS.take_chain();
+    let start = SystemTime::now();
+    let vars = Vars::new(Some(&target_conf));
+
+    // the chains of chain_order, or those selected for the thread, are tried
+    // in turn, on a new socket after a failure as the previous chain may have
+    // connected it. The chain of chain_order which last reached the target is
+    // tried first.
+    let selected = api::chains();
+    let names = match &selected {
+        Some(names) => names.iter().map(String::as_str).collect(),
+        None => config.chain_names(),
+    };
+    let reused = match (&selected, names.len()) {
+        (Some(_), _) | (None, 1) => None,
+        _ => reuse::chain(target_ip, target_port).filter(|i| *i < names.len()),
+    };
+    let mut order = reused
+        .into_iter()
+        .chain((0..names.len()).filter(|i| Some(*i) != reused));
+    let mut tried = order.next().unwrap_or(0);
+    let mut res = chain_named(ns, names[tried], &route, &target_conf, &vars);
+    for i in order {
+        match &res {
+            Ok(_) => break,
+            Err(e) => warn!("{}, trying chain {}", e, names[i]),
+        }
+        tried = i;
+        res = renew_socket(ns, target.family())
+            .and_then(|_| chain_named(ns, names[i], &route, &target_conf, &vars));
+    }
+    if selected.is_none() && names.len() > 1 {
+        match &res {
+            Ok(_) if reused != Some(tried) => reuse::set_chain(target_ip, target_port, tried),
+            Ok(_) => (),
+            Err(_) => reuse::forget_chain(target_ip, target_port),
+        }
+    }
+
+    let res = res.inspect_err(|e| {
+        STATS.connection(false);
+        audit::failed(target_ip, target_port, start, e);
+    })?;
+    STATS.connection(true);
+    Ok(res)
+}
+
+/// Tunnels `ns` to `target` through the chain `name` of chain_order, the
+/// errors being attributed to the chain when there is an order.
+fn chain_named(
+    ns: RawFd,
+    name: &str,
+    route: &Route,
+    target: &ProxyConf,
+    vars: &Vars,
+) -> Result<(RawFd, Option<Bound>), Error> {
+    let config = &*CONFIG;
+    let rule = route.rule(config);
+    let timeouts = route.timeouts;
+
     // based on the current type strict, dynamic, random etc..
     // - 1 select proxy from list
     // - 2 start chain
@@ -220,40 +1347,187 @@ pub fn connect_proxyc(sock: RawFd, ns: RawFd, target: &SockAddr) -> Result<(), E
     // - 4 tunnel previous to this one
     // - 5 repeat step 3
     // - 6 connect to target
-    let new_sock = match config.chain_type {
-        ChainType::Strict => {
-            // start the chain by connecting to the first proxy
-            chain_start(
-                ns,
-                config
-                    .proxies
-                    .first()
-                    .expect("chain_start: empty proxy list"),
-            )?;
-
-            // chain each proxy ends
-            for w in config.proxies.windows(2) {
-                chain_step(ns, &w[0], &w[1])?;
+    let proxies = match name {
+        DIRECT_CHAIN => {
+            return chain_direct(ns, target, &timeouts)
+                .map(|_| (ns, None))
+                .map_err(|e| Error::Chain {
+                    name: name.into(),
+                    source: Box::new(e),
+                })
+        }
+        // taken from the route, cached
+        DEFAULT_CHAIN => route.chain(config),
+        name => config.chain_named(name, rule),
+    };
+    let res = match proxies {
+        Some(proxies) => match config.chain_type {
+            ChainType::Strict => {
+                let target = (!route.forward_http).then_some(target);
+                chain_connect(ns, proxies, target, rule, &timeouts, vars)
+            }
+            ChainType::Dynamic => {
+                let target = (!route.forward_http).then_some(target);
+                let pinned = Pinned::new(name, rule);
+                chain_dynamic(ns, proxies, pinned, target, rule, &timeouts, vars)
             }
-            // chain the target
-            chain_step(
-                ns,
-                config.proxies.last().expect("chain_step: empty proxy list"),
-                &target_conf,
-            )?;
+            ChainType::Random => {
+                let target = (!route.forward_http).then_some(target);
+                let drawn: Vec<ProxyConf> = random::chain(proxies, Pinned::new(name, rule))
+                    .into_iter()
+                    .map(|i| proxies[i].clone())
+                    .collect();
+                chain_connect(ns, &drawn, target, rule, &timeouts, vars)
+            }
+        },
+        None => Err(Error::Generic(
+            "no proxy exits in the requested country".into(),
+        )),
+    };
+    match config.chain_order.is_empty() {
+        true => res,
+        false => res.map_err(|e| Error::Chain {
+            name: name.into(),
+            source: Box::new(e),
+        }),
+    }
+}
+
+/// Connects `ns` to `target` directly, as the direct chain of chain_order.
+/// Internal addresses are replaced by the address of their hostname,
+/// resolved locally.
+fn chain_direct(ns: RawFd, target: &ProxyConf, timeouts: &Timeouts) -> Result<(), Error> {
+    let addr = match find_ip_hostname(target.ip) {
+        // internal addresses are IPv4, so is the socket
+        Some(hostname) => resolve_local(&hostname, target.port, libc::AF_INET)
+            .into_iter()
+            .next()
+            .ok_or(Error::Unresolved(hostname))?,
+        None => SocketAddr::new(target.ip, target.port),
+    };
+    debug!("connecting directly to {}", addr);
+    let addr = SockAddr::new_inet(InetAddr::from_std(&addr));
+    timed_connect(ns, &addr, timeouts.connect)
+}
+
+/// Replaces `ns` by a new socket of `family`.
+fn renew_socket(ns: RawFd, family: AddressFamily) -> Result<(), Error> {
+    let fresh = socket(family, SockType::Stream, SockFlag::empty(), None)?;
+    let res = dup2(fresh, ns);
+    close(fresh)?;
+    res?;
+    Ok(())
+}
 
-            Ok(ns)
+/// Records `sock` as connected to `target` through the proxies at indexes
+/// `hops` in the statistics.
+pub fn register_proxied(sock: RawFd, target: &SockAddr, bound: Option<Bound>, hops: Vec<usize>) {
+    conn::register(
+        sock,
+        Direction::Outbound,
+        true,
+        target.to_str(),
+        bound,
+        hops,
+    );
+    idle::watch();
+    if let Ok((ip, port)) = inet_target(target) {
+        audit::established(ip, port);
+        let route = route::lookup(ip, port);
+        if route.forward_http {
+            absolute_uri::register(sock, ip, route.hostname);
         }
-        _ => Err(Error::Generic("chain type not handled".into())),
-    }?;
+    }
+}
+
+pub fn connect_proxyc(sock: RawFd, ns: RawFd, target: &SockAddr) -> Result<(), Error> {
+    let (stream, bound) = chain_target(ns, target)?;
 
-    dup2(new_sock, sock)?;
-    close(new_sock)?;
+    dup2(stream, sock)?;
+    close(stream)?;
+    if stream != ns {
+        close(ns)?;
+    }
 
+    register_proxied(sock, target, bound, STATS.take_chain());
     debug!("connected to {}", target.to_str());
     Ok(())
 }
 
+/// Connects `sock` directly to `target` after the chain failed. Internal
+/// addresses are replaced by the address of their hostname, resolved
+/// locally. Returns the result of connect().
+pub fn connect_direct(sock: RawFd, target: SocketAddr) -> c_int {
+    let c_connect = CONNECT.expect("Cannot load symbol 'connect'");
+
+    let target = match find_ip_hostname(target.ip()) {
+        // internal addresses are IPv4, so is the socket
+        Some(hostname) => match resolve_local(&hostname, target.port(), libc::AF_INET)
+            .into_iter()
+            .next()
+        {
+            Some(addr) => {
+                warn!(
+                    "chain failed, connecting directly to {} ({})",
+                    hostname, addr
+                );
+                addr
+            }
+            None => {
+                error!("chain failed, and {} cannot be resolved locally", hostname);
+                set_errno(Errno::ECONNREFUSED);
+                return -1;
+            }
+        },
+        None => {
+            warn!("chain failed, connecting directly to {}", target);
+            target
+        }
+    };
+
+    let target = SockAddr::new_inet(InetAddr::from_std(&target));
+    let (ptr, len) = target.as_ffi_pair();
+    unsafe { c_connect(sock, ptr, len) }
+}
+
+/// Resolves a hostname to addresses of `family` with the real resolver,
+/// bypassing the proxies.
+pub fn resolve_local(hostname: &str, port: u16, family: c_int) -> Vec<SocketAddr> {
+    let c_getaddrinfo = GETADDRINFO.expect("Cannot load symbol 'getaddrinfo'");
+    let c_freeaddrinfo = FREEADDRINFO.expect("Cannot load symbol 'freeaddrinfo'");
+    let node = match std::ffi::CString::new(hostname) {
+        Ok(n) => n,
+        Err(_) => return vec![],
+    };
+
+    let mut addrs = vec![];
+    unsafe {
+        let mut hints: addrinfo = mem::zeroed();
+        hints.ai_family = family;
+        hints.ai_socktype = libc::SOCK_STREAM;
+        let mut res: *mut addrinfo = std::ptr::null_mut();
+        if c_getaddrinfo(node.as_ptr(), std::ptr::null(), &hints, &mut res) != 0 {
+            return addrs;
+        }
+        let mut ai = res;
+        while !ai.is_null() {
+            if let Some(SockAddr::Inet(addr)) = from_libc_sockaddr((*ai).ai_addr) {
+                let mut addr = addr.to_std();
+                addr.set_port(port);
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+            ai = (*ai).ai_next;
+        }
+        c_freeaddrinfo(res);
+    }
+    addrs
+}
+
+/// Results of proxyc_getaddrinfo() not freed yet, the others being glibc's.
+static OWN_ADDRINFO: Lazy<Mutex<HashSet<usize>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
 #[repr(C)]
 struct AddrinfoData {
     ai_buf: addrinfo,
@@ -261,45 +1535,115 @@ struct AddrinfoData {
     addr_name: [c_char; 256],
 }
 
-fn contains_numeric_ip(node: *const c_char, sa_buf: *mut sockaddr_storage) -> bool {
-    unsafe {
-        (*(sa_buf as *mut _ as *mut sockaddr_in)).sin_family = libc::AF_INET as u16;
-        let ret = inet_aton(node, &(*(sa_buf as *mut _ as *mut sockaddr_in)).sin_addr);
-        if ret != 0 {
-            return true;
-        }
+/// Parses a numeric host, IPv6 addresses possibly carrying a scope as an
+/// interface name or index (`fe80::1%eth0`). Returns the address and its
+/// scope ID, None for hostnames and the EAI_* error for invalid IPv6
+/// addresses, hostnames having no colon.
+fn numeric_host(node: &CStr) -> Result<Option<(std::net::IpAddr, u32)>, c_int> {
+    let mut v4: libc::in_addr = unsafe { mem::zeroed() };
+    // inet_aton() accepts the shorthands getaddrinfo() does, such as 127.1
+    if unsafe { inet_aton(node.as_ptr(), &mut v4) } != 0 {
+        return Ok(Some((Ipv4Addr::from(u32::from_be(v4.s_addr)).into(), 0)));
+    }
 
-        (*(sa_buf as *mut _ as *mut sockaddr_in6)).sin6_family = libc::AF_INET6 as u16;
-        let ret = inet_pton(
-            libc::AF_INET6,
-            node,
-            &(*(sa_buf as *mut _ as *mut sockaddr_in6)).sin6_addr as *const _ as *const c_void,
-        );
-        if ret != 0 {
-            return true;
+    let name = match node.to_str() {
+        Ok(n) if n.contains(':') => n,
+        _ => return Ok(None),
+    };
+    let (addr, scope) = match name.split_once('%') {
+        Some((addr, scope)) => (addr, Some(scope)),
+        None => (name, None),
+    };
+    let ip: std::net::Ipv6Addr = addr.parse().map_err(|_| libc::EAI_NONAME)?;
+    let scope_id = match scope.map(|s| (s, s.parse::<u32>())) {
+        None => 0,
+        Some((_, Ok(index))) => index,
+        Some((name, Err(_))) => {
+            let name = std::ffi::CString::new(name).map_err(|_| libc::EAI_NONAME)?;
+            match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+                0 => return Err(libc::EAI_NONAME),
+                index => index,
+            }
         }
+    };
+    Ok(Some((ip.into(), scope_id)))
+}
 
-        false
+/// Writes the address of a getaddrinfo() result, returns its family and
+/// length.
+///
+/// # Safety
+///
+/// `sa_buf` must be valid for writes.
+unsafe fn write_ai_addr(
+    sa_buf: *mut sockaddr_storage,
+    ip: std::net::IpAddr,
+    port: u16,
+    scope_id: u32,
+) -> (c_int, socklen_t) {
+    match ip {
+        std::net::IpAddr::V4(ip) => {
+            let mut sin: sockaddr_in = mem::zeroed();
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = port.to_be();
+            sin.sin_addr.s_addr = u32::from(ip).to_be();
+            std::ptr::write(sa_buf as *mut sockaddr_in, sin);
+            (libc::AF_INET, mem::size_of::<sockaddr_in>() as socklen_t)
+        }
+        std::net::IpAddr::V6(ip) => {
+            // the flow info stays zeroed
+            let mut sin6: sockaddr_in6 = mem::zeroed();
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = port.to_be();
+            sin6.sin6_addr.s6_addr = ip.octets();
+            sin6.sin6_scope_id = scope_id;
+            std::ptr::write(sa_buf as *mut sockaddr_in6, sin6);
+            (libc::AF_INET6, mem::size_of::<sockaddr_in6>() as socklen_t)
+        }
     }
 }
 
+/// Hostname assigned an internal address.
+struct Mapping {
+    hostname: String,
+    /// Key of the mapping in the recency index.
+    tick: u64,
+    last_used: Instant,
+}
+
+/// Internal addresses assigned to hostnames.
+///
+/// Once dns_table_size addresses are assigned, those used the least recently
+/// are reclaimed for the following hostnames, unless used within
+/// dns_table_ttl or still the destination of an open connection.
 pub struct InternalIpAddr {
-    table: Arc<RwLock<HashMap<u32, String>>>,
+    table: HashMap<u32, Mapping>,
+    by_hostname: HashMap<String, u32>,
+    /// Indexes of the assigned addresses, the least recently used first.
+    recency: BTreeMap<u64, u32>,
+    tick: u64,
+    /// Highest index assigned so far.
     idx: u32,
+    /// Indexes reclaimed and not assigned again yet.
+    free: Vec<u32>,
 }
 
 impl InternalIpAddr {
     fn new() -> Self {
         Self {
-            table: Arc::new(RwLock::new(HashMap::new())),
+            table: HashMap::new(),
+            by_hostname: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
             idx: 0,
+            free: vec![],
         }
     }
 
     fn make_addr(idx: u32) -> Ipv4Addr {
         let config = &*CONFIG;
         let parts = [
-            config.dns_subnet,
+            config.dns_octet(),
             ((idx & 0xFF0000) >> 16).try_into().unwrap(),
             ((idx & 0xFF00) >> 8).try_into().unwrap(),
             (idx & 0xFF).try_into().unwrap(),
@@ -308,36 +1652,118 @@ impl InternalIpAddr {
         Ipv4Addr::from(parts)
     }
 
-    pub fn get_hostname(&self, idx: u32) -> Result<String, Error> {
-        let map = self.table.read().expect("Read lock poisoned");
-        let v = map.get(&(idx & 0x00FFFFFF)).ok_or(Error::MissingData)?;
-        Ok(v.to_owned())
+    /// Returns the assigned addresses along with their hostname.
+    pub fn entries(&self) -> Vec<(Ipv4Addr, String)> {
+        let mut entries: Vec<_> = self
+            .table
+            .iter()
+            .map(|(i, m)| (InternalIpAddr::make_addr(*i), m.hostname.clone()))
+            .collect();
+        entries.sort();
+        entries
     }
 
-    /// assigns a reserved IP address for the given hostname, if not already
-    /// saved.
-    pub fn assign_addr(&mut self, hn: &str) -> Result<Ipv4Addr, Error> {
-        self.idx += 1;
+    /// Marks the mapping at `idx` as the most recently used.
+    fn touch(&mut self, idx: u32) {
+        if let Some(m) = self.table.get_mut(&idx) {
+            self.recency.remove(&m.tick);
+            self.tick += 1;
+            m.tick = self.tick;
+            m.last_used = Instant::now();
+            self.recency.insert(self.tick, idx);
+        }
+    }
 
-        if self.idx > 0xFFFFFF {
-            return Err(Error::Generic("exhausted internal ip addresses".into()));
+    pub fn get_hostname(&mut self, idx: u32) -> Result<String, Error> {
+        let idx = idx & 0x00FFFFFF;
+        let hostname = self
+            .table
+            .get(&idx)
+            .ok_or(Error::MissingData)?
+            .hostname
+            .clone();
+        self.touch(idx);
+        Ok(hostname)
+    }
+
+    /// Frees the least recently used addresses which are neither used within
+    /// dns_table_ttl nor connected to, a batch at a time so that the open
+    /// connections are not listed for each new hostname.
+    fn reclaim(&mut self) -> Result<(), Error> {
+        let ttl = Duration::from_secs(CONFIG.dns_table_ttl as u64);
+        let connected = conn::destinations();
+        let batch = (self.table.len() / 64).max(1);
+
+        let mut reclaimed = vec![];
+        for (&tick, &idx) in &self.recency {
+            if reclaimed.len() == batch || self.table[&idx].last_used.elapsed() < ttl {
+                break;
+            }
+            if !connected.contains(&InternalIpAddr::make_addr(idx).into()) {
+                reclaimed.push((tick, idx));
+            }
+        }
+        if reclaimed.is_empty() {
+            return Err(Error::Generic(format!(
+                "all {} internal ip addresses in use, raise dns_table_size",
+                self.table.len()
+            )));
         }
 
-        // if ip addresses have already been assigned,
-        // check if the provided hostname is not already stored
-        if self.idx > 1 {
-            let map = self.table.read().expect("RwLock read poisoned");
-            for i in 1..self.idx {
-                if map.get(&i) == Some(&hn.to_string()) {
-                    return Ok(InternalIpAddr::make_addr(i));
-                }
+        for (tick, idx) in reclaimed {
+            self.recency.remove(&tick);
+            if let Some(m) = self.table.remove(&idx) {
+                debug!(
+                    "reclaimed {} from {}",
+                    InternalIpAddr::make_addr(idx),
+                    m.hostname
+                );
+                self.by_hostname.remove(&m.hostname);
             }
-            drop(map);
+            self.free.push(idx);
+            STATS.dns_reclaimed();
+        }
+        Ok(())
+    }
+
+    /// Returns the index of an unassigned address, reclaiming some if the
+    /// table is full.
+    fn next_index(&mut self) -> Result<u32, Error> {
+        if let Some(idx) = self.free.pop() {
+            return Ok(idx);
+        }
+        if self.table.len() < CONFIG.dns_table_size && self.idx < 0xFFFFFF {
+            self.idx += 1;
+            return Ok(self.idx);
+        }
+        self.reclaim()?;
+        Ok(self.free.pop().expect("addresses reclaimed"))
+    }
+
+    /// assigns a reserved IP address for the given hostname, if not already
+    /// saved.
+    pub fn assign_addr(&mut self, hn: &str) -> Result<Ipv4Addr, Error> {
+        if let Some(&idx) = self.by_hostname.get(hn) {
+            self.touch(idx);
+            return Ok(InternalIpAddr::make_addr(idx));
         }
 
-        let addr = InternalIpAddr::make_addr(self.idx);
-        let mut map = self.table.write().expect("RwLock write poisoned");
-        map.insert(self.idx, hn.to_string());
+        let idx = self.next_index()?;
+        let addr = InternalIpAddr::make_addr(idx);
+        self.table.insert(
+            idx,
+            Mapping {
+                hostname: hn.to_string(),
+                tick: 0,
+                last_used: Instant::now(),
+            },
+        );
+        self.by_hostname.insert(hn.to_string(), idx);
+        self.touch(idx);
+        STATS.dns_entries(self.table.len());
+        // a connection may have been attempted before the assignment, or the
+        // address reclaimed from another hostname
+        route::forget(addr.into());
 
         Ok(addr)
     }
@@ -358,7 +1784,7 @@ pub fn proxyc_gethostbyname(
     name: *const c_char,
     gh: *mut GetHostByNameData,
 ) -> Result<*mut hostent, Error> {
-    let mut ptr = unsafe { &mut *gh };
+    let ptr = unsafe { &mut *gh };
     ptr.raddr_p[0] = &ptr.raddr as *const _ as *const c_char;
     ptr.raddr_p[1] = std::ptr::null();
 
@@ -374,123 +1800,301 @@ pub fn proxyc_gethostbyname(
     // TODO: check /etc/hosts
     // TODO: assign ip for name
 
-    let raddr: u32 = {
-        let ns = unsafe { CStr::from_ptr(name) };
-        let ns = ns.to_str().unwrap();
-        let internal_addr = &mut *INTERNALADDR.lock().expect("mutex poisoned");
-        let addr = internal_addr.assign_addr(ns)?;
-        addr.into()
-    };
+    let ns = unsafe { CStr::from_ptr(name) };
+    let raddr: u32 = resolve_proxied(ns.to_str().unwrap())?.into();
 
     ptr.raddr = raddr.to_be();
 
     Ok(&mut ptr.hs)
 }
 
-const LOCALHOST_B: [u8; 4] = [127, 0, 0, 1];
+/// Resolves `name` through the proxies, to an internal address or with the
+/// Tor RESOLVE extension depending on proxy_dns_mode.
+pub fn resolve_proxied(name: &str) -> Result<Ipv4Addr, Error> {
+    // Tor cannot resolve onion names to addresses
+    let mode = match is_onion(name) {
+        true => ProxyDnsMode::Fake,
+        false => CONFIG.proxy_dns_mode,
+    };
+    match mode {
+        ProxyDnsMode::Fake => {
+            let internal_addr = &mut *INTERNALADDR.lock().expect("mutex poisoned");
+            internal_addr.assign_addr(name)
+        }
+        ProxyDnsMode::Tor => match tor_resolve(name)? {
+            std::net::IpAddr::V4(addr) => Ok(addr),
+            std::net::IpAddr::V6(_) => Err(Error::Generic(format!(
+                "{} resolved to an ipv6 address",
+                name
+            ))),
+        },
+    }
+}
+
+/// Whether reverse lookups are resolved through the chain.
+pub fn proxies_reverse_dns() -> bool {
+    let config = &*CONFIG;
+    config.proxy_dns && config.proxy_dns_mode == ProxyDnsMode::Tor && !config.dry_run
+}
+
+/// Fills `gh` with the result of a reverse lookup.
+pub fn proxyc_gethostbyaddr(
+    addr: Ipv4Addr,
+    hostname: &str,
+    gh: *mut GetHostByNameData,
+) -> *mut hostent {
+    let ptr = unsafe { &mut *gh };
+    ptr.raddr_p[0] = &ptr.raddr as *const _ as *const c_char;
+    ptr.raddr_p[1] = std::ptr::null();
+
+    ptr.hs.h_addr_list = ptr.raddr_p.as_mut_ptr() as *mut *mut i8;
+    ptr.hs.h_aliases = &mut ptr.raddr_p[1] as *mut _ as *mut *mut i8;
+
+    ptr.raddr = u32::from(addr).to_be();
+    ptr.hs.h_addrtype = libc::AF_INET;
+    ptr.hs.h_length = std::mem::size_of::<libc::in_addr_t>() as i32;
+
+    let len = hostname.len().min(ptr.addr_name.len() - 1);
+    for (dst, src) in ptr.addr_name.iter_mut().zip(&hostname.as_bytes()[..len]) {
+        *dst = *src as c_char;
+    }
+    ptr.addr_name[len] = 0;
+    ptr.hs.h_name = ptr.addr_name.as_mut_ptr();
+
+    &mut ptr.hs
+}
+
+/// Fills a caller provided hostent with the result of a reverse lookup, its
+/// fields pointing inside `buf`. Returns false if `buf` is too small.
+pub fn proxyc_gethostbyaddr_r(
+    addr: Ipv4Addr,
+    hostname: &str,
+    ret: *mut hostent,
+    buf: *mut c_char,
+    buflen: size_t,
+) -> bool {
+    let ptr_size = mem::size_of::<*mut c_char>();
+    // pointers first for alignment: h_addr_list[2], h_aliases[1], then the
+    // address and the hostname.
+    let needed = 3 * ptr_size + 4 + hostname.len() + 1;
+    if buflen < needed + ptr_size {
+        return false;
+    }
+
+    unsafe {
+        let start = buf.add(buf.align_offset(ptr_size));
+        let list = start as *mut *mut c_char;
+        let raddr = start.add(3 * ptr_size);
+        let name = raddr.add(4);
+
+        std::ptr::copy_nonoverlapping(addr.octets().as_ptr() as *const c_char, raddr, 4);
+        std::ptr::copy_nonoverlapping(hostname.as_ptr() as *const c_char, name, hostname.len());
+        *name.add(hostname.len()) = 0;
+
+        *list = raddr;
+        *list.add(1) = std::ptr::null_mut();
+        *list.add(2) = std::ptr::null_mut();
+
+        (*ret).h_name = name;
+        (*ret).h_aliases = list.add(2);
+        (*ret).h_addrtype = libc::AF_INET;
+        (*ret).h_length = 4;
+        (*ret).h_addr_list = list;
+    }
+    true
+}
+
+/// Longest service name looked up, as found in /etc/services.
+const MAX_SERVICE_LEN: usize = 64;
+/// Number of service lookups kept before starting over.
+const SERVICES_CACHE_LEN: usize = 64;
+
+/// Service name and protocol of a lookup.
+type ServiceKey = (String, Option<&'static CStr>);
+
+/// Ports of the service names already looked up, unknown services being
+/// cached as well.
+static SERVICES: Lazy<Mutex<HashMap<ServiceKey, Option<u16>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Looks up a service in the services database, returns its port in host
+/// byte order.
+#[cfg(not(target_os = "android"))]
+unsafe fn lookup_service(name: &CStr, proto: Option<&CStr>) -> Option<u16> {
+    let mut se_buf: MaybeUninit<servent> = MaybeUninit::uninit();
+    let mut se: *mut servent = std::ptr::null_mut();
+    let mut buf = [0 as c_char; 1024];
+    let ret = getservbyname_r(
+        name.as_ptr(),
+        proto.map_or(std::ptr::null(), CStr::as_ptr),
+        se_buf.as_mut_ptr(),
+        buf.as_mut_ptr(),
+        buf.len(),
+        &mut se,
+    );
+    // se points to se_buf once filled, which is never read otherwise
+    match ret == 0 && !se.is_null() {
+        true => Some(u16::from_be((*se).s_port as u16)),
+        false => None,
+    }
+}
+
+/// Looks up a service in the services database, returns its port in host
+/// byte order.
+#[cfg(target_os = "android")]
+unsafe fn lookup_service(name: &CStr, proto: Option<&CStr>) -> Option<u16> {
+    let se = getservbyname(name.as_ptr(), proto.map_or(std::ptr::null(), CStr::as_ptr));
+    match se.is_null() {
+        false => Some(u16::from_be((*se).s_port as u16)),
+        true => None,
+    }
+}
+
+/// Returns the port of a getaddrinfo() service, in host byte order, or the
+/// EAI_* error to return.
+fn service_port(service: *const c_char, hints: *const addrinfo) -> Result<u16, c_int> {
+    if service.is_null() {
+        return Ok(0);
+    }
+    let service = unsafe { CStr::from_ptr(service) };
+    let name = service.to_str().map_err(|_| libc::EAI_SERVICE)?;
+    if let Ok(port) = name.parse::<u16>() {
+        return Ok(port);
+    }
+
+    let (flags, socktype) = match hints.is_null() {
+        true => (0, 0),
+        false => unsafe { ((*hints).ai_flags, (*hints).ai_socktype) },
+    };
+    if flags & libc::AI_NUMERICSERV != 0 {
+        return Err(libc::EAI_NONAME);
+    }
+    if name.len() > MAX_SERVICE_LEN {
+        return Err(libc::EAI_SERVICE);
+    }
+    let proto = match socktype {
+        libc::SOCK_STREAM => Some(cstr!("tcp")),
+        libc::SOCK_DGRAM => Some(cstr!("udp")),
+        _ => None,
+    };
+
+    let key = (name.to_string(), proto);
+    if let Some(port) = SERVICES.lock().expect("mutex poisoned").get(&key) {
+        return port.ok_or(libc::EAI_SERVICE);
+    }
+
+    let port = unsafe { lookup_service(service, proto) };
+    trace!("service {} ({:?}) is port {:?}", name, proto, port);
+
+    let mut services = SERVICES.lock().expect("mutex poisoned");
+    if services.len() >= SERVICES_CACHE_LEN {
+        services.clear();
+    }
+    services.insert(key, port);
+    port.ok_or(libc::EAI_SERVICE)
+}
+/// Resolves a hostname through the proxies for getaddrinfo(), returning the
+/// EAI_* error on failure.
+fn proxied_ipv4(node: *const c_char) -> Result<Ipv4Addr, c_int> {
+    let mut gh: MaybeUninit<GetHostByNameData> = MaybeUninit::uninit();
+    let hs = match proxyc_gethostbyname(node, gh.as_mut_ptr()) {
+        Ok(hs) => hs,
+        Err(e) => {
+            error!("{}", e);
+            return Err(netdb::gai_failed(&e));
+        }
+    };
+    if hs.is_null() {
+        return Err(libc::EAI_NONAME);
+    }
+    let mut octets = [0; 4];
+    unsafe {
+        std::ptr::copy_nonoverlapping(*(*hs).h_addr_list as *const u8, octets.as_mut_ptr(), 4)
+    };
+    Ok(Ipv4Addr::from(octets))
+}
+
+#[allow(clippy::unnecessary_cast)]
 pub fn proxyc_getaddrinfo(
     node: *const c_char,
     service: *const c_char,
     hints: *const addrinfo,
     res: *mut *mut addrinfo,
 ) -> c_int {
-    let mut af = libc::AF_INET;
-    let ai_data: *mut AddrinfoData =
-        unsafe { mem::transmute(libc::calloc(1, mem::size_of::<AddrinfoData>() as size_t)) };
-    if ai_data.is_null() {
-        return libc::EAI_MEMORY;
-    }
-
-    let ai_buf = unsafe { &mut (*ai_data).ai_buf as *mut addrinfo };
-    let sa_buf = unsafe { &mut (*ai_data).sa_buf as *mut sockaddr_storage };
+    let port = match service_port(service, hints) {
+        Ok(p) => p,
+        Err(e) => return e,
+    };
 
-    unsafe {
-        if !node.is_null() && !contains_numeric_ip(node, sa_buf) {
-            // fail in case inet_aton / inet_pton did not work and AI_NUMERICHOST
-            // has been set by the caller.
-            if !hints.is_null() && (*hints).ai_flags & libc::AI_NUMERICHOST != 0 {
-                libc::free(ai_data as *mut _);
-                return libc::EAI_NONAME;
-            }
+    let (family, flags) = match hints.is_null() {
+        true => (libc::AF_UNSPEC, 0),
+        false => unsafe { ((*hints).ai_family, (*hints).ai_flags) },
+    };
+    if ![libc::AF_UNSPEC, libc::AF_INET, libc::AF_INET6].contains(&family) {
+        return libc::EAI_FAMILY;
+    }
 
-            let mut gh: MaybeUninit<GetHostByNameData> = MaybeUninit::uninit();
-            let hs = proxyc_gethostbyname(node, gh.as_mut_ptr()).unwrap();
-            if !hs.is_null() {
-                let p = *hs;
-                libc::memcpy(
-                    &mut (*(sa_buf as *mut _ as *mut sockaddr_in)).sin_addr as *mut _
-                        as *mut c_void,
-                    *p.h_addr_list as *const c_void,
-                    4,
-                );
-            } else {
-                libc::free(ai_data as *mut _);
-                return libc::EAI_NONAME;
-            }
-        } else if !node.is_null() {
-            af = (*(sa_buf as *mut _ as *mut sockaddr_in)).sin_family as i32;
-        } else if node.is_null() && (*hints).ai_flags & libc::AI_PASSIVE != 0 {
-            af = libc::AF_INET;
-            libc::memcpy(
-                &mut (*(sa_buf as *mut _ as *mut sockaddr_in)).sin_addr as *mut _ as *mut c_void,
-                LOCALHOST_B.as_ptr() as *const c_void,
-                4,
-            );
-        }
-    }
-
-    let port: u16 = unsafe {
-        let mut se: *mut servent = std::ptr::null_mut();
-        let mut buf: [u8; 1024] = [0; 1024];
-        let mut se_buf: MaybeUninit<servent> = MaybeUninit::uninit();
-
-        if !service.is_null() {
-            getservbyname_r(
-                service,
-                std::ptr::null(),
-                se_buf.as_mut_ptr(),
-                buf.as_mut_ptr() as *mut c_char,
-                std::mem::size_of_val(&buf),
-                &mut se,
-            );
-        }
-
-        se_buf.assume_init();
-        match se.is_null() {
-            false => (*se).s_port as u16,
-            true => {
-                if !service.is_null() {
-                    (libc::atoi(service) as u16).to_be()
-                } else {
-                    0
+    let (ip, scope_id) = if node.is_null() {
+        // no node stands for the wildcard address when binding, the
+        // loopback otherwise.
+        let passive = flags & libc::AI_PASSIVE != 0;
+        let ip: std::net::IpAddr = match (family == libc::AF_INET6, passive) {
+            (true, true) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+            (true, false) => std::net::Ipv6Addr::LOCALHOST.into(),
+            (false, true) => Ipv4Addr::UNSPECIFIED.into(),
+            (false, false) => Ipv4Addr::LOCALHOST.into(),
+        };
+        (ip, 0)
+    } else {
+        match numeric_host(unsafe { CStr::from_ptr(node) }) {
+            Ok(Some(host)) => host,
+            Err(e) => return e,
+            // fail in case the node is not numeric and AI_NUMERICHOST has
+            // been set by the caller.
+            Ok(None) if flags & libc::AI_NUMERICHOST != 0 => return libc::EAI_NONAME,
+            Ok(None) => {
+                let name = unsafe { CStr::from_ptr(node) }.to_string_lossy();
+                let local = match CONFIG.nsswitch_sources {
+                    true => nss::lookup(&name, family),
+                    false => None,
+                };
+                match local
+                    .map(Ok)
+                    .unwrap_or_else(|| proxied_ipv4(node).map(Into::into))
+                {
+                    Ok(ip) => (ip, 0),
+                    Err(e) => return e,
                 }
             }
         }
     };
 
-    unsafe {
-        match af {
-            libc::AF_INET => {
-                (*(sa_buf as *mut _ as *mut sockaddr_in)).sin_port = port;
-            }
-            _ => {
-                (*(sa_buf as *mut _ as *mut sockaddr_in6)).sin6_port = port;
-            }
-        };
+    // IPv4 addresses are only returned to IPv6 callers when mapped
+    let ip = match (family, ip) {
+        (libc::AF_INET6, std::net::IpAddr::V4(ip)) if flags & libc::AI_V4MAPPED != 0 => {
+            ip.to_ipv6_mapped().into()
+        }
+        (libc::AF_INET6, std::net::IpAddr::V4(_)) | (libc::AF_INET, std::net::IpAddr::V6(_)) => {
+            return libc::EAI_NONAME;
+        }
+        (_, ip) => ip,
+    };
+
+    let ai_data: *mut AddrinfoData =
+        unsafe { mem::transmute(libc::calloc(1, mem::size_of::<AddrinfoData>() as size_t)) };
+    if ai_data.is_null() {
+        return libc::EAI_MEMORY;
     }
 
     unsafe {
-        (*ai_buf).ai_addr = sa_buf as *mut sockaddr;
+        let ai_buf = &mut (*ai_data).ai_buf as *mut addrinfo;
+        let sa_buf = &mut (*ai_data).sa_buf as *mut sockaddr_storage;
+        let (af, addrlen) = write_ai_addr(sa_buf, ip, port, scope_id);
 
-        (*ai_buf).ai_next = std::ptr::null_mut() as *mut addrinfo;
-        (*sa_buf).ss_family = af as u16;
+        (*ai_buf).ai_addr = sa_buf as *mut sockaddr;
+        (*ai_buf).ai_addrlen = addrlen;
         (*ai_buf).ai_family = af;
-        match af {
-            libc::AF_INET => {
-                (*ai_buf).ai_addrlen = std::mem::size_of::<sockaddr_in>() as u32;
-            }
-            _ => (*ai_buf).ai_addrlen = std::mem::size_of::<sockaddr_in6>() as u32,
-        };
+        (*ai_buf).ai_next = std::ptr::null_mut() as *mut addrinfo;
 
         if !hints.is_null() {
             (*ai_buf).ai_socktype = (*hints).ai_socktype;
@@ -499,11 +2103,83 @@ pub fn proxyc_getaddrinfo(
         } else {
             (*ai_buf).ai_flags = libc::AI_V4MAPPED | libc::AI_ADDRCONFIG;
         }
-    }
 
-    unsafe {
         *res = ai_buf;
     }
+    OWN_ADDRINFO
+        .lock()
+        .expect("mutex poisoned")
+        .insert(ai_data as usize);
 
     0
 }
+
+/// Frees `res` if it was returned by proxyc_getaddrinfo(), returns whether
+/// it was.
+pub fn free_own_addrinfo(res: *mut addrinfo) -> bool {
+    let own = OWN_ADDRINFO
+        .lock()
+        .expect("mutex poisoned")
+        .remove(&(res as usize));
+    if own {
+        unsafe { libc::free(res as *mut c_void) };
+    }
+    own
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::Stats;
+    use std::str::FromStr;
+
+    #[test]
+    fn adapted_timeouts_follow_the_proxy_and_target() {
+        let proxies: Vec<_> = ["socks5://10.0.0.1:1080", "socks5://10.0.0.2:1080"]
+            .iter()
+            .map(|p| ProxyConf::from_str(p).unwrap())
+            .collect();
+        let (first, second) = (&proxies[0], &proxies[1]);
+        let near = ProxyConf::from_str("raw://10.1.0.1:80").unwrap();
+        let far = ProxyConf::from_str("raw://10.1.0.2:80").unwrap();
+        let stats = Stats::new(proxies.iter(), 0);
+        // the second proxy drawn first in a random chain
+        stats.rtt(second, Duration::from_millis(500));
+        stats.exit(second, &near, Duration::from_millis(300));
+
+        let adaptive = AdaptiveTimeout {
+            factor: 4,
+            min: 100,
+            max: 60000,
+        };
+        let timeouts = Timeouts {
+            connect: 1000,
+            read: 15000,
+        };
+        let read = |avg_ms| timeouts.adapted(Some(&adaptive), avg_ms).read;
+        assert_eq!(read(stats.rtt_ms(second)), 2000);
+        assert_eq!(read(stats.rtt_ms(first)), 15000);
+        assert_eq!(read(stats.exit_ms(second, &near)), 1200);
+        assert_eq!(read(stats.exit_ms(second, &far)), 15000);
+        assert_eq!(read(stats.exit_ms(first, &near)), 15000);
+        assert_eq!(
+            timeouts.adapted(None, stats.rtt_ms(second)).read,
+            timeouts.read
+        );
+    }
+
+    #[test]
+    fn pinned_hops_are_the_managed_ones_and_the_exit_proxy() {
+        let pinned = Pinned {
+            first: 2,
+            last: true,
+        };
+        let hops: Vec<_> = (0..5).filter(|i| pinned.contains(*i, 5)).collect();
+        assert_eq!(hops, [0, 1, 4]);
+        let pinned = Pinned {
+            first: 0,
+            last: false,
+        };
+        assert!((0..5).all(|i| !pinned.contains(i, 5)));
+    }
+}
@@ -0,0 +1,379 @@
+/// DNS responder answering the resolver of the C library
+///
+/// Programs querying nameservers with res_query() and the like, rather than
+/// getaddrinfo(), get the internal addresses all the same: with dns_server, a
+/// thread answers DNS queries on a loopback UDP port, the resolver state of
+/// the C library being pointed at it. A records are resolved like
+/// gethostbyname() does, PTR records of internal addresses answered with
+/// their hostname, and other records have no answer. Reverse lookups of
+/// other addresses fail rather than leak.
+///
+/// The responder thread does not survive fork(): forked children keep
+/// querying the parent's, whose internal addresses they do not know.
+use crate::core::{self, CONFIG};
+use crate::error::Error;
+use nix::errno::Errno;
+use nix::libc::{self, c_int, c_ulong, c_void, sockaddr, sockaddr_in, sockaddr_storage, socklen_t};
+use nix::sys::socket::{
+    bind, getsockname, socket, AddressFamily, InetAddr, SockAddr, SockFlag, SockType,
+};
+use once_cell::sync::OnceCell;
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::unix::io::RawFd;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const CLASS_IN: u16 = 1;
+
+const RCODE_FORMERR: u16 = 1;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+
+/// Largest query read, EDNS ones included.
+const MAX_QUERY_LEN: usize = 1500;
+
+/// Address of the responder, None if it failed to start.
+static ADDR: OnceCell<Option<SocketAddrV4>> = OnceCell::new();
+
+/// Resolver state of glibc, up to its nameservers.
+#[repr(C)]
+pub struct ResState {
+    retrans: c_int,
+    retry: c_int,
+    options: c_ulong,
+    nscount: c_int,
+    nsaddr_list: [sockaddr_in; 3],
+}
+
+/// Returns the address of the responder, started on first use.
+pub fn addr() -> Option<SocketAddrV4> {
+    if !CONFIG.dns_server {
+        return None;
+    }
+    *ADDR.get_or_init(|| match start() {
+        Ok(addr) => {
+            debug!("dns server listening on {}", addr);
+            Some(addr)
+        }
+        Err(e) => {
+            error!("failed to start the dns server: {}", e);
+            None
+        }
+    })
+}
+
+/// Whether `dest` is the responder, which datagrams reach directly.
+pub fn is_server(dest: &SocketAddr) -> bool {
+    matches!(ADDR.get(), Some(Some(addr)) if SocketAddr::V4(*addr) == *dest)
+}
+
+/// Makes the resolver `state` query the responder only.
+pub fn point(state: *mut ResState) {
+    let (addr, state) = match (addr(), unsafe { state.as_mut() }) {
+        (Some(addr), Some(state)) => (addr, state),
+        _ => return,
+    };
+    state.nsaddr_list[0] = sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    state.nscount = 1;
+}
+
+fn start() -> Result<SocketAddrV4, Error> {
+    let sock = socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    let local = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    bind(sock, &SockAddr::new_inet(InetAddr::from_std(&local)))?;
+    let addr = match getsockname(sock)? {
+        SockAddr::Inet(inet) => match inet.to_std() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => return Err(Error::Socket),
+        },
+        _ => return Err(Error::Socket),
+    };
+    std::thread::Builder::new()
+        .name("proxyc-dns".into())
+        .spawn(move || serve(sock))?;
+    Ok(addr)
+}
+
+/// Answers the queries received on `sock`. The datagrams are exchanged with
+/// the real functions, the relaying of UDP not applying to them.
+fn serve(sock: RawFd) {
+    let c_recvfrom = core::RECVFROM.expect("Cannot load symbol 'recvfrom'");
+    let c_sendto = core::SENDTO.expect("Cannot load symbol 'sendto'");
+    let mut buf = [0; MAX_QUERY_LEN];
+    loop {
+        let mut from: sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<sockaddr_storage>() as socklen_t;
+        let n = unsafe {
+            c_recvfrom(
+                sock,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len(),
+                0,
+                &mut from as *mut _ as *mut sockaddr,
+                &mut len,
+            )
+        };
+        if n < 0 {
+            match core::errno() {
+                Errno::EINTR => continue,
+                e => {
+                    error!("dns server stopped: {}", e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(reply) = answer(&buf[..n as usize]) {
+            unsafe {
+                c_sendto(
+                    sock,
+                    reply.as_ptr() as *const c_void,
+                    reply.len(),
+                    0,
+                    &from as *const _ as *const sockaddr,
+                    len,
+                )
+            };
+        }
+    }
+}
+
+/// Reads the name at `pos` of a question, uncompressed. Returns it without
+/// its trailing dot along with the position following it.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    loop {
+        let len = *msg.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // compression pointers are not expected in questions
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(msg.get(pos..pos + len)?).into_owned());
+        pos += len;
+    }
+    Some((labels.join("."), pos))
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+/// Returns the addresses of `name`, resolved through the proxies unless it
+/// is one of the names resolved locally.
+fn resolve_a(name: &str) -> Result<Vec<Ipv4Addr>, Error> {
+    let cname = CString::new(name).map_err(|_| Error::Unresolved(name.into()))?;
+    if core::proxies_name(cname.as_ptr()) {
+        return Ok(vec![core::resolve_proxied(name)?]);
+    }
+    Ok(core::resolve_local(name, 0, libc::AF_INET)
+        .into_iter()
+        .filter_map(|a| match a.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+        .collect())
+}
+
+/// Returns the hostname of the internal address named by `name`, in the
+/// in-addr.arpa domain.
+fn resolve_ptr(name: &str) -> Option<String> {
+    let reversed = name.strip_suffix(".in-addr.arpa")?;
+    let mut octets = [0; 4];
+    let mut parts = reversed.split('.');
+    for octet in octets.iter_mut().rev() {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    core::find_ip_hostname(Ipv4Addr::from(octets).into())
+}
+
+/// Returns the reply to `query`, None if it is not one.
+fn answer(query: &[u8]) -> Option<Vec<u8>> {
+    if query.len() < 12 {
+        return None;
+    }
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    if flags & 0x8000 != 0 {
+        return None;
+    }
+    let opcode = (flags >> 11) & 0xF;
+    let qdcount = u16::from_be_bytes([query[4], query[5]]);
+
+    let mut reply = Vec::with_capacity(512);
+    reply.extend_from_slice(&query[..2]);
+    let header = |reply: &mut Vec<u8>, rcode: u16, qdcount: u16, ancount: u16| {
+        // response, with the opcode and recursion desired of the query, and
+        // recursion available
+        let flags = 0x8000 | (flags & 0x7900) | 0x0080 | rcode;
+        reply.extend_from_slice(&flags.to_be_bytes());
+        reply.extend_from_slice(&qdcount.to_be_bytes());
+        reply.extend_from_slice(&ancount.to_be_bytes());
+        reply.extend_from_slice(&[0; 4]);
+    };
+
+    if opcode != 0 {
+        header(&mut reply, RCODE_NOTIMP, 0, 0);
+        return Some(reply);
+    }
+    let question = match qdcount {
+        1 => read_name(query, 12).filter(|(_, end)| end + 4 <= query.len()),
+        _ => None,
+    };
+    let (name, end) = match question {
+        Some(q) => q,
+        None => {
+            header(&mut reply, RCODE_FORMERR, 0, 0);
+            return Some(reply);
+        }
+    };
+    let qtype = u16::from_be_bytes([query[end], query[end + 1]]);
+    let qclass = u16::from_be_bytes([query[end + 2], query[end + 3]]);
+    debug!("dns server query {} type {}", name, qtype);
+
+    let (rcode, records): (u16, Vec<Vec<u8>>) = match (qtype, qclass) {
+        (TYPE_A, CLASS_IN) => match resolve_a(&name) {
+            Ok(ips) => (0, ips.iter().map(|ip| ip.octets().to_vec()).collect()),
+            Err(e) => {
+                error!("dns server: {}: {}", name, e);
+                (RCODE_SERVFAIL, vec![])
+            }
+        },
+        (TYPE_PTR, CLASS_IN) => match resolve_ptr(&name) {
+            Some(hostname) => {
+                let mut rdata = vec![];
+                write_name(&mut rdata, &hostname);
+                (0, vec![rdata])
+            }
+            None => (RCODE_NXDOMAIN, vec![]),
+        },
+        // AAAA included, programs falling back to A
+        _ => (0, vec![]),
+    };
+
+    header(&mut reply, rcode, 1, records.len() as u16);
+    reply.extend_from_slice(&query[12..end + 4]);
+    for rdata in records {
+        // the name of the question
+        reply.extend_from_slice(&[0xC0, 12]);
+        reply.extend_from_slice(&qtype.to_be_bytes());
+        reply.extend_from_slice(&CLASS_IN.to_be_bytes());
+        // internal addresses are not reclaimed while the answer is cached
+        reply.extend_from_slice(&(CONFIG.dns_table_ttl as u32).to_be_bytes());
+        reply.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        reply.extend_from_slice(&rdata);
+    }
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a query of `qdcount` questions for `name`, with the
+    /// recursion desired flag and the opcode `opcode`.
+    fn query(opcode: u16, qdcount: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34];
+        query.extend_from_slice(&(0x0100 | opcode << 11).to_be_bytes());
+        query.extend_from_slice(&qdcount.to_be_bytes());
+        query.extend_from_slice(&[0; 6]);
+        write_name(&mut query, name);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    /// Returns the flags, question and answer counts of `reply`.
+    fn header(reply: &[u8]) -> (u16, u16, u16) {
+        let field = |i: usize| u16::from_be_bytes([reply[i], reply[i + 1]]);
+        (field(2), field(4), field(6))
+    }
+
+    #[test]
+    fn replies_and_short_messages_are_not_answered() {
+        assert_eq!(answer(&[0; 11]), None);
+        let mut reply = query(0, 1, "example.com", TYPE_A);
+        reply[2] |= 0x80;
+        assert_eq!(answer(&reply), None);
+    }
+
+    #[test]
+    fn other_opcodes_are_not_implemented() {
+        let reply = answer(&query(2, 1, "example.com", TYPE_A)).unwrap();
+        assert_eq!(reply[..2], [0x12, 0x34]);
+        assert_eq!(
+            header(&reply),
+            (0x8000 | 2 << 11 | 0x0180 | RCODE_NOTIMP, 0, 0)
+        );
+        assert_eq!(reply.len(), 12);
+    }
+
+    #[test]
+    fn malformed_questions_are_format_errors() {
+        let two = query(0, 2, "example.com", TYPE_A);
+        let mut truncated = query(0, 1, "example.com", TYPE_A);
+        truncated.truncate(truncated.len() - 1);
+        let mut compressed = query(0, 1, "", TYPE_A);
+        compressed.splice(12..13, [0xC0, 12]);
+        for query in [two, truncated, compressed] {
+            let reply = answer(&query).unwrap();
+            assert_eq!(header(&reply), (0x8180 | RCODE_FORMERR, 0, 0));
+        }
+    }
+
+    #[test]
+    fn other_types_have_no_answer() {
+        let query = query(0, 1, "example.com", 28);
+        let reply = answer(&query).unwrap();
+        assert_eq!(header(&reply), (0x8180, 1, 0));
+        // the question is echoed
+        assert_eq!(reply[12..], query[12..]);
+    }
+
+    #[test]
+    fn reverse_lookups_of_other_names_fail() {
+        for name in [
+            "example.com",
+            "1.2.3.in-addr.arpa",
+            "1.2.3.4.5.in-addr.arpa",
+        ] {
+            let reply = answer(&query(0, 1, name, TYPE_PTR)).unwrap();
+            assert_eq!(header(&reply), (0x8180 | RCODE_NXDOMAIN, 1, 0));
+        }
+    }
+
+    #[test]
+    fn names_are_read_back() {
+        let mut msg = vec![0; 12];
+        write_name(&mut msg, "www.example.com.");
+        assert_eq!(
+            read_name(&msg, 12),
+            Some(("www.example.com".into(), msg.len()))
+        );
+        assert_eq!(read_name(&msg[..msg.len() - 1], 12), None);
+    }
+}
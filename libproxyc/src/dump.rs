@@ -0,0 +1,198 @@
+/// SIGUSR1 state dump, for diagnosing hung long running processes
+use crate::conn::{self, Direction};
+use crate::core::{CONFIG, INTERNALADDR};
+use crate::health;
+use crate::stats::STATS;
+use crate::util;
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::libc::{self, c_int};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{close, pipe2, read, write};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+
+/// Ends of the pipe waking up the dump thread.
+static DUMP_RX: AtomicI32 = AtomicI32::new(-1);
+static DUMP_TX: AtomicI32 = AtomicI32::new(-1);
+/// Inode of the pipe.
+static DUMP_INO: AtomicU64 = AtomicU64::new(0);
+/// Whether the dump thread runs in this process, forked children starting
+/// without it.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Whether `fd` is still an end of the pipe.
+fn is_pipe(fd: RawFd) -> bool {
+    fd >= 0 && util::inode(fd) == Some(DUMP_INO.load(Ordering::Relaxed))
+}
+
+/// The dump takes locks the interrupted thread may hold, the handler only
+/// wakes the dump thread up.
+extern "C" fn on_sigusr1(_: c_int) {
+    let fd = DUMP_TX.load(Ordering::Relaxed);
+    if is_pipe(fd) {
+        let _ = write(fd, &[0]);
+    }
+}
+
+/// Creates the pipe, non-blocking so that the handler never waits on a
+/// thread which is not there.
+fn open_pipe() -> nix::Result<()> {
+    let (rx, tx) = pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+    let ino = match util::inode(rx) {
+        Some(ino) => ino,
+        None => {
+            let _ = close(rx);
+            let _ = close(tx);
+            return Err(Errno::last());
+        }
+    };
+    DUMP_INO.store(ino, Ordering::Relaxed);
+    DUMP_RX.store(rx, Ordering::Relaxed);
+    DUMP_TX.store(tx, Ordering::Relaxed);
+    Ok(())
+}
+
+/// The parent's pipe would have both processes dumping on the signals of
+/// either, the child gets its own. Its dump thread is started by the next
+/// hook it calls, spawning threads not being safe here.
+extern "C" fn reset_child() {
+    for fd in [&DUMP_RX, &DUMP_TX] {
+        let fd = fd.swap(-1, Ordering::Relaxed);
+        if is_pipe(fd) {
+            let _ = close(fd);
+        }
+    }
+    RUNNING.store(false, Ordering::Relaxed);
+    let _ = open_pipe();
+}
+
+fn run() {
+    let rx = DUMP_RX.load(Ordering::Relaxed);
+    let mut fds = [PollFd::new(rx, PollFlags::POLLIN)];
+    let mut buf = [0; 16];
+    while let Ok(_) | Err(Errno::EINTR) = poll(&mut fds, -1) {
+        // closed by the program
+        if !is_pipe(rx) {
+            break;
+        }
+        match read(rx, &mut buf) {
+            Ok(0) => break,
+            Ok(_) => dump_state(),
+            Err(Errno::EINTR | Errno::EAGAIN) => {}
+            Err(_) => break,
+        }
+    }
+    RUNNING.store(false, Ordering::Relaxed);
+}
+
+/// Starts the dump thread unless it runs already, the signals received
+/// before waiting in the pipe.
+pub fn ensure_thread() {
+    if !CONFIG.state_dump
+        || DUMP_RX.load(Ordering::Relaxed) < 0
+        || RUNNING.swap(true, Ordering::Relaxed)
+    {
+        return;
+    }
+    let spawned = std::thread::Builder::new()
+        .name("proxyc-dump".into())
+        .spawn(run);
+    if let Err(e) = spawned {
+        RUNNING.store(false, Ordering::Relaxed);
+        error!("failed to spawn dump thread: {}", e);
+    }
+}
+
+fn dump_state() {
+    info!("state dump for pid {}", std::process::id());
+
+    let entries = INTERNALADDR.lock().expect("mutex poisoned").entries();
+    info!(
+        "dns table: {}/{} entries, {} reclaimed",
+        entries.len(),
+        CONFIG.dns_table_size,
+        STATS.snapshot().dns_reclaimed
+    );
+    for (ip, hostname) in entries {
+        info!("\t{} => {}", ip, hostname);
+    }
+
+    info!("connections:");
+    for (fd, c) in conn::snapshot() {
+        let route = match (c.direction, c.proxied) {
+            (Direction::Inbound, _) => "inbound".to_string(),
+            (Direction::Outbound, true) => match &c.bound {
+                Some(bound) => format!("via {} bound {}", c.peer, bound),
+                None => format!("via {}", c.peer),
+            },
+            (Direction::Outbound, false) => "direct".to_string(),
+        };
+        info!(
+            "\tfd {}: {} {} ({}s, {} bytes sent, {} received)",
+            fd,
+            c.target,
+            route,
+            c.since.elapsed().as_secs(),
+            c.sent,
+            c.received
+        );
+    }
+
+    let stats = STATS.snapshot();
+    info!("proxy health:");
+    for p in stats.proxies {
+        let rtt = p
+            .rtt_ms
+            .map_or(String::new(), |r| format!(", rtt {:.1}ms", r));
+        let throughput = p
+            .throughput
+            .map_or(String::new(), |t| format!(", {:.0} B/s", t));
+        let check = match health::failing(&p.proxy) {
+            true => ", failing its health check",
+            false => "",
+        };
+        info!(
+            "\t{}: {} ok, {} failed{}{}{}",
+            p.proxy, p.success, p.failures, rtt, throughput, check
+        );
+    }
+
+    info!("rule hits:");
+    for r in stats.rules {
+        info!("\t{}: {}", r.rule, r.hits);
+    }
+}
+
+/// Installs the SIGUSR1 handler when state_dump is set, unless the program
+/// already handles or ignores this signal.
+pub fn init() {
+    if !CONFIG.state_dump {
+        return;
+    }
+    let mut old: libc::sigaction = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sigaction(libc::SIGUSR1, std::ptr::null(), &mut old) } != 0
+        || old.sa_sigaction != libc::SIG_DFL
+    {
+        warn!("SIGUSR1 is handled by the program, its state is not dumped");
+        return;
+    }
+
+    if let Err(e) = open_pipe() {
+        error!("failed to create dump pipe: {}", e);
+        return;
+    }
+    unsafe {
+        libc::pthread_atfork(None, None, Some(reset_child));
+    }
+
+    let action = SigAction::new(
+        SigHandler::Handler(on_sigusr1),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    if let Err(e) = unsafe { sigaction(Signal::SIGUSR1, &action) } {
+        error!("failed to install SIGUSR1 handler: {}", e);
+    }
+}
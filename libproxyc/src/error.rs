@@ -1,5 +1,34 @@
+use proxyc_common::{ProxyConf, ProxyType};
+use std::fmt;
 use thiserror::Error as ThisError;
 
+/// Step of the handshake with a proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Connecting to the first proxy.
+    Connect,
+    /// Negotiating the authentication method.
+    Greeting,
+    Auth,
+    /// Asking the proxy to reach the next hop.
+    Request,
+    Tls,
+    Quic,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Connect => "connect",
+            Stage::Greeting => "greeting",
+            Stage::Auth => "auth",
+            Stage::Request => "request",
+            Stage::Tls => "tls",
+            Stage::Quic => "quic",
+        })
+    }
+}
+
 #[derive(ThisError, Debug)]
 pub enum Error {
     #[error("poll timeout")]
@@ -10,6 +39,38 @@ pub enum Error {
     Connect(String),
     #[error("missing data")]
     MissingData,
+    #[error("cannot resolve {0}")]
+    Unresolved(String),
+    #[error("{what} not supported by {proto}")]
+    Unsupported { proto: ProxyType, what: String },
+    /// Failure of the TLS session, reported under the tls stage.
+    #[error("{0}")]
+    Tls(String),
+    /// Failure of the QUIC connection, reported under the quic stage.
+    #[error("{0}")]
+    Quic(String),
+    /// Failure at a step of a handshake.
+    #[error("{stage}: {source}")]
+    Stage {
+        stage: Stage,
+        #[source]
+        source: Box<Error>,
+    },
+    /// Failure with a proxy of the chain, numbered from 1.
+    #[error("hop {hop} {proxy}: {source}")]
+    Hop {
+        hop: usize,
+        proxy: String,
+        #[source]
+        source: Box<Error>,
+    },
+    /// Failure of a chain of `chain_order`.
+    #[error("chain {name}: {source}")]
+    Chain {
+        name: String,
+        #[source]
+        source: Box<Error>,
+    },
     #[error("{0}")]
     Generic(String),
     #[error(transparent)]
@@ -17,3 +78,33 @@ pub enum Error {
     #[error(transparent)]
     Errno(#[from] nix::errno::Errno),
 }
+
+impl Error {
+    /// Attributes the error to `stage` of a handshake.
+    pub fn at(self, stage: Stage) -> Self {
+        Error::Stage {
+            stage,
+            source: Box::new(self),
+        }
+    }
+
+    /// Attributes the error to `proxy`, the `hop`th of the chain. The
+    /// credentials of the proxy are left out.
+    pub fn at_hop(self, hop: usize, proxy: &ProxyConf) -> Self {
+        Error::Hop {
+            hop,
+            proxy: proxy.endpoint(),
+            source: Box::new(self),
+        }
+    }
+
+    /// Returns the error at the origin of the chain of causes.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Stage { source, .. }
+            | Error::Hop { source, .. }
+            | Error::Chain { source, .. } => source.root(),
+            e => e,
+        }
+    }
+}
@@ -0,0 +1,153 @@
+/// Fast rejection of the connections bypassing the proxies
+///
+/// Every connect() of the program is checked, scanners making tens of
+/// thousands of them a second. The ignored subnets are compiled at load into
+/// sorted disjoint intervals searched in logarithmic time, the ignored ports
+/// into a bitmap, and the type of the sockets is cached by descriptor rather
+/// than asked to the kernel on every call.
+use crate::core::CONFIG;
+use crate::route;
+use nix::libc::{self, c_int};
+use nix::sys::socket::{getsockopt, sockopt};
+use once_cell::sync::Lazy;
+use proxyc_common::IgnoreSubnet;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Highest file descriptor whose socket type is cached.
+const MAX_CACHED_FD: usize = 16383;
+
+/// Destinations of `ignore_subnets`.
+pub struct IgnoreSet {
+    /// First and last address of the subnets, sorted and merged.
+    ranges: Vec<(u32, u32)>,
+    /// Ports of the subnets restricted to one, a bit per port.
+    ports: Box<[u64; 1024]>,
+}
+
+impl IgnoreSet {
+    pub fn new(subnets: &[IgnoreSubnet]) -> Self {
+        let mut ranges: Vec<(u32, u32)> = subnets
+            .iter()
+            .map(|s| (s.cidr.first_address().into(), s.cidr.last_address().into()))
+            .collect();
+        ranges.sort_unstable();
+        let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+        for (first, last) in ranges {
+            match merged.last_mut() {
+                Some(prev) if first <= prev.1.saturating_add(1) => prev.1 = prev.1.max(last),
+                _ => merged.push((first, last)),
+            }
+        }
+
+        let mut ports = Box::new([0; 1024]);
+        for port in subnets.iter().filter_map(|s| s.port) {
+            ports[port as usize / 64] |= 1 << (port % 64);
+        }
+        IgnoreSet {
+            ranges: merged,
+            ports,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Whether connections to `ip` on `port` bypass the proxies, as matched
+    /// by either the subnet or the port of an entry.
+    pub fn contains(&self, ip: IpAddr, port: u16) -> bool {
+        if self.ports[port as usize / 64] & (1 << (port % 64)) != 0 {
+            return true;
+        }
+        let ip = match ip {
+            IpAddr::V4(ip) => u32::from(ip),
+            IpAddr::V6(_) => return false,
+        };
+        // the last range starting at or before the address
+        let i = self.ranges.partition_point(|&(first, _)| first <= ip);
+        i > 0 && ip <= self.ranges[i - 1].1
+    }
+}
+
+pub static IGNORED: Lazy<IgnoreSet> = Lazy::new(|| IgnoreSet::new(&CONFIG.ignore_subnets));
+
+/// Addresses of the .local names, resolved by multicast DNS on the local
+/// network and connected to directly.
+static MDNS_ADDRS: Lazy<Mutex<HashSet<IpAddr>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Number of addresses of .local names, lets connect() skip the lock in the
+/// common case.
+static MDNS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Connects the address of a .local name directly.
+pub fn bypass_mdns(ip: IpAddr) {
+    if MDNS_ADDRS.lock().expect("mutex poisoned").insert(ip) {
+        MDNS_COUNT.fetch_add(1, Ordering::Relaxed);
+        route::forget(ip);
+    }
+}
+
+/// Whether some rules connect their destinations directly.
+static DIRECT_RULES: Lazy<bool> = Lazy::new(|| CONFIG.rules.iter().any(|r| r.direct));
+
+/// Whether connections to `ip` on `port` bypass the proxies.
+pub fn bypasses(ip: IpAddr, port: u16) -> bool {
+    IGNORED.contains(ip, port)
+        || (MDNS_COUNT.load(Ordering::Relaxed) > 0
+            && MDNS_ADDRS.lock().expect("mutex poisoned").contains(&ip))
+        || (*DIRECT_RULES && CONFIG.rule_for(ip, port).is_some_and(|r| r.direct))
+}
+
+/// Whether every destination goes through the proxies.
+pub fn bypasses_none() -> bool {
+    IGNORED.is_empty() && MDNS_COUNT.load(Ordering::Relaxed) == 0 && !*DIRECT_RULES
+}
+
+/// Socket types indexed by file descriptor, 0 if unknown. Set when a socket
+/// is created or first connected, cleared when its descriptor is closed or
+/// replaced.
+static SOCKTYPES: Lazy<Vec<AtomicI32>> =
+    Lazy::new(|| (0..=MAX_CACHED_FD).map(|_| AtomicI32::new(0)).collect());
+
+fn cached(fd: RawFd) -> Option<&'static AtomicI32> {
+    SOCKTYPES.get(usize::try_from(fd).ok()?)
+}
+
+/// Records the type of a socket just created, `ty` as given to socket().
+pub fn created(fd: RawFd, ty: c_int) {
+    if let Some(c) = cached(fd) {
+        c.store(
+            ty & !(libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Forgets the type of a closed or replaced descriptor.
+pub fn forget(fd: RawFd) {
+    if let Some(c) = cached(fd) {
+        c.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Whether `fd` is a stream socket.
+pub fn is_stream(fd: RawFd) -> bool {
+    let ty = match cached(fd).map_or(0, |c| c.load(Ordering::Relaxed)) {
+        0 => match getsockopt(fd, sockopt::SockType) {
+            Ok(ty) => {
+                let ty = ty as c_int;
+                if let Some(c) = cached(fd) {
+                    c.store(ty, Ordering::Relaxed);
+                }
+                ty
+            }
+            Err(_) => return false,
+        },
+        ty => ty,
+    };
+    ty == libc::SOCK_STREAM
+}
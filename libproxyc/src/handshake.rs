@@ -0,0 +1,229 @@
+/// Handshakes of non-blocking sockets in the background
+///
+/// Event loops expect connect() to return at once on a non-blocking socket.
+/// The socket of the program is replaced by one end of a local socket pair
+/// and connect() returns EINPROGRESS, while a small pool of threads builds
+/// the chain. A thread then relays the other end through it, the program
+/// having possibly written already.
+use crate::core::{self, CONFIG};
+use crate::error::Error;
+use crate::quota::Slot;
+use crate::stats::STATS;
+use crate::util::FdStream;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{
+    shutdown, socketpair, AddressFamily, Shutdown, SockAddr, SockFlag, SockType,
+};
+use nix::unistd::{close, dup2, getpid, Pid};
+use once_cell::sync::Lazy;
+use std::io::{Read, Write};
+use std::os::unix::io::RawFd;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Chain to build for a socket of the program.
+struct Job {
+    /// Socket the chain is built with.
+    ns: RawFd,
+    /// End of the pair relayed through the chain.
+    local: RawFd,
+    target: SockAddr,
+    /// Place of the connection under the quotas, held until it closes.
+    slot: Slot,
+}
+
+/// Queue of the workers of this process.
+struct Pool {
+    /// Process the workers run in, a forked child starts its own.
+    pid: Pid,
+    jobs: Sender<Job>,
+}
+
+static POOL: Lazy<Mutex<Option<Pool>>> = Lazy::new(|| Mutex::new(None));
+
+/// Queues `job`, starting the workers if needed.
+fn submit(job: Job) -> Result<(), Error> {
+    let mut pool = POOL.lock().expect("mutex poisoned");
+    let pid = getpid();
+    if let Some(p) = pool.take_if(|p| p.pid != pid) {
+        // the workers were not forked
+        std::mem::forget(p);
+    }
+    let pool = match pool.as_mut() {
+        Some(p) => p,
+        None => {
+            let (jobs, rx) = channel();
+            let rx = Arc::new(Mutex::new(rx));
+            let threads = CONFIG.handshake_threads.unwrap_or(1);
+            for i in 0..threads {
+                let rx = rx.clone();
+                std::thread::Builder::new()
+                    .name(format!("proxyc-handshake-{}", i))
+                    .spawn(move || work(&rx))?;
+            }
+            pool.insert(Pool { pid, jobs })
+        }
+    };
+    pool.jobs
+        .send(job)
+        .map_err(|_| Error::Generic("handshake workers stopped".into()))
+}
+
+fn work(rx: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match rx.lock().expect("mutex poisoned").recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        run(job);
+    }
+}
+
+/// Builds the chain of a job, and relays its local end through it.
+fn run(job: Job) {
+    let Job {
+        ns,
+        local,
+        target,
+        slot,
+    } = job;
+    let stream = match core::chain_target(ns, &target) {
+        Ok((stream, _)) => stream,
+        Err(e) => {
+            error!("{}", e);
+            // the program sees the connection closed
+            close(ns).ok();
+            close(local).ok();
+            return;
+        }
+    };
+    if stream != ns {
+        close(ns).ok();
+    }
+    debug!("connected to {}", target.to_str());
+    let hops = STATS.take_chain();
+
+    let spawned = std::thread::Builder::new()
+        .name("proxyc-relay".into())
+        .spawn(move || {
+            let start = Instant::now();
+            let mut moved = (0, start);
+            if let Err(e) = relay(FdStream(local), FdStream(stream), &mut moved) {
+                debug!("relay: {}", e);
+            }
+            STATS.throughput(&hops, moved.0, moved.1 - start);
+            close(local).ok();
+            close(stream).ok();
+            drop(slot);
+        });
+    if let Err(e) = spawned {
+        error!("failed to spawn relay thread: {}", e);
+        close(local).ok();
+        close(stream).ok();
+    }
+}
+
+/// Connects `sock`, non-blocking, to `target` in the background with the
+/// socket `ns`, counted under the quotas by `slot` until it fails or closes.
+/// On success `sock` is one end of a local socket pair, and the caller
+/// reports the connection in progress.
+pub fn start(sock: RawFd, ns: RawFd, target: &SockAddr, slot: Slot) -> Result<(), Error> {
+    let flags = fcntl(sock, FcntlArg::F_GETFL)?;
+    let fd_flags = fcntl(sock, FcntlArg::F_GETFD)?;
+    let (plain, local) = socketpair(
+        AddressFamily::Unix,
+        SockType::Stream,
+        None,
+        SockFlag::SOCK_CLOEXEC,
+    )?;
+    // the file status and descriptor flags of the program are kept
+    let res = dup2(plain, sock)
+        .and_then(|_| fcntl(sock, FcntlArg::F_SETFL(OFlag::from_bits_truncate(flags))))
+        .and_then(|_| {
+            fcntl(
+                sock,
+                FcntlArg::F_SETFD(FdFlag::from_bits_truncate(fd_flags)),
+            )
+        });
+    close(plain).ok();
+    if let Err(e) = res {
+        close(local).ok();
+        return Err(e.into());
+    }
+
+    core::register_proxied(sock, target, None, vec![]);
+    debug!(
+        "socket {} connects to {} in the background",
+        sock,
+        target.to_str()
+    );
+    submit(Job {
+        ns,
+        local,
+        target: *target,
+        slot,
+    })
+    .inspect_err(|_| {
+        close(local).ok();
+    })
+}
+
+/// Relays `local` through `stream` until both directions are closed, `moved`
+/// counting the bytes relayed and the time of the last ones.
+fn relay(
+    mut local: FdStream,
+    mut stream: FdStream,
+    moved: &mut (u64, Instant),
+) -> Result<(), Error> {
+    let mut buf = [0; 16384];
+    // directions still open: local to stream, stream to local
+    let mut open = [true, true];
+
+    while open.contains(&true) {
+        let mut fds = [
+            PollFd::new(local.0, PollFlags::POLLIN),
+            PollFd::new(stream.0, PollFlags::POLLIN),
+        ];
+        // a closed direction is no longer polled
+        for (fd, open) in fds.iter_mut().zip(open) {
+            if !open {
+                *fd = PollFd::new(-1, PollFlags::empty());
+            }
+        }
+        match poll(&mut fds, -1) {
+            Ok(_) => (),
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+        let ready = |fd: &PollFd| fd.revents().is_some_and(|r| !r.is_empty());
+
+        if open[0] && ready(&fds[0]) {
+            match local.read(&mut buf)? {
+                0 => {
+                    shutdown(stream.0, Shutdown::Write).ok();
+                    open[0] = false;
+                }
+                n => {
+                    stream.write_all(&buf[..n])?;
+                    *moved = (moved.0 + n as u64, Instant::now());
+                }
+            }
+        }
+        if open[1] && ready(&fds[1]) {
+            match stream.read(&mut buf)? {
+                0 => {
+                    shutdown(local.0, Shutdown::Write).ok();
+                    open[1] = false;
+                }
+                n => {
+                    local.write_all(&buf[..n])?;
+                    *moved = (moved.0 + n as u64, Instant::now());
+                }
+            }
+        }
+    }
+    Ok(())
+}
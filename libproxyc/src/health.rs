@@ -0,0 +1,149 @@
+/// Health checks of the proxies
+///
+/// The proxies defining a check are checked every health_interval seconds by
+/// a thread of the process, from the end of the first interval on: programs
+/// exiting before it make none. A proxy is checked end to end, through the
+/// proxies before it in its chain then to the target of its check, reached
+/// over TCP or asked for an HTTP page answered with a status below 400.
+///
+/// The dynamic chains leave out the proxies failing their checks, as long as
+/// min_chain_len proxies are left. The thread does not survive fork(), forked
+/// children keep the health known at the time.
+use crate::core::{self, CONFIG};
+use crate::error::Error;
+use crate::util::{read_timeout, FdStream};
+use nix::unistd::close;
+use once_cell::sync::Lazy;
+use proxyc_common::{HealthCheck, ProxyConf, ProxyType};
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Proxies which failed their last check, by endpoint.
+static UNHEALTHY: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Whether `proxy` passed its last check, or has none.
+pub fn is_healthy(proxy: &ProxyConf) -> bool {
+    proxy.check.is_none() || !failing(&proxy.endpoint())
+}
+
+/// Whether the proxy at `endpoint` failed its last check.
+pub fn failing(endpoint: &str) -> bool {
+    UNHEALTHY.lock().unwrap().contains(endpoint)
+}
+
+/// Returns the proxies to check, with the proxies before them in their chain.
+fn checked() -> Vec<&'static [ProxyConf]> {
+    let config = &*CONFIG;
+    let mut seen = HashSet::new();
+    std::iter::once(&config.proxies)
+        .chain(config.chains.values().map(|c| &c.proxies))
+        .flat_map(|proxies| (1..=proxies.len()).map(move |i| &proxies[..i]))
+        .filter(|prefix| {
+            let proxy = &prefix[prefix.len() - 1];
+            proxy.check.is_some() && seen.insert(proxy.endpoint())
+        })
+        .collect()
+}
+
+/// Spawns the thread checking the proxies, if one defines a check.
+pub fn init() {
+    let checked = checked();
+    if checked.is_empty() {
+        return;
+    }
+
+    let interval = Duration::from_secs(CONFIG.health_interval as u64);
+    let spawned = std::thread::Builder::new()
+        .name("proxyc-health".into())
+        .spawn(move || loop {
+            std::thread::sleep(interval);
+            for prefix in &checked {
+                update(prefix);
+            }
+        });
+    if let Err(e) = spawned {
+        error!("failed to spawn health thread: {}", e);
+    }
+}
+
+/// Checks the last proxy of `prefix`, logging the changes of its health.
+fn update(prefix: &[ProxyConf]) {
+    let proxy = &prefix[prefix.len() - 1];
+    let check = match &proxy.check {
+        Some(c) => c,
+        None => return,
+    };
+
+    let res = run(prefix, check);
+    let endpoint = proxy.endpoint();
+    let mut unhealthy = UNHEALTHY.lock().unwrap();
+    match res {
+        Ok(()) if unhealthy.remove(&endpoint) => {
+            info!("{} passes its health check again", endpoint)
+        }
+        Ok(()) => debug!("{} passes its health check", endpoint),
+        Err(e) => {
+            if unhealthy.insert(endpoint.clone()) {
+                warn!("{} fails its health check {}: {}", endpoint, check, e);
+            }
+        }
+    }
+}
+
+/// Runs `check` through the proxies of `prefix`.
+fn run(prefix: &[ProxyConf], check: &HealthCheck) -> Result<(), Error> {
+    let (host, port) = check.target();
+    // hostnames are resolved by the proxy
+    let (ip, hostname) = match host.parse() {
+        Ok(ip) => (ip, None),
+        Err(_) => (IpAddr::V4(Ipv4Addr::UNSPECIFIED), Some(host.to_string())),
+    };
+    let target = ProxyConf {
+        proto: ProxyType::Raw,
+        ip,
+        port,
+        auth: None,
+        alt_ips: vec![],
+        hostname,
+        country: None,
+        auth_methods: vec![],
+        check: None,
+    };
+
+    let sock = core::tunnel_check(prefix, &target)?;
+    let res = match check {
+        HealthCheck::Tcp { .. } => Ok(()),
+        HealthCheck::Http { path, .. } => http_status(sock, &target, path),
+    };
+    close(sock).ok();
+    res
+}
+
+/// Asks the server at the end of `sock` for `path`, checking the status of
+/// its answer.
+fn http_status(sock: RawFd, target: &ProxyConf, path: &str) -> Result<(), Error> {
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path,
+        target.host_port()
+    );
+    FdStream(sock).write_all(request.as_bytes())?;
+
+    // HTTP/1.x NNN
+    let mut status = [0; 12];
+    read_timeout(sock, &mut status, CONFIG.tcp_read_timeout)?;
+    let status = String::from_utf8_lossy(&status);
+    match status
+        .strip_prefix("HTTP/1.")
+        .and_then(|s| s.get(2..5))
+        .and_then(|c| c.parse::<u16>().ok())
+    {
+        Some(code) if code < 400 => Ok(()),
+        Some(code) => Err(io::Error::other(format!("HTTP status {}", code)).into()),
+        None => Err(io::Error::other("not an HTTP answer").into()),
+    }
+}
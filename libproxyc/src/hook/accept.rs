@@ -0,0 +1,67 @@
+use crate::conn::{self, Direction};
+use crate::core;
+use crate::filter;
+use nix::libc::{c_int, sockaddr, socklen_t};
+use nix::sys::socket::getpeername;
+use std::os::unix::io::RawFd;
+
+/// Records an accepted socket as inbound.
+fn register(fd: RawFd) {
+    if fd < 0 {
+        return;
+    }
+    filter::forget(fd);
+    if let Ok(peer) = getpeername(fd) {
+        conn::register(fd, Direction::Inbound, false, peer.to_str(), None, vec![]);
+    }
+}
+
+#[no_mangle]
+extern "C" fn accept(sock: RawFd, addr: *mut sockaddr, len: *mut socklen_t) -> c_int {
+    let c_accept4 = core::ACCEPT4.expect("Cannot load symbol 'accept4'");
+    if !crate::ensure_init() {
+        return unsafe { c_accept4(sock, addr, len, 0) };
+    }
+
+    trace!("accept hooked");
+
+    let fd = unsafe { c_accept4(sock, addr, len, 0) };
+    register(fd);
+    fd
+}
+
+versioned!(
+    proxyc_v_accept => accept(
+        sock: RawFd,
+        addr: *mut sockaddr,
+        len: *mut socklen_t,
+    ) -> c_int
+);
+
+#[no_mangle]
+extern "C" fn accept4(
+    sock: RawFd,
+    addr: *mut sockaddr,
+    len: *mut socklen_t,
+    flags: c_int,
+) -> c_int {
+    let c_accept4 = core::ACCEPT4.expect("Cannot load symbol 'accept4'");
+    if !crate::ensure_init() {
+        return unsafe { c_accept4(sock, addr, len, flags) };
+    }
+
+    trace!("accept4 hooked");
+
+    let fd = unsafe { c_accept4(sock, addr, len, flags) };
+    register(fd);
+    fd
+}
+
+versioned!(
+    proxyc_v_accept4 => accept4(
+        sock: RawFd,
+        addr: *mut sockaddr,
+        len: *mut socklen_t,
+        flags: c_int,
+    ) -> c_int
+);
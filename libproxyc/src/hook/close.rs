@@ -0,0 +1,27 @@
+use crate::absolute_uri;
+use crate::audit;
+use crate::conn;
+use crate::core;
+use crate::filter;
+use crate::udp;
+use nix::libc::c_int;
+use std::os::unix::io::RawFd;
+
+#[no_mangle]
+extern "C" fn close(fd: RawFd) -> c_int {
+    let c_close = core::CLOSE.expect("Cannot load symbol 'close'");
+    if !crate::ensure_init() {
+        return unsafe { c_close(fd) };
+    }
+
+    filter::forget(fd);
+    udp::close(fd);
+    absolute_uri::close(fd);
+    if let Some(c) = conn::forget(fd) {
+        audit::closed(fd, &c);
+    }
+
+    unsafe { c_close(fd) }
+}
+
+versioned!(proxyc_v_close => close(fd: RawFd) -> c_int);
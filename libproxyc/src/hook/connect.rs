@@ -1,25 +1,38 @@
+use crate::audit;
 use crate::core;
 use crate::error::Error;
+use crate::filter;
+use crate::handshake;
+use crate::quota;
+use crate::route;
+use crate::stats::STATS;
+use crate::udp;
 use nix::errno::Errno;
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
-use nix::libc::{c_int, sockaddr, socklen_t};
-use nix::sys::socket::{getsockopt, socket, sockopt, AddressFamily, SockAddr, SockFlag, SockType};
+use nix::libc::{
+    c_int, sockaddr, sockaddr_in, sockaddr_in6, sockaddr_storage, sockaddr_un, socklen_t, AF_INET,
+    AF_INET6, AF_UNIX, AF_UNSPEC,
+};
+use nix::sys::socket::{
+    sockaddr_storage_to_addr, socket, AddressFamily, SockAddr, SockFlag, SockType,
+};
 use nix::unistd::close;
+use proxyc_common::UnsupportedFamily;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::os::unix::io::RawFd;
+use std::{mem, ptr};
 
-fn check_socket(sock: RawFd, addr: &SockAddr) -> Result<(), Error> {
-    let socktype = getsockopt(sock, sockopt::SockType).unwrap();
+/// Whether a connection of `sock` to `addr` goes through the proxies.
+pub fn check_socket(sock: RawFd, addr: &SockAddr) -> Result<(), Error> {
     let fam = addr.family();
 
-    if !((fam == (AddressFamily::Inet) || fam == AddressFamily::Inet6)
-        && socktype == SockType::Stream)
-    {
+    if !((fam == (AddressFamily::Inet) || fam == AddressFamily::Inet6) && filter::is_stream(sock)) {
         // socket is not of the appropriate type
         return Err(Error::Socket);
     }
 
     let config = &*core::CONFIG;
-    if config.ignore_subnets.is_empty() {
+    if filter::bypasses_none() && !config.dry_run {
         return Ok(());
     }
 
@@ -32,34 +45,146 @@ fn check_socket(sock: RawFd, addr: &SockAddr) -> Result<(), Error> {
         _ => Err(Error::Socket),
     }?;
 
-    for i in config.ignore_subnets.iter() {
-        if let Some(p) = i.port {
-            if p == target_port {
-                return Err(Error::Socket);
-            }
-        }
+    let route = route::lookup(target_ip, target_port);
 
-        if let std::net::IpAddr::V4(ip) = target_ip {
-            if i.cidr.contains(&ip) {
-                return Err(Error::Socket);
-            }
-        }
+    if config.dry_run {
+        core::log_dry_run(target_ip, target_port, route.direct);
+        return Err(Error::Socket);
+    }
+
+    if route.direct {
+        STATS.bypass(target_ip, target_port, route.rule_index());
+        return Err(Error::Socket);
     }
 
     Ok(())
 }
 
+/// Whether `address` is a loopback address or a Unix socket, read from the
+/// raw address for the check to stay cheap.
+fn is_local(address: *const sockaddr, len: socklen_t) -> bool {
+    let family = match unsafe { address.as_ref() } {
+        Some(a) => c_int::from(a.sa_family),
+        None => return false,
+    };
+    let len = len as usize;
+    match family {
+        AF_UNIX => true,
+        AF_INET if len >= mem::size_of::<sockaddr_in>() => {
+            let sin = unsafe { &*(address as *const sockaddr_in) };
+            Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)).is_loopback()
+        }
+        AF_INET6 if len >= mem::size_of::<sockaddr_in6>() => {
+            let sin6 = unsafe { &*(address as *const sockaddr_in6) };
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            ip.is_loopback() || ip.to_ipv4_mapped().is_some_and(|ip| ip.is_loopback())
+        }
+        _ => false,
+    }
+}
+
+/// Names the address `address` of a family the proxies do not carry, and its
+/// family.
+fn describe(address: *const sockaddr, len: socklen_t) -> (String, String) {
+    let family = i32::from(unsafe { (*address).sa_family });
+    let name = match AddressFamily::from_i32(family) {
+        Some(f) => format!("{:?}", f).to_lowercase(),
+        None => format!("family {}", family),
+    };
+
+    let len = (len as usize).min(mem::size_of::<sockaddr_un>());
+    let mut storage: sockaddr_storage = unsafe { mem::zeroed() };
+    unsafe {
+        ptr::copy_nonoverlapping(
+            address as *const u8,
+            &mut storage as *mut sockaddr_storage as *mut u8,
+            len,
+        );
+    }
+    let target = match sockaddr_storage_to_addr(&storage, len) {
+        Ok(addr) => addr.to_str(),
+        Err(_) => name.clone(),
+    };
+    (target, name)
+}
+
+/// Applies the unsupported_family policy to a connection to `address`, of a
+/// family other than IPv4 and IPv6. Returns whether it is made directly.
+fn allow_unsupported(address: *const sockaddr, len: socklen_t) -> bool {
+    let config = &*core::CONFIG;
+    let (target, family) = describe(address, len);
+
+    if config.dry_run {
+        info!(
+            "dry-run: {} is of the {} address family, connecting directly",
+            target, family
+        );
+        return true;
+    }
+
+    match config.unsupported_family {
+        UnsupportedFamily::Direct => {
+            debug!(
+                "unsupported family: family={} target={} policy=direct",
+                family, target
+            );
+            true
+        }
+        UnsupportedFamily::Deny => {
+            warn!(
+                "unsupported family: family={} target={} policy=deny",
+                family, target
+            );
+            let e = Error::Generic(format!("{} address family denied", family));
+            audit::denied(target, &e);
+            false
+        }
+    }
+}
+
 #[no_mangle]
-pub fn connect(sock: RawFd, address: *const sockaddr, len: socklen_t) -> c_int {
+pub extern "C" fn connect(sock: RawFd, address: *const sockaddr, len: socklen_t) -> c_int {
     let c_connect = core::CONNECT.expect("Cannot load symbol 'connect'");
+    if !crate::ensure_init() {
+        return unsafe { c_connect(sock, address, len) };
+    }
+
+    if core::CONFIG.protect_loopback && is_local(address, len) {
+        return unsafe { c_connect(sock, address, len) };
+    }
+
     let addr_opt = unsafe { core::from_libc_sockaddr(address) };
 
     trace!("connect hooked");
 
+    if let Some(SockAddr::Inet(inet)) = &addr_opt {
+        let peer = inet.to_std();
+        if udp::is_relayed(sock, &peer) {
+            return match udp::connect(sock, peer) {
+                Ok(_) => 0,
+                Err(e) => {
+                    error!("{}", e);
+                    core::set_errno(Errno::ECONNREFUSED);
+                    -1
+                }
+            };
+        }
+    }
+
+    let family = unsafe { address.as_ref() }.map(|a| c_int::from(a.sa_family));
+    if let Some(family) = family.filter(|f| ![AF_INET, AF_INET6, AF_UNSPEC].contains(f)) {
+        if !allow_unsupported(address, len) {
+            core::set_errno(Errno::EACCES);
+            return -1;
+        }
+        trace!("connect of family {} passed through", family);
+    }
+
     if let Some(addr) = addr_opt {
-        // if the socket is not of the correct type, or the target address
-        // should be ignored, use the true connect call.
+        // if the socket is not of the correct type, the target address
+        // should be ignored or in dry-run mode, use the true connect call.
         if check_socket(sock, &addr).is_ok() {
+            let config = &*core::CONFIG;
             let ns = match socket(addr.family(), SockType::Stream, SockFlag::empty(), None) {
                 Ok(s) => s,
                 Err(_e) => return -1,
@@ -74,6 +199,38 @@ pub fn connect(sock: RawFd, address: *const sockaddr, len: socklen_t) -> c_int {
             };
             let flags_orig = flags;
 
+            let slot = match &addr {
+                SockAddr::Inet(inet) => {
+                    quota::acquire(inet.to_std().ip(), flags.contains(OFlag::O_NONBLOCK))
+                }
+                _ => Err(Error::Socket),
+            };
+            let slot = match slot {
+                Ok(slot) => slot,
+                Err(e) => {
+                    close(ns).ok();
+                    warn!("{}: {}", addr.to_str(), e);
+                    audit::denied(addr.to_str(), &e);
+                    core::set_errno(Errno::EAGAIN);
+                    return -1;
+                }
+            };
+
+            if flags.contains(OFlag::O_NONBLOCK) && config.handshake_threads.is_some() {
+                return match handshake::start(sock, ns, &addr, slot) {
+                    Ok(_) => {
+                        core::set_errno(Errno::EINPROGRESS);
+                        -1
+                    }
+                    Err(e) => {
+                        close(ns).ok();
+                        error!("{}", e);
+                        core::set_errno(Errno::ECONNREFUSED);
+                        -1
+                    }
+                };
+            }
+
             if flags.contains(OFlag::O_NONBLOCK) {
                 flags.toggle(OFlag::O_NONBLOCK);
                 fcntl(sock, FcntlArg::F_SETFL(flags)).expect("fcntl force blocking failed");
@@ -91,6 +248,12 @@ pub fn connect(sock: RawFd, address: *const sockaddr, len: socklen_t) -> c_int {
                 Err(e) => {
                     close(ns).ok();
                     error!("{}", e);
+                    if let (true, SockAddr::Inet(inet)) = (config.fallback_direct, &addr) {
+                        if let Err(e) = fcntl(sock, FcntlArg::F_SETFL(flags_orig)) {
+                            error!("fcntl apply original flags error: {}", e)
+                        }
+                        return core::connect_direct(sock, inet.to_std());
+                    }
                     core::set_errno(Errno::ECONNREFUSED); // for nmap
                     return -1;
                 }
@@ -100,3 +263,11 @@ pub fn connect(sock: RawFd, address: *const sockaddr, len: socklen_t) -> c_int {
 
     unsafe { c_connect(sock, address, len) }
 }
+
+versioned!(
+    proxyc_v_connect => connect(
+        sock: RawFd,
+        address: *const sockaddr,
+        len: socklen_t,
+    ) -> c_int
+);
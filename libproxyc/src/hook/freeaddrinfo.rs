@@ -1,18 +1,19 @@
 use crate::core;
-use nix::libc::{self, addrinfo, c_void};
+use nix::libc::addrinfo;
 
 #[no_mangle]
-fn freeaddrinfo(res: *mut addrinfo) {
+extern "C" fn freeaddrinfo(res: *mut addrinfo) {
     let c_freeaddrinfo = core::FREEADDRINFO.expect("Cannot load symbol 'freeaddrinfo'");
-    let config = &*core::CONFIG;
+    if !crate::ensure_init() {
+        unsafe { c_freeaddrinfo(res) };
+        return;
+    }
 
     trace!("freeaddrinfo hooked");
 
-    if config.proxy_dns {
-        if !res.is_null() {
-            unsafe { libc::free(res as *mut c_void) };
-        }
-    } else {
+    if !res.is_null() && !core::free_own_addrinfo(res) {
         unsafe { c_freeaddrinfo(res) };
     }
 }
+
+versioned!(proxyc_v_freeaddrinfo => freeaddrinfo(res: *mut addrinfo));
@@ -0,0 +1,20 @@
+use crate::core;
+use crate::netdb;
+use nix::libc::{c_char, c_int};
+use std::ffi::CStr;
+
+#[no_mangle]
+extern "C" fn gai_strerror(errcode: c_int) -> *const c_char {
+    let c_gai_strerror = core::GAI_STRERROR.expect("Cannot load symbol 'gai_strerror'");
+    let message = unsafe { c_gai_strerror(errcode) };
+    if message.is_null() {
+        return message;
+    }
+
+    // the reason is added when the last failure of the thread with this
+    // code was produced by proxyc
+    let text = unsafe { CStr::from_ptr(message) }.to_string_lossy();
+    netdb::gai_message(errcode, &text).unwrap_or(message)
+}
+
+versioned!(proxyc_v_gai_strerror => gai_strerror(errcode: c_int) -> *const c_char);
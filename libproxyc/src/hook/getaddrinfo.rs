@@ -1,21 +1,55 @@
 use crate::core;
+use crate::filter;
 use nix::libc::{addrinfo, c_char, c_int};
+use nix::sys::socket::SockAddr;
+use std::ffi::CStr;
 
 #[no_mangle]
-fn getaddrinfo(
+extern "C" fn getaddrinfo(
     node: *const c_char,
     service: *const c_char,
     hints: *const addrinfo,
     res: *mut *mut addrinfo,
 ) -> c_int {
     let c_getaddrinfo = core::GETADDRINFO.expect("Cannot load symbol 'getaddrinfo'");
+    if !crate::ensure_init() {
+        return unsafe { c_getaddrinfo(node, service, hints, res) };
+    }
 
     trace!("getaddrinfo hooked");
 
     let config = &*core::CONFIG;
-    if config.proxy_dns {
-        core::proxyc_getaddrinfo(node, service, hints, res)
-    } else {
-        unsafe { c_getaddrinfo(node, service, hints, res) }
+    if config.proxy_dns && config.dry_run && !node.is_null() {
+        let name = unsafe { CStr::from_ptr(node) };
+        info!("dry-run: {:?} would be resolved by the proxy", name);
+    }
+
+    if core::proxies_name(node) {
+        return core::proxyc_getaddrinfo(node, service, hints, res);
     }
+
+    let ret = unsafe { c_getaddrinfo(node, service, hints, res) };
+    if ret == 0
+        && !node.is_null()
+        && core::is_mdns(&unsafe { CStr::from_ptr(node) }.to_string_lossy())
+    {
+        // the local network is not reachable through the proxies
+        let mut ai = unsafe { *res };
+        while let Some(a) = unsafe { ai.as_ref() } {
+            if let Some(SockAddr::Inet(addr)) = unsafe { core::from_libc_sockaddr(a.ai_addr) } {
+                filter::bypass_mdns(addr.ip().to_std());
+            }
+            ai = a.ai_next;
+        }
+    }
+    ret
 }
+
+versioned!(
+    proxyc_v_getaddrinfo => getaddrinfo(
+        node: *const c_char,
+        service: *const c_char,
+        hints: *const addrinfo,
+        res: *mut *mut addrinfo,
+    ) -> c_int
+);
@@ -0,0 +1,115 @@
+use crate::core;
+use crate::netdb::{self, Failure};
+use nix::libc::{self, c_char, c_int, c_void, hostent, in_addr, size_t, socklen_t};
+use std::mem::MaybeUninit;
+use std::net::Ipv4Addr;
+
+// The man page of gethostbyaddr states that it can return static data.
+static mut GETHOSTBYADDR_DATA: MaybeUninit<core::GetHostByNameData> = MaybeUninit::uninit();
+
+/// Returns the address to resolve through the chain, if any.
+fn reverse_target(addr: *const c_void, len: socklen_t, type_: c_int) -> Option<Ipv4Addr> {
+    if core::proxies_reverse_dns()
+        && !addr.is_null()
+        && type_ == libc::AF_INET
+        && len as usize == std::mem::size_of::<in_addr>()
+    {
+        let s_addr = unsafe { (*(addr as *const in_addr)).s_addr };
+        Some(Ipv4Addr::from(u32::from_be(s_addr)))
+    } else {
+        None
+    }
+}
+
+#[no_mangle]
+extern "C" fn gethostbyaddr(addr: *const c_void, len: socklen_t, type_: c_int) -> *mut hostent {
+    let c_gethostbyaddr = core::GETHOSTBYADDR.expect("Cannot load symbol 'gethostbyaddr'");
+    if !crate::ensure_init() {
+        return unsafe { c_gethostbyaddr(addr, len, type_) };
+    }
+
+    trace!("gethostbyaddr hooked");
+
+    if let Some(ip) = reverse_target(addr, len, type_) {
+        return match core::tor_resolve_ptr(ip.into()) {
+            Ok(hostname) => {
+                let ptr = unsafe { (*std::ptr::addr_of_mut!(GETHOSTBYADDR_DATA)).as_mut_ptr() };
+                core::proxyc_gethostbyaddr(ip, &hostname, ptr)
+            }
+            Err(e) => {
+                error!("{}", e);
+                netdb::h_failed(&e);
+                std::ptr::null_mut()
+            }
+        };
+    }
+
+    unsafe { c_gethostbyaddr(addr, len, type_) }
+}
+
+versioned!(
+    proxyc_v_gethostbyaddr => gethostbyaddr(
+        addr: *const c_void,
+        len: socklen_t,
+        type_: c_int,
+    ) -> *mut hostent
+);
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+extern "C" fn gethostbyaddr_r(
+    addr: *const c_void,
+    len: socklen_t,
+    type_: c_int,
+    ret: *mut hostent,
+    buf: *mut c_char,
+    buflen: size_t,
+    result: *mut *mut hostent,
+    h_errnop: *mut c_int,
+) -> c_int {
+    let c_gethostbyaddr_r = core::GETHOSTBYADDR_R.expect("Cannot load symbol 'gethostbyaddr_r'");
+    if !crate::ensure_init() {
+        return unsafe { c_gethostbyaddr_r(addr, len, type_, ret, buf, buflen, result, h_errnop) };
+    }
+
+    trace!("gethostbyaddr_r hooked");
+
+    if let Some(ip) = reverse_target(addr, len, type_) {
+        unsafe { *result = std::ptr::null_mut() };
+        return match core::tor_resolve_ptr(ip.into()) {
+            Ok(hostname) => {
+                if !core::proxyc_gethostbyaddr_r(ip, &hostname, ret, buf, buflen) {
+                    return libc::ERANGE;
+                }
+                unsafe { *result = ret };
+                0
+            }
+            Err(e) => {
+                error!("{}", e);
+                let failure = Failure::of(&e);
+                if !h_errnop.is_null() {
+                    unsafe { *h_errnop = failure.h_errno() };
+                }
+                match failure {
+                    Failure::Again => libc::EAGAIN,
+                    _ => libc::ENOENT,
+                }
+            }
+        };
+    }
+
+    unsafe { c_gethostbyaddr_r(addr, len, type_, ret, buf, buflen, result, h_errnop) }
+}
+
+versioned!(
+    proxyc_v_gethostbyaddr_r => gethostbyaddr_r(
+        addr: *const c_void,
+        len: socklen_t,
+        type_: c_int,
+        ret: *mut hostent,
+        buf: *mut c_char,
+        buflen: size_t,
+        result: *mut *mut hostent,
+        h_errnop: *mut c_int,
+    ) -> c_int
+);
@@ -1,27 +1,64 @@
 use crate::core;
-use nix::libc::{c_char, hostent};
+use crate::filter;
+use crate::netdb;
+use crate::nss;
+use nix::libc::{self, c_char, hostent};
+use std::ffi::CStr;
 use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr};
 
 // The man page of gehostbyname states that it can return static data.
 static mut GETHOSTBYNAME_DATA: MaybeUninit<core::GetHostByNameData> = MaybeUninit::uninit();
 
 #[no_mangle]
-fn gethostbyname(name: *const c_char) -> *mut hostent {
+extern "C" fn gethostbyname(name: *const c_char) -> *mut hostent {
     let c_gethostbyname = core::GETHOSTBYNAME.expect("Cannot load symbol 'gethostbyname'");
+    if !crate::ensure_init() {
+        return unsafe { c_gethostbyname(name) };
+    }
 
     trace!("gethostbyname hooked");
 
     let config = &*core::CONFIG;
-    if config.proxy_dns {
-        let ptr = unsafe { GETHOSTBYNAME_DATA.as_mut_ptr() };
+    if config.proxy_dns && config.dry_run && !name.is_null() {
+        let name = unsafe { CStr::from_ptr(name) };
+        info!("dry-run: {:?} would be resolved by the proxy", name);
+    }
+
+    if core::proxies_name(name) {
+        let ptr = unsafe { (*std::ptr::addr_of_mut!(GETHOSTBYNAME_DATA)).as_mut_ptr() };
+        if config.nsswitch_sources && !name.is_null() {
+            let hostname = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+            if let Some(IpAddr::V4(ip)) = nss::lookup(&hostname, libc::AF_INET) {
+                return core::proxyc_gethostbyaddr(ip, &hostname, ptr);
+            }
+        }
         match core::proxyc_gethostbyname(name, ptr) {
             Ok(hs) => hs,
             Err(e) => {
                 error!("{}", e);
+                netdb::h_failed(&e);
                 std::ptr::null_mut()
             }
         }
     } else {
-        unsafe { c_gethostbyname(name) }
+        let hs = unsafe { c_gethostbyname(name) };
+        let mdns =
+            !name.is_null() && core::is_mdns(&unsafe { CStr::from_ptr(name) }.to_string_lossy());
+        if let Some(h) = unsafe { hs.as_ref() }.filter(|h| mdns && h.h_addrtype == libc::AF_INET) {
+            // the local network is not reachable through the proxies
+            let mut addr = h.h_addr_list;
+            while !unsafe { *addr }.is_null() {
+                let mut octets = [0; 4];
+                unsafe {
+                    std::ptr::copy_nonoverlapping(*addr as *const u8, octets.as_mut_ptr(), 4)
+                };
+                filter::bypass_mdns(Ipv4Addr::from(octets).into());
+                addr = unsafe { addr.add(1) };
+            }
+        }
+        hs
     }
 }
+
+versioned!(proxyc_v_gethostbyname => gethostbyname(name: *const c_char) -> *mut hostent);
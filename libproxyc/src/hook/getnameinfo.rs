@@ -0,0 +1,90 @@
+use crate::core;
+use crate::netdb;
+use nix::libc::{self, c_char, c_int, sockaddr, socklen_t};
+use nix::sys::socket::SockAddr;
+
+#[no_mangle]
+extern "C" fn getnameinfo(
+    sa: *const sockaddr,
+    salen: socklen_t,
+    host: *mut c_char,
+    hostlen: socklen_t,
+    serv: *mut c_char,
+    servlen: socklen_t,
+    flags: c_int,
+) -> c_int {
+    let c_getnameinfo = core::GETNAMEINFO.expect("Cannot load symbol 'getnameinfo'");
+    if !crate::ensure_init() {
+        return unsafe { c_getnameinfo(sa, salen, host, hostlen, serv, servlen, flags) };
+    }
+
+    trace!("getnameinfo hooked");
+
+    if !core::proxies_reverse_dns()
+        || host.is_null()
+        || hostlen == 0
+        || flags & libc::NI_NUMERICHOST != 0
+    {
+        return unsafe { c_getnameinfo(sa, salen, host, hostlen, serv, servlen, flags) };
+    }
+
+    let ip = match unsafe { core::from_libc_sockaddr(sa) } {
+        Some(SockAddr::Inet(addr)) => addr.ip().to_std(),
+        _ => return unsafe { c_getnameinfo(sa, salen, host, hostlen, serv, servlen, flags) },
+    };
+
+    // services are looked up locally, which does not leak anything.
+    if !serv.is_null() && servlen > 0 {
+        let ret =
+            unsafe { c_getnameinfo(sa, salen, std::ptr::null_mut(), 0, serv, servlen, flags) };
+        if ret != 0 {
+            return ret;
+        }
+    }
+
+    match core::tor_resolve_ptr(ip) {
+        Ok(hostname) => {
+            if hostname.len() >= hostlen as usize {
+                return libc::EAI_OVERFLOW;
+            }
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    hostname.as_ptr() as *const c_char,
+                    host,
+                    hostname.len(),
+                );
+                *host.add(hostname.len()) = 0;
+            }
+            0
+        }
+        Err(e) => {
+            error!("{}", e);
+            if flags & libc::NI_NAMEREQD != 0 {
+                return netdb::gai_failed(&e);
+            }
+            unsafe {
+                c_getnameinfo(
+                    sa,
+                    salen,
+                    host,
+                    hostlen,
+                    std::ptr::null_mut(),
+                    0,
+                    flags | libc::NI_NUMERICHOST,
+                )
+            }
+        }
+    }
+}
+
+versioned!(
+    proxyc_v_getnameinfo => getnameinfo(
+        sa: *const sockaddr,
+        salen: socklen_t,
+        host: *mut c_char,
+        hostlen: socklen_t,
+        serv: *mut c_char,
+        servlen: socklen_t,
+        flags: c_int,
+    ) -> c_int
+);
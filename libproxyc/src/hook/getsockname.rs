@@ -0,0 +1,35 @@
+use crate::conn;
+use crate::core::{self, CONFIG};
+use crate::udp;
+use nix::libc::{c_int, sockaddr, socklen_t};
+use std::os::unix::io::RawFd;
+
+#[no_mangle]
+extern "C" fn getsockname(sock: RawFd, addr: *mut sockaddr, addrlen: *mut socklen_t) -> c_int {
+    let c_getsockname = core::GETSOCKNAME.expect("Cannot load symbol 'getsockname'");
+    if !crate::ensure_init() {
+        return unsafe { c_getsockname(sock, addr, addrlen) };
+    }
+
+    trace!("getsockname hooked");
+
+    let ret = unsafe { c_getsockname(sock, addr, addrlen) };
+    if ret != 0 || !CONFIG.spoof_sockname {
+        return ret;
+    }
+
+    // report the address the peer sees instead of the local end of the
+    // connection to the first proxy.
+    if let Some(bound) = conn::bound(sock).or_else(|| udp::relay(sock)) {
+        unsafe { udp::write_sockaddr(sock, bound, addr, addrlen) };
+    }
+    ret
+}
+
+versioned!(
+    proxyc_v_getsockname => getsockname(
+        sock: RawFd,
+        addr: *mut sockaddr,
+        addrlen: *mut socklen_t,
+    ) -> c_int
+);
@@ -0,0 +1,31 @@
+use crate::core;
+use crate::netdb;
+use nix::libc::{c_char, c_int};
+use std::ffi::CStr;
+use std::io::Write;
+
+extern "C" {
+    fn hstrerror(err: c_int) -> *const c_char;
+}
+
+#[no_mangle]
+extern "C" fn herror(s: *const c_char) {
+    let c_herror = core::HERROR.expect("Cannot load symbol 'herror'");
+    let reason = match netdb::h_reason() {
+        Some(r) => r,
+        None => return unsafe { c_herror(s) },
+    };
+
+    let message = unsafe { CStr::from_ptr(hstrerror(netdb::h_errno())) }.to_string_lossy();
+    let prefix = match unsafe { s.as_ref() } {
+        Some(_) => unsafe { CStr::from_ptr(s) }.to_string_lossy(),
+        None => "".into(),
+    };
+    let line = match prefix.is_empty() {
+        true => format!("{} ({})\n", message, reason),
+        false => format!("{}: {} ({})\n", prefix, message, reason),
+    };
+    std::io::stderr().write_all(line.as_bytes()).ok();
+}
+
+versioned!(proxyc_v_herror => herror(s: *const c_char));
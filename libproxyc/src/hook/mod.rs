@@ -1,4 +1,42 @@
+/// Exports a forwarder to a hook under the versioned name of the hook, the
+/// version of the glibc symbol being set by build.rs. The `.symver` directive
+/// must live in the object defining the forwarder.
+macro_rules! versioned {
+    ($alias:ident => $hook:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?) => {
+        #[cfg(proxyc_symver)]
+        #[no_mangle]
+        extern "C" fn $alias($($arg: $ty),*) $(-> $ret)? {
+            $hook($($arg),*)
+        }
+
+        #[cfg(proxyc_symver)]
+        std::arch::global_asm!(concat!(
+            ".symver ",
+            stringify!($alias),
+            ", ",
+            stringify!($hook),
+            "@",
+            env!(concat!("PROXYC_SYMVER_", stringify!($hook)))
+        ));
+    };
+}
+
+pub mod accept;
+pub mod close;
 pub mod connect;
 pub mod freeaddrinfo;
+pub mod gai_strerror;
 pub mod getaddrinfo;
+pub mod gethostbyaddr;
 pub mod gethostbyname;
+pub mod getnameinfo;
+pub mod getsockname;
+pub mod herror;
+pub mod read;
+pub mod recvfrom;
+#[cfg(target_env = "gnu")]
+pub mod res_init;
+pub mod sendfile;
+pub mod sendto;
+pub mod socket;
+pub mod write;
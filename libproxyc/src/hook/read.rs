@@ -0,0 +1,14 @@
+use crate::core;
+use crate::hook::recvfrom::counted;
+use nix::libc::{c_void, size_t, ssize_t};
+use std::os::unix::io::RawFd;
+
+// Like write(), only accounts the bytes of the sockets registered once the
+// library is initialized, and does not initialize it.
+#[no_mangle]
+extern "C" fn read(fd: RawFd, buf: *mut c_void, count: size_t) -> ssize_t {
+    let c_read = core::READ.expect("Cannot load symbol 'read'");
+    counted(fd, unsafe { c_read(fd, buf, count) }, 0)
+}
+
+versioned!(proxyc_v_read => read(fd: RawFd, buf: *mut c_void, count: size_t) -> ssize_t);
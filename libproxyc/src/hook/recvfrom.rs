@@ -0,0 +1,155 @@
+use crate::conn;
+use crate::core;
+use crate::udp;
+use nix::libc::{self, c_int, c_void, msghdr, size_t, sockaddr, socklen_t, ssize_t};
+use std::os::unix::io::RawFd;
+
+fn fail(e: crate::error::Error) -> ssize_t {
+    error!("{}", e);
+    core::set_errno(match e {
+        crate::error::Error::Errno(errno) => errno,
+        _ => nix::errno::Errno::EIO,
+    });
+    -1
+}
+
+/// Accounts the bytes received on `sock`, returning the result of the
+/// receive. Peeked data is received again later.
+pub fn counted(sock: RawFd, ret: ssize_t, flags: c_int) -> ssize_t {
+    if ret > 0 && flags & libc::MSG_PEEK == 0 {
+        conn::count(sock, 0, ret as usize);
+    }
+    ret
+}
+
+#[no_mangle]
+extern "C" fn recvfrom(
+    sock: RawFd,
+    buf: *mut c_void,
+    len: size_t,
+    flags: c_int,
+    addr: *mut sockaddr,
+    addrlen: *mut socklen_t,
+) -> ssize_t {
+    let c_recvfrom = core::RECVFROM.expect("Cannot load symbol 'recvfrom'");
+    if !crate::ensure_init() {
+        return unsafe { c_recvfrom(sock, buf, len, flags, addr, addrlen) };
+    }
+
+    trace!("recvfrom hooked");
+
+    if buf.is_null() || !udp::is_associated(sock) {
+        let ret = unsafe { c_recvfrom(sock, buf, len, flags, addr, addrlen) };
+        return counted(sock, ret, flags);
+    }
+
+    let buf = unsafe { std::slice::from_raw_parts_mut(buf as *mut u8, len) };
+    match udp::recv_from(sock, buf, flags, &mut []) {
+        Ok(d) => {
+            unsafe { udp::write_sockaddr(sock, d.source, addr, addrlen) };
+            match flags & libc::MSG_TRUNC != 0 {
+                true => d.len as ssize_t,
+                false => d.len.min(len) as ssize_t,
+            }
+        }
+        Err(e) => fail(e),
+    }
+}
+
+versioned!(
+    proxyc_v_recvfrom => recvfrom(
+        sock: RawFd,
+        buf: *mut c_void,
+        len: size_t,
+        flags: c_int,
+        addr: *mut sockaddr,
+        addrlen: *mut socklen_t,
+    ) -> ssize_t
+);
+
+#[no_mangle]
+extern "C" fn recv(sock: RawFd, buf: *mut c_void, len: size_t, flags: c_int) -> ssize_t {
+    crate::ensure_init();
+    recvfrom(
+        sock,
+        buf,
+        len,
+        flags,
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+    )
+}
+
+versioned!(
+    proxyc_v_recv => recv(
+        sock: RawFd,
+        buf: *mut c_void,
+        len: size_t,
+        flags: c_int,
+    ) -> ssize_t
+);
+
+#[no_mangle]
+extern "C" fn recvmsg(sock: RawFd, msg: *mut msghdr, flags: c_int) -> ssize_t {
+    let c_recvmsg = core::RECVMSG.expect("Cannot load symbol 'recvmsg'");
+    if !crate::ensure_init() {
+        return unsafe { c_recvmsg(sock, msg, flags) };
+    }
+
+    trace!("recvmsg hooked");
+
+    if msg.is_null() || !udp::is_associated(sock) {
+        return counted(sock, unsafe { c_recvmsg(sock, msg, flags) }, flags);
+    }
+
+    let msg = unsafe { &mut *msg };
+    let iovs = match msg.msg_iov.is_null() {
+        true => &mut [][..],
+        false => unsafe { std::slice::from_raw_parts_mut(msg.msg_iov, msg.msg_iovlen) },
+    };
+
+    // receive in a single buffer, then scatter the payload
+    let total = iovs.iter().map(|v| v.iov_len).sum();
+    let mut buf = vec![0; total];
+    let control = match msg.msg_control.is_null() {
+        true => &mut [][..],
+        false => unsafe {
+            std::slice::from_raw_parts_mut(msg.msg_control as *mut u8, msg.msg_controllen)
+        },
+    };
+    let d = match udp::recv_from(sock, &mut buf, flags, control) {
+        Ok(d) => d,
+        Err(e) => return fail(e),
+    };
+
+    let mut remaining = &buf[..d.len.min(total)];
+    for iov in iovs {
+        let n = remaining.len().min(iov.iov_len);
+        unsafe { std::ptr::copy_nonoverlapping(remaining.as_ptr(), iov.iov_base as *mut u8, n) };
+        remaining = &remaining[n..];
+    }
+
+    unsafe {
+        udp::write_sockaddr(
+            sock,
+            d.source,
+            msg.msg_name as *mut sockaddr,
+            &mut msg.msg_namelen,
+        )
+    };
+    msg.msg_controllen = d.control_len as _;
+    msg.msg_flags = match d.len > total {
+        true => libc::MSG_TRUNC,
+        false => 0,
+    };
+    if d.control_truncated {
+        msg.msg_flags |= libc::MSG_CTRUNC;
+    }
+
+    match flags & libc::MSG_TRUNC != 0 {
+        true => d.len as ssize_t,
+        false => d.len.min(total) as ssize_t,
+    }
+}
+
+versioned!(proxyc_v_recvmsg => recvmsg(sock: RawFd, msg: *mut msghdr, flags: c_int) -> ssize_t);
@@ -0,0 +1,46 @@
+use crate::core;
+use crate::dns_server::{self, ResState};
+use nix::libc::c_int;
+
+extern "C" {
+    fn __res_state() -> *mut ResState;
+}
+
+/// Initializes the resolver state of the calling thread and points it at the
+/// DNS responder.
+pub fn init_resolver() -> c_int {
+    let c_res_init = core::RES_INIT.expect("Cannot load symbol '__res_init'");
+    let ret = unsafe { c_res_init() };
+    if ret == 0 {
+        dns_server::point(unsafe { __res_state() });
+    }
+    ret
+}
+
+#[no_mangle]
+extern "C" fn __res_init() -> c_int {
+    if !crate::ensure_init() {
+        let c_res_init = core::RES_INIT.expect("Cannot load symbol '__res_init'");
+        return unsafe { c_res_init() };
+    }
+
+    trace!("res_init hooked");
+
+    init_resolver()
+}
+
+#[no_mangle]
+extern "C" fn __res_ninit(state: *mut ResState) -> c_int {
+    let c_res_ninit = core::RES_NINIT.expect("Cannot load symbol '__res_ninit'");
+    if !crate::ensure_init() {
+        return unsafe { c_res_ninit(state) };
+    }
+
+    trace!("res_ninit hooked");
+
+    let ret = unsafe { c_res_ninit(state) };
+    if ret == 0 {
+        dns_server::point(state);
+    }
+    ret
+}
@@ -0,0 +1,151 @@
+use crate::absolute_uri;
+use crate::core;
+use crate::hook::recvfrom;
+use crate::hook::sendto::{self, counted};
+use crate::udp;
+use nix::errno::Errno;
+use nix::libc::{self, c_uint, c_void, loff_t, off64_t, off_t, size_t, ssize_t};
+use std::os::unix::io::RawFd;
+
+/// Most bytes copied by one call falling back to read and send.
+const MAX_COPY_LEN: usize = 64 * 1024;
+
+/// Whether the data sent on `sock` is rewritten by the hooks, which the
+/// kernel copying it by itself would bypass.
+fn rewritten(sock: RawFd) -> bool {
+    absolute_uri::is_pending(sock) || udp::is_associated(sock)
+}
+
+/// Sends up to `count` bytes of `in_fd` from `offset`, or from its file
+/// position if None, on `out_fd` through the send() hook, then moves the
+/// offset or the position past the bytes sent.
+fn copy(out_fd: RawFd, in_fd: RawFd, offset: Option<&mut off64_t>, count: size_t) -> ssize_t {
+    let start = match &offset {
+        Some(o) => **o,
+        None => unsafe { libc::lseek64(in_fd, 0, libc::SEEK_CUR) },
+    };
+    if start < 0 {
+        // not a file, programs fall back to read() and write() themselves
+        core::set_errno(Errno::EINVAL);
+        return -1;
+    }
+
+    let mut buf = vec![0u8; count.min(MAX_COPY_LEN)];
+    let read = unsafe { libc::pread64(in_fd, buf.as_mut_ptr() as *mut c_void, buf.len(), start) };
+    if read <= 0 {
+        return read;
+    }
+    let sent = sendto::send(out_fd, buf.as_ptr() as *const c_void, read as usize, 0);
+    if sent > 0 {
+        match offset {
+            Some(o) => *o += sent as off64_t,
+            None => unsafe {
+                libc::lseek64(in_fd, start + sent as off64_t, libc::SEEK_SET);
+            },
+        }
+    }
+    sent
+}
+
+// The bytes the kernel copies to and from the sockets are accounted like
+// those of send() and recv(), those of the sockets whose data is rewritten
+// go through the hooks instead.
+#[no_mangle]
+extern "C" fn sendfile(out_fd: RawFd, in_fd: RawFd, offset: *mut off_t, count: size_t) -> ssize_t {
+    let c_sendfile = core::SENDFILE.expect("Cannot load symbol 'sendfile'");
+    if !crate::ensure_init() {
+        return unsafe { c_sendfile(out_fd, in_fd, offset, count) };
+    }
+
+    trace!("sendfile hooked");
+
+    if !rewritten(out_fd) {
+        return counted(out_fd, unsafe { c_sendfile(out_fd, in_fd, offset, count) });
+    }
+    if offset.is_null() {
+        return copy(out_fd, in_fd, None, count);
+    }
+    let mut o = unsafe { *offset } as off64_t;
+    let ret = copy(out_fd, in_fd, Some(&mut o), count);
+    unsafe { *offset = o as off_t };
+    ret
+}
+
+versioned!(
+    proxyc_v_sendfile => sendfile(
+        out_fd: RawFd,
+        in_fd: RawFd,
+        offset: *mut off_t,
+        count: size_t,
+    ) -> ssize_t
+);
+
+#[no_mangle]
+extern "C" fn sendfile64(
+    out_fd: RawFd,
+    in_fd: RawFd,
+    offset: *mut off64_t,
+    count: size_t,
+) -> ssize_t {
+    let c_sendfile64 = core::SENDFILE64.expect("Cannot load symbol 'sendfile64'");
+    if !crate::ensure_init() {
+        return unsafe { c_sendfile64(out_fd, in_fd, offset, count) };
+    }
+
+    trace!("sendfile64 hooked");
+
+    if !rewritten(out_fd) {
+        return counted(out_fd, unsafe {
+            c_sendfile64(out_fd, in_fd, offset, count)
+        });
+    }
+    copy(out_fd, in_fd, unsafe { offset.as_mut() }, count)
+}
+
+versioned!(
+    proxyc_v_sendfile64 => sendfile64(
+        out_fd: RawFd,
+        in_fd: RawFd,
+        offset: *mut off64_t,
+        count: size_t,
+    ) -> ssize_t
+);
+
+// Pipes cannot be read back, splice() is refused on the sockets whose data
+// is rewritten, which programs handle by falling back to read() and write().
+#[no_mangle]
+extern "C" fn splice(
+    fd_in: RawFd,
+    off_in: *mut loff_t,
+    fd_out: RawFd,
+    off_out: *mut loff_t,
+    len: size_t,
+    flags: c_uint,
+) -> ssize_t {
+    let c_splice = core::SPLICE.expect("Cannot load symbol 'splice'");
+    if !crate::ensure_init() {
+        return unsafe { c_splice(fd_in, off_in, fd_out, off_out, len, flags) };
+    }
+
+    trace!("splice hooked");
+
+    if rewritten(fd_out) || udp::is_associated(fd_in) {
+        debug!("splice from {} to {} refused", fd_in, fd_out);
+        core::set_errno(Errno::EINVAL);
+        return -1;
+    }
+    let ret = unsafe { c_splice(fd_in, off_in, fd_out, off_out, len, flags) };
+    recvfrom::counted(fd_in, ret, 0);
+    counted(fd_out, ret)
+}
+
+versioned!(
+    proxyc_v_splice => splice(
+        fd_in: RawFd,
+        off_in: *mut loff_t,
+        fd_out: RawFd,
+        off_out: *mut loff_t,
+        len: size_t,
+        flags: c_uint,
+    ) -> ssize_t
+);
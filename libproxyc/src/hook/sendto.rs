@@ -0,0 +1,168 @@
+use crate::absolute_uri;
+use crate::conn;
+use crate::core;
+use crate::hook::connect;
+use crate::udp;
+use nix::libc::{self, c_int, c_void, size_t, sockaddr, socklen_t, ssize_t};
+use nix::sys::socket::SockAddr;
+use std::os::unix::io::RawFd;
+
+/// Sends through the relay if `sock` is relayed, returns None otherwise.
+fn relay_send(
+    sock: RawFd,
+    buf: *const c_void,
+    len: size_t,
+    flags: c_int,
+    addr: *const sockaddr,
+) -> Option<ssize_t> {
+    let dest = match unsafe { core::from_libc_sockaddr(addr) } {
+        Some(SockAddr::Inet(a)) => Some(a.to_std()),
+        Some(_) => return None,
+        None => None,
+    };
+
+    let relayed = match dest {
+        Some(d) => udp::is_relayed(sock, &d),
+        None => udp::is_associated(sock),
+    };
+    if !relayed || buf.is_null() {
+        return None;
+    }
+
+    let buf = unsafe { std::slice::from_raw_parts(buf as *const u8, len) };
+    Some(match udp::send_to(sock, buf, flags, dest) {
+        Ok(n) => n as ssize_t,
+        Err(e) => {
+            error!("{}", e);
+            core::set_errno(match e {
+                crate::error::Error::Errno(errno) => errno,
+                _ => nix::errno::Errno::ECONNREFUSED,
+            });
+            -1
+        }
+    })
+}
+
+/// Rewrites the first request of a socket forwarding plain http, returns None
+/// if `sock` does not.
+fn forward_send(sock: RawFd, buf: *const c_void, len: size_t, flags: c_int) -> Option<ssize_t> {
+    if buf.is_null() {
+        return None;
+    }
+    let c_sendto = core::SENDTO.expect("Cannot load symbol 'sendto'");
+    let buf = unsafe { std::slice::from_raw_parts(buf as *const u8, len) };
+    absolute_uri::send(sock, buf, |b| unsafe {
+        c_sendto(
+            sock,
+            b.as_ptr() as *const c_void,
+            b.len(),
+            flags,
+            std::ptr::null(),
+            0,
+        )
+    })
+}
+
+/// Accounts the bytes sent on `sock`, returning the result of the send.
+pub fn counted(sock: RawFd, ret: ssize_t) -> ssize_t {
+    if ret > 0 {
+        conn::count(sock, ret as usize, 0);
+    }
+    ret
+}
+
+/// Clears MSG_OOB on the connections through the proxies, which relay the
+/// stream without its urgent data: it is sent in band rather than lost.
+fn in_band(sock: RawFd, flags: c_int) -> c_int {
+    if flags & libc::MSG_OOB == 0 || !conn::is_proxied(sock) {
+        return flags;
+    }
+    debug!("socket {}: urgent data sent in band", sock);
+    flags & !libc::MSG_OOB
+}
+
+#[no_mangle]
+extern "C" fn sendto(
+    sock: RawFd,
+    buf: *const c_void,
+    len: size_t,
+    flags: c_int,
+    addr: *const sockaddr,
+    addrlen: socklen_t,
+) -> ssize_t {
+    let c_sendto = core::SENDTO.expect("Cannot load symbol 'sendto'");
+    if !crate::ensure_init() {
+        return unsafe { c_sendto(sock, buf, len, flags, addr, addrlen) };
+    }
+
+    trace!("sendto hooked");
+    let flags = in_band(sock, flags);
+
+    // TCP Fast Open connects with sendto(), fall back to a chained connect
+    // followed by a regular write of the payload.
+    if flags & libc::MSG_FASTOPEN != 0 {
+        if let Some(target) = unsafe { core::from_libc_sockaddr(addr) } {
+            if connect::check_socket(sock, &target).is_ok() {
+                if connect::connect(sock, addr, addrlen) != 0 {
+                    return -1;
+                }
+                let flags = flags & !libc::MSG_FASTOPEN;
+                let ret = unsafe { c_sendto(sock, buf, len, flags, std::ptr::null(), 0) };
+                return counted(sock, ret);
+            }
+        }
+    }
+
+    if let Some(ret) = relay_send(sock, buf, len, flags, addr) {
+        return ret;
+    }
+    if addr.is_null() {
+        if let Some(ret) = forward_send(sock, buf, len, flags) {
+            return counted(sock, ret);
+        }
+    }
+    counted(sock, unsafe {
+        c_sendto(sock, buf, len, flags, addr, addrlen)
+    })
+}
+
+versioned!(
+    proxyc_v_sendto => sendto(
+        sock: RawFd,
+        buf: *const c_void,
+        len: size_t,
+        flags: c_int,
+        addr: *const sockaddr,
+        addrlen: socklen_t,
+    ) -> ssize_t
+);
+
+#[no_mangle]
+pub extern "C" fn send(sock: RawFd, buf: *const c_void, len: size_t, flags: c_int) -> ssize_t {
+    let c_sendto = core::SENDTO.expect("Cannot load symbol 'sendto'");
+    if !crate::ensure_init() {
+        return unsafe { c_sendto(sock, buf, len, flags, std::ptr::null(), 0) };
+    }
+
+    trace!("send hooked");
+    let flags = in_band(sock, flags);
+
+    if let Some(ret) = relay_send(sock, buf, len, flags, std::ptr::null()) {
+        return ret;
+    }
+    if let Some(ret) = forward_send(sock, buf, len, flags) {
+        return counted(sock, ret);
+    }
+    counted(sock, unsafe {
+        c_sendto(sock, buf, len, flags, std::ptr::null(), 0)
+    })
+}
+
+versioned!(
+    proxyc_v_send => send(
+        sock: RawFd,
+        buf: *const c_void,
+        len: size_t,
+        flags: c_int,
+    ) -> ssize_t
+);
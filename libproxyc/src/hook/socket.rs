@@ -0,0 +1,42 @@
+use crate::core;
+use crate::filter;
+use nix::libc::c_int;
+use std::os::unix::io::RawFd;
+
+// These only keep the cache of the socket types current, and do not
+// initialize the library.
+#[no_mangle]
+extern "C" fn socket(domain: c_int, ty: c_int, protocol: c_int) -> c_int {
+    let c_socket = core::SOCKET.expect("Cannot load symbol 'socket'");
+    let fd = unsafe { c_socket(domain, ty, protocol) };
+    if fd >= 0 {
+        filter::created(fd, ty);
+    }
+    fd
+}
+
+versioned!(proxyc_v_socket => socket(domain: c_int, ty: c_int, protocol: c_int) -> c_int);
+
+#[no_mangle]
+extern "C" fn dup2(oldfd: RawFd, newfd: RawFd) -> c_int {
+    let c_dup2 = core::DUP2.expect("Cannot load symbol 'dup2'");
+    let fd = unsafe { c_dup2(oldfd, newfd) };
+    if fd >= 0 {
+        filter::forget(fd);
+    }
+    fd
+}
+
+versioned!(proxyc_v_dup2 => dup2(oldfd: RawFd, newfd: RawFd) -> c_int);
+
+#[no_mangle]
+extern "C" fn dup3(oldfd: RawFd, newfd: RawFd, flags: c_int) -> c_int {
+    let c_dup3 = core::DUP3.expect("Cannot load symbol 'dup3'");
+    let fd = unsafe { c_dup3(oldfd, newfd, flags) };
+    if fd >= 0 {
+        filter::forget(fd);
+    }
+    fd
+}
+
+versioned!(proxyc_v_dup3 => dup3(oldfd: RawFd, newfd: RawFd, flags: c_int) -> c_int);
@@ -0,0 +1,25 @@
+use crate::absolute_uri;
+use crate::core;
+use crate::hook::sendto::counted;
+use nix::libc::{c_void, size_t, ssize_t};
+use std::os::unix::io::RawFd;
+
+// The logger writes through this hook, which therefore neither logs nor
+// initializes the library: sockets only need rewriting or counting once it
+// is.
+#[no_mangle]
+extern "C" fn write(fd: RawFd, buf: *const c_void, count: size_t) -> ssize_t {
+    let c_write = core::WRITE.expect("Cannot load symbol 'write'");
+
+    if !buf.is_null() {
+        let data = unsafe { std::slice::from_raw_parts(buf as *const u8, count) };
+        let send = |b: &[u8]| unsafe { c_write(fd, b.as_ptr() as *const c_void, b.len()) };
+        if let Some(ret) = absolute_uri::send(fd, data, send) {
+            return counted(fd, ret);
+        }
+    }
+
+    counted(fd, unsafe { c_write(fd, buf, count) })
+}
+
+versioned!(proxyc_v_write => write(fd: RawFd, buf: *const c_void, count: size_t) -> ssize_t);
@@ -0,0 +1,54 @@
+/// Shutdown of the proxied connections exchanging nothing
+///
+/// A thread of each process opening proxied connections checks them every
+/// second, and shuts down the ones idle for longer than idle_timeout: the
+/// tunnel is torn down along the chain, and the program sees the connection
+/// closed. Traffic is tracked by the data-path hooks, and by the kernel for
+/// TCP sockets, which also sees the calls that are not hooked and the other
+/// processes sharing the socket.
+use crate::conn;
+use crate::core::CONFIG;
+use crate::util;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+/// Interval between two checks.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Process the checking thread runs in, a forked child starts its own.
+static WATCHER_PID: AtomicU32 = AtomicU32::new(0);
+
+/// Time since the kernel last sent or received data on a TCP socket.
+fn tcp_idle(fd: RawFd) -> Option<Duration> {
+    util::tcp_info(fd).map(|info| {
+        let ms = info.tcpi_last_data_sent.min(info.tcpi_last_data_recv);
+        Duration::from_millis(ms as u64)
+    })
+}
+
+/// Starts checking the connections of this process, unless done already or
+/// disabled.
+pub fn watch() {
+    let timeout = match CONFIG.idle_timeout {
+        Some(secs) => Duration::from_secs(secs as u64),
+        None => return,
+    };
+    let pid = std::process::id();
+    if WATCHER_PID.swap(pid, Ordering::Relaxed) == pid {
+        return;
+    }
+
+    let spawned = std::thread::Builder::new()
+        .name("proxyc-idle".into())
+        .spawn(move || loop {
+            std::thread::sleep(TICK);
+            for (fd, target) in conn::shut_idle(timeout, tcp_idle) {
+                info!("shut down idle connection to {} (fd {})", target, fd);
+            }
+        });
+    if let Err(e) = spawned {
+        error!("failed to spawn idle connection thread: {}", e);
+        WATCHER_PID.store(0, Ordering::Relaxed);
+    }
+}
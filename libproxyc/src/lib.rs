@@ -1,29 +1,137 @@
 #[macro_use]
 extern crate log;
-extern crate pretty_env_logger;
 
+mod absolute_uri;
+mod api;
+mod audit;
+mod chaos;
+mod conn;
 mod core;
+mod dns_server;
+mod dump;
 mod error;
+mod filter;
+mod handshake;
+mod health;
 mod hook;
+mod idle;
+mod logger;
+mod netdb;
+mod nss;
 mod proxy;
+#[cfg(feature = "quic")]
+mod quic;
+mod quota;
+mod random;
+mod reuse;
+mod rotation;
+mod route;
+mod stats;
+mod tls;
+mod transcript;
+mod udp;
+mod username;
 mod util;
+mod webhook;
+
+use nix::unistd::{getgid, getgroups, getuid};
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Initialization state: not started, running or done.
+static INIT_STATE: AtomicU8 = AtomicU8::new(0);
+
+/// Whether the hooks act in this process, decided when the library is
+/// loaded. The processes without a configuration, the library being preloaded
+/// system-wide, and those hooked_uids and hooked_gids leave out only call the
+/// C library.
+static HOOKED: Lazy<bool> = Lazy::new(|| {
+    if std::env::var_os("PROXYC_CONFIG").is_none() {
+        return false;
+    }
+    let mut gids = getgroups().unwrap_or_default();
+    gids.push(getgid());
+    let gids: Vec<u32> = gids.into_iter().map(|g| g.as_raw()).collect();
+    core::CONFIG.hooks(getuid().as_raw(), &gids)
+});
 
-static ONCE: std::sync::Once = std::sync::Once::new();
 /// This is called when our dynamic library is loaded, so we setup our internals
 /// here.
 #[no_mangle]
 #[link_section = ".init_array"]
 static LD_PRELOAD_INIT: extern "C" fn() = self::init;
 extern "C" fn init() {
-    ONCE.call_once(|| {
-        let config = &*core::CONFIG;
-        std::env::set_var("RUST_LOG", config.log_level.to_string());
-        pretty_env_logger::init();
-        debug!("init pid: {}", std::process::id());
-        info!("chain_type: {:?}", config.chain_type);
-        info!("proxies:");
-        for p in &config.proxies {
-            info!("\t{}", p);
+    ensure_init();
+}
+
+/// Sets up our internals unless done already, returning whether the hooks
+/// act in this process. Hooks call it as well, the constructors of preloaded
+/// libraries not being run in every case on Android. Calls made while it
+/// runs, from another thread or from a hook it calls, do not wait for it.
+pub fn ensure_init() -> bool {
+    if !*HOOKED {
+        return false;
+    }
+    if INIT_STATE.load(Ordering::Acquire) == 2 {
+        // the threads are started by the hooks rather than the constructor,
+        // and again in forked children which lose them
+        logger::ensure_thread();
+        dump::ensure_thread();
+        return true;
+    }
+    if INIT_STATE
+        .compare_exchange(0, 1, Ordering::Acquire, Ordering::Acquire)
+        .is_err()
+    {
+        return true;
+    }
+
+    let config = &*core::CONFIG;
+    logger::init(config.log_level);
+    debug!("init pid: {}", std::process::id());
+    info!("chain_type: {:?}", config.chain_type);
+    match &config.proxy_pool {
+        // pools hold thousands of proxies
+        Some(path) => info!("proxies: {} from {}", config.proxies.len(), path.display()),
+        None => {
+            info!("proxies:");
+            for p in &config.proxies {
+                info!("\t{}", p);
+            }
         }
-    });
+    }
+    stats::init();
+    dump::init();
+    health::init();
+    // programs querying the resolver seldom call res_init() first
+    #[cfg(target_env = "gnu")]
+    if config.dns_server {
+        hook::res_init::init_resolver();
+    }
+    // bionic does not run the destructors of preloaded libraries
+    #[cfg(target_os = "android")]
+    unsafe {
+        nix::libc::atexit(fini);
+    }
+
+    INIT_STATE.store(2, Ordering::Release);
+    true
+}
+
+/// This is called when our dynamic library is unloaded, usually when the
+/// process exits.
+#[cfg(not(target_os = "android"))]
+#[no_mangle]
+#[link_section = ".fini_array"]
+static LD_PRELOAD_FINI: extern "C" fn() = self::fini;
+extern "C" fn fini() {
+    if !*HOOKED {
+        return;
+    }
+    #[cfg(feature = "quic")]
+    quic::drain();
+    stats::dump();
+    audit::dump_open();
+    webhook::flush();
+    logger::flush();
 }
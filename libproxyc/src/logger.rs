@@ -0,0 +1,404 @@
+/// Lock-free logger, for hooks running inside signal handlers
+///
+/// Hooks may run inside signal handlers or while the program holds locks of
+/// its own, where allocating or locking is not allowed. Records are formatted
+/// on the stack into a fixed ring of slots, and written to stderr by a
+/// background thread. Records that do not fit in the ring are dropped and
+/// counted. The logger itself neither allocates nor locks, the Display
+/// implementations of the logged values may, and so may the hooks logging
+/// them.
+use crate::util;
+use log::{LevelFilter, Log, Metadata, Record};
+use nix::fcntl::OFlag;
+use nix::libc;
+use nix::unistd::{close, pipe2, read, write};
+use std::cell::UnsafeCell;
+use std::fmt::{self, Write};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering};
+
+/// Number of records the ring holds.
+const SLOTS: usize = 256;
+/// Longest record, longer ones are truncated.
+const RECORD_LEN: usize = 512;
+
+struct Slot {
+    /// Twice the lap of the ring the slot is free for, plus one once the
+    /// record of that lap is ready to be written.
+    seq: AtomicUsize,
+    len: UnsafeCell<usize>,
+    buf: UnsafeCell<[u8; RECORD_LEN]>,
+}
+
+// slots are only accessed by the side owning them, as told by `seq`.
+unsafe impl Sync for Slot {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const SLOT: Slot = Slot {
+    seq: AtomicUsize::new(0),
+    len: UnsafeCell::new(0),
+    buf: UnsafeCell::new([0; RECORD_LEN]),
+};
+
+struct Ring {
+    slots: [Slot; SLOTS],
+    /// Next position to be claimed by a producer.
+    head: AtomicUsize,
+    /// Next position to be drained, owned by whoever holds `draining`.
+    tail: AtomicUsize,
+    draining: AtomicBool,
+    dropped: AtomicUsize,
+}
+
+static RING: Ring = Ring::new();
+
+/// Ends of the pipe waking up the log thread.
+static WAKE_RX: AtomicI32 = AtomicI32::new(-1);
+static WAKE_TX: AtomicI32 = AtomicI32::new(-1);
+/// Inode of the pipe, its descriptors being checked before each write in
+/// case the program closed them and reused their number.
+static WAKE_INO: AtomicU64 = AtomicU64::new(0);
+/// Process running the log thread, records are written synchronously until
+/// it is started and in forked children.
+static DRAIN_PID: AtomicI32 = AtomicI32::new(-1);
+static STARTING: AtomicBool = AtomicBool::new(false);
+/// Whether a record was logged, processes logging nothing not needing the
+/// thread.
+static LOGGED: AtomicBool = AtomicBool::new(false);
+static COLORS: AtomicBool = AtomicBool::new(false);
+
+/// Formats into a fixed buffer, silently truncating.
+struct StackBuf {
+    buf: [u8; RECORD_LEN],
+    len: usize,
+}
+
+impl Write for StackBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(RECORD_LEN - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Ring {
+            slots: [SLOT; SLOTS],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Claims a slot and copies `record` to it, returns false when full.
+    fn push(&self, record: &[u8]) -> bool {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % SLOTS];
+            let lap = pos / SLOTS * 2;
+            let seq = slot.seq.load(Ordering::Acquire);
+            if seq == lap {
+                match self.head.compare_exchange_weak(
+                    pos,
+                    pos + 1,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe {
+                            let buf = &mut *slot.buf.get();
+                            buf[..record.len()].copy_from_slice(record);
+                            *slot.len.get() = record.len();
+                        }
+                        slot.seq.store(lap + 1, Ordering::Release);
+                        return true;
+                    }
+                    Err(p) => pos = p,
+                }
+            } else if seq < lap {
+                return false;
+            } else {
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Writes the ready records with `out`. Spins while another thread does,
+    /// never called from signal handlers.
+    fn drain(&self, mut out: impl FnMut(&[u8])) {
+        while self.draining.swap(true, Ordering::Acquire) {
+            std::thread::yield_now();
+        }
+
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % SLOTS];
+            let lap = pos / SLOTS * 2;
+            if slot.seq.load(Ordering::Acquire) != lap + 1 {
+                break;
+            }
+            unsafe {
+                let buf = &*slot.buf.get();
+                out(&buf[..*slot.len.get()]);
+            }
+            slot.seq.store(lap + 2, Ordering::Release);
+            pos += 1;
+        }
+        self.tail.store(pos, Ordering::Relaxed);
+
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            let mut line = StackBuf {
+                buf: [0; RECORD_LEN],
+                len: 0,
+            };
+            let _ = writeln!(line, " WARN  proxyc > {} log records dropped", dropped);
+            out(&line.buf[..line.len]);
+        }
+
+        self.draining.store(false, Ordering::Release);
+    }
+
+    /// Empties the ring in a forked child, whose records, and the thread
+    /// draining them, were left in the parent.
+    fn reset(&self) {
+        for slot in &self.slots {
+            slot.seq.store(0, Ordering::Relaxed);
+        }
+        self.head.store(0, Ordering::Relaxed);
+        self.tail.store(0, Ordering::Relaxed);
+        self.dropped.store(0, Ordering::Relaxed);
+        self.draining.store(false, Ordering::Release);
+    }
+}
+
+fn write_stderr(mut buf: &[u8]) {
+    while !buf.is_empty() {
+        match write(libc::STDERR_FILENO, buf) {
+            Ok(n) => buf = &buf[n..],
+            Err(nix::errno::Errno::EINTR) => (),
+            Err(_) => return,
+        }
+    }
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut line = StackBuf {
+            buf: [0; RECORD_LEN],
+            len: 0,
+        };
+        let level = record.level();
+        let _ = match COLORS.load(Ordering::Relaxed) {
+            true => {
+                let color = match level {
+                    log::Level::Error => 31,
+                    log::Level::Warn => 33,
+                    log::Level::Info => 32,
+                    log::Level::Debug => 34,
+                    log::Level::Trace => 35,
+                };
+                write!(
+                    line,
+                    " \x1b[{}m{:<5}\x1b[0m \x1b[1m{}\x1b[0m > {}",
+                    color,
+                    level,
+                    record.target(),
+                    record.args()
+                )
+            }
+            false => write!(
+                line,
+                " {:<5} {} > {}",
+                level,
+                record.target(),
+                record.args()
+            ),
+        };
+        // keep room for the newline of truncated records
+        line.len = line.len.min(RECORD_LEN - 1);
+        let _ = line.write_str("\n");
+        let line = &line.buf[..line.len];
+
+        // getpid() is async-signal-safe, and not cached by the libc
+        if unsafe { libc::getpid() } != DRAIN_PID.load(Ordering::Relaxed) {
+            LOGGED.store(true, Ordering::Relaxed);
+            write_stderr(line);
+            return;
+        }
+
+        if !RING.push(line) {
+            RING.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        let fd = WAKE_TX.load(Ordering::Relaxed);
+        if is_pipe(fd) {
+            // a full pipe already wakes the thread up
+            let _ = write(fd, &[0]);
+        }
+    }
+
+    fn flush(&self) {
+        RING.drain(write_stderr);
+    }
+}
+
+static LOGGER: Logger = Logger;
+
+/// Whether `fd` is still an end of the pipe.
+fn is_pipe(fd: RawFd) -> bool {
+    fd >= 0 && util::inode(fd) == Some(WAKE_INO.load(Ordering::Relaxed))
+}
+
+/// Installs the logger, records being written synchronously until the
+/// thread writing them is started.
+pub fn init(level: LevelFilter) {
+    if log::set_logger(&LOGGER).is_err() {
+        return;
+    }
+    log::set_max_level(level);
+    COLORS.store(
+        unsafe { libc::isatty(libc::STDERR_FILENO) } == 1,
+        Ordering::Relaxed,
+    );
+    unsafe {
+        libc::pthread_atfork(None, None, Some(reset_child));
+    }
+}
+
+/// A thread of the parent may have been pushing, draining or starting the log
+/// thread while it forked, the child would wait for it forever.
+extern "C" fn reset_child() {
+    RING.reset();
+    STARTING.store(false, Ordering::Relaxed);
+}
+
+/// Spawns the thread writing the records once some were logged, unless it
+/// runs in this process already. The hooks start it rather than the
+/// constructor, some programs requiring to be single threaded until their
+/// main function runs, and forked children start their own.
+pub fn ensure_thread() {
+    if !LOGGED.load(Ordering::Relaxed) {
+        return;
+    }
+    let pid = unsafe { libc::getpid() };
+    if DRAIN_PID.load(Ordering::Relaxed) == pid || STARTING.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    // the pipe of the parent would wake its thread up
+    for fd in [&WAKE_RX, &WAKE_TX] {
+        let fd = fd.swap(-1, Ordering::Relaxed);
+        if is_pipe(fd) {
+            let _ = close(fd);
+        }
+    }
+
+    match spawn() {
+        Ok(()) => {
+            DRAIN_PID.store(pid, Ordering::Relaxed);
+            STARTING.store(false, Ordering::Release);
+        }
+        // records keep being written synchronously, without trying again
+        Err(e) => error!("failed to start log thread: {}", e),
+    }
+}
+
+fn spawn() -> std::io::Result<()> {
+    // only the write end must not block
+    let (rx, tx) = pipe2(OFlag::O_CLOEXEC)?;
+    nix::fcntl::fcntl(tx, nix::fcntl::FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+    WAKE_INO.store(util::inode(rx).unwrap_or_default(), Ordering::Relaxed);
+
+    std::thread::Builder::new()
+        .name("proxyc-log".into())
+        .spawn(move || {
+            let mut buf = [0; 64];
+            // closed by the program
+            while is_pipe(rx) {
+                match read(rx, &mut buf) {
+                    Ok(0) => break,
+                    Ok(_) => RING.drain(write_stderr),
+                    Err(nix::errno::Errno::EINTR) => (),
+                    Err(_) => break,
+                }
+            }
+        })?;
+    WAKE_RX.store(rx, Ordering::Relaxed);
+    WAKE_TX.store(tx, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Writes the pending records, called on exit.
+pub fn flush() {
+    log::logger().flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drained(ring: &Ring) -> Vec<Vec<u8>> {
+        let mut records = vec![];
+        ring.drain(|r| records.push(r.to_vec()));
+        records
+    }
+
+    #[test]
+    fn records_are_drained_in_order_across_laps() {
+        let ring = Box::new(Ring::new());
+        for lap in 0..3 {
+            for i in 0..SLOTS {
+                assert!(ring.push(format!("{} {}", lap, i).as_bytes()));
+            }
+            assert!(!ring.push(b"full"));
+            let records = drained(&ring);
+            assert_eq!(records.len(), SLOTS);
+            assert_eq!(records[0], format!("{} 0", lap).as_bytes());
+            assert_eq!(
+                records[SLOTS - 1],
+                format!("{} {}", lap, SLOTS - 1).as_bytes()
+            );
+        }
+        assert!(drained(&ring).is_empty());
+    }
+
+    #[test]
+    fn dropped_records_are_reported() {
+        let ring = Box::new(Ring::new());
+        ring.push(b"kept");
+        ring.dropped.store(2, Ordering::Relaxed);
+        let records = drained(&ring);
+        assert_eq!(records[0], b"kept");
+        assert!(String::from_utf8_lossy(&records[1]).contains("2 log records dropped"));
+        assert_eq!(drained(&ring).len(), 0);
+    }
+
+    #[test]
+    fn reset_ring_is_empty_and_drainable() {
+        let ring = Box::new(Ring::new());
+        for _ in 0..SLOTS + 10 {
+            ring.push(b"parent");
+        }
+        drained(&ring);
+        ring.push(b"pending");
+        // forked while another thread was draining
+        ring.draining.store(true, Ordering::Relaxed);
+        ring.reset();
+        assert!(drained(&ring).is_empty());
+        assert!(ring.push(b"child"));
+        assert_eq!(drained(&ring), [b"child"]);
+    }
+}
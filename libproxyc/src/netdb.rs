@@ -0,0 +1,136 @@
+/// Error codes of the resolutions through the proxies
+///
+/// A resolution failing because the proxy is unreachable is reported as a
+/// temporary failure, one the proxy answered as a host not found, so that
+/// the programs retry or give up as they would with their own resolver.
+/// The reason is kept per thread, for gai_strerror() and herror() to show
+/// it along the usual message.
+use crate::error::Error;
+use nix::libc::{self, c_int};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+
+/// netdb.h error codes, not exposed by the libc crate.
+pub const HOST_NOT_FOUND: c_int = 1;
+pub const TRY_AGAIN: c_int = 2;
+pub const NO_RECOVERY: c_int = 3;
+
+extern "C" {
+    fn __h_errno_location() -> *mut c_int;
+}
+
+/// Returns the h_errno of the thread.
+pub fn h_errno() -> c_int {
+    unsafe { *__h_errno_location() }
+}
+
+/// Kind of a failed resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The proxy answered that the host does not exist.
+    NotFound,
+    /// The proxy could not be reached or did not answer in time.
+    Again,
+    /// Anything else, such as running out of internal addresses.
+    Fail,
+}
+
+impl Failure {
+    pub fn of(e: &Error) -> Self {
+        match e.root() {
+            Error::Timeout
+            | Error::Connect(_)
+            | Error::Errno(_)
+            | Error::MissingData
+            | Error::Unresolved(_)
+            | Error::Tls(_)
+            | Error::Quic(_) => Failure::Again,
+            Error::Io(e) => match e.kind() {
+                io::ErrorKind::HostUnreachable | io::ErrorKind::InvalidInput => Failure::NotFound,
+                io::ErrorKind::TimedOut
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NetworkUnreachable
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::Interrupted => Failure::Again,
+                _ => Failure::Fail,
+            },
+            Error::Socket
+            | Error::Unsupported { .. }
+            | Error::Generic(_)
+            | Error::Stage { .. }
+            | Error::Chain { .. }
+            | Error::Hop { .. } => Failure::Fail,
+        }
+    }
+
+    pub fn eai(self) -> c_int {
+        match self {
+            Failure::NotFound => libc::EAI_NONAME,
+            Failure::Again => libc::EAI_AGAIN,
+            Failure::Fail => libc::EAI_FAIL,
+        }
+    }
+
+    pub fn h_errno(self) -> c_int {
+        match self {
+            Failure::NotFound => HOST_NOT_FOUND,
+            Failure::Again => TRY_AGAIN,
+            Failure::Fail => NO_RECOVERY,
+        }
+    }
+}
+
+thread_local! {
+    /// Reasons of the last failures of the thread by EAI_* code, and their
+    /// messages once formatted.
+    static GAI_REASONS: RefCell<HashMap<c_int, (String, Option<CString>)>> =
+        RefCell::new(HashMap::new());
+    /// Last h_errno set by a failure of the thread, and its reason.
+    static H_REASON: RefCell<Option<(c_int, String)>> = const { RefCell::new(None) };
+}
+
+/// Returns the EAI_* code of a failed getaddrinfo() or getnameinfo(),
+/// recording its reason.
+pub fn gai_failed(e: &Error) -> c_int {
+    let code = Failure::of(e).eai();
+    GAI_REASONS.with(|r| r.borrow_mut().insert(code, (e.to_string(), None)));
+    code
+}
+
+/// Sets h_errno after a failed gethostby*() and returns it, recording its
+/// reason.
+pub fn h_failed(e: &Error) -> c_int {
+    let code = Failure::of(e).h_errno();
+    unsafe { *__h_errno_location() = code };
+    H_REASON.with(|r| *r.borrow_mut() = Some((code, e.to_string())));
+    code
+}
+
+/// Formats the message of an EAI_* code with the reason of the last failure
+/// of the thread with this code. The string stays valid until the thread
+/// fails again with the same code.
+pub fn gai_message(code: c_int, message: &str) -> Option<*const libc::c_char> {
+    GAI_REASONS.with(|r| {
+        let mut reasons = r.borrow_mut();
+        let (reason, formatted) = reasons.get_mut(&code)?;
+        if formatted.is_none() {
+            *formatted = CString::new(format!("{} ({})", message, reason)).ok();
+        }
+        formatted.as_ref().map(|m| m.as_ptr())
+    })
+}
+
+/// Returns the reason of the last failure of the thread if it set the
+/// current h_errno.
+pub fn h_reason() -> Option<String> {
+    let code = h_errno();
+    H_REASON.with(|r| match &*r.borrow() {
+        Some((c, reason)) if *c == code => Some(reason.clone()),
+        _ => None,
+    })
+}
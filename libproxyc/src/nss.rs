@@ -0,0 +1,185 @@
+/// Local sources of the hosts database
+///
+/// With nsswitch_sources, the sources of the hosts line of nsswitch.conf that
+/// answer without the network are consulted in order before a hostname is
+/// resolved through the proxies, stopping at the first network source (dns).
+/// They are emulated rather than called, glibc offering no way to query a
+/// single source:
+///
+/// - files: /etc/hosts.
+/// - myhostname: localhost, the hostname of the machine and _gateway.
+/// - resolve: what systemd-resolved answers locally, the above and its stub
+///   names.
+///
+/// The other sources are skipped.
+use nix::ifaddrs::getifaddrs;
+use nix::libc::{self, c_int};
+use nix::sys::socket::SockAddr;
+use nix::unistd::gethostname;
+use once_cell::sync::Lazy;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const NSSWITCH_PATH: &str = "/etc/nsswitch.conf";
+const HOSTS_PATH: &str = "/etc/hosts";
+const ROUTE_PATH: &str = "/proc/net/route";
+
+/// Sources of glibc when nsswitch.conf has no hosts line.
+const DEFAULT_SOURCES: &str = "dns [!UNAVAIL=return] files";
+
+/// Address nss-myhostname resolves the hostname to without configured
+/// addresses.
+const HOSTNAME_FALLBACK: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Files,
+    MyHostname,
+    Resolve,
+    /// Queries the network, the lookup continues through the proxies.
+    Dns,
+}
+
+/// Local sources consulted before the proxies, in order.
+static SOURCES: Lazy<Vec<Source>> = Lazy::new(|| {
+    let conf = fs::read_to_string(NSSWITCH_PATH).unwrap_or_default();
+    let line = conf
+        .lines()
+        .map(|l| l.split('#').next().unwrap_or_default())
+        .find_map(|l| l.trim().strip_prefix("hosts:"))
+        .unwrap_or(DEFAULT_SOURCES);
+    let sources: Vec<_> = line
+        .split_whitespace()
+        // actions such as [NOTFOUND=return] are not supported
+        .filter(|s| !s.starts_with('['))
+        .filter_map(|s| match s {
+            "files" => Some(Source::Files),
+            "myhostname" => Some(Source::MyHostname),
+            "resolve" => Some(Source::Resolve),
+            "dns" => Some(Source::Dns),
+            _ => None,
+        })
+        .take_while(|s| *s != Source::Dns)
+        .collect();
+    debug!("local hosts sources: {:?}", sources);
+    sources
+});
+
+/// Whether `ip` is of `family`, AF_UNSPEC matching both.
+fn of_family(ip: &IpAddr, family: c_int) -> bool {
+    match family {
+        libc::AF_INET => ip.is_ipv4(),
+        libc::AF_INET6 => ip.is_ipv6(),
+        _ => true,
+    }
+}
+
+/// Looks `name` up in /etc/hosts.
+fn files(name: &str, family: c_int) -> Option<IpAddr> {
+    let hosts = fs::read_to_string(HOSTS_PATH).ok()?;
+    hosts
+        .lines()
+        .map(|l| l.split('#').next().unwrap_or_default())
+        .find_map(|l| {
+            let mut fields = l.split_whitespace();
+            let ip: IpAddr = fields.next()?.parse().ok()?;
+            let found = of_family(&ip, family) && fields.any(|n| n.eq_ignore_ascii_case(name));
+            found.then_some(ip)
+        })
+}
+
+/// Returns the default IPv4 gateways.
+fn gateways() -> Vec<IpAddr> {
+    let routes = fs::read_to_string(ROUTE_PATH).unwrap_or_default();
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|l| {
+            let fields: Vec<_> = l.split_whitespace().collect();
+            // destination 0.0.0.0, gateway in host byte order
+            if fields.get(1) != Some(&"00000000") {
+                return None;
+            }
+            let gw = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(gw))))
+        })
+        .filter(|ip| !ip.is_unspecified())
+        .collect()
+}
+
+/// Returns the addresses configured on the interfaces other than loopback,
+/// global ones first.
+fn local_addresses() -> Vec<IpAddr> {
+    let mut addrs: Vec<IpAddr> = match getifaddrs() {
+        Ok(ifaddrs) => ifaddrs
+            .filter_map(|i| match i.address {
+                Some(SockAddr::Inet(addr)) => Some(addr.ip().to_std()),
+                _ => None,
+            })
+            .filter(|ip| !ip.is_loopback())
+            .collect(),
+        Err(_) => vec![],
+    };
+    addrs.sort_by_key(|ip| match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_unicast_link_local(),
+    });
+    addrs
+}
+
+/// Synthesizes the names of nss-myhostname.
+fn myhostname(name: &str, family: c_int) -> Option<IpAddr> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let is_localhost = |n: &str| {
+        n == "localhost"
+            || n.ends_with(".localhost")
+            || n == "localhost.localdomain"
+            || n.ends_with(".localhost.localdomain")
+    };
+
+    let candidates = if is_localhost(&name) {
+        vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()]
+    } else if name == "_gateway" {
+        gateways()
+    } else {
+        let mut buf = [0; 256];
+        let hostname = gethostname(&mut buf)
+            .ok()?
+            .to_str()
+            .ok()?
+            .to_ascii_lowercase();
+        if name != hostname {
+            return None;
+        }
+        let mut addrs = local_addresses();
+        addrs.push(HOSTNAME_FALLBACK.into());
+        addrs
+    };
+    candidates.into_iter().find(|ip| of_family(ip, family))
+}
+
+/// Synthesizes the stub names of systemd-resolved.
+fn resolved_stub(name: &str, family: c_int) -> Option<IpAddr> {
+    let ip: IpAddr = match name.trim_end_matches('.') {
+        "_localdnsstub" => Ipv4Addr::new(127, 0, 0, 53).into(),
+        "_localdnsproxy" => Ipv4Addr::new(127, 0, 0, 54).into(),
+        _ => return None,
+    };
+    Some(ip).filter(|ip| of_family(ip, family))
+}
+
+/// Resolves `name` with the local sources, None if the proxies resolve it.
+pub fn lookup(name: &str, family: c_int) -> Option<IpAddr> {
+    let ip = SOURCES.iter().find_map(|source| match source {
+        Source::Files => files(name, family),
+        Source::MyHostname => myhostname(name, family),
+        Source::Resolve => files(name, family)
+            .or_else(|| myhostname(name, family))
+            .or_else(|| resolved_stub(name, family)),
+        Source::Dns => None,
+    });
+    if let Some(ip) = ip {
+        debug!("{} resolved locally to {}", name, ip);
+    }
+    ip
+}
@@ -1,8 +1,6 @@
-use super::Proxy;
-use crate::core::CONFIG;
-use crate::error::Error;
-use crate::util::read_timeout;
-use nix::unistd::write;
+use super::{send, Bound, Proxy};
+use crate::error::{Error, Stage};
+use crate::util::{base64, read_timeout};
 use proxyc_common::{Auth, ProxyConf};
 use std::io;
 use std::os::unix::io::RawFd;
@@ -12,21 +10,48 @@ pub struct Http;
 impl Proxy for Http {
     type E = Error;
 
-    fn connect(sock: RawFd, target: &ProxyConf, _auth: Option<&Auth>) -> Result<(), Self::E> {
-        let config = &*CONFIG;
-        let ip = match target.ip {
-            std::net::IpAddr::V4(addr) => addr.to_string(),
-            std::net::IpAddr::V6(addr) => addr.to_string(),
-        };
+    fn connect(
+        sock: RawFd,
+        _proxy: &ProxyConf,
+        target: &ProxyConf,
+        auth: Option<&Auth>,
+        timeout: usize,
+    ) -> Result<Option<Bound>, Self::E> {
+        match Self::request(sock, target, auth, timeout) {
+            // rejected credentials are reported under the auth stage
+            Err(e @ Error::Stage { .. }) => Err(e),
+            res => res.map_err(|e| e.at(Stage::Request)),
+        }
+    }
+}
 
-        let packet = format!("CONNECT {}:{} HTTP/1.0\r\n\r\n", ip, target.port);
-        let packet = packet.as_bytes();
-        write(sock, packet)?;
+impl Http {
+    /// Sends a CONNECT request to `target`, with Basic credentials if `auth`
+    /// holds some, and reads the reply.
+    #[allow(clippy::io_other_error)]
+    fn request(
+        sock: RawFd,
+        target: &ProxyConf,
+        auth: Option<&Auth>,
+        timeout: usize,
+    ) -> Result<Option<Bound>, Error> {
+        // proxies named by hostname are resolved by this one
+        let mut packet = format!("CONNECT {} HTTP/1.0\r\n", target.host_port());
+        let mut secrets = vec![];
+        if let Some(Auth::UserPassword(user, password)) = auth {
+            packet.push_str("Proxy-Authorization: Basic ");
+            let credentials = base64(format!("{}:{}", user, password).as_bytes());
+            secrets.push(packet.len()..packet.len() + credentials.len());
+            packet.push_str(&credentials);
+            packet.push_str("\r\n");
+        }
+        packet.push_str("\r\n");
+        send(sock, packet.as_bytes(), &secrets)?;
 
         let mut len = 0;
         let mut buf = [0; 1024];
         while len < 1024 {
-            read_timeout(sock, &mut buf[len..len + 1], config.tcp_read_timeout)?;
+            read_timeout(sock, &mut buf[len..len + 1], timeout)?;
             len += 1;
             if len > 4
                 && (buf[len - 1] == b'\n'
@@ -38,10 +63,22 @@ impl Proxy for Http {
             }
         }
 
-        if len == 1024 || !(buf[9] == b'2' && buf[10] == b'0' && buf[11] == b'0') {
+        if len == 1024 {
             return Err(io::Error::new(io::ErrorKind::Other, "HTTP proxy blocked").into());
         }
+        match &buf[9..12] {
+            b"200" => (),
+            b"407" => {
+                let reason = match auth {
+                    Some(_) => "HTTP proxy rejected the credentials",
+                    None => "HTTP proxy requires authentication",
+                };
+                let e = io::Error::new(io::ErrorKind::PermissionDenied, reason);
+                return Err(Error::from(e).at(Stage::Auth));
+            }
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "HTTP proxy blocked").into()),
+        }
 
-        Ok(())
+        Ok(None)
     }
 }
@@ -1,15 +1,73 @@
+use crate::transcript;
 pub use http::Http;
+use nix::unistd::write;
 use proxyc_common::{Auth, ProxyConf};
-pub use socks::{Socks4, Socks5};
+pub use socks::{parse_udp_header, udp_header, Socks4, Socks5};
+use std::fmt;
+use std::net::SocketAddr;
+use std::ops::Range;
 use std::os::unix::io::RawFd;
 
 mod http;
 mod socks;
 
+/// Address a proxy bound for a connection, which socks5 servers may report
+/// by hostname.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bound {
+    Addr(SocketAddr),
+    Host(String, u16),
+}
+
+impl Bound {
+    /// Returns the bound address, unless reported by hostname.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            Bound::Addr(addr) => Some(*addr),
+            Bound::Host(..) => None,
+        }
+    }
+
+    /// Whether the proxy left the bound address unset.
+    fn is_unspecified(&self) -> bool {
+        match self {
+            Bound::Addr(addr) => addr.ip().is_unspecified(),
+            Bound::Host(host, _) => host.is_empty(),
+        }
+    }
+}
+
+impl fmt::Display for Bound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bound::Addr(addr) => write!(f, "{}", addr),
+            Bound::Host(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// Proxy handshakes with `proxy`, `timeout` being the read timeout in
+/// milliseconds.
+///
+/// `connect` returns the address the proxy bound for the connection, when
+/// its protocol reports one.
 pub trait Proxy {
     type E;
-    fn connect(sock: RawFd, target: &ProxyConf, auth: Option<&Auth>) -> Result<(), Self::E>;
-    fn authenticate(_sock: RawFd, _auth: Option<&Auth>) -> Result<(), Self::E> {
+    fn connect(
+        sock: RawFd,
+        proxy: &ProxyConf,
+        target: &ProxyConf,
+        auth: Option<&Auth>,
+        timeout: usize,
+    ) -> Result<Option<Bound>, Self::E>;
+    fn authenticate(_sock: RawFd, _auth: Option<&Auth>, _timeout: usize) -> Result<(), Self::E> {
         Ok(())
     }
 }
+
+/// Writes `packet` to `sock`, recording it in the transcript of the
+/// handshake with the bytes of `secrets` masked.
+fn send(sock: RawFd, packet: &[u8], secrets: &[Range<usize>]) -> nix::Result<usize> {
+    transcript::sent(packet, secrets);
+    write(sock, packet)
+}
@@ -1,13 +1,12 @@
-use super::Proxy;
-use crate::core::{CONFIG, INTERNALADDR};
-use crate::error::Error;
+use super::{send, Bound, Proxy};
+use crate::core::find_ip_hostname;
+use crate::error::{Error, Stage};
 use crate::util::read_timeout;
 use byteorder::{BigEndian, WriteBytesExt};
-use nix::unistd::write;
-use proxyc_common::{Auth, ProxyConf};
+use proxyc_common::{Auth, AuthMethod, ProxyConf, ProxyType};
 use std::io;
 use std::io::Write;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::os::unix::io::RawFd;
 
 pub struct Socks4;
@@ -16,31 +15,61 @@ pub struct Socks5;
 impl Proxy for Socks4 {
     type E = Error;
 
-    fn connect(sock: RawFd, target: &ProxyConf, _auth: Option<&Auth>) -> Result<(), Self::E> {
-        let config = &*CONFIG;
+    fn connect(
+        sock: RawFd,
+        _proxy: &ProxyConf,
+        target: &ProxyConf,
+        _auth: Option<&Auth>,
+        timeout: usize,
+    ) -> Result<Option<Bound>, Self::E> {
         let mut packet = vec![];
 
         let _ = packet.write_u8(4); // version
         let _ = packet.write_u8(1); // connect
 
-        match target.ip {
-            std::net::IpAddr::V4(addr) => {
+        // proxies named by hostname, and the internal addresses of proxy_dns,
+        // are resolved by this one with socks4a
+        let hostname = target
+            .hostname
+            .clone()
+            .or_else(|| find_ip_hostname(target.ip));
+
+        match (hostname, target.ip) {
+            (Some(hostname), _) => {
+                packet.write_u16::<BigEndian>(target.port)?;
+                // 0.0.0.x, x non-zero, announces the hostname
+                packet.write_u32::<BigEndian>(1)?;
+                packet.write_u8(0)?; // empty user
+                packet.write_all(hostname.as_bytes())?;
+                packet.write_u8(0)?;
+            }
+            (None, std::net::IpAddr::V4(addr)) => {
                 packet.write_u16::<BigEndian>(target.port)?;
                 packet.write_u32::<BigEndian>(addr.into())?;
                 // write user here
                 packet.write_u8(0)?;
             }
-            _ => {
-                return Err(Error::Generic(
-                    "address family not supported by socks4".into(),
-                ))
+            (None, _) => {
+                return Err(Error::Unsupported {
+                    proto: ProxyType::Socks4,
+                    what: "ipv6".into(),
+                }
+                .at(Stage::Request))
             }
         }
 
-        write(sock, &packet)?;
+        Self::request(sock, &packet, timeout).map_err(|e| e.at(Stage::Request))
+    }
+}
+
+impl Socks4 {
+    /// Sends a CONNECT request, returning the bound address of the reply.
+    #[allow(clippy::io_other_error)]
+    fn request(sock: RawFd, packet: &[u8], timeout: usize) -> Result<Option<Bound>, Error> {
+        send(sock, packet, &[])?;
 
         let mut buf = [0; 8];
-        read_timeout(sock, &mut buf, config.tcp_read_timeout)?;
+        read_timeout(sock, &mut buf, timeout)?;
 
         if buf[0] != 0 {
             return Err(
@@ -78,17 +107,20 @@ impl Proxy for Socks4 {
             }
         }
 
-        Ok(())
+        // socks4 servers usually leave the bound address zeroed
+        let ip = IpAddr::from(<[u8; 4]>::try_from(&buf[4..8]).unwrap());
+        let port = u16::from_be_bytes([buf[2], buf[3]]);
+        Ok(Some(Bound::Addr(SocketAddr::new(ip, port))).filter(|b| !b.is_unspecified()))
     }
 }
 
-fn write_hostname(mut packet: &mut [u8], target: &ProxyConf, hn: String) -> Result<usize, Error> {
+fn write_hostname(mut packet: &mut [u8], hn: &str, port: u16) -> Result<usize, Error> {
     let start_len = packet.len();
     let hn_len: u8 = hn.len().try_into().unwrap();
     packet.write_u8(3).unwrap(); // dns
     packet.write_u8(hn_len).unwrap();
     packet.write_all(hn.as_bytes()).unwrap();
-    packet.write_u16::<BigEndian>(target.port).unwrap();
+    packet.write_u16::<BigEndian>(port).unwrap();
     Ok(start_len - packet.len())
 }
 
@@ -109,10 +141,12 @@ fn write_addr(mut packet: &mut [u8], target: &ProxyConf) -> Result<usize, Error>
     Ok(start_len - packet.len())
 }
 
-fn read_response(sock: RawFd) -> Result<(), Error> {
+/// Reads the header of a reply, returning the type of the address that
+/// follows.
+#[allow(clippy::io_other_error)]
+fn read_response_header(sock: RawFd, timeout: usize) -> Result<u8, Error> {
     let mut buf = [0; 4];
-    let config = &*CONFIG;
-    read_timeout(sock, &mut buf, config.tcp_read_timeout)?;
+    read_timeout(sock, &mut buf, timeout)?;
 
     if buf[0] != 5 {
         return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid response version").into());
@@ -128,9 +162,17 @@ fn read_response(sock: RawFd) -> Result<(), Error> {
                 io::Error::new(io::ErrorKind::Other, "connection not allowed by ruleset").into(),
             )
         }
-        3 => return Err(io::Error::new(io::ErrorKind::Other, "network unreachable").into()),
-        4 => return Err(io::Error::new(io::ErrorKind::Other, "host unreachable").into()),
-        5 => return Err(io::Error::new(io::ErrorKind::Other, "connection refused").into()),
+        3 => {
+            return Err(
+                io::Error::new(io::ErrorKind::NetworkUnreachable, "network unreachable").into(),
+            )
+        }
+        4 => return Err(io::Error::new(io::ErrorKind::HostUnreachable, "host unreachable").into()),
+        5 => {
+            return Err(
+                io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused").into(),
+            )
+        }
         6 => return Err(io::Error::new(io::ErrorKind::Other, "TTL expired").into()),
         7 => return Err(io::Error::new(io::ErrorKind::Other, "command not supported").into()),
         8 => return Err(io::Error::new(io::ErrorKind::Other, "address kind not supported").into()),
@@ -141,52 +183,379 @@ fn read_response(sock: RawFd) -> Result<(), Error> {
         return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid reserved byte").into());
     }
 
+    Ok(buf[3])
+}
+
+/// Reads a reply, returning the bound address it carries.
+#[allow(clippy::io_other_error)]
+fn read_response(sock: RawFd, timeout: usize) -> Result<Bound, Error> {
     // read addr
-    let len = match buf[3] {
+    let atyp = read_response_header(sock, timeout)?;
+    let len = match atyp {
         1 => 4,
+        3 => {
+            let mut len = [0];
+            read_timeout(sock, &mut len, timeout)?;
+            len[0] as usize
+        }
         4 => 16,
         _ => return Err(io::Error::new(io::ErrorKind::Other, "unsupported address type").into()),
     };
 
     let mut buf = vec![0; len + 2];
-    read_timeout(sock, &mut buf, config.tcp_read_timeout)?;
+    read_timeout(sock, &mut buf, timeout)?;
+
+    let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
+    let ip = match atyp {
+        1 => IpAddr::from(<[u8; 4]>::try_from(&buf[..4]).unwrap()),
+        4 => IpAddr::from(<[u8; 16]>::try_from(&buf[..16]).unwrap()),
+        _ => {
+            buf.truncate(len);
+            let host = String::from_utf8(buf).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "bound hostname is not UTF-8")
+            })?;
+            return Ok(Bound::Host(host, port));
+        }
+    };
+    Ok(Bound::Addr(SocketAddr::new(ip, port)))
+}
+
+/// Returns the address of `bound`, for the replies which must carry one.
+fn bound_addr(bound: Bound) -> Result<SocketAddr, Error> {
+    bound.addr().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("reply carries hostname {} rather than an address", bound),
+        )
+        .into()
+    })
+}
+
+/// Builds the header prepended to datagrams sent to a UDP relay.
+pub fn udp_header(target: SocketAddr) -> Vec<u8> {
+    let mut packet = vec![0; 3 + 262];
+    let conf = ProxyConf {
+        proto: ProxyType::Raw,
+        ip: target.ip(),
+        port: target.port(),
+        auth: None,
+        alt_ips: vec![],
+        hostname: None,
+        country: None,
+        auth_methods: vec![],
+        check: None,
+    };
+    let len = match find_ip_hostname(target.ip()) {
+        Some(hn) if hn.len() <= 255 => write_hostname(&mut packet[3..], &hn, target.port()),
+        _ => write_addr(&mut packet[3..], &conf),
+    }
+    .expect("udp header buffer too small");
+    packet.truncate(3 + len);
+    packet
+}
 
-    Ok(())
+/// Parses the header of a datagram received from a UDP relay, returning the
+/// source it carries, its FRAG field and the length of the header.
+pub fn parse_udp_header(buf: &[u8]) -> Result<(SocketAddr, u8, usize), Error> {
+    let invalid = || {
+        Error::from(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid udp header",
+        ))
+    };
+
+    if buf.len() < 4 {
+        return Err(invalid());
+    }
+
+    let (ip, start) = match buf[3] {
+        1 if buf.len() >= 10 => (IpAddr::from(<[u8; 4]>::try_from(&buf[4..8]).unwrap()), 8),
+        4 if buf.len() >= 22 => (IpAddr::from(<[u8; 16]>::try_from(&buf[4..20]).unwrap()), 20),
+        _ => return Err(invalid()),
+    };
+    let port = u16::from_be_bytes([buf[start], buf[start + 1]]);
+
+    Ok((SocketAddr::new(ip, port), buf[2], start + 2))
 }
 
 impl Socks5 {
-    fn auth_id(auth: Option<&Auth>) -> u8 {
-        match auth {
-            Some(Auth::UserPassword { .. }) => 2,
-            None => 0,
+    fn auth_id(method: AuthMethod) -> u8 {
+        match method {
+            AuthMethod::None => 0,
+            AuthMethod::UserPass => 2,
         }
     }
-}
 
-fn find_ip_hostname(ip: IpAddr) -> Option<String> {
-    let config = &*CONFIG;
+    /// Negotiates the authentication method and authenticates. The methods
+    /// configured for `proxy` are offered, or the one matching `auth`.
+    fn greet(
+        sock: RawFd,
+        proxy: &ProxyConf,
+        auth: Option<&Auth>,
+        timeout: usize,
+    ) -> Result<(), Error> {
+        match Self::negotiate(sock, proxy, auth, timeout).map_err(|e| e.at(Stage::Greeting))? {
+            AuthMethod::None => Ok(()),
+            AuthMethod::UserPass => {
+                Self::authenticate(sock, auth, timeout).map_err(|e| e.at(Stage::Auth))
+            }
+        }
+    }
+
+    /// Negotiates the authentication method, returning the selected one.
+    #[allow(clippy::io_other_error)]
+    fn negotiate(
+        sock: RawFd,
+        proxy: &ProxyConf,
+        auth: Option<&Auth>,
+        timeout: usize,
+    ) -> Result<AuthMethod, Error> {
+        let methods = match (proxy.auth_methods.is_empty(), auth) {
+            (false, _) => proxy.auth_methods.clone(),
+            (true, Some(Auth::UserPassword { .. })) => vec![AuthMethod::UserPass],
+            (true, None) => vec![AuthMethod::None],
+        };
+
+        let mut packet = vec![
+            5,                   // version
+            methods.len() as u8, // methods
+        ];
+        packet.extend(methods.iter().map(|m| Self::auth_id(*m)));
+
+        send(sock, &packet, &[])?;
+
+        let mut buf = [0; 2];
+        read_timeout(sock, &mut buf, timeout)?;
+
+        let response_version = buf[0];
+        let selected_method = buf[1];
+
+        if response_version != 5 {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidData, "invalid response version").into(),
+            );
+        }
+
+        if selected_method == 0xff {
+            return Err(io::Error::new(io::ErrorKind::Other, "no acceptable auth method").into());
+        }
+
+        // hardened servers expect the choice to be checked
+        match methods
+            .into_iter()
+            .find(|m| Self::auth_id(*m) == selected_method)
+        {
+            Some(AuthMethod::None) => Ok(AuthMethod::None),
+            Some(AuthMethod::UserPass) if auth.is_some() => Ok(AuthMethod::UserPass),
+            Some(AuthMethod::UserPass) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "no credentials for the selected auth method",
+            )
+            .into()),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unoffered auth method {:#x} selected", selected_method),
+            )
+            .into()),
+        }
+    }
+
+    /// Builds a CONNECT request to `target`.
+    fn connect_request(target: &ProxyConf) -> Result<Vec<u8>, Error> {
+        let mut packet = [0; 264];
+        packet[0] = 5; // protocol version
+        packet[1] = 1; // connect
+        packet[2] = 0; // reserved
+
+        // proxies named by hostname are resolved by this one
+        let hnret = target
+            .hostname
+            .clone()
+            .or_else(|| find_ip_hostname(target.ip));
+
+        // write address
+        let len = match hnret {
+            Some(hn) => write_hostname(&mut packet[3..], &hn, target.port)?,
+            None => write_addr(&mut packet[3..], target)?,
+        };
+        Ok(packet[..len + 3].to_vec())
+    }
+
+    /// Builds a greeting offering no authentication followed by a CONNECT
+    /// request to `target`, sent without waiting for the replies.
+    pub fn pipelined_request(target: &ProxyConf) -> Result<Vec<u8>, Error> {
+        let mut packet = vec![
+            5, // version
+            1, // methods
+            Self::auth_id(AuthMethod::None),
+        ];
+        packet.extend(Self::connect_request(target)?);
+        Ok(packet)
+    }
+
+    /// Reads the replies to a pipelined request, returning the bound address.
+    pub fn pipelined_reply(sock: RawFd, timeout: usize) -> Result<Option<Bound>, Error> {
+        let greeting = || {
+            let mut buf = [0; 2];
+            read_timeout(sock, &mut buf, timeout)?;
+            match buf {
+                [5, 0] => Ok(()),
+                [5, 0xff] => Err(io::Error::other("no acceptable auth method").into()),
+                [5, m] => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unoffered auth method {:#x} selected", m),
+                )
+                .into()),
+                _ => Err(
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid response version").into(),
+                ),
+            }
+        };
+        greeting().map_err(|e: Error| e.at(Stage::Greeting))?;
 
-    if !config.proxy_dns {
-        return None;
+        let bound = read_response(sock, timeout).map_err(|e| e.at(Stage::Request))?;
+        Ok(Some(bound).filter(|b| !b.is_unspecified()))
     }
 
-    let internal_addr = &mut *INTERNALADDR.lock().expect("mutex poisoned");
-    if let std::net::IpAddr::V4(addr) = ip {
-        let parts = addr.octets();
-        let idx: u32 = addr.into();
-        if parts[0] == config.dns_subnet {
-            return internal_addr.get_hostname(idx).ok();
+    /// Resolves a hostname with the Tor RESOLVE (0xF0) extension.
+    pub fn resolve(
+        sock: RawFd,
+        proxy: &ProxyConf,
+        hostname: &str,
+        auth: Option<&Auth>,
+        timeout: usize,
+    ) -> Result<IpAddr, Error> {
+        if hostname.is_empty() || hostname.len() > 255 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid hostname").into());
         }
+
+        Self::greet(sock, proxy, auth, timeout)?;
+
+        let mut packet = [0; 264];
+        packet[0] = 5; // protocol version
+        packet[1] = 0xf0; // resolve
+        packet[2] = 0; // reserved
+        let len = write_hostname(&mut packet[3..], hostname, 0)?;
+        let request = || {
+            send(sock, &packet[..len + 3], &[])?;
+            read_response(sock, timeout)
+                .and_then(bound_addr)
+                .map(|addr| addr.ip())
+        };
+        request().map_err(|e| e.at(Stage::Request))
+    }
+
+    /// Requests a UDP relay with UDP ASSOCIATE, returning its address. The
+    /// relay lives as long as `sock` is open.
+    pub fn udp_associate(
+        sock: RawFd,
+        proxy: &ProxyConf,
+        auth: Option<&Auth>,
+        timeout: usize,
+    ) -> Result<SocketAddr, Error> {
+        Self::greet(sock, proxy, auth, timeout)?;
+
+        // the client address is not known in advance, let the relay accept
+        // datagrams from any.
+        let packet = [
+            5, // protocol version
+            3, // udp associate
+            0, // reserved
+            1, 0, 0, 0, 0, // ipv4 0.0.0.0
+            0, 0, // port 0
+        ];
+        let request = || {
+            send(sock, &packet, &[])?;
+            read_response(sock, timeout).and_then(bound_addr)
+        };
+        request().map_err(|e| e.at(Stage::Request))
+    }
+
+    /// Asks the proxy to listen for a connection with BIND, returning the
+    /// address it listens at. The connection is announced by a second reply,
+    /// read by bind_accepted().
+    pub fn bind(
+        sock: RawFd,
+        proxy: &ProxyConf,
+        auth: Option<&Auth>,
+        timeout: usize,
+    ) -> Result<Bound, Error> {
+        Self::greet(sock, proxy, auth, timeout)?;
+
+        // connections are accepted from any peer
+        let packet = [
+            5, // protocol version
+            2, // bind
+            0, // reserved
+            1, 0, 0, 0, 0, // ipv4 0.0.0.0
+            0, 0, // port 0
+        ];
+        let request = || {
+            send(sock, &packet, &[])?;
+            read_response(sock, timeout)
+        };
+        request().map_err(|e| e.at(Stage::Request))
+    }
+
+    /// Waits for the connection to the address of a BIND request, returning
+    /// the address of its peer. `sock` then carries the connection.
+    pub fn bind_accepted(sock: RawFd) -> Result<Bound, Error> {
+        // the peer may take any time to connect
+        read_response(sock, i32::MAX as usize).map_err(|e| e.at(Stage::Request))
+    }
+
+    /// Resolves an address to a hostname with the Tor RESOLVE_PTR (0xF1)
+    /// extension.
+    pub fn resolve_ptr(
+        sock: RawFd,
+        proxy: &ProxyConf,
+        ip: IpAddr,
+        auth: Option<&Auth>,
+        timeout: usize,
+    ) -> Result<String, Error> {
+        Self::greet(sock, proxy, auth, timeout)?;
+
+        let target = ProxyConf {
+            proto: ProxyType::Raw,
+            ip,
+            port: 0,
+            auth: None,
+            alt_ips: vec![],
+            hostname: None,
+            country: None,
+            auth_methods: vec![],
+            check: None,
+        };
+        let mut packet = [0; 264];
+        packet[0] = 5; // protocol version
+        packet[1] = 0xf1; // resolve_ptr
+        packet[2] = 0; // reserved
+        let len = write_addr(&mut packet[3..], &target)?;
+        let request = || {
+            send(sock, &packet[..len + 3], &[])?;
+
+            if read_response_header(sock, timeout)? != 3 {
+                return Err(io::Error::other("unexpected address type").into());
+            }
+
+            let mut len = [0; 1];
+            read_timeout(sock, &mut len, timeout)?;
+            let mut buf = vec![0; len[0] as usize + 2];
+            read_timeout(sock, &mut buf, timeout)?;
+            buf.truncate(len[0] as usize);
+
+            String::from_utf8(buf)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid hostname").into())
+        };
+        request().map_err(|e: Error| e.at(Stage::Request))
     }
-    None
 }
 
 impl Proxy for Socks5 {
     type E = Error;
 
-    fn authenticate(sock: RawFd, auth: Option<&Auth>) -> Result<(), Self::E> {
+    fn authenticate(sock: RawFd, auth: Option<&Auth>, timeout: usize) -> Result<(), Self::E> {
         if let Some(Auth::UserPassword(user, password)) = auth {
-            let config = &*CONFIG;
             if user.is_empty() || user.len() > 255 {
                 return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid username").into());
             };
@@ -202,10 +571,16 @@ impl Proxy for Socks5 {
             packet[2 + user.len()] = password.len() as u8;
             packet[3 + user.len()..packet_size].copy_from_slice(password.as_bytes());
 
-            write(sock, &packet[..packet_size])?;
+            // the user is masked along with the password, as some providers
+            // encode their settings in it
+            send(
+                sock,
+                &packet[..packet_size],
+                &[2..2 + user.len(), 3 + user.len()..packet_size],
+            )?;
 
             let mut buf = [0; 2];
-            read_timeout(sock, &mut buf, config.tcp_read_timeout)?;
+            read_timeout(sock, &mut buf, timeout)?;
 
             if buf[0] != 1 {
                 return Err(
@@ -223,63 +598,21 @@ impl Proxy for Socks5 {
         Ok(())
     }
 
-    fn connect(sock: RawFd, target: &ProxyConf, auth: Option<&Auth>) -> Result<(), Self::E> {
-        let config = &*CONFIG;
-
-        let methods = match target.auth {
-            Some(_) => 2,
-            None => 1,
+    fn connect(
+        sock: RawFd,
+        proxy: &ProxyConf,
+        target: &ProxyConf,
+        auth: Option<&Auth>,
+        timeout: usize,
+    ) -> Result<Option<Bound>, Self::E> {
+        Self::greet(sock, proxy, auth, timeout)?;
+        let request = || {
+            send(sock, &Self::connect_request(target)?, &[])?;
+            // read response + address on success
+            read_response(sock, timeout)
         };
+        let bound = request().map_err(|e| e.at(Stage::Request))?;
 
-        let packet = [
-            5,                   // version
-            methods,             // methods
-            Self::auth_id(auth), // method
-        ];
-
-        write(sock, &packet)?;
-
-        let mut buf = [0; 2];
-        read_timeout(sock, &mut buf, config.tcp_read_timeout)?;
-
-        let response_version = buf[0];
-        let selected_method = buf[1];
-
-        if response_version != 5 {
-            return Err(
-                io::Error::new(io::ErrorKind::InvalidData, "invalid response version").into(),
-            );
-        }
-
-        if selected_method == 0xff {
-            return Err(io::Error::new(io::ErrorKind::Other, "no acceptable auth method").into());
-        }
-
-        Self::authenticate(sock, auth)?;
-
-        let mut packet = [0; 264];
-        packet[0] = 5; // protocol version
-        packet[1] = 1; // connect
-        packet[2] = 0; // reserved
-
-        let hnret = find_ip_hostname(target.ip);
-
-        match hnret {
-            Some(hn) => {
-                // write address
-                let len = write_hostname(&mut packet[3..], target, hn)?;
-                write(sock, &packet[..len + 3])?;
-            }
-            None => {
-                // write address
-                let len = write_addr(&mut packet[3..], target)?;
-                write(sock, &packet[..len + 3])?;
-            }
-        }
-
-        // read response + address on success
-        read_response(sock)?;
-
-        Ok(())
+        Ok(Some(bound).filter(|b| !b.is_unspecified()))
     }
 }
@@ -0,0 +1,686 @@
+/// Transport of the chain over QUIC to an HTTP/3 proxy
+///
+/// The first hop is an HTTP/3 proxy, as run by MASQUE deployments, reaching
+/// the first proxy of the chain for each CONNECT request (RFC 9114, section
+/// 4.4). A single QUIC connection to it carries every chained connection in a
+/// request stream: streams do not block each other on lost packets, and the
+/// connection survives the changes of address of the client. The socket of
+/// the program remains a TCP socket, connected to a loopback socket whose
+/// other end a task relays through the DATA frames of the stream. The
+/// connection is closed on exit rather than left to time out on the proxy.
+///
+/// Only the static table of QPACK is used, no dynamic table being offered to
+/// the proxy.
+use crate::core::{self, Timeouts, CONFIG};
+use crate::error::{Error, Stage};
+use crate::tls;
+use crate::util::{base64, FdStream};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::socket::{
+    bind, getpeername, getsockname, listen, shutdown, socket, AddressFamily, InetAddr, Shutdown,
+    SockAddr, SockFlag, SockType,
+};
+use nix::unistd::{close, getpid, Pid};
+use once_cell::sync::Lazy;
+use proxyc_common::{Auth, ProxyConf, QuicProxy};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{
+    ClientConfig, Connection, Endpoint, ReadExactError, RecvStream, SendStream, TransportConfig,
+};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::runtime::{Handle, Runtime};
+use tokio::time::timeout;
+
+/// ALPN protocol of HTTP/3.
+const ALPN: &[u8] = b"h3";
+
+/// Interval of the keep-alives holding the path to the proxy open.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Longest time the exit waits for the close of the connection to be sent.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Frame types of HTTP/3.
+const FRAME_DATA: u64 = 0x0;
+const FRAME_HEADERS: u64 = 0x1;
+const FRAME_SETTINGS: u64 = 0x4;
+
+/// Type of the control stream of HTTP/3.
+const CONTROL_STREAM: u64 = 0x0;
+
+/// Longest header section of a response read.
+const MAX_FIELD_SECTION: u64 = 16384;
+
+/// Indexes of the :status entries of the QPACK static table, and their
+/// value.
+const STATIC_STATUS: [(u64, &str); 14] = [
+    (24, "103"),
+    (25, "200"),
+    (26, "304"),
+    (27, "404"),
+    (28, "503"),
+    (63, "100"),
+    (64, "204"),
+    (65, "206"),
+    (66, "302"),
+    (67, "400"),
+    (68, "403"),
+    (69, "421"),
+    (70, "425"),
+    (71, "500"),
+];
+
+/// Client configuration shared by the connections, or why it could not be
+/// built.
+static CLIENT_CONFIG: Lazy<Result<ClientConfig, String>> = Lazy::new(client_config);
+
+/// Runtime driving the connection to the proxy, and the connection once
+/// established.
+static CLIENT: Lazy<Mutex<Option<Client>>> = Lazy::new(|| Mutex::new(None));
+
+struct Client {
+    /// Process the runtime threads run in, a forked child starts over.
+    pid: Pid,
+    runtime: Runtime,
+    /// Endpoint of the connection, kept to drain it on exit.
+    endpoint: Option<Endpoint>,
+    connection: Option<Connection>,
+    /// Control stream of the connection, whose end would close it.
+    control: Option<SendStream>,
+}
+
+fn client_config() -> Result<ClientConfig, String> {
+    let ca_file = CONFIG.quic.as_ref().and_then(|q| q.ca_file.as_deref());
+    let roots = tls::root_store(ca_file)?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let crypto = QuicClientConfig::try_from(Arc::new(tls)).map_err(|e| e.to_string())?;
+    let mut config = ClientConfig::new(Arc::new(crypto));
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE));
+    config.transport_config(Arc::new(transport));
+    Ok(config)
+}
+
+fn quic_error(e: impl std::fmt::Display) -> Error {
+    Error::Quic(e.to_string())
+}
+
+fn stream_error(e: ReadExactError) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Runs `fut` on the runtime and waits for its result. The runtime is not
+/// entered, the calling thread may be one of the program's own runtime.
+fn run<T: Send + 'static>(
+    handle: &Handle,
+    fut: impl Future<Output = Result<T, Error>> + Send + 'static,
+) -> Result<T, Error> {
+    let (tx, rx) = sync_channel(1);
+    handle.spawn(async move {
+        tx.send(fut.await).ok();
+    });
+    rx.recv()
+        .map_err(|_| quic_error("the runtime stopped"))
+        .and_then(|r| r)
+}
+
+/// Returns the runtime and the live connection to the proxy, if any.
+fn client() -> Result<(Handle, Option<Connection>), Error> {
+    let mut client = CLIENT.lock().expect("mutex poisoned");
+    let pid = getpid();
+    if let Some(c) = client.take_if(|c| c.pid != pid) {
+        // the threads of the runtime were not forked
+        std::mem::forget(c);
+    }
+    let client = match client.as_mut() {
+        Some(c) => c,
+        None => {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .thread_name("proxyc-quic")
+                .enable_all()
+                .build()?;
+            client.insert(Client {
+                pid,
+                runtime,
+                endpoint: None,
+                connection: None,
+                control: None,
+            })
+        }
+    };
+    let connection = client
+        .connection
+        .clone()
+        .filter(|c| c.close_reason().is_none());
+    Ok((client.runtime.handle().clone(), connection))
+}
+
+/// Establishes the connection to the proxy and opens its control stream.
+fn establish(handle: &Handle, quic: &QuicProxy, timeouts: &Timeouts) -> Result<Connection, Error> {
+    let config = CLIENT_CONFIG.as_ref().map_err(quic_error)?.clone();
+    let proxy = &quic.proxy;
+    let addr = match &proxy.hostname {
+        Some(hostname) => core::resolve_local(hostname, proxy.port, libc::AF_UNSPEC)
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::Unresolved(hostname.clone()))?,
+        None => SocketAddr::new(proxy.ip, proxy.port),
+    };
+    let name = quic.server_name.clone().unwrap_or_else(|| proxy.host());
+    let bind = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    debug!("quic connect {}", addr);
+
+    let connect_timeout = Duration::from_millis(timeouts.connect as u64);
+    let (endpoint, connection, control) = run(handle, async move {
+        let endpoint = Endpoint::client(bind)?;
+        let connecting = endpoint
+            .connect_with(config, addr, &name)
+            .map_err(quic_error)?;
+        let connection = match timeout(connect_timeout, connecting).await {
+            Ok(res) => res.map_err(quic_error)?,
+            Err(_) => return Err(Error::Timeout),
+        };
+        // the settings are left to their defaults, without dynamic table
+        let mut control = connection.open_uni().await.map_err(quic_error)?;
+        let mut settings = vec![];
+        put_varint(&mut settings, CONTROL_STREAM);
+        settings.extend(frame(FRAME_SETTINGS, &[]));
+        control.write_all(&settings).await.map_err(quic_error)?;
+        Ok((endpoint, connection, control))
+    })?;
+
+    let mut client = CLIENT.lock().expect("mutex poisoned");
+    if let Some(c) = client.as_mut() {
+        c.endpoint = Some(endpoint);
+        c.connection = Some(connection.clone());
+        c.control = Some(control);
+    }
+    Ok(connection)
+}
+
+/// Connects `sock` to `to` with a CONNECT request of the proxy, with Basic
+/// credentials if `auth` holds some, connecting to the proxy first if needed.
+pub fn connect(
+    sock: RawFd,
+    quic: &QuicProxy,
+    to: &ProxyConf,
+    auth: Option<&Auth>,
+    timeouts: &Timeouts,
+) -> Result<(), Error> {
+    match open(sock, quic, to, auth, timeouts) {
+        // refused requests are reported under the request and auth stages
+        Err(e @ Error::Stage { .. }) => Err(e),
+        res => res.map_err(|e| e.at(Stage::Quic)),
+    }
+}
+
+fn open(
+    sock: RawFd,
+    quic: &QuicProxy,
+    to: &ProxyConf,
+    auth: Option<&Auth>,
+    timeouts: &Timeouts,
+) -> Result<(), Error> {
+    let (handle, connection) = client()?;
+    let connection = match connection {
+        Some(c) => c,
+        None => establish(&handle, quic, timeouts)?,
+    };
+    debug!("quic request {}", to.host_port());
+
+    // proxies named by hostname are resolved by this one
+    let headers = frame(FRAME_HEADERS, &connect_headers(&to.host_port(), auth));
+    let has_auth = auth.is_some();
+    let open_timeout = Duration::from_millis(timeouts.connect as u64);
+    let read_timeout = Duration::from_millis(timeouts.read as u64);
+    let (mut send, recv) = run(&handle, async move {
+        let (mut send, mut recv) = match timeout(open_timeout, connection.open_bi()).await {
+            Ok(res) => res.map_err(quic_error)?,
+            Err(_) => return Err(Error::Timeout),
+        };
+        send.write_all(&headers).await.map_err(quic_error)?;
+        match timeout(read_timeout, response(&mut recv)).await {
+            Ok(status) => check_status(status?, has_auth)?,
+            Err(_) => return Err(Error::Timeout.at(Stage::Request)),
+        }
+        Ok((send, recv))
+    })?;
+
+    let local = match loopback(sock, timeouts) {
+        Ok(local) => local,
+        Err(e) => {
+            send.reset(0u32.into()).ok();
+            return Err(e);
+        }
+    };
+    debug!("quic stream {} opened", send.id());
+
+    handle.spawn(async move {
+        if let Err(e) = relay_stream(local, send, recv).await {
+            debug!("quic relay: {}", e);
+        }
+        close(local).ok();
+    });
+    Ok(())
+}
+
+/// Connects `sock` to a loopback socket of its family, and returns the other
+/// end of the connection. `sock` remains a TCP socket for the program.
+fn loopback(sock: RawFd, timeouts: &Timeouts) -> Result<RawFd, Error> {
+    let (family, ip) = match core::is_ipv6_socket(sock) {
+        true => (AddressFamily::Inet6, IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        false => (AddressFamily::Inet, IpAddr::V4(Ipv4Addr::LOCALHOST)),
+    };
+    let listener = socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)?;
+    let res = accept_loopback(listener, sock, ip, timeouts);
+    close(listener).ok();
+    res
+}
+
+fn accept_loopback(
+    listener: RawFd,
+    sock: RawFd,
+    ip: IpAddr,
+    timeouts: &Timeouts,
+) -> Result<RawFd, Error> {
+    let c_accept4 = core::ACCEPT4.expect("Cannot load symbol 'accept4'");
+
+    let addr = SockAddr::new_inet(InetAddr::from_std(&SocketAddr::new(ip, 0)));
+    bind(listener, &addr)?;
+    listen(listener, 1)?;
+    core::timed_connect(sock, &getsockname(listener)?, timeouts.connect)?;
+
+    // the accepted socket is not one of the program's
+    let flags = libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK;
+    let local =
+        Errno::result(unsafe { c_accept4(listener, ptr::null_mut(), ptr::null_mut(), flags) })?;
+    // another local process may have connected first
+    if getpeername(local).ok() != getsockname(sock).ok() {
+        close(local).ok();
+        return Err(quic_error("the loopback connection was taken over"));
+    }
+    Ok(local)
+}
+
+/// Closes the connection to the proxy when the process exits, for the proxy
+/// to release the streams at once rather than when the connection times out.
+pub fn drain() {
+    let (handle, endpoint, connection) = match Lazy::get(&CLIENT).map(|c| c.try_lock()) {
+        Some(Ok(client)) => match client.as_ref() {
+            Some(c) if c.pid == getpid() => (
+                c.runtime.handle().clone(),
+                c.endpoint.clone(),
+                c.connection.clone(),
+            ),
+            _ => return,
+        },
+        _ => return,
+    };
+    let connection = match connection.filter(|c| c.close_reason().is_none()) {
+        Some(c) => c,
+        None => return,
+    };
+    debug!("closing the quic connection");
+
+    let (tx, rx) = sync_channel(1);
+    handle.spawn(async move {
+        // H3_NO_ERROR
+        connection.close(0x100u32.into(), b"exit");
+        if let Some(endpoint) = endpoint {
+            timeout(DRAIN_TIMEOUT, endpoint.wait_idle()).await.ok();
+        }
+        tx.send(()).ok();
+    });
+    rx.recv_timeout(DRAIN_TIMEOUT * 2).ok();
+}
+
+/// Checks the status of the response to a CONNECT request.
+fn check_status(status: u16, has_auth: bool) -> Result<(), Error> {
+    match status {
+        200..=299 => Ok(()),
+        407 => {
+            let reason = match has_auth {
+                true => "HTTP/3 proxy rejected the credentials",
+                false => "HTTP/3 proxy requires authentication",
+            };
+            let e = io::Error::new(io::ErrorKind::PermissionDenied, reason);
+            Err(Error::from(e).at(Stage::Auth))
+        }
+        _ => {
+            let e = io::Error::other(format!("HTTP/3 proxy answered {}", status));
+            Err(Error::from(e).at(Stage::Request))
+        }
+    }
+}
+
+/// Reads the response to a request, skipping the interim ones, and returns
+/// its status.
+async fn response(recv: &mut RecvStream) -> Result<u16, Error> {
+    loop {
+        let (ty, len) = read_frame_header(recv).await?.ok_or(Error::MissingData)?;
+        match ty {
+            FRAME_HEADERS if len <= MAX_FIELD_SECTION => {
+                let mut block = vec![0; len as usize];
+                recv.read_exact(&mut block).await.map_err(stream_error)?;
+                match response_status(&block) {
+                    Some(100..=199) => (),
+                    Some(status) => return Ok(status),
+                    None => return Err(quic_error("malformed response headers")),
+                }
+            }
+            FRAME_HEADERS => return Err(quic_error("response headers too long")),
+            FRAME_DATA => return Err(quic_error("DATA frame before the response")),
+            // reserved and unknown frame types are ignored
+            _ => skip(recv, len).await?,
+        }
+    }
+}
+
+/// Relays `local` through the DATA frames of a request stream until both
+/// directions are closed.
+async fn relay_stream(local: RawFd, mut send: SendStream, mut recv: RecvStream) -> io::Result<()> {
+    let local = AsyncFd::new(FdStream(local))?;
+
+    let upload = async {
+        let mut buf = [0; 16384];
+        loop {
+            let mut guard = local.readable().await?;
+            match guard.try_io(|fd| FdStream(fd.as_raw_fd()).read(&mut buf)) {
+                Ok(Ok(0)) => break,
+                Ok(Ok(n)) => {
+                    let mut header = vec![];
+                    put_varint(&mut header, FRAME_DATA);
+                    put_varint(&mut header, n as u64);
+                    send.write_all(&header).await?;
+                    send.write_all(&buf[..n]).await?;
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(_would_block) => continue,
+            }
+        }
+        send.finish().map_err(io::Error::other)
+    };
+
+    let download = async {
+        let mut buf = [0; 16384];
+        let res = async {
+            while let Some((ty, mut len)) = read_frame_header(&mut recv).await? {
+                while len > 0 {
+                    let n = len.min(buf.len() as u64) as usize;
+                    recv.read_exact(&mut buf[..n]).await.map_err(stream_error)?;
+                    len -= n as u64;
+                    // trailers and unknown frames are skipped
+                    if ty != FRAME_DATA {
+                        continue;
+                    }
+                    let mut data = &buf[..n];
+                    while !data.is_empty() {
+                        let mut guard = local.writable().await?;
+                        match guard.try_io(|fd| FdStream(fd.as_raw_fd()).write(data)) {
+                            Ok(Ok(n)) => data = &data[n..],
+                            Ok(Err(e)) => return Err(e),
+                            Err(_would_block) => continue,
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+        .await;
+        // the program sees the end of the stream, reset or not
+        shutdown(local.as_raw_fd(), Shutdown::Write).ok();
+        res
+    };
+
+    let (up, down) = tokio::join!(upload, download);
+    up.and(down)
+}
+
+/// Reads the type and length of the next frame, None at the end of the
+/// stream.
+async fn read_frame_header(recv: &mut RecvStream) -> io::Result<Option<(u64, u64)>> {
+    let ty = match read_varint(recv).await? {
+        Some(ty) => ty,
+        None => return Ok(None),
+    };
+    match read_varint(recv).await? {
+        Some(len) => Ok(Some((ty, len))),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// Reads a variable-length integer of QUIC, None at the end of the stream.
+async fn read_varint(recv: &mut RecvStream) -> io::Result<Option<u64>> {
+    let mut buf = [0; 8];
+    match recv.read_exact(&mut buf[..1]).await {
+        Ok(()) => (),
+        Err(ReadExactError::FinishedEarly(0)) => return Ok(None),
+        Err(e) => return Err(stream_error(e)),
+    }
+    // the two high bits give the length
+    let len = 1 << (buf[0] >> 6);
+    buf[0] &= 0x3f;
+    recv.read_exact(&mut buf[1..len])
+        .await
+        .map_err(stream_error)?;
+    Ok(Some(buf[..len].iter().fold(0, |v, b| v << 8 | *b as u64)))
+}
+
+/// Reads and drops `len` bytes of the stream.
+async fn skip(recv: &mut RecvStream, mut len: u64) -> io::Result<()> {
+    let mut buf = [0; 1024];
+    while len > 0 {
+        let n = len.min(buf.len() as u64) as usize;
+        recv.read_exact(&mut buf[..n]).await.map_err(stream_error)?;
+        len -= n as u64;
+    }
+    Ok(())
+}
+
+/// Appends a variable-length integer of QUIC.
+fn put_varint(buf: &mut Vec<u8>, v: u64) {
+    match v {
+        0..=0x3f => buf.push(v as u8),
+        0x40..=0x3fff => buf.extend((v as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend((v as u32 | 0x8000_0000).to_be_bytes()),
+        _ => buf.extend((v | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+/// Returns a frame of type `ty` carrying `payload`.
+fn frame(ty: u64, payload: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    put_varint(&mut buf, ty);
+    put_varint(&mut buf, payload.len() as u64);
+    buf.extend(payload);
+    buf
+}
+
+/// Encodes the header section of a CONNECT request to `authority`, with
+/// Basic credentials if `auth` holds some.
+fn connect_headers(authority: &str, auth: Option<&Auth>) -> Vec<u8> {
+    // required insert count and base, the dynamic table is not used
+    let mut block = vec![0, 0];
+    // :method CONNECT, index 15 of the static table
+    put_int(&mut block, 0xc0, 6, 15);
+    // :authority, named by index 0 of the static table
+    put_int(&mut block, 0x50, 4, 0);
+    put_string(&mut block, 0, 7, authority.as_bytes());
+    if let Some(Auth::UserPassword(user, password)) = auth {
+        let credentials = base64(format!("{}:{}", user, password).as_bytes());
+        // literal name, never to be indexed by the intermediaries
+        put_string(&mut block, 0x30, 3, b"proxy-authorization");
+        put_string(
+            &mut block,
+            0,
+            7,
+            format!("Basic {}", credentials).as_bytes(),
+        );
+    }
+    block
+}
+
+/// Returns the status of a response header section, None if it could not be
+/// decoded without the dynamic table.
+fn response_status(mut block: &[u8]) -> Option<u16> {
+    let buf = &mut block;
+    // required insert count, and base
+    if get_int(buf, 8)? != 0 {
+        return None;
+    }
+    get_int(buf, 7)?;
+
+    while let Some(&b) = buf.first() {
+        let status = if b & 0x80 != 0 {
+            // indexed field line, of the static table if T is set
+            let index = get_int(buf, 6)?;
+            if b & 0x40 == 0 {
+                return None;
+            }
+            STATIC_STATUS
+                .iter()
+                .find(|(i, _)| *i == index)
+                .map(|(_, s)| s.to_string())
+        } else if b & 0x40 != 0 {
+            // literal field line with a name reference
+            let index = get_int(buf, 4)?;
+            let value = get_string(buf, 7)?;
+            if b & 0x10 == 0 {
+                return None;
+            }
+            match STATIC_STATUS.iter().any(|(i, _)| *i == index) {
+                true => Some(string_value(value)?),
+                false => None,
+            }
+        } else if b & 0x20 != 0 {
+            // literal field line with a literal name
+            let name = get_string(buf, 3)?;
+            let value = get_string(buf, 7)?;
+            match name == (false, &b":status"[..]) {
+                true => Some(string_value(value)?),
+                false => None,
+            }
+        } else {
+            // field lines referring to the dynamic table after the base
+            return None;
+        };
+        if let Some(status) = status {
+            return status.parse().ok();
+        }
+    }
+    None
+}
+
+/// Appends a QPACK integer of an `n` bits prefix, the first byte starting
+/// with `flags`.
+fn put_int(buf: &mut Vec<u8>, flags: u8, n: u32, mut v: u64) {
+    let max = (1 << n) - 1;
+    if v < max {
+        buf.push(flags | v as u8);
+        return;
+    }
+    buf.push(flags | max as u8);
+    v -= max;
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// Appends a QPACK string literal of an `n` bits length prefix, not Huffman
+/// encoded.
+fn put_string(buf: &mut Vec<u8>, flags: u8, n: u32, s: &[u8]) {
+    put_int(buf, flags, n, s.len() as u64);
+    buf.extend(s);
+}
+
+/// Reads a QPACK integer of an `n` bits prefix from the start of `buf`.
+fn get_int(buf: &mut &[u8], n: u32) -> Option<u64> {
+    let (first, rest) = buf.split_first()?;
+    *buf = rest;
+    let max = (1 << n) - 1;
+    let mut v = *first as u64 & max;
+    if v < max {
+        return Some(v);
+    }
+    for shift in (0..63).step_by(7) {
+        let (b, rest) = buf.split_first()?;
+        *buf = rest;
+        v = v.checked_add((*b as u64 & 0x7f) << shift)?;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+/// Reads a QPACK string literal of an `n` bits length prefix from the start
+/// of `buf`. Returns whether it is Huffman encoded, and its bytes.
+fn get_string<'a>(buf: &mut &'a [u8], n: u32) -> Option<(bool, &'a [u8])> {
+    let huffman = buf.first()? & (1 << n) != 0;
+    let len = get_int(buf, n)? as usize;
+    if len > buf.len() {
+        return None;
+    }
+    let (s, rest) = buf.split_at(len);
+    *buf = rest;
+    Some((huffman, s))
+}
+
+/// Decodes a status code, the digits of the Huffman code of HPACK taking 5
+/// or 6 bits.
+fn string_value((huffman, s): (bool, &[u8])) -> Option<String> {
+    if !huffman {
+        return String::from_utf8(s.to_vec()).ok();
+    }
+    let bits = s.len() * 8;
+    let bit = |i: usize| (s[i / 8] >> (7 - i % 8)) & 1;
+    let code = |i: usize, n: usize| (i..i + n).fold(0, |c, k| c << 1 | bit(k));
+    let mut digits = String::new();
+    let mut i = 0;
+    while i < bits {
+        // the padding is the start of the end of string code, all ones
+        if bits - i < 8 && code(i, bits - i) == (1 << (bits - i)) - 1 {
+            break;
+        }
+        if bits - i < 5 {
+            return None;
+        }
+        match code(i, 5) {
+            // 0 to 2 on 5 bits
+            c @ 0..=2 => {
+                digits.push((b'0' + c) as char);
+                i += 5;
+            }
+            // 3 to 9 on 6 bits, 011001 to 011111
+            0x0c..=0x0f if bits - i >= 6 && code(i, 6) >= 0x19 => {
+                digits.push((b'3' + code(i, 6) - 0x19) as char);
+                i += 6;
+            }
+            _ => return None,
+        }
+    }
+    Some(digits)
+}
@@ -0,0 +1,147 @@
+/// Quotas of the proxied connections open at once
+///
+/// A connection over max_connections, or over max_connections_per_destination
+/// to its destination address, waits in connect() for another to close,
+/// backing off up to quota_wait, then fails with EAGAIN. Non-blocking sockets
+/// fail at once, event loops retrying on their own. The connections being
+/// chained count along the open ones, for concurrent connect() calls not to
+/// overshoot, as do the ones relayed by the handshake threads, whose socket
+/// in the table is a local socket pair.
+use crate::conn;
+use crate::core::CONFIG;
+use crate::error::Error;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// First wait for a connection to close, doubled each time.
+const FIRST_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Longest wait between two checks of the quotas.
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Connections being chained, by destination address.
+static PENDING: Lazy<Mutex<HashMap<IpAddr, usize>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Place of a connection being chained under the quotas, released once it is
+/// registered or has failed. The handshakes in the background hold it until
+/// the connection closes instead.
+pub struct Slot(Option<IpAddr>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let ip = match self.0 {
+            Some(ip) => ip,
+            None => return,
+        };
+        let mut pending = PENDING.lock().expect("mutex poisoned");
+        if let Some(n) = pending.get_mut(&ip) {
+            *n -= 1;
+            if *n == 0 {
+                pending.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Takes a slot for a connection to `ip` under the quotas, waiting for one
+/// unless `nonblocking`.
+pub fn acquire(ip: IpAddr, nonblocking: bool) -> Result<Slot, Error> {
+    let config = &*CONFIG;
+    if config.max_connections.is_none() && config.max_connections_per_destination.is_none() {
+        return Ok(Slot(None));
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(config.quota_wait as u64);
+    let mut backoff = FIRST_BACKOFF;
+    loop {
+        let exceeded = match try_acquire(
+            ip,
+            config.max_connections,
+            config.max_connections_per_destination,
+        ) {
+            None => return Ok(Slot(Some(ip))),
+            Some(quota) => quota,
+        };
+        let now = Instant::now();
+        if nonblocking || now >= deadline {
+            return Err(Error::Generic(format!("{} reached", exceeded)));
+        }
+        std::thread::sleep(backoff.min(deadline - now));
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Counts a connection to `ip` as being chained if under the quotas of `max`
+/// connections and `max_to` to a destination, or returns the quota it
+/// exceeds.
+fn try_acquire(ip: IpAddr, max: Option<usize>, max_to: Option<usize>) -> Option<String> {
+    let mut pending = PENDING.lock().expect("mutex poisoned");
+    let exceeded = |(all, to): (usize, usize)| {
+        let all = all + pending.values().sum::<usize>();
+        let to = to + pending.get(&ip).copied().unwrap_or(0);
+        match (max, max_to) {
+            (Some(max), _) if all >= max => Some(format!("max_connections {}", max)),
+            (_, Some(max)) if to >= max => {
+                Some(format!("max_connections_per_destination {} to {}", max, ip))
+            }
+            _ => None,
+        }
+    };
+
+    if exceeded(conn::proxied_to(ip)).is_some() {
+        // sockets closed without close(), by dup2() or close_range(), are
+        // only dropped from the table by a snapshot
+        conn::snapshot();
+        if let Some(quota) = exceeded(conn::proxied_to(ip)) {
+            return Some(quota);
+        }
+    }
+    *pending.entry(ip).or_default() += 1;
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Takes a slot to `ip` under the quotas at once.
+    fn take(ip: &str, max: Option<usize>, max_to: Option<usize>) -> Result<Slot, String> {
+        let ip = ip.parse().unwrap();
+        match try_acquire(ip, max, max_to) {
+            None => Ok(Slot(Some(ip))),
+            Some(quota) => Err(quota),
+        }
+    }
+
+    #[test]
+    fn pending_connections_count_until_released() {
+        let a1 = take("10.0.0.1", Some(3), Some(2)).unwrap();
+        let a2 = take("10.0.0.1", Some(3), Some(2)).unwrap();
+        assert_eq!(
+            take("10.0.0.1", Some(3), Some(2)).err().as_deref(),
+            Some("max_connections_per_destination 2 to 10.0.0.1")
+        );
+        // other destinations only count towards max_connections
+        let b1 = take("10.0.0.2", Some(3), Some(2)).unwrap();
+        assert_eq!(
+            take("10.0.0.2", Some(3), Some(2)).err().as_deref(),
+            Some("max_connections 3")
+        );
+        let b2 = take("10.0.0.2", None, Some(2)).unwrap();
+
+        drop(a1);
+        let a3 = take("10.0.0.1", Some(4), Some(2)).unwrap();
+        drop((a2, a3));
+        assert!(!PENDING
+            .lock()
+            .unwrap()
+            .contains_key(&"10.0.0.1".parse().unwrap()));
+        drop((b1, b2));
+        assert!(PENDING.lock().unwrap().is_empty());
+        // a slot outside the quotas releases nothing
+        drop(Slot(None));
+    }
+}
@@ -0,0 +1,142 @@
+/// Random chains
+///
+/// A random chain is made of chain_len proxies drawn from the proxies of the
+/// chain, in a random order, behind the upstreams and transport managed by
+/// proxyc and ending with the proxy of the exit country if any. With
+/// random_scope set to connection, a chain is drawn for every connection,
+/// otherwise the chain drawn by the first connection through a set of
+/// proxies is kept for the whole process.
+///
+/// The draws come from a generator seeded by random_seed if set, a given seed
+/// drawing the same chains in the same order run after run.
+use crate::core::{Pinned, CONFIG};
+use crate::util;
+use once_cell::sync::Lazy;
+use proxyc_common::{ProxyConf, RandomScope};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// State of the generator, splitmix64.
+static STATE: Lazy<Mutex<u64>> =
+    Lazy::new(|| Mutex::new(CONFIG.random_seed.unwrap_or_else(util::random_u64)));
+
+/// Chains drawn for the process, by set of proxies, with random_scope set to
+/// process.
+static PROCESS_CHAINS: Lazy<Mutex<HashMap<Vec<String>, Vec<usize>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn next(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Returns `len` distinct indices below `count`, in a random order.
+fn draw(count: usize, len: usize) -> Vec<usize> {
+    draw_with(&mut STATE.lock().unwrap(), count, len)
+}
+
+/// Draws like draw() from the generator `state`.
+fn draw_with(state: &mut u64, count: usize, len: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..count).collect();
+    // partial Fisher-Yates shuffle
+    for i in 0..len.min(count) {
+        let j = i + (next(state) % (count - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    indices.truncate(len);
+    indices
+}
+
+/// Returns the indices of the `proxies` of a random chain: the `pinned`
+/// first hops, chain_len proxies drawn among the others, the proxy of the
+/// exit country counting as the last one when pinned.
+pub fn chain(proxies: &[ProxyConf], pinned: Pinned) -> Vec<usize> {
+    pick(proxies.len(), pinned, CONFIG.chain_len, |pool, len| {
+        match CONFIG.random_scope {
+            RandomScope::Connection => draw(pool, len),
+            // the proxies of the default chain depend on the exit country of
+            // the rule, each set has its own draw
+            RandomScope::Process => {
+                let key: Vec<String> = proxies.iter().map(ProxyConf::to_string).collect();
+                let mut chains = PROCESS_CHAINS.lock().unwrap();
+                chains.entry(key).or_insert_with(|| draw(pool, len)).clone()
+            }
+        }
+    })
+}
+
+/// Returns the indices of a random chain of `chain_len` proxies among
+/// `count`, like chain(), `draw` drawing the given number of indices below
+/// the size of the pool.
+fn pick(
+    count: usize,
+    pinned: Pinned,
+    chain_len: usize,
+    draw: impl FnOnce(usize, usize) -> Vec<usize>,
+) -> Vec<usize> {
+    let first = pinned.first.min(count);
+    let last = usize::from(pinned.last && count > first);
+    let pool = count - first - last;
+    let drawn = draw(pool, chain_len.saturating_sub(last).min(pool));
+    (0..first)
+        .chain(drawn.into_iter().map(|i| first + i))
+        .chain((last == 1).then_some(count - 1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random(count: usize, first: usize, last: bool, chain_len: usize) -> Vec<usize> {
+        let mut state = 7;
+        pick(count, Pinned { first, last }, chain_len, |pool, len| {
+            draw_with(&mut state, pool, len)
+        })
+    }
+
+    #[test]
+    fn draws_are_distinct_and_seeded() {
+        let (mut a, mut b) = (1, 1);
+        let drawn = draw_with(&mut a, 10, 4);
+        assert_eq!(drawn.len(), 4);
+        assert!(drawn.iter().all(|i| *i < 10));
+        assert!(drawn
+            .iter()
+            .all(|i| drawn.iter().filter(|j| *j == i).count() == 1));
+        assert_eq!(drawn, draw_with(&mut b, 10, 4));
+        assert_eq!(draw_with(&mut a, 3, 5).len(), 3);
+    }
+
+    #[test]
+    fn managed_hops_come_first() {
+        let chain = random(6, 2, false, 3);
+        assert_eq!(chain.len(), 5);
+        assert_eq!(chain[..2], [0, 1]);
+        assert!(chain[2..].iter().all(|i| (2..6).contains(i)));
+    }
+
+    #[test]
+    fn exit_proxy_comes_last_and_counts_in_chain_len() {
+        let chain = random(6, 1, true, 3);
+        assert_eq!(chain.len(), 4);
+        assert_eq!(chain[0], 0);
+        assert_eq!(chain[3], 5);
+        assert!(chain[1..3].iter().all(|i| (1..5).contains(i)));
+    }
+
+    #[test]
+    fn short_pools_are_drawn_whole() {
+        let mut chain = random(4, 1, true, 10);
+        assert_eq!((chain[0], chain[3]), (0, 3));
+        chain[1..3].sort_unstable();
+        assert_eq!(chain, [0, 1, 2, 3]);
+        // pinned hops only
+        assert_eq!(random(2, 2, true, 3), [0, 1]);
+        assert_eq!(random(2, 3, false, 3), [0, 1]);
+        assert_eq!(random(1, 0, true, 3), [0]);
+    }
+}
@@ -0,0 +1,100 @@
+/// Chain selections reused by bursty reconnections
+///
+/// With chain_reuse_window, the chain of chain_order which reached a
+/// destination is tried first by the connections made to it within the
+/// window, rather than the chains failing before it. A first proxy given by
+/// hostname or several addresses is connected to at the address it answered
+/// at, sparing the resolution of its hostname and the race of its addresses.
+///
+/// Selections are made again once the window elapsed, and forgotten when
+/// they fail, the connection then trying the chains and addresses in order.
+use crate::core::CONFIG;
+use once_cell::sync::Lazy;
+use proxyc_common::ProxyConf;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Selections of each kind kept at most, the expired ones being dropped
+/// first when full.
+const MAX_SELECTIONS: usize = 4096;
+
+/// Selections by key, with the time they were made.
+type Selections<K, V> = Lazy<Mutex<HashMap<K, (Instant, V)>>>;
+
+/// Index in chain_order of the chain which reached a destination.
+static CHAINS: Selections<(IpAddr, u16), usize> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Address a first proxy answered at, by endpoint.
+static FIRST_HOPS: Selections<String, SocketAddr> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn window() -> Option<Duration> {
+    CONFIG
+        .chain_reuse_window
+        .map(|w| Duration::from_millis(w as u64))
+}
+
+fn get<K: Hash + Eq, V: Copy>(selections: &Selections<K, V>, key: &K) -> Option<V> {
+    let window = window()?;
+    selections
+        .lock()
+        .expect("mutex poisoned")
+        .get(key)
+        .filter(|(at, _)| at.elapsed() < window)
+        .map(|(_, v)| *v)
+}
+
+fn set<K: Hash + Eq, V>(selections: &Selections<K, V>, key: K, value: V) {
+    let window = match window() {
+        Some(w) => w,
+        None => return,
+    };
+    let mut selections = selections.lock().expect("mutex poisoned");
+    if selections.len() >= MAX_SELECTIONS {
+        selections.retain(|_, (at, _)| at.elapsed() < window);
+        if selections.len() >= MAX_SELECTIONS {
+            selections.clear();
+        }
+    }
+    selections.insert(key, (Instant::now(), value));
+}
+
+fn forget<K: Hash + Eq, V>(selections: &Selections<K, V>, key: &K) {
+    if window().is_some() {
+        selections.lock().expect("mutex poisoned").remove(key);
+    }
+}
+
+/// Returns the index in chain_order of the chain which reached `ip` on
+/// `port` within the window.
+pub fn chain(ip: IpAddr, port: u16) -> Option<usize> {
+    get(&CHAINS, &(ip, port))
+}
+
+/// Records the chain at index `idx` in chain_order as the one reaching `ip`
+/// on `port`.
+pub fn set_chain(ip: IpAddr, port: u16, idx: usize) {
+    set(&CHAINS, (ip, port), idx);
+}
+
+/// Forgets the chain reaching `ip` on `port`, which failed.
+pub fn forget_chain(ip: IpAddr, port: u16) {
+    forget(&CHAINS, &(ip, port));
+}
+
+/// Returns the address `proxy` answered at within the window.
+pub fn first_hop(proxy: &ProxyConf) -> Option<SocketAddr> {
+    get(&FIRST_HOPS, &proxy.endpoint())
+}
+
+/// Records `addr` as the address `proxy` answers at.
+pub fn set_first_hop(proxy: &ProxyConf, addr: SocketAddr) {
+    set(&FIRST_HOPS, proxy.endpoint(), addr);
+}
+
+/// Forgets the address `proxy` answered at, which failed.
+pub fn forget_first_hop(proxy: &ProxyConf) {
+    forget(&FIRST_HOPS, &proxy.endpoint());
+}
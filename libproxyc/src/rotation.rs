@@ -0,0 +1,93 @@
+/// Credentials rotated per proxy
+///
+/// Providers throttling individual accounts are given several sets of
+/// credentials with credential_rotation. With rotate = "failure", a proxy is
+/// authenticated with the same set until it rejects it, the next connections
+/// using the next one. With rotate = "connection", every connection uses the
+/// next set, each process starting at a random one not to load the first set
+/// with the first connection of every program.
+///
+/// The sets in use are tracked by each process, the rejections seen by a
+/// process being unknown to the others.
+use crate::core::CONFIG;
+use crate::error::{Error, Stage};
+use crate::util;
+use once_cell::sync::Lazy;
+use proxyc_common::{Auth, ProxyConf, RotateOn};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Index of the next set of each rotation, growing past the number of sets.
+static NEXT: Lazy<Vec<AtomicUsize>> = Lazy::new(|| {
+    CONFIG
+        .credential_rotation
+        .iter()
+        .map(|r| match r.rotate {
+            RotateOn::Failure => AtomicUsize::new(0),
+            RotateOn::Connection => {
+                AtomicUsize::new((util::random_u64() % r.credentials.len() as u64) as usize)
+            }
+        })
+        .collect()
+});
+
+/// Credentials picked for a hop.
+pub struct Rotated {
+    rotation: usize,
+    index: usize,
+    pub auth: Auth,
+}
+
+/// Whether the credentials of `proxy` are rotated.
+pub fn applies(proxy: &ProxyConf) -> bool {
+    CONFIG.credential_rotation.iter().any(|r| r.applies(proxy))
+}
+
+/// Returns the credentials to authenticate with `proxy`, if rotated.
+pub fn credentials(proxy: &ProxyConf) -> Option<Rotated> {
+    let (rotation, r) = CONFIG
+        .credential_rotation
+        .iter()
+        .enumerate()
+        .find(|(_, r)| r.applies(proxy))?;
+    let index = match r.rotate {
+        RotateOn::Failure => NEXT[rotation].load(Ordering::Relaxed),
+        RotateOn::Connection => NEXT[rotation].fetch_add(1, Ordering::Relaxed),
+    };
+    let set = &r.credentials[index % r.credentials.len()];
+    Some(Rotated {
+        rotation,
+        index,
+        auth: set.auth(),
+    })
+}
+
+/// Moves to the next set if `rotated` was rejected by `proxy`, the hop
+/// failing with `e`. The connections failing together with the same set
+/// rotate it once.
+pub fn failed(proxy: &ProxyConf, rotated: Option<&Rotated>, e: &Error) {
+    let rotated = match (rotated, e) {
+        (
+            Some(r),
+            Error::Stage {
+                stage: Stage::Auth, ..
+            },
+        ) => r,
+        _ => return,
+    };
+    let r = &CONFIG.credential_rotation[rotated.rotation];
+    if r.rotate != RotateOn::Failure {
+        return;
+    }
+    let next = rotated.index.wrapping_add(1);
+    if NEXT[rotated.rotation]
+        .compare_exchange(rotated.index, next, Ordering::Relaxed, Ordering::Relaxed)
+        .is_ok()
+    {
+        warn!(
+            "{} rejected credentials {}, rotating to credentials {}",
+            proxy.endpoint(),
+            rotated.index % r.credentials.len() + 1,
+            next % r.credentials.len() + 1
+        );
+    }
+}
@@ -0,0 +1,110 @@
+/// Routing decisions cached by destination
+///
+/// With route_cache_ttl, the decision taken for a destination, whether it is
+/// connected to directly and otherwise through which rule, chain and
+/// timeouts, is kept for that time so that repeated connections to it skip
+/// the rules and the table of internal addresses. The configuration is read
+/// once per process and lives as long as the cache; the decisions depending
+/// on the .local names resolved meanwhile are forgotten when one is.
+use crate::core::{self, Timeouts, CONFIG};
+use crate::filter;
+use once_cell::sync::Lazy;
+use proxyc_common::{ProxyConf, ProxycConfig, Rule};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Destinations whose decision is kept at most, the expired ones being
+/// dropped first when full.
+const MAX_ROUTES: usize = 4096;
+
+/// How connections to a destination are made.
+#[derive(Debug, Clone)]
+pub struct Route {
+    /// Connected to directly, bypassing the proxies.
+    pub direct: bool,
+    /// Index of the first matching rule.
+    rule: Option<usize>,
+    /// Number of proxies of the chain, None if none exits in the requested
+    /// country.
+    chain_len: Option<usize>,
+    pub timeouts: Timeouts,
+    /// Plain http forwarded in absolute-URI form.
+    pub forward_http: bool,
+    /// Hostname of an internal address.
+    pub hostname: Option<String>,
+}
+
+impl Route {
+    fn new(config: &ProxycConfig, ip: IpAddr, port: u16) -> Self {
+        let rule = config.rules.iter().position(|r| r.matches(ip, port));
+        Route {
+            direct: filter::bypasses(ip, port),
+            rule,
+            chain_len: config
+                .chain_for(rule.map(|i| &config.rules[i]))
+                .map(<[ProxyConf]>::len),
+            timeouts: Timeouts::for_target(config, ip, port),
+            forward_http: core::forwards_http(ip, port),
+            hostname: core::find_ip_hostname(ip),
+        }
+    }
+
+    pub fn rule<'a>(&self, config: &'a ProxycConfig) -> Option<&'a Rule> {
+        self.rule.map(|i| &config.rules[i])
+    }
+
+    /// Index of the rule in the configuration.
+    pub fn rule_index(&self) -> Option<usize> {
+        self.rule
+    }
+
+    pub fn chain<'a>(&self, config: &'a ProxycConfig) -> Option<&'a [ProxyConf]> {
+        self.chain_len.map(|len| &config.proxies[..len])
+    }
+}
+
+/// Decisions by destination, with the time they were taken.
+type Routes = HashMap<(IpAddr, u16), (Instant, Route)>;
+
+static ROUTES: Lazy<Mutex<Routes>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Returns the decision for connections to `ip` on `port`, cached or taken
+/// now.
+pub fn lookup(ip: IpAddr, port: u16) -> Route {
+    let config = &*CONFIG;
+    let ttl = match config.route_cache_ttl {
+        Some(ttl) => Duration::from_millis(ttl as u64),
+        None => return Route::new(config, ip, port),
+    };
+
+    let now = Instant::now();
+    if let Some((at, route)) = ROUTES.lock().expect("mutex poisoned").get(&(ip, port)) {
+        if now.duration_since(*at) < ttl {
+            return route.clone();
+        }
+    }
+
+    // taken without the lock, the table of internal addresses having its own
+    let route = Route::new(config, ip, port);
+    let mut routes = ROUTES.lock().expect("mutex poisoned");
+    if routes.len() >= MAX_ROUTES {
+        routes.retain(|_, (at, _)| now.duration_since(*at) < ttl);
+        if routes.len() >= MAX_ROUTES {
+            routes.clear();
+        }
+    }
+    routes.insert((ip, port), (now, route.clone()));
+    route
+}
+
+/// Forgets the decisions for `ip`, on any port.
+pub fn forget(ip: IpAddr) {
+    if CONFIG.route_cache_ttl.is_some() {
+        ROUTES
+            .lock()
+            .expect("mutex poisoned")
+            .retain(|(i, _), _| *i != ip);
+    }
+}
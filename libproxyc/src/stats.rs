@@ -0,0 +1,372 @@
+/// Per process connection statistics
+use crate::core::CONFIG;
+use nix::libc;
+use once_cell::sync::Lazy;
+use proxyc_common::{ProcessStats, ProxyConf, ProxyStats, RuleStats};
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Weight of a new sample in the moving averages, that of the smoothed RTT of
+/// TCP.
+const EWMA_WEIGHT: f64 = 0.125;
+
+/// Fewest bytes a connection moves for its throughput to be measured, the
+/// handshakes of smaller ones weighing more than their transfer.
+const MIN_THROUGHPUT_BYTES: u64 = 64 * 1024;
+
+/// Slots of the times taken to reach the targets, by proxy and target. A
+/// target taking the slot of another starts its average over.
+const EXIT_SLOTS: usize = 1024;
+
+/// Exponentially weighted moving average, as the bits of an f64, 0 until the
+/// first sample.
+#[derive(Default)]
+struct Ewma(AtomicU64);
+
+impl Ewma {
+    fn add(&self, sample: f64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let avg = match bits {
+                    0 => sample,
+                    bits => {
+                        let avg = f64::from_bits(bits);
+                        avg + EWMA_WEIGHT * (sample - avg)
+                    }
+                };
+                Some(avg.to_bits())
+            });
+    }
+
+    fn get(&self) -> Option<f64> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            bits => Some(f64::from_bits(bits)),
+        }
+    }
+
+    fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Counters and averages of a proxy.
+#[derive(Default)]
+struct Proxy {
+    success: AtomicU64,
+    failures: AtomicU64,
+    rtt_ms: Ewma,
+    throughput: Ewma,
+}
+
+/// Time in milliseconds a proxy took to reach a target, as the last of the
+/// chain.
+#[derive(Default)]
+struct Exit {
+    /// Hash of the proxy and target, 0 while free.
+    key: AtomicU64,
+    ms: Ewma,
+}
+
+thread_local! {
+    /// Indexes of the proxies of the last chain this thread built.
+    static CHAIN: RefCell<Vec<usize>> = const { RefCell::new(vec![]) };
+}
+
+/// Counters are atomics rather than a mutex-protected struct so that they can
+/// be reset safely in a forked child, whatever the state of other threads.
+pub struct Stats {
+    connections: AtomicU64,
+    failures: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+    /// Endpoints of the proxies of the configuration, those of the default
+    /// chain then of the named chains, each proxy once.
+    endpoints: Vec<String>,
+    index: HashMap<String, usize>,
+    proxies: Vec<Proxy>,
+    exits: Vec<Exit>,
+    /// Hits of the rules then of the ignored subnets.
+    rules: Vec<AtomicU64>,
+    /// Hostnames holding an internal address, inherited by forked children
+    /// along with the table.
+    dns_entries: AtomicU64,
+    dns_reclaimed: AtomicU64,
+}
+
+pub static STATS: Lazy<Stats> = Lazy::new(|| {
+    let proxies = CONFIG
+        .proxies
+        .iter()
+        .chain(CONFIG.chains.values().flat_map(|c| &c.proxies));
+    Stats::new(proxies, CONFIG.rules.len() + CONFIG.ignore_subnets.len())
+});
+
+impl Stats {
+    pub fn new<'a>(proxies: impl Iterator<Item = &'a ProxyConf>, rules: usize) -> Self {
+        let mut endpoints = vec![];
+        let mut index = HashMap::new();
+        for p in proxies {
+            index.entry(p.endpoint()).or_insert_with_key(|e| {
+                endpoints.push(e.clone());
+                endpoints.len() - 1
+            });
+        }
+        Self {
+            connections: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            proxies: endpoints.iter().map(|_| Proxy::default()).collect(),
+            exits: (0..EXIT_SLOTS).map(|_| Exit::default()).collect(),
+            endpoints,
+            index,
+            rules: (0..rules).map(|_| AtomicU64::new(0)).collect(),
+            dns_entries: AtomicU64::new(0),
+            dns_reclaimed: AtomicU64::new(0),
+        }
+    }
+
+    /// Records the outcome of a proxied connection.
+    pub fn connection(&self, success: bool) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records bytes sent and received on proxied connections.
+    pub fn bytes(&self, sent: u64, received: u64) {
+        self.sent.fetch_add(sent, Ordering::Relaxed);
+        self.received.fetch_add(received, Ordering::Relaxed);
+    }
+
+    /// Returns the index of `proxy` among the proxies of the configuration,
+    /// the proxies managed by proxyc and those of the named chains included.
+    fn index(&self, proxy: &ProxyConf) -> Option<usize> {
+        self.index.get(&proxy.endpoint()).copied()
+    }
+
+    fn proxy(&self, proxy: &ProxyConf) -> Option<&Proxy> {
+        self.proxies.get(self.index(proxy)?)
+    }
+
+    /// Returns the slot of the time taken by `proxy` to reach `target`, and
+    /// its key.
+    fn exit_slot(&self, proxy: &ProxyConf, target: &ProxyConf) -> (&Exit, u64) {
+        let mut hasher = DefaultHasher::new();
+        (proxy.endpoint(), target.host_port()).hash(&mut hasher);
+        let key = hasher.finish() | 1;
+        (&self.exits[key as usize % EXIT_SLOTS], key)
+    }
+
+    /// Records the outcome of a hop through `proxy`.
+    pub fn hop(&self, proxy: &ProxyConf, success: bool) {
+        if let Some(p) = self.proxy(proxy) {
+            match success {
+                true => p.success.fetch_add(1, Ordering::Relaxed),
+                false => p.failures.fetch_add(1, Ordering::Relaxed),
+            };
+        }
+    }
+
+    /// Records the time taken to reach `proxy`, from the previous hop.
+    pub fn rtt(&self, proxy: &ProxyConf, elapsed: Duration) {
+        if let Some(p) = self.proxy(proxy) {
+            p.rtt_ms.add(elapsed.as_secs_f64() * 1000.0);
+        }
+    }
+
+    /// Records the time taken by `proxy` to reach `target`.
+    pub fn exit(&self, proxy: &ProxyConf, target: &ProxyConf, elapsed: Duration) {
+        let (slot, key) = self.exit_slot(proxy, target);
+        if slot.key.swap(key, Ordering::Relaxed) != key {
+            slot.ms.reset();
+        }
+        slot.ms.add(elapsed.as_secs_f64() * 1000.0);
+    }
+
+    /// Returns the average time in milliseconds taken to reach `proxy`, if
+    /// measured.
+    pub fn rtt_ms(&self, proxy: &ProxyConf) -> Option<f64> {
+        self.proxy(proxy)?.rtt_ms.get()
+    }
+
+    /// Returns the average time in milliseconds taken by `proxy` to reach
+    /// `target`, if measured.
+    pub fn exit_ms(&self, proxy: &ProxyConf, target: &ProxyConf) -> Option<f64> {
+        let (slot, key) = self.exit_slot(proxy, target);
+        match slot.key.load(Ordering::Relaxed) == key {
+            true => slot.ms.get(),
+            false => None,
+        }
+    }
+
+    /// Records `proxies` as the chain built by this thread, for the
+    /// connection to be registered with it.
+    pub fn chained(&self, proxies: &[ProxyConf]) {
+        let hops = proxies.iter().filter_map(|p| self.index(p)).collect();
+        CHAIN.with(|c| *c.borrow_mut() = hops);
+    }
+
+    /// Returns the indexes of the proxies of the last chain built by this
+    /// thread, forgetting them.
+    pub fn take_chain(&self) -> Vec<usize> {
+        CHAIN.with(|c| c.take())
+    }
+
+    /// Records the throughput of a proxied connection through the proxies at
+    /// indexes `hops`, which moved `bytes` between its connection and its
+    /// last traffic `active` later.
+    pub fn throughput(&self, hops: &[usize], bytes: u64, active: Duration) {
+        if bytes < MIN_THROUGHPUT_BYTES || active.is_zero() {
+            return;
+        }
+        let sample = bytes as f64 / active.as_secs_f64();
+        for p in hops.iter().filter_map(|i| self.proxies.get(*i)) {
+            p.throughput.add(sample);
+        }
+    }
+
+    /// Records a connection matching the rule at index `idx` in the
+    /// configuration.
+    pub fn rule(&self, idx: usize) {
+        if let Some(hits) = self.rules.get(idx) {
+            hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records a connection to `ip` on `port` bypassing the proxies, on the
+    /// first ignored subnet matching it or else on its direct rule.
+    pub fn bypass(&self, ip: IpAddr, port: u16, rule: Option<usize>) {
+        let config = &*CONFIG;
+        match config
+            .ignore_subnets
+            .iter()
+            .position(|s| s.matches(ip, port))
+        {
+            Some(i) => self.rule(config.rules.len() + i),
+            None => {
+                if let Some(i) = rule.filter(|i| config.rules[*i].direct) {
+                    self.rule(i);
+                }
+            }
+        }
+    }
+
+    /// Records the number of hostnames holding an internal address.
+    pub fn dns_entries(&self, entries: usize) {
+        self.dns_entries.store(entries as u64, Ordering::Relaxed);
+    }
+
+    /// Records an internal address reclaimed for another hostname.
+    pub fn dns_reclaimed(&self) {
+        self.dns_reclaimed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.connections.store(0, Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+        self.sent.store(0, Ordering::Relaxed);
+        self.received.store(0, Ordering::Relaxed);
+        for p in &self.proxies {
+            p.success.store(0, Ordering::Relaxed);
+            p.failures.store(0, Ordering::Relaxed);
+            p.rtt_ms.reset();
+            p.throughput.reset();
+        }
+        for e in &self.exits {
+            e.key.store(0, Ordering::Relaxed);
+            e.ms.reset();
+        }
+        for hits in &self.rules {
+            hits.store(0, Ordering::Relaxed);
+        }
+        self.dns_reclaimed.store(0, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ProcessStats {
+        ProcessStats {
+            pid: std::process::id(),
+            connections: self.connections.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            bytes_sent: self.sent.load(Ordering::Relaxed),
+            bytes_received: self.received.load(Ordering::Relaxed),
+            proxies: self
+                .endpoints
+                .iter()
+                .zip(&self.proxies)
+                .map(|(endpoint, p)| ProxyStats {
+                    proxy: endpoint.clone(),
+                    success: p.success.load(Ordering::Relaxed),
+                    failures: p.failures.load(Ordering::Relaxed),
+                    rtt_ms: p.rtt_ms.get(),
+                    throughput: p.throughput.get(),
+                })
+                .collect(),
+            rules: CONFIG
+                .rule_names()
+                .into_iter()
+                .zip(&self.rules)
+                .map(|(rule, hits)| RuleStats {
+                    rule,
+                    hits: hits.load(Ordering::Relaxed),
+                })
+                .collect(),
+            dns_entries: self.dns_entries.load(Ordering::Relaxed),
+            dns_reclaimed: self.dns_reclaimed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+extern "C" fn reset_child() {
+    STATS.reset();
+}
+
+/// Makes forked children start with empty counters, so that the parent's
+/// connections are not reported twice.
+pub fn init() {
+    Lazy::force(&STATS);
+    unsafe {
+        libc::pthread_atfork(None, None, Some(reset_child));
+    }
+}
+
+/// Appends the statistics of this process to the configured stats file.
+pub fn dump() {
+    let path = match &CONFIG.stats_file {
+        Some(p) => p,
+        None => return,
+    };
+
+    let stats = STATS.snapshot();
+    let line = match serde_json::to_string(&stats) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("failed to serialize stats: {}", e);
+            return;
+        }
+    };
+
+    // the processes of a run all append their line, the file is not
+    // followed if replaced by a symbolic link
+    let res = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_CLOEXEC)
+        .open(path)
+        .and_then(|mut f| f.write_all(format!("{}\n", line).as_bytes()));
+    if let Err(e) = res {
+        error!("failed to write stats to {:?}: {}", path, e);
+    }
+}
@@ -0,0 +1,188 @@
+/// TLS wrapping of the chained connections to a terminator
+///
+/// The chain is extended to the terminator, with which a TLS session is
+/// established. The program then gets one end of a local socket pair, a
+/// thread relaying the other end through the session.
+use crate::core::{Timeouts, CONFIG};
+use crate::error::Error;
+use crate::util::{poll_retry, FdStream};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
+use nix::unistd::close;
+use once_cell::sync::Lazy;
+use proxyc_common::TlsTerminator;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore};
+use std::io::{Read, Write};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Client configuration shared by the sessions, or why it could not be
+/// built.
+static CLIENT_CONFIG: Lazy<Result<Arc<ClientConfig>, String>> = Lazy::new(client_config);
+
+fn client_config() -> Result<Arc<ClientConfig>, String> {
+    let ca_file = CONFIG
+        .tls_terminator
+        .as_ref()
+        .and_then(|t| t.ca_file.as_deref());
+    let roots = root_store(ca_file)?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Returns the certificate authorities of `ca_file`, the Mozilla ones by
+/// default.
+pub fn root_store(ca_file: Option<&Path>) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            let certs =
+                CertificateDer::pem_file_iter(path).map_err(|e| format!("{:?}: {}", path, e))?;
+            for cert in certs {
+                let cert = cert.map_err(|e| format!("{:?}: {}", path, e))?;
+                roots.add(cert).map_err(|e| format!("{:?}: {}", path, e))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    Ok(roots)
+}
+
+fn tls_error(e: rustls::Error) -> Error {
+    Error::Tls(e.to_string())
+}
+
+/// Establishes a TLS session with the terminator `sock` is connected to.
+/// Returns the socket the program reads and writes in plaintext, the caller
+/// keeping ownership of `sock`.
+pub fn wrap(sock: RawFd, terminator: &TlsTerminator, timeouts: &Timeouts) -> Result<RawFd, Error> {
+    let config = CLIENT_CONFIG
+        .as_ref()
+        .map_err(|e| Error::Tls(e.to_string()))?;
+    let name = terminator
+        .server_name
+        .clone()
+        .unwrap_or_else(|| terminator.proxy.host());
+    let name = ServerName::try_from(name)
+        .map_err(|e| Error::Tls(format!("invalid server name: {}", e)))?;
+    let mut tls = ClientConnection::new(config.clone(), name).map_err(tls_error)?;
+
+    // the relay thread owns its own descriptor
+    let mut stream = FdStream(fcntl(sock, FcntlArg::F_DUPFD_CLOEXEC(0))?);
+    let res = handshake(&mut tls, &mut stream, timeouts.read).and_then(|_| {
+        Ok(socketpair(
+            AddressFamily::Unix,
+            SockType::Stream,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )?)
+    });
+    let (plain, local) = match res {
+        Ok(pair) => pair,
+        Err(e) => {
+            close(stream.0).ok();
+            return Err(e);
+        }
+    };
+    debug!("tls session established with {}", terminator.proxy);
+
+    std::thread::spawn(move || {
+        if let Err(e) = relay(&mut tls, &mut stream, local) {
+            debug!("tls relay: {}", e);
+        }
+        close(local).ok();
+        close(stream.0).ok();
+    });
+    Ok(plain)
+}
+
+fn handshake(
+    tls: &mut ClientConnection,
+    stream: &mut FdStream,
+    timeout: usize,
+) -> Result<(), Error> {
+    while tls.is_handshaking() {
+        while tls.wants_write() {
+            tls.write_tls(stream)?;
+        }
+        if tls.is_handshaking() && tls.wants_read() {
+            let mut fds = [PollFd::new(stream.0, PollFlags::POLLIN)];
+            poll_retry(&mut fds, timeout)?;
+            if tls.read_tls(stream)? == 0 {
+                return Err(Error::Tls("connection closed during the handshake".into()));
+            }
+            tls.process_new_packets().map_err(tls_error)?;
+        }
+    }
+    while tls.wants_write() {
+        tls.write_tls(stream)?;
+    }
+    Ok(())
+}
+
+/// Relays `local` through the session until the terminator closes it.
+fn relay(tls: &mut ClientConnection, stream: &mut FdStream, local: RawFd) -> Result<(), Error> {
+    let mut local = Some(FdStream(local));
+    let mut buf = [0; 16384];
+
+    loop {
+        while tls.wants_write() {
+            tls.write_tls(stream)?;
+        }
+
+        let mut fds = vec![PollFd::new(stream.0, PollFlags::POLLIN)];
+        if let Some(l) = &local {
+            fds.push(PollFd::new(l.0, PollFlags::POLLIN));
+        }
+        match poll(&mut fds, -1) {
+            Ok(_) => (),
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into()),
+        }
+        let ready = |fd: &PollFd| fd.revents().is_some_and(|r| !r.is_empty());
+
+        if ready(&fds[0]) {
+            if tls.read_tls(stream)? == 0 {
+                return Ok(());
+            }
+            let state = tls.process_new_packets().map_err(tls_error)?;
+            let len = state.plaintext_bytes_to_read();
+            if len > 0 {
+                let mut data = vec![0; len];
+                tls.reader().read_exact(&mut data)?;
+                // the program may have closed its end already
+                if let Some(l) = &mut local {
+                    if l.write_all(&data).is_err() {
+                        local = None;
+                    }
+                }
+            }
+            if state.peer_has_closed() {
+                return Ok(());
+            }
+        }
+
+        if fds.get(1).is_some_and(ready) {
+            let l = local.as_mut().expect("polled a closed local socket");
+            match l.read(&mut buf)? {
+                // half-closed, the replies are still relayed
+                0 => {
+                    tls.send_close_notify();
+                    local = None;
+                }
+                n => tls.writer().write_all(&buf[..n])?,
+            }
+        }
+    }
+}
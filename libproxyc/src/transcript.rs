@@ -0,0 +1,150 @@
+/// Transcripts of the handshakes, for bug reports about misbehaving proxies
+///
+/// With debug_transcript set, the bytes exchanged with the proxy during the
+/// handshake of each hop are appended to the file as hex dumps, with their
+/// time since the start of the handshake and the outcome of the hop.
+/// Credentials are masked.
+use crate::core::CONFIG;
+use crate::error::Error;
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::ops::Range;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Bytes shown on a line of the hex dumps.
+const LINE_LEN: usize = 16;
+
+/// Bytes moved in one direction, until the other one is taken.
+struct Chunk {
+    at: Duration,
+    sent: bool,
+    data: Vec<u8>,
+}
+
+struct Recording {
+    start: Instant,
+    chunks: Vec<Chunk>,
+}
+
+thread_local! {
+    /// Handshake being recorded by the thread.
+    static RECORDING: RefCell<Option<Recording>> = const { RefCell::new(None) };
+}
+
+fn push(sent: bool, data: &[u8]) {
+    RECORDING.with(|r| {
+        if let Some(rec) = r.borrow_mut().as_mut() {
+            match rec.chunks.last_mut() {
+                Some(c) if c.sent == sent => c.data.extend_from_slice(data),
+                _ => rec.chunks.push(Chunk {
+                    at: rec.start.elapsed(),
+                    sent,
+                    data: data.to_vec(),
+                }),
+            }
+        }
+    });
+}
+
+fn recording() -> bool {
+    RECORDING.with(|r| r.borrow().is_some())
+}
+
+/// Records `data` as sent to the proxy, the bytes of `secrets` masked, if the
+/// handshake is recorded.
+pub fn sent(data: &[u8], secrets: &[Range<usize>]) {
+    if !recording() {
+        return;
+    }
+    let mut shown = data.to_vec();
+    for s in secrets {
+        shown[s.clone()].fill(b'*');
+    }
+    push(true, &shown);
+}
+
+/// Records `data` as received from the proxy, if the handshake is recorded.
+pub fn received(data: &[u8]) {
+    push(false, data);
+}
+
+/// Appends the hex dump of `chunk` to `out`.
+fn dump(out: &mut String, chunk: &Chunk) {
+    let arrow = match chunk.sent {
+        true => '>',
+        false => '<',
+    };
+    for (i, line) in chunk.data.chunks(LINE_LEN).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&b| match b.is_ascii_graphic() || b == b' ' {
+                true => b as char,
+                false => '.',
+            })
+            .collect();
+        let at = match i {
+            0 => format!("+{:.3}ms", chunk.at.as_secs_f64() * 1000.0),
+            _ => String::new(),
+        };
+        let _ = writeln!(
+            out,
+            "  {:>11} {} {:<47} |{}|",
+            at,
+            arrow,
+            hex.join(" "),
+            ascii
+        );
+    }
+}
+
+/// Runs the handshake `step`, appending its transcript under the heading
+/// `title` to debug_transcript if set.
+pub fn record<T>(
+    title: impl FnOnce() -> String,
+    step: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let path = match &CONFIG.debug_transcript {
+        Some(p) => p,
+        None => return step(),
+    };
+
+    let start = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis());
+    RECORDING.with(|r| {
+        r.replace(Some(Recording {
+            start: Instant::now(),
+            chunks: vec![],
+        }))
+    });
+    let res = step();
+    let chunks = RECORDING
+        .with(|r| r.take())
+        .map_or(vec![], |rec| rec.chunks);
+
+    let mut out = format!("{} pid {} {}\n", start, std::process::id(), title());
+    for chunk in &chunks {
+        dump(&mut out, chunk);
+    }
+    match &res {
+        Ok(_) => out.push_str("  ok\n"),
+        Err(e) => {
+            let _ = writeln!(out, "  error: {}", e);
+        }
+    }
+
+    // transcripts are written whole, for those of concurrent handshakes not
+    // to interleave
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut f| f.write_all(out.as_bytes()));
+    if let Err(e) = written {
+        error!("failed to write transcript to {:?}: {}", path, e);
+    }
+    res
+}
@@ -0,0 +1,638 @@
+/// UDP relaying through the socks5 UDP ASSOCIATE of the proxy
+///
+/// Datagrams sent by a hooked UDP socket are prefixed with the socks5 UDP
+/// header and sent to the relay, datagrams received from the relay are
+/// stripped of it and their source rewritten to the logical peer, so that the
+/// program never sees the relay.
+///
+/// A socket has a single association, whatever the number of peers it talks
+/// to, as the header carries the destination of every datagram. Associations
+/// of idle sockets are released and requested again on their next datagram.
+///
+/// Datagrams larger than `udp_fragment_size` are split into fragments, the
+/// FRAG field of their header numbering them. Fragments received from the
+/// relay are reassembled into the datagram given to the program, as RFC 1928
+/// describes: a fragment out of sequence, or arriving more than 5 seconds
+/// after the previous one, abandons the datagram.
+use crate::core::{self, CONFIG};
+use crate::dns_server;
+use crate::error::Error;
+use crate::proxy::{self, Socks5};
+use nix::errno::Errno;
+use nix::libc::{self, c_int, c_void, sockaddr};
+use nix::sys::socket::{getpeername, getsockopt, sockopt, InetAddr, SockAddr, SockType};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Longest socks5 UDP header, carrying a 255 bytes hostname.
+const MAX_HEADER_LEN: usize = 3 + 1 + 1 + 255 + 2;
+
+/// Largest UDP payload over IPv4.
+const MAX_DATAGRAM_LEN: usize = 65507;
+
+/// Bit of the FRAG field marking the last fragment of a datagram.
+const FRAG_LAST: u8 = 0x80;
+
+/// Most fragments a datagram is split into, their position taking 7 bits.
+const MAX_FRAGMENTS: usize = 127;
+
+/// Time after which a datagram whose fragments stopped arriving is
+/// abandoned.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fragments of a datagram received from the relay.
+struct Reassembly {
+    source: SocketAddr,
+    /// Position of the last fragment added, from 1.
+    position: u8,
+    payload: Vec<u8>,
+    /// Time the last fragment was added.
+    received: Instant,
+}
+
+/// Association of a hooked UDP socket with a relay.
+struct Association {
+    /// Connection to the proxy, the relay lives as long as it is open. None
+    /// once released for idleness.
+    control: Option<RawFd>,
+    relay: SocketAddr,
+    /// Peer set by connect(), used when no destination is given.
+    peer: Option<SocketAddr>,
+    /// Last time a datagram was sent or received.
+    last_used: Instant,
+    /// Datagram being reassembled.
+    fragments: Option<Reassembly>,
+}
+
+static ASSOCIATIONS: Lazy<Mutex<HashMap<RawFd, Association>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Number of associations, lets close() skip the lock in the common case.
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether datagrams of `sock` to `dest` go through the relay.
+pub fn is_relayed(sock: RawFd, dest: &SocketAddr) -> bool {
+    let config = &*CONFIG;
+    config.proxy_udp
+        && !config.dry_run
+        && getsockopt(sock, sockopt::SockType).is_ok_and(|t| t == SockType::Datagram)
+        && !core::is_ignored(dest.ip(), dest.port())
+        && !dns_server::is_server(dest)
+}
+
+/// Whether `sock` is associated with a relay.
+pub fn is_associated(sock: RawFd) -> bool {
+    COUNT.load(Ordering::Relaxed) > 0
+        && ASSOCIATIONS
+            .lock()
+            .expect("mutex poisoned")
+            .contains_key(&sock)
+}
+
+/// Returns the relay `sock` is associated with, if any.
+pub fn relay(sock: RawFd) -> Option<SocketAddr> {
+    if COUNT.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    ASSOCIATIONS
+        .lock()
+        .expect("mutex poisoned")
+        .get(&sock)
+        .map(|a| a.relay)
+}
+
+/// Returns the relay of `sock`, requesting one from the proxy on first use
+/// or after it was released.
+fn associate(sock: RawFd) -> Result<SocketAddr, Error> {
+    release_idle(sock);
+
+    let peer = match ASSOCIATIONS.lock().expect("mutex poisoned").get_mut(&sock) {
+        Some(a) if a.control.is_some() => {
+            a.last_used = Instant::now();
+            return Ok(a.relay);
+        }
+        Some(a) => a.peer,
+        None => None,
+    };
+
+    let (control, mut relay) = core::last_hop_request(Socks5::udp_associate)?;
+    // relays bound to every interface are reachable at the proxy address,
+    // the one the race to a proxy having several was won with.
+    if relay.ip().is_unspecified() {
+        let ip = match getpeername(control) {
+            Ok(SockAddr::Inet(addr)) => unmap(addr.to_std()).ip(),
+            _ => CONFIG.proxies[0].ip,
+        };
+        relay.set_ip(ip);
+    }
+
+    // another thread may have associated the socket meanwhile, its relay is
+    // kept and the lock must not be held while closing ours.
+    let mut associations = ASSOCIATIONS.lock().expect("mutex poisoned");
+    if let Some(a) = associations.get_mut(&sock).filter(|a| a.control.is_some()) {
+        a.last_used = Instant::now();
+        let relay = a.relay;
+        drop(associations);
+        nix::unistd::close(control).ok();
+        return Ok(relay);
+    }
+    debug!("udp socket {} associated with relay {}", sock, relay);
+    let peer = associations.get(&sock).map_or(peer, |a| a.peer);
+    let previous = associations.insert(
+        sock,
+        Association {
+            control: Some(control),
+            relay,
+            peer,
+            last_used: Instant::now(),
+            fragments: None,
+        },
+    );
+    drop(associations);
+    if previous.is_none() {
+        COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+    // a connected socket follows its new relay
+    if peer.is_some() {
+        connect_relay(sock, relay)?;
+    }
+    Ok(relay)
+}
+
+/// Releases the relays of the sockets other than `sock` which sent and
+/// received nothing for `udp_idle_timeout`.
+fn release_idle(sock: RawFd) {
+    let timeout = Duration::from_millis(CONFIG.udp_idle_timeout as u64);
+    // the lock must not be held while closing, close() being hooked.
+    let released: Vec<_> = ASSOCIATIONS
+        .lock()
+        .expect("mutex poisoned")
+        .iter_mut()
+        .filter(|(s, a)| **s != sock && a.last_used.elapsed() > timeout)
+        .filter_map(|(s, a)| a.control.take().map(|c| (*s, a.relay, c)))
+        .collect();
+
+    for (s, relay, control) in released {
+        debug!("udp socket {} idle, released relay {}", s, relay);
+        nix::unistd::close(control).ok();
+    }
+}
+
+/// Converts an address to the family of `sock`, IPv4 addresses being mapped
+/// for IPv6 sockets.
+fn to_sock_family(sock: RawFd, addr: SocketAddr) -> SockAddr {
+    // getsockname() is hooked and relies on this, call the real one
+    let c_getsockname = core::GETSOCKNAME.expect("Cannot load symbol 'getsockname'");
+    let mut local: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut local_len = std::mem::size_of_val(&local) as libc::socklen_t;
+    let ret = unsafe { c_getsockname(sock, &mut local as *mut _ as *mut sockaddr, &mut local_len) };
+    let inet6 = ret == 0 && local.ss_family == libc::AF_INET6 as libc::sa_family_t;
+    let addr = match addr.ip() {
+        IpAddr::V4(ip) if inet6 => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        _ => addr,
+    };
+    SockAddr::new_inet(InetAddr::from_std(&addr))
+}
+
+/// Associates `sock` with a relay and makes `peer` its default destination,
+/// the socket itself being connected to the relay.
+pub fn connect(sock: RawFd, peer: SocketAddr) -> Result<(), Error> {
+    let relay = associate(sock)?;
+    if let Some(a) = ASSOCIATIONS.lock().expect("mutex poisoned").get_mut(&sock) {
+        a.peer = Some(peer);
+    }
+    connect_relay(sock, relay)
+}
+
+/// Connects `sock` to its relay.
+fn connect_relay(sock: RawFd, relay: SocketAddr) -> Result<(), Error> {
+    let c_connect = core::CONNECT.expect("Cannot load symbol 'connect'");
+    let relay = to_sock_family(sock, relay);
+    let (ptr, len) = relay.as_ffi_pair();
+    Errno::result(unsafe { c_connect(sock, ptr, len) })?;
+    Ok(())
+}
+
+/// Sends a datagram to `dest`, or to the peer set by connect(), through the
+/// relay. Returns the length of the payload sent.
+pub fn send_to(
+    sock: RawFd,
+    buf: &[u8],
+    flags: c_int,
+    dest: Option<SocketAddr>,
+) -> Result<usize, Error> {
+    let relay = associate(sock)?;
+    let dest = match dest {
+        Some(d) => d,
+        None => ASSOCIATIONS
+            .lock()
+            .expect("mutex poisoned")
+            .get(&sock)
+            .and_then(|a| a.peer)
+            .ok_or(Errno::EDESTADDRREQ)?,
+    };
+
+    let header = proxy::udp_header(unmap(dest));
+    // payload carried by a datagram sent to the relay, and by a datagram
+    // once fragmented
+    let room = CONFIG.udp_fragment_size.unwrap_or(MAX_DATAGRAM_LEN) - header.len();
+    let max_payload = match CONFIG.udp_fragment_size {
+        Some(_) => room * MAX_FRAGMENTS,
+        None => room,
+    };
+    if buf.len() > max_payload {
+        error!(
+            "datagram of {} bytes too large for the relay, {} bytes at most",
+            buf.len(),
+            max_payload
+        );
+        return Err(Errno::EMSGSIZE.into());
+    }
+    let packets = fragment(&header, buf, room);
+
+    let c_sendto = core::SENDTO.expect("Cannot load symbol 'sendto'");
+    let relay = to_sock_family(sock, relay);
+    let (ptr, len) = relay.as_ffi_pair();
+    for packet in packets {
+        let ret = unsafe {
+            c_sendto(
+                sock,
+                packet.as_ptr() as *const c_void,
+                packet.len(),
+                flags,
+                ptr,
+                len,
+            )
+        };
+        Errno::result(ret)?;
+    }
+    Ok(buf.len())
+}
+
+/// Prefixes `buf` with `header`, split into fragments numbered by the FRAG
+/// field of their header when it exceeds `room`.
+fn fragment(header: &[u8], buf: &[u8], room: usize) -> Vec<Vec<u8>> {
+    let count = buf.chunks(room).len();
+    match count {
+        0 | 1 => vec![[header, buf].concat()],
+        _ => buf
+            .chunks(room)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut packet = header.to_vec();
+                packet[2] = (i + 1) as u8;
+                if i + 1 == count {
+                    packet[2] |= FRAG_LAST;
+                }
+                packet.extend_from_slice(chunk);
+                packet
+            })
+            .collect(),
+    }
+}
+
+/// A datagram received through the relay.
+pub struct Datagram {
+    /// Length of the payload, which may exceed the buffer it was copied to.
+    pub len: usize,
+    pub source: SocketAddr,
+    /// Length of the ancillary data written to the control buffer.
+    pub control_len: usize,
+    /// Whether the ancillary data did not fit in the control buffer.
+    pub control_truncated: bool,
+}
+
+/// Receives a datagram through the relay into `buf`, stripped of its header.
+/// Datagrams not coming from the relay are dropped.
+///
+/// The ancillary data the socket was asked for with setsockopt(), such as
+/// IP_PKTINFO or IP_RECVTTL, is written to `control` as the kernel gives it
+/// for the datagram of the relay: the local address and interface it was
+/// received on are those of the proxied datagram, its TTL is the one of the
+/// last hop, from the relay.
+pub fn recv_from(
+    sock: RawFd,
+    buf: &mut [u8],
+    flags: c_int,
+    control: &mut [u8],
+) -> Result<Datagram, Error> {
+    let relay = ASSOCIATIONS
+        .lock()
+        .expect("mutex poisoned")
+        .get_mut(&sock)
+        .map(|a| {
+            a.last_used = Instant::now();
+            a.relay
+        })
+        .ok_or(Errno::EBADF)?;
+
+    let c_recvmsg = core::RECVMSG.expect("Cannot load symbol 'recvmsg'");
+    // fragments are received whole whatever the size of `buf`
+    let mut packet = vec![0; buf.len().max(MAX_DATAGRAM_LEN) + MAX_HEADER_LEN];
+    let peek = flags & libc::MSG_PEEK != 0;
+
+    loop {
+        let mut from: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: packet.as_mut_ptr() as *mut c_void,
+            iov_len: packet.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_name = &mut from as *mut _ as *mut c_void;
+        msg.msg_namelen = std::mem::size_of_val(&from) as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !control.is_empty() {
+            msg.msg_control = control.as_mut_ptr() as *mut c_void;
+            msg.msg_controllen = control.len();
+        }
+        let ret = unsafe { c_recvmsg(sock, &mut msg, flags & !libc::MSG_TRUNC) };
+        let len = Errno::result(ret)? as usize;
+
+        let from = unsafe { core::from_libc_sockaddr(&from as *const _ as *const sockaddr) };
+        let from_relay = match from {
+            Some(SockAddr::Inet(addr)) => unmap(addr.to_std()) == relay,
+            _ => false,
+        };
+        let parsed = match from_relay {
+            true => proxy::parse_udp_header(&packet[..len]),
+            false => Err(Error::Generic("not coming from the relay".into())),
+        };
+        let (source, frag, header_len) = match parsed {
+            Ok(h) => h,
+            Err(e) => {
+                debug!("dropping datagram: {}", e);
+                // a peeked datagram stays queued, consume it
+                if peek {
+                    discard(sock, flags);
+                }
+                continue;
+            }
+        };
+
+        let payload = &packet[header_len..len];
+        let reassembled;
+        let payload = match frag {
+            0 => payload,
+            frag => {
+                match reassemble(sock, source, frag, payload, peek) {
+                    Ok(Some(datagram)) => {
+                        reassembled = datagram;
+                        &reassembled
+                    }
+                    // the fragment is queued, or dropped, and a peeked one
+                    // must be consumed all the same
+                    queued => {
+                        if let Err(e) = queued {
+                            debug!("dropping fragment: {}", e);
+                        }
+                        if peek {
+                            discard(sock, flags);
+                        }
+                        continue;
+                    }
+                }
+            }
+        };
+        let copied = payload.len().min(buf.len());
+        buf[..copied].copy_from_slice(&payload[..copied]);
+
+        return Ok(Datagram {
+            len: payload.len(),
+            source,
+            control_len: msg.msg_controllen,
+            control_truncated: msg.msg_flags & libc::MSG_CTRUNC != 0,
+        });
+    }
+}
+
+/// Receives and drops the next datagram queued on `sock`.
+fn discard(sock: RawFd, flags: c_int) {
+    let c_recvfrom = core::RECVFROM.expect("Cannot load symbol 'recvfrom'");
+    let flags = flags & !(libc::MSG_PEEK | libc::MSG_TRUNC);
+    let mut byte = 0u8;
+    // the rest of a datagram is dropped with its first byte
+    unsafe {
+        c_recvfrom(
+            sock,
+            &mut byte as *mut u8 as *mut c_void,
+            1,
+            flags,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+}
+
+/// Adds the fragment `frag` of a datagram from `source` to the reassembly
+/// queue of `sock`. Returns the datagram once its last fragment is added,
+/// without removing it from the queue if `peek`, the fragment being received
+/// again.
+fn reassemble(
+    sock: RawFd,
+    source: SocketAddr,
+    frag: u8,
+    payload: &[u8],
+    peek: bool,
+) -> Result<Option<Vec<u8>>, Error> {
+    let mut associations = ASSOCIATIONS.lock().expect("mutex poisoned");
+    let a = associations.get_mut(&sock).ok_or(Errno::EBADF)?;
+    add_fragment(&mut a.fragments, source, frag, payload, peek)
+}
+
+/// Adds a fragment to the datagram being reassembled in `fragments`, like
+/// reassemble().
+fn add_fragment(
+    fragments: &mut Option<Reassembly>,
+    source: SocketAddr,
+    frag: u8,
+    payload: &[u8],
+    peek: bool,
+) -> Result<Option<Vec<u8>>, Error> {
+    let position = frag & !FRAG_LAST;
+    let queue = fragments.take().filter(|r| {
+        r.source == source
+            && r.position + 1 == position
+            && r.received.elapsed() < REASSEMBLY_TIMEOUT
+    });
+    let mut queue = match (queue, position) {
+        (Some(queue), _) => queue,
+        (None, 1) => Reassembly {
+            source,
+            position: 0,
+            payload: vec![],
+            received: Instant::now(),
+        },
+        (None, _) => {
+            return Err(Error::Generic(format!(
+                "fragment {} from {} out of sequence",
+                position, source
+            )))
+        }
+    };
+
+    if frag & FRAG_LAST == 0 {
+        queue.payload.extend_from_slice(payload);
+        queue.position = position;
+        queue.received = Instant::now();
+        *fragments = Some(queue);
+        return Ok(None);
+    }
+    let datagram = [&queue.payload[..], payload].concat();
+    if peek {
+        *fragments = Some(queue);
+    }
+    Ok(Some(datagram))
+}
+
+/// Writes `addr` in the family of `sock` to a caller provided sockaddr.
+///
+/// # Safety
+///
+/// `addr_ptr` and `addr_len` must be null or valid, as given to recvfrom.
+pub unsafe fn write_sockaddr(
+    sock: RawFd,
+    addr: SocketAddr,
+    addr_ptr: *mut sockaddr,
+    addr_len: *mut libc::socklen_t,
+) {
+    if addr_ptr.is_null() || addr_len.is_null() {
+        return;
+    }
+
+    let addr = to_sock_family(sock, addr);
+    let (ptr, len) = addr.as_ffi_pair();
+    let copied = (*addr_len).min(len) as usize;
+    std::ptr::copy_nonoverlapping(
+        ptr as *const sockaddr as *const u8,
+        addr_ptr as *mut u8,
+        copied,
+    );
+    *addr_len = len;
+}
+
+/// Maps IPv4-mapped IPv6 addresses back to IPv4.
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Drops the association of a closed socket, closing its control connection.
+pub fn close(sock: RawFd) {
+    if COUNT.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+
+    // the lock must not be held while closing, close() being hooked.
+    let removed = ASSOCIATIONS.lock().expect("mutex poisoned").remove(&sock);
+    if let Some(a) = removed {
+        COUNT.fetch_sub(1, Ordering::Relaxed);
+        debug!("udp socket {} released relay {}", sock, a.relay);
+        if let Some(control) = a.control {
+            nix::unistd::close(control).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: [u8; 10] = [0, 0, 0, 1, 10, 0, 0, 1, 0, 53];
+
+    fn source() -> SocketAddr {
+        "10.0.0.1:53".parse().unwrap()
+    }
+
+    #[test]
+    fn small_datagrams_are_not_fragmented() {
+        let packets = fragment(&HEADER, b"query", 100);
+        assert_eq!(packets, [[&HEADER[..], b"query"].concat()]);
+        assert_eq!(fragment(&HEADER, b"", 100), [HEADER.to_vec()]);
+        assert_eq!(fragment(&HEADER, &[7; 100], 100).len(), 1);
+    }
+
+    #[test]
+    fn large_datagrams_are_numbered_fragments() {
+        let buf: Vec<u8> = (0..250).map(|i| i as u8).collect();
+        let packets = fragment(&HEADER, &buf, 100);
+        let frags: Vec<_> = packets.iter().map(|p| p[2]).collect();
+        assert_eq!(frags, [1, 2, 3 | FRAG_LAST]);
+        assert_eq!(packets[2].len(), HEADER.len() + 50);
+        for p in &packets {
+            assert_eq!(p[..2], HEADER[..2]);
+            assert_eq!(p[3..HEADER.len()], HEADER[3..]);
+        }
+        let payload: Vec<u8> = packets
+            .iter()
+            .flat_map(|p| p[HEADER.len()..].to_vec())
+            .collect();
+        assert_eq!(payload, buf);
+    }
+
+    #[test]
+    fn fragments_are_reassembled() {
+        let buf: Vec<u8> = (0..250).map(|i| i as u8).collect();
+        let mut queue = None;
+        let mut datagram = None;
+        for p in fragment(&HEADER, &buf, 100) {
+            assert!(datagram.is_none());
+            datagram = add_fragment(&mut queue, source(), p[2], &p[HEADER.len()..], false).unwrap();
+        }
+        assert_eq!(datagram, Some(buf));
+        assert!(queue.is_none());
+    }
+
+    #[test]
+    fn peeked_datagrams_stay_queued() {
+        let mut queue = None;
+        add_fragment(&mut queue, source(), 1, b"ab", false).unwrap();
+        for _ in 0..2 {
+            let datagram = add_fragment(&mut queue, source(), 2 | FRAG_LAST, b"cd", true);
+            assert_eq!(datagram.unwrap().as_deref(), Some(&b"abcd"[..]));
+        }
+        let datagram = add_fragment(&mut queue, source(), 2 | FRAG_LAST, b"cd", false);
+        assert_eq!(datagram.unwrap().as_deref(), Some(&b"abcd"[..]));
+        assert!(queue.is_none());
+    }
+
+    #[test]
+    fn fragments_out_of_sequence_abandon_the_datagram() {
+        let mut queue = None;
+        assert!(add_fragment(&mut queue, source(), 2, b"cd", false).is_err());
+
+        add_fragment(&mut queue, source(), 1, b"ab", false).unwrap();
+        assert!(add_fragment(&mut queue, source(), 3 | FRAG_LAST, b"ef", false).is_err());
+        assert!(queue.is_none());
+
+        // a fragment of another source starts over
+        add_fragment(&mut queue, source(), 1, b"ab", false).unwrap();
+        let other = "10.0.0.2:53".parse().unwrap();
+        assert!(add_fragment(&mut queue, other, 2 | FRAG_LAST, b"cd", false).is_err());
+    }
+
+    #[test]
+    fn stale_fragments_abandon_the_datagram() {
+        let mut queue = None;
+        add_fragment(&mut queue, source(), 1, b"ab", false).unwrap();
+        if let Some(r) = &mut queue {
+            r.received -= REASSEMBLY_TIMEOUT;
+        }
+        assert!(add_fragment(&mut queue, source(), 2 | FRAG_LAST, b"cd", false).is_err());
+
+        // the first fragment of a new datagram starts over
+        add_fragment(&mut queue, source(), 1, b"xy", false).unwrap();
+        let datagram = add_fragment(&mut queue, source(), 2 | FRAG_LAST, b"z", false);
+        assert_eq!(datagram.unwrap().as_deref(), Some(&b"xyz"[..]));
+    }
+}
@@ -0,0 +1,99 @@
+/// Usernames templated per connection
+///
+/// Some providers select the session or the exit of a connection from the
+/// username it authenticates with, e.g. `user-session-{random}-country-us`.
+/// The variables of the usernames are expanded when a chain is built:
+///
+/// - {conn}: number of the connection in the process, from 1.
+/// - {pid}: process id.
+/// - {host}: destination hostname, or address, empty for the requests made to
+///   the last proxy such as resolutions.
+/// - {port}: destination port, empty likewise.
+/// - {random}: random token of 16 hexadecimal digits, the same for every hop
+///   of the connection.
+///
+/// Other text between braces is kept as is.
+use crate::core::find_ip_hostname;
+use crate::util;
+use once_cell::unsync::OnceCell;
+use proxyc_common::{Auth, ProxyConf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Connections whose chain has been started.
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Values of the variables, fixed for a connection and computed when first
+/// used.
+#[derive(Debug)]
+pub struct Vars<'a> {
+    conn: u64,
+    target: Option<&'a ProxyConf>,
+    random: OnceCell<String>,
+}
+
+impl<'a> Vars<'a> {
+    /// Numbers a new connection to `target`.
+    pub fn new(target: Option<&'a ProxyConf>) -> Self {
+        Vars {
+            conn: CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1,
+            target,
+            random: OnceCell::new(),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "conn" => Some(self.conn.to_string()),
+            "pid" => Some(std::process::id().to_string()),
+            "host" => Some(self.target.map_or_else(String::new, |t| {
+                t.hostname
+                    .clone()
+                    .or_else(|| find_ip_hostname(t.ip))
+                    .unwrap_or_else(|| t.ip.to_string())
+            })),
+            "port" => Some(self.target.map_or_else(String::new, |t| t.port.to_string())),
+            "random" => Some(self.random.get_or_init(random_token).clone()),
+            _ => None,
+        }
+    }
+
+    /// Expands the variables of `template`.
+    pub fn expand(&self, template: &str) -> String {
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let value = rest
+                .find('}')
+                .and_then(|end| Some((self.get(&rest[1..end])?, end)));
+            match value {
+                Some((value, end)) => {
+                    out.push_str(&value);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    out.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Returns `auth` with its username expanded.
+    pub fn auth(&self, auth: Auth) -> Auth {
+        match auth {
+            Auth::UserPassword(user, password) if user.contains('{') => {
+                Auth::UserPassword(self.expand(&user), password)
+            }
+            auth => auth,
+        }
+    }
+}
+
+/// Returns 16 random hexadecimal digits.
+fn random_token() -> String {
+    format!("{:016x}", util::random_u64())
+}
@@ -1,10 +1,23 @@
 /// Utility functions
 use crate::error::Error;
+use crate::transcript;
 use nix::errno::Errno;
+use nix::libc;
 use nix::poll::{poll, PollFd, PollFlags};
 use nix::unistd::read;
-use std::os::unix::io::RawFd;
-use std::time::Instant;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Returns the inode of `fd`, telling the descriptors of a pipe apart from
+/// those the program reused their number for after closing them. fstat() is
+/// async-signal-safe.
+// ino_t is 32 bits wide on some targets
+#[allow(clippy::unnecessary_cast)]
+pub fn inode(fd: RawFd) -> Option<u64> {
+    nix::sys::stat::fstat(fd).ok().map(|st| st.st_ino as u64)
+}
 
 pub fn poll_retry(fds: &mut [PollFd], timeout: usize) -> Result<i32, Error> {
     let now = Instant::now();
@@ -28,6 +41,7 @@ pub fn poll_retry(fds: &mut [PollFd], timeout: usize) -> Result<i32, Error> {
     }
 }
 
+#[allow(clippy::unnecessary_map_or)]
 pub fn read_timeout(fd: RawFd, mut buf: &mut [u8], timeout: usize) -> Result<(), Error> {
     let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
 
@@ -44,6 +58,7 @@ pub fn read_timeout(fd: RawFd, mut buf: &mut [u8], timeout: usize) -> Result<(),
         match read(fd, buf) {
             Ok(0) => break,
             Ok(n) => {
+                transcript::received(&buf[..n]);
                 let tmp = buf;
                 buf = &mut tmp[n..];
             }
@@ -56,3 +71,88 @@ pub fn read_timeout(fd: RawFd, mut buf: &mut [u8], timeout: usize) -> Result<(),
         Ok(())
     }
 }
+
+/// Returns a random number, read from /dev/urandom.
+pub fn random_u64() -> u64 {
+    let mut buf = [0; 8];
+    if let Err(e) = File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut buf)) {
+        warn!("cannot read random bytes: {}", e);
+        // still distinct between calls
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        return nanos ^ u64::from(std::process::id()) << 32;
+    }
+    u64::from_be_bytes(buf)
+}
+
+/// Returns the TCP_INFO of a socket, None if it is not a TCP one.
+pub fn tcp_info(fd: RawFd) -> Option<libc::tcp_info> {
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of_val(&info) as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (ret == 0).then_some(info)
+}
+
+/// A socket read and written without raising SIGPIPE.
+#[derive(Clone, Copy)]
+pub struct FdStream(pub RawFd);
+
+impl io::Read for FdStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        read(self.0, buf).map_err(io::Error::from)
+    }
+}
+
+impl io::Write for FdStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let ret = unsafe {
+            libc::send(
+                self.0,
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        Errno::result(ret)
+            .map(|n| n as usize)
+            .map_err(io::Error::from)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for FdStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// Encodes `bytes` in standard base64, padded.
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,164 @@
+/// Connection events posted to webhook_url
+///
+/// The events are queued and posted in batches, as JSON arrays, by a thread
+/// of each process: once a second, or as soon as BATCH_LEN events are
+/// pending. The requests are made through the hooked connect() as those of
+/// the program, the chain carrying them unless a rule routes them otherwise.
+/// The connections of the webhook are not reported themselves.
+///
+/// Events still queued when the process exits are posted before it ends. A
+/// batch that cannot be posted is dropped, a warning being logged.
+use crate::core::CONFIG;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+use std::cell::Cell;
+use std::io::{BufRead, BufReader, Write};
+use std::mem;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Events pending after which a batch is posted without waiting.
+const BATCH_LEN: usize = 100;
+/// Time waited for more events after the first one of a batch.
+const BATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Events pending, and whether a batch is being posted.
+#[derive(Default)]
+struct Queue {
+    events: Vec<Value>,
+    posting: bool,
+}
+
+/// Queue, and the condition its changes are signaled on.
+static QUEUE: Lazy<(Mutex<Queue>, Condvar)> =
+    Lazy::new(|| (Mutex::new(Queue::default()), Condvar::new()));
+
+/// Process whose thread posts the events, a forked child spawning its own.
+static SENDER_PID: AtomicU32 = AtomicU32::new(0);
+
+thread_local! {
+    /// Whether the thread is posting events, its connections being those of
+    /// the webhook.
+    static POSTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether connection events are posted by the calling thread.
+pub fn enabled() -> bool {
+    CONFIG.webhook_url.is_some() && !POSTING.with(Cell::get)
+}
+
+/// Queues `event` to be posted.
+pub fn push(event: Value) {
+    if !enabled() {
+        return;
+    }
+
+    let (queue, changed) = &*QUEUE;
+    let pid = std::process::id();
+    let mut queue = queue.lock().unwrap();
+    if SENDER_PID.swap(pid, Ordering::Relaxed) != pid {
+        // the events queued before a fork are posted by the parent
+        *queue = Queue::default();
+        spawn_sender();
+    }
+    queue.events.push(event);
+    if queue.events.len() == 1 || queue.events.len() >= BATCH_LEN {
+        changed.notify_all();
+    }
+}
+
+fn spawn_sender() {
+    let spawned = std::thread::Builder::new()
+        .name("proxyc-webhook".into())
+        .spawn(|| {
+            POSTING.with(|p| p.set(true));
+            let (queue, changed) = &*QUEUE;
+            loop {
+                let mut q = queue.lock().unwrap();
+                while q.events.is_empty() {
+                    q = changed.wait(q).unwrap();
+                }
+                // woken up early by flush()
+                if q.events.len() < BATCH_LEN {
+                    q = changed.wait_timeout(q, BATCH_INTERVAL).unwrap().0;
+                }
+                let batch = mem::take(&mut q.events);
+                q.posting = true;
+                drop(q);
+
+                post(&batch);
+                queue.lock().unwrap().posting = false;
+                changed.notify_all();
+            }
+        });
+    if let Err(e) = spawned {
+        warn!("failed to spawn webhook thread: {}", e);
+    }
+}
+
+/// Has the events still queued posted, called on exit. The thread posting
+/// them is waited for, the exiting one being unable to make connections.
+pub fn flush() {
+    if CONFIG.webhook_url.is_none() || SENDER_PID.load(Ordering::Relaxed) != std::process::id() {
+        return;
+    }
+
+    let timeout =
+        Duration::from_millis((CONFIG.tcp_connect_timeout + CONFIG.tcp_read_timeout) as u64);
+    let (queue, changed) = &*QUEUE;
+    let queue = queue.lock().unwrap();
+    changed.notify_all();
+    let (queue, _) = changed
+        .wait_timeout_while(queue, timeout, |q| !q.events.is_empty() || q.posting)
+        .unwrap();
+    if !queue.events.is_empty() {
+        warn!("dropped {} webhook events on exit", queue.events.len());
+    }
+}
+
+fn post(batch: &[Value]) {
+    let (host, port, path) = match CONFIG.webhook_endpoint() {
+        Some(e) => e,
+        None => return,
+    };
+    let authority = match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    };
+    let body = Value::from(batch).to_string();
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        authority,
+        body.len(),
+        body
+    );
+
+    let timeout = Duration::from_millis(CONFIG.tcp_read_timeout as u64);
+    let res = TcpStream::connect((host.as_str(), port)).and_then(|mut stream| {
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.write_all(request.as_bytes())?;
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        Ok(status)
+    });
+    match res {
+        Ok(status) if status.split(' ').nth(1).is_some_and(|c| c.starts_with('2')) => {
+            debug!("posted {} events to the webhook", batch.len())
+        }
+        Ok(status) => warn!(
+            "webhook refused {} events: {}",
+            batch.len(),
+            status.trim_end()
+        ),
+        Err(e) => warn!(
+            "failed to post {} events to the webhook: {}",
+            batch.len(),
+            e
+        ),
+    }
+}
@@ -3,6 +3,7 @@
 # launch microsocks
 microsocks -p 1080 &
 microsocks -u admin -P password -p 1081 &
+microsocks -u 'ad:min' -P 'p@ss:wörd' -p 1082 &
 
 # launch http proxy
 tinyproxy -c /etc/tinyproxy.conf
@@ -45,6 +45,24 @@ def test_socks5_auth():
     assert out == 'OK'
     assert rc == 0
 
+def test_socks5_auth_special_chars():
+    out, rc, err = execute([
+        PROXYC,
+        f'--proxy=socks5://ad%3Amin:p%40ss%3Aw%C3%B6rd@{CONTAINER_IP}:1082',
+        'curl',
+        'http://127.0.0.1:8000'])
+    assert out == 'OK'
+    assert rc == 0
+
+def test_socks5_auth_unencoded_chars():
+    out, rc, err = execute([
+        PROXYC,
+        f'--proxy=socks5://ad%3Amin:p@ss:wörd@{CONTAINER_IP}:1082',
+        'curl',
+        'http://127.0.0.1:8000'])
+    assert out == 'OK'
+    assert rc == 0
+
 def test_http_simple():
     out, rc, err = execute([
         PROXYC,